@@ -0,0 +1,56 @@
+//! Cost of the `metrics` counters for small bulk transfers. There's no device so this compares
+//! the host side work of an `AsyncDevice` transfer (allocating the `Transfer`, its completion link
+//! and a 64 byte buffer) with and without the counting done on submission and completion. Most of
+//! the counting is reading the clock twice for the latency. A real transfer also waits for the
+//! bus, at least a 125 µs high speed microframe, so the overhead is also shown against that.
+use std::time::{Duration, Instant};
+use usbw::libusb::metrics::DeviceMetrics;
+use usbw::libusb::safe_transfer::{SafeTransfer, SafeTransferAsyncLink};
+use usbw::libusb::transfer::{Status, Transfer};
+
+const ITERATIONS: u32 = 200_000;
+const BUF_SIZE: usize = 64;
+const MICROFRAME: Duration = Duration::from_micros(125);
+
+fn bench(mut f: impl FnMut()) -> Duration {
+    // Warm up the allocator
+    for _ in 0..ITERATIONS / 10 {
+        f()
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f()
+    }
+    start.elapsed() / ITERATIONS
+}
+fn transfer() {
+    let mut buf = vec![0_u8; BUF_SIZE];
+    let transfer = SafeTransfer::from_parts(
+        buf.as_mut_slice(),
+        Transfer::new(0),
+        SafeTransferAsyncLink::new(),
+    );
+    drop(transfer);
+}
+fn main() {
+    let metrics = DeviceMetrics::new();
+    let plain = bench(transfer);
+    let counted = bench(|| {
+        let submitted = Instant::now();
+        metrics.record_submit(true);
+        transfer();
+        metrics.record_completion(0x81, Status::Completed, BUF_SIZE, submitted.elapsed());
+    });
+    let extra = counted.saturating_sub(plain).as_nanos() as f64;
+    println!("without metrics {:>10?} per transfer", plain);
+    println!("with metrics    {:>10?} per transfer", counted);
+    println!(
+        "overhead        {:>9.1}% of the host side work, {:.2}% with a microframe on the bus",
+        extra * 100.0 / plain.as_nanos().max(1) as f64,
+        extra * 100.0 / (plain + MICROFRAME).as_nanos() as f64
+    );
+    assert_eq!(
+        metrics.snapshot().submitted,
+        u64::from(ITERATIONS + ITERATIONS / 10)
+    );
+}
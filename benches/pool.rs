@@ -0,0 +1,41 @@
+//! Compares the per-transfer setup cost of `AsyncDevice` (allocating a `Transfer`, its completion
+//! link and a buffer every time) against drawing them from a `Pool`. No device is needed, the IO
+//! itself costs the same either way.
+use std::time::Instant;
+use usbw::libusb::buffer::Pool;
+use usbw::libusb::safe_transfer::{SafeTransfer, SafeTransferAsyncLink};
+use usbw::libusb::transfer::Transfer;
+
+const ITERATIONS: u32 = 100_000;
+const BUF_SIZE: usize = 16 * 1024;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up the allocator
+    for _ in 0..ITERATIONS / 10 {
+        f()
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f()
+    }
+    let elapsed = start.elapsed();
+    println!("{:<12} {:>10?} per transfer", name, elapsed / ITERATIONS);
+}
+// The libusb allocations can't be optimized out so there's no need for `black_box`.
+fn main() {
+    bench("allocating", || {
+        let mut buf = vec![0_u8; BUF_SIZE];
+        let transfer = SafeTransfer::from_parts(
+            buf.as_mut_slice(),
+            Transfer::new(0),
+            SafeTransferAsyncLink::new(),
+        );
+        drop(transfer);
+    });
+    let pool = Pool::new(4, BUF_SIZE);
+    bench("pooled", || {
+        let mut pooled = pool.acquire();
+        let transfer = pooled.safe_transfer();
+        drop(transfer);
+    });
+}
@@ -0,0 +1,47 @@
+//! Staging a header and a payload from separate buffers for one OUT transfer: concatenating them
+//! into a fresh `Vec` (growing it slice by slice), `SafeTransfer::from_io_slices` (one allocation)
+//! and `PooledTransfer::staged_vectored` (no allocation). No device is needed, the IO itself costs
+//! the same either way.
+use std::io::IoSlice;
+use std::time::Instant;
+use usbw::libusb::buffer::Pool;
+use usbw::libusb::safe_transfer::SafeTransfer;
+
+const ITERATIONS: u32 = 100_000;
+const HEADER_SIZE: usize = 16;
+const PAYLOAD_SIZE: usize = 16 * 1024;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up the allocator
+    for _ in 0..ITERATIONS / 10 {
+        f()
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f()
+    }
+    let elapsed = start.elapsed();
+    println!("{:<16} {:>10?} per transfer", name, elapsed / ITERATIONS);
+}
+// The libusb allocations can't be optimized out so there's no need for `black_box`.
+fn main() {
+    let header = [0xA5_u8; HEADER_SIZE];
+    let payload = vec![0x5A_u8; PAYLOAD_SIZE];
+    let bufs = [IoSlice::new(&header), IoSlice::new(&payload)];
+    bench("naive concat", || {
+        let mut data = Vec::new();
+        for buf in &bufs {
+            data.extend_from_slice(buf);
+        }
+        drop(SafeTransfer::from_buf(data));
+    });
+    bench("from_io_slices", || {
+        drop(SafeTransfer::from_io_slices(&bufs));
+    });
+    let pool = Pool::new(4, HEADER_SIZE + PAYLOAD_SIZE);
+    bench("pooled staging", || {
+        let mut pooled = pool.acquire();
+        let transfer = pooled.staged_vectored(&bufs).expect("fits in the pool");
+        drop(transfer);
+    });
+}
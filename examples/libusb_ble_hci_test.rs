@@ -1,32 +1,25 @@
+use futures_util::future::Either;
+use usbw::device::DeviceFilter;
+use usbw::libusb::async_device::AsyncDevice;
 use usbw::libusb::device::Device;
 use usbw::libusb::error::Error;
+use usbw::libusb::hci::{HciTransport, COMMAND_COMPLETE_EVENT};
 
 const WIRELESS_CONTROLLER_CLASS: u8 = 0xE0;
 const SUBCLASS: u8 = 0x01;
 const BLUETOOTH_PROGRAMMING_INTERFACE_PROTOCOL: u8 = 0x01;
-pub fn has_bluetooth_interface(device: &Device) -> Result<bool, Error> {
-    match device.active_config_descriptor() {
-        Ok(config) => Ok(config
-            .interfaces()
-            .iter()
-            .next()
-            .and_then(|i| {
-                i.descriptors().iter().next().map(|d| {
-                    d.class_code() == WIRELESS_CONTROLLER_CLASS
-                        && d.sub_class_code() == SUBCLASS
-                        && d.protocol_code() == BLUETOOTH_PROGRAMMING_INTERFACE_PROTOCOL
-                })
-            })
-            .unwrap_or(false)),
-        Err(usbw::libusb::error::Error::NotFound) => Ok(false),
-        Err(e) => Err(e),
-    }
+pub fn bluetooth_filter() -> DeviceFilter {
+    DeviceFilter::new()
+        .class(WIRELESS_CONTROLLER_CLASS)
+        .subclass(SUBCLASS)
+        .protocol(BLUETOOTH_PROGRAMMING_INTERFACE_PROTOCOL)
 }
 
 pub fn bluetooth_adapters<'a>(
     i: impl Iterator<Item = Device> + 'a,
 ) -> impl Iterator<Item = Result<Device, Error>> + 'a {
-    i.filter_map(|d| match has_bluetooth_interface(&d) {
+    let filter = bluetooth_filter();
+    i.filter_map(move |d| match filter.matches_device(&d) {
         Ok(true) => Some(Ok(d)),
         Ok(false) => None,
         Err(e) => Some(Err(e)),
@@ -41,7 +34,6 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     runtime.block_on(main_async())?;
     Ok(())
 }
-pub const HCI_EVENT_ENDPOINT: u8 = 0x81;
 async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     println!("starting");
     let context = usbw::libusb::context::Context::default()?;
@@ -59,13 +51,21 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         println!("using {:?}", device);
         match device.open() {
             Ok(adapter) => break adapter,
-            Err(usbw::libusb::error::Error::NotSupported) => (),
+            Err(e) if e.error == usbw::libusb::error::Error::NotSupported => (),
+            // Like "Access denied (insufficient permissions) opening 0a12:0001 (bus 3, addr 7)"
             Err(e) => Err(e)?,
         }
     };
-    let context = context.start_async();
-    let handle = context.make_async_device(handle);
-    let mut adapter = handle;
+    // Handle the libusb events on this runtime instead of spawning an event thread
+    let pump = context.event_pump();
+    let adapter = pump.make_async_device(handle)?;
+    let result = match futures_util::future::select(Box::pin(pump), Box::pin(run(adapter))).await {
+        Either::Left((pump_result, _)) => Err(pump_result.err().unwrap_or(Error::Other).into()),
+        Either::Right((result, _)) => result,
+    };
+    result
+}
+async fn run(adapter: AsyncDevice) -> Result<(), Box<dyn std::error::Error>> {
     println!("reset");
     adapter.handle_ref().reset()?;
     println!(
@@ -73,27 +73,17 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         adapter.handle_ref().active_configuration()
     );
     println!("claim");
-    adapter.handle_mut().claim_interface(0)?;
+    let mut hci = HciTransport::new(adapter)?;
     println!("write!");
-    let _n = adapter
-        .control_write(
-            0x20,
-            0,
-            0,
-            0,
-            &[0x03, 0x0C, 0x00],
-            core::time::Duration::from_secs(1),
-        )
-        .await?;
-    println!("reading a byte");
-    let mut out = [0; 7];
-    adapter
-        .interrupt_read(
-            HCI_EVENT_ENDPOINT,
-            &mut out,
-            core::time::Duration::from_secs(1),
-        )
-        .await?;
-    println!("out {:?}", out);
+    // HCI_Reset
+    hci.send_command(&[0x03, 0x0C, 0x00]).await?;
+    println!("reading events");
+    loop {
+        let event = hci.read_event().await?;
+        println!("event {:?}", event);
+        if event.first() == Some(&COMMAND_COMPLETE_EVENT) {
+            break;
+        }
+    }
     Ok(())
 }
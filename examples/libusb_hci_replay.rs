@@ -0,0 +1,92 @@
+//! The HCI reset of `libusb_ble_hci_test` recorded against a real Bluetooth adapter and replayed
+//! without one (in CI for example).
+//!
+//! `cargo run --example libusb_hci_replay --features "tokio replay" -- record hci_reset.usbw`
+//! `cargo run --example libusb_hci_replay --features "tokio replay" -- replay hci_reset.usbw`
+use futures_util::future::Either;
+use usbw::device::DeviceFilter;
+use usbw::libusb::error::Error;
+use usbw::replay::{RecordingDevice, ReplayDevice};
+use usbw::traits::AsyncUsbHandle;
+
+const WIRELESS_CONTROLLER_CLASS: u8 = 0xE0;
+const SUBCLASS: u8 = 0x01;
+const BLUETOOTH_PROGRAMMING_INTERFACE_PROTOCOL: u8 = 0x01;
+const HCI_EVENT_ENDPOINT: u8 = 0x81;
+/// Event code + length + 255 bytes of parameters.
+const HCI_EVENT_MAX_SIZE: usize = 257;
+const HCI_COMMAND_COMPLETE_EVENT: u8 = 0x0E;
+const TIMEOUT: core::time::Duration = core::time::Duration::from_secs(1);
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: libusb_hci_replay (record|replay) FILE";
+    let mode = args.next().ok_or(usage)?;
+    let path = args.next().ok_or(usage)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("can't make async runtime");
+    match mode.as_str() {
+        "record" => runtime.block_on(record(&path)),
+        "replay" => {
+            let replay = ReplayDevice::load(&path)?;
+            let event = runtime.block_on(hci_reset(&replay))?;
+            if !replay.is_finished() {
+                return Err(
+                    format!("{} recorded transfers not replayed", replay.remaining()).into(),
+                );
+            }
+            println!("replayed, command complete {:02x?}", event);
+            Ok(())
+        }
+        _ => Err(usage.into()),
+    }
+}
+/// The driver under test. Works with any backend's async handle.
+async fn hci_reset<H: AsyncUsbHandle>(adapter: &H) -> Result<Vec<u8>, H::Error> {
+    adapter
+        .control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x00], TIMEOUT)
+        .await?;
+    let mut event = [0_u8; HCI_EVENT_MAX_SIZE];
+    loop {
+        let len = adapter
+            .interrupt_read(HCI_EVENT_ENDPOINT, &mut event, TIMEOUT)
+            .await?;
+        if event[..len].first() == Some(&HCI_COMMAND_COMPLETE_EVENT) {
+            return Ok(event[..len].to_vec());
+        }
+    }
+}
+async fn record(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let context = usbw::libusb::context::Context::default()?;
+    let filter = DeviceFilter::new()
+        .class(WIRELESS_CONTROLLER_CLASS)
+        .subclass(SUBCLASS)
+        .protocol(BLUETOOTH_PROGRAMMING_INTERFACE_PROTOCOL);
+    let device = context
+        .find_devices(&filter)
+        .into_iter()
+        .next()
+        .ok_or("no Bluetooth adapter found")?;
+    // `to_string` so the error says which device failed and in which call
+    let mut handle = device.open().map_err(|e| e.to_string())?;
+    handle.claim_interface(0).map_err(|e| e.to_string())?;
+    let pump = context.event_pump();
+    let recorder = RecordingDevice::new(pump.make_async_device(handle)?);
+    let result =
+        match futures_util::future::select(Box::pin(pump), Box::pin(hci_reset(&recorder))).await {
+            Either::Left((pump_result, _)) => Err(pump_result.err().unwrap_or(Error::Other)),
+            Either::Right((result, _)) => result,
+        };
+    let event = result?;
+    let session = recorder.session();
+    session.save(path)?;
+    println!(
+        "recorded {} transfers to {}, command complete {:02x?}",
+        session.records.len(),
+        path,
+        event
+    );
+    Ok(())
+}
@@ -4,9 +4,9 @@ pub fn main() -> Result<(), usbw::libusb::error::Error> {
     context.hotplug_register_callback(
         |_context, d, e| {
             println!("{:?} {:?}", d, e);
-            true
+            usbw::libusb::hotplug::Action::Keep
         },
-        usbw::libusb::hotplug::Event::Both,
+        usbw::libusb::hotplug::EventMask::BOTH,
         usbw::libusb::hotplug::Flags::Enumerate,
         None,
         None,
@@ -0,0 +1,20 @@
+//! `lsusb -v` for every device, or only `vvvv:pppp` if given.
+use usbw::device::DeviceIdentifier;
+use usbw::libusb;
+pub fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let filter = match std::env::args().nth(1) {
+        Some(id) => Some(id.parse::<DeviceIdentifier>()?),
+        None => None,
+    };
+    let context = libusb::context::Context::new()?;
+    for device in context.device_list().iter() {
+        match device.probe() {
+            Ok(report) => match filter {
+                Some(id) if id != report.descriptor.device_identifier => (),
+                _ => println!("{}", report),
+            },
+            Err(e) => eprintln!("couldn't probe {:?}: {}", device, e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use futures_util::future::Either;
+use usbw::descriptors::ParsedConfig;
+use usbw::libusb::device::Device;
+use usbw::libusb::error::Error;
+use usbw::libusb::msc::{bulk_only_endpoints, BulkOnly};
+
+/// Sectors dumped from the start of the disk.
+const SECTORS: u32 = 2;
+
+/// First Bulk-Only interface of `device`'s active configuration.
+fn bulk_only_interface(device: &Device) -> Result<Option<u8>, Error> {
+    let config = ParsedConfig::from(&device.active_config_descriptor()?);
+    Ok(config
+        .interfaces
+        .iter()
+        .map(|i| i.number)
+        .find(|&i| bulk_only_endpoints(&config, i).is_ok()))
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("can't make async runtime");
+    runtime.block_on(main_async())?;
+    Ok(())
+}
+async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
+    let context = usbw::libusb::context::Context::default()?;
+    let device_list = context.device_list();
+    let (device, interface) = device_list
+        .iter()
+        .find_map(|d| match bulk_only_interface(&d) {
+            Ok(Some(interface)) => Some((d, interface)),
+            _ => None,
+        })
+        .ok_or_else(|| String::from("no mass storage device found"))?;
+    println!("using {:?} interface {}", device, interface);
+    // `to_string` so the error says which device failed to open
+    let handle = device.open().map_err(|e| e.to_string())?;
+    let pump = context.event_pump();
+    let device = pump.make_async_device(handle)?;
+    let result = match futures_util::future::select(
+        Box::pin(pump),
+        Box::pin(run(BulkOnly::new(device, interface)?)),
+    )
+    .await
+    {
+        Either::Left((pump_result, _)) => Err(pump_result.err().unwrap_or(Error::Other).into()),
+        Either::Right((result, _)) => result,
+    };
+    result
+}
+async fn run(mut disk: BulkOnly) -> Result<(), Box<dyn std::error::Error>> {
+    println!("max LUN {}", disk.max_lun().await?);
+    let inquiry = disk.inquiry().await?;
+    println!(
+        "{} {} {}",
+        inquiry.vendor, inquiry.product, inquiry.revision
+    );
+    let capacity = disk.read_capacity().await?;
+    println!(
+        "{} blocks of {} bytes ({} bytes)",
+        capacity.blocks(),
+        capacity.block_len,
+        capacity.bytes()
+    );
+    let mut buf = vec![0_u8; (SECTORS * capacity.block_len) as usize];
+    let len = disk.read_blocks(0, capacity.block_len, &mut buf).await?;
+    for (i, line) in buf[..len].chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{:08x}  {}", i * 16, hex.join(" "));
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+//! `libusb_list` but through WinUSB (no libusb needed).
+#[cfg(windows)]
+pub fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    use usbw::winusb::device_list::DeviceList;
+    for device in &DeviceList::new()? {
+        match device.device_identifier() {
+            Some(identifier) => println!("{}", identifier),
+            None => println!("unknown ({:?})", device.path()),
+        }
+    }
+    Ok(())
+}
+#[cfg(not(windows))]
+pub fn main() {
+    eprintln!("winusb is only available on Windows");
+}
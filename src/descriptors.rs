@@ -0,0 +1,788 @@
+//! Parsing standard descriptors from their raw bytes and serializing them back. Doesn't need a
+//! backend (works on `no_std`), so blobs from anywhere (a management protocol, a capture file)
+//! can be parsed.
+use crate::device::{Codes, Descriptor, DeviceIdentifier, ProductID, StringIndices, VendorID};
+use crate::version::Version;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+pub const DEVICE_DESCRIPTOR_SIZE: usize = 18;
+pub const CONFIG_DESCRIPTOR_SIZE: usize = 9;
+pub const INTERFACE_DESCRIPTOR_SIZE: usize = 9;
+pub const ENDPOINT_DESCRIPTOR_SIZE: usize = 7;
+pub const DEVICE_DESCRIPTOR_TYPE: u8 = 0x01;
+pub const CONFIG_DESCRIPTOR_TYPE: u8 = 0x02;
+pub const INTERFACE_DESCRIPTOR_TYPE: u8 = 0x04;
+pub const ENDPOINT_DESCRIPTOR_TYPE: u8 = 0x05;
+pub const INTERFACE_ASSOCIATION_DESCRIPTOR_SIZE: usize = 8;
+pub const INTERFACE_ASSOCIATION_DESCRIPTOR_TYPE: u8 = 0x0B;
+
+/// Where and why a descriptor blob couldn't be parsed. `offset` is the start of the descriptor
+/// at fault.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ParseError {
+    /// The descriptor runs past the end of the blob (or `wTotalLength`).
+    Truncated { offset: usize },
+    /// `bLength` is too small for the descriptor type.
+    BadLength { offset: usize },
+    /// The blob doesn't start with the expected descriptor type.
+    UnexpectedType { offset: usize, descriptor_type: u8 },
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { offset } => {
+                write!(f, "descriptor at offset {} is truncated", offset)
+            }
+            ParseError::BadLength { offset } => {
+                write!(f, "descriptor at offset {} has a bad bLength", offset)
+            }
+            ParseError::UnexpectedType {
+                offset,
+                descriptor_type,
+            } => write!(
+                f,
+                "unexpected descriptor type 0x{:02X} at offset {}",
+                descriptor_type, offset
+            ),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// A descriptor the parser doesn't know (class specific, SuperSpeed companion, ...). `data` is
+/// everything after `bLength` and `bDescriptorType`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RawDescriptor {
+    pub descriptor_type: u8,
+    pub data: Vec<u8>,
+}
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ParsedEndpoint {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    /// Bytes after the standard fields (`bRefresh` and `bSynchAddress` of audio endpoints).
+    pub trailing: Vec<u8>,
+    /// Descriptors following the endpoint.
+    pub extra: Vec<RawDescriptor>,
+}
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ParsedAltSetting {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub codes: Codes,
+    pub string_index: Option<u8>,
+    pub endpoints: Vec<ParsedEndpoint>,
+    /// Descriptors between the interface and its first endpoint.
+    pub extra: Vec<RawDescriptor>,
+}
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ParsedInterface {
+    pub number: u8,
+    pub alt_settings: Vec<ParsedAltSetting>,
+}
+/// Groups the interfaces of one function of a composite device (like the control and data
+/// interfaces of a CDC-ACM port).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InterfaceAssociation {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_sub_class: u8,
+    pub function_protocol: u8,
+    pub function_string_index: Option<u8>,
+}
+impl InterfaceAssociation {
+    /// From a descriptor's type and the bytes after `bDescriptorType`. `None` if it isn't an
+    /// interface association or is too short.
+    pub fn from_descriptor(descriptor_type: u8, data: &[u8]) -> Option<InterfaceAssociation> {
+        if descriptor_type != INTERFACE_ASSOCIATION_DESCRIPTOR_TYPE {
+            return None;
+        }
+        match *data {
+            [first, count, class, sub_class, protocol, string, ..] => Some(InterfaceAssociation {
+                first_interface: first,
+                interface_count: count,
+                function_class: class,
+                function_sub_class: sub_class,
+                function_protocol: protocol,
+                function_string_index: string_index(string),
+            }),
+            _ => None,
+        }
+    }
+    /// If `interface` is one of the function's.
+    pub fn contains(&self, interface: u8) -> bool {
+        interface >= self.first_interface
+            && u16::from(interface)
+                < u16::from(self.first_interface) + u16::from(self.interface_count)
+    }
+}
+/// An owned configuration descriptor with everything under it (`wTotalLength` bytes).
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ParsedConfig {
+    pub configuration_value: u8,
+    pub string_index: Option<u8>,
+    pub attributes: u8,
+    /// In 2 mA units (8 mA for SuperSpeed).
+    pub max_power: u8,
+    /// In the order their first alt setting appears.
+    pub interfaces: Vec<ParsedInterface>,
+    /// Descriptors before the first interface (interface associations, ...).
+    pub extra: Vec<RawDescriptor>,
+}
+
+fn string_index(index: u8) -> Option<u8> {
+    match index {
+        0 => None,
+        n => Some(n),
+    }
+}
+fn u16_at(buf: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([buf[i], buf[i + 1]])
+}
+/// Checks the descriptor at the start of `buf` is `descriptor_type` and at least `size` long.
+fn check_header(buf: &[u8], size: usize, descriptor_type: u8) -> Result<(), ParseError> {
+    if buf.len() < 2 {
+        return Err(ParseError::Truncated { offset: 0 });
+    }
+    if buf[1] != descriptor_type {
+        return Err(ParseError::UnexpectedType {
+            offset: 0,
+            descriptor_type: buf[1],
+        });
+    }
+    if usize::from(buf[0]) < size {
+        return Err(ParseError::BadLength { offset: 0 });
+    }
+    if buf.len() < size {
+        return Err(ParseError::Truncated { offset: 0 });
+    }
+    Ok(())
+}
+/// Parses a device descriptor (as read with `GET_DESCRIPTOR`). Bytes after `bLength` are ignored.
+pub fn parse_device_descriptor(buf: &[u8]) -> Result<Descriptor, ParseError> {
+    check_header(buf, DEVICE_DESCRIPTOR_SIZE, DEVICE_DESCRIPTOR_TYPE)?;
+    Ok(Descriptor {
+        usb_version: Version(u16_at(buf, 2)),
+        codes: Codes {
+            class: buf[4],
+            sub_class: buf[5],
+            protocol: buf[6],
+        },
+        max_packet_size: buf[7],
+        device_identifier: DeviceIdentifier {
+            vendor_id: VendorID(u16_at(buf, 8)),
+            product_id: ProductID(u16_at(buf, 10)),
+        },
+        device_version: Version(u16_at(buf, 12)),
+        string_indices: StringIndices {
+            manufacturer: string_index(buf[14]),
+            product: string_index(buf[15]),
+            serial_number: string_index(buf[16]),
+        },
+        num_configurations: buf[17],
+    })
+}
+impl TryFrom<&[u8]> for Descriptor {
+    type Error = ParseError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        parse_device_descriptor(buf)
+    }
+}
+/// Parses a full configuration descriptor blob. Only the first `wTotalLength` bytes are used.
+/// `bNumInterfaces` isn't checked against the interfaces found.
+pub fn parse_config_descriptor(buf: &[u8]) -> Result<ParsedConfig, ParseError> {
+    check_header(buf, CONFIG_DESCRIPTOR_SIZE, CONFIG_DESCRIPTOR_TYPE)?;
+    let total_len = usize::from(u16_at(buf, 2));
+    if total_len < usize::from(buf[0]) {
+        return Err(ParseError::BadLength { offset: 0 });
+    }
+    if total_len > buf.len() {
+        return Err(ParseError::Truncated { offset: 0 });
+    }
+    let mut config = ParsedConfig {
+        configuration_value: buf[5],
+        string_index: string_index(buf[6]),
+        attributes: buf[7],
+        max_power: buf[8],
+        interfaces: Vec::new(),
+        extra: Vec::new(),
+    };
+    let buf = &buf[..total_len];
+    // (interface index, alt setting index) and if an endpoint of it was the last descriptor
+    let mut current: Option<(usize, usize, bool)> = None;
+    let mut offset = usize::from(buf[0]);
+    while offset < total_len {
+        let rest = &buf[offset..];
+        if rest.len() < 2 {
+            return Err(ParseError::Truncated { offset });
+        }
+        let len = usize::from(rest[0]);
+        if len < 2 {
+            return Err(ParseError::BadLength { offset });
+        }
+        if len > rest.len() {
+            return Err(ParseError::Truncated { offset });
+        }
+        let d = &rest[..len];
+        match d[1] {
+            INTERFACE_DESCRIPTOR_TYPE => {
+                if len < INTERFACE_DESCRIPTOR_SIZE {
+                    return Err(ParseError::BadLength { offset });
+                }
+                let alt = ParsedAltSetting {
+                    interface_number: d[2],
+                    alternate_setting: d[3],
+                    codes: Codes {
+                        class: d[5],
+                        sub_class: d[6],
+                        protocol: d[7],
+                    },
+                    string_index: string_index(d[8]),
+                    endpoints: Vec::new(),
+                    extra: Vec::new(),
+                };
+                let index = match config.interfaces.iter().position(|i| i.number == d[2]) {
+                    Some(index) => index,
+                    None => {
+                        config.interfaces.push(ParsedInterface {
+                            number: d[2],
+                            alt_settings: Vec::new(),
+                        });
+                        config.interfaces.len() - 1
+                    }
+                };
+                let alt_settings = &mut config.interfaces[index].alt_settings;
+                alt_settings.push(alt);
+                current = Some((index, alt_settings.len() - 1, false));
+            }
+            ENDPOINT_DESCRIPTOR_TYPE if current.is_some() => {
+                if len < ENDPOINT_DESCRIPTOR_SIZE {
+                    return Err(ParseError::BadLength { offset });
+                }
+                let (interface, alt, _) = current.expect("checked by the match guard");
+                config.interfaces[interface].alt_settings[alt]
+                    .endpoints
+                    .push(ParsedEndpoint {
+                        address: d[2],
+                        attributes: d[3],
+                        max_packet_size: u16_at(d, 4),
+                        interval: d[6],
+                        trailing: d[ENDPOINT_DESCRIPTOR_SIZE..].to_vec(),
+                        extra: Vec::new(),
+                    });
+                current = Some((interface, alt, true));
+            }
+            descriptor_type => {
+                let raw = RawDescriptor {
+                    descriptor_type,
+                    data: d[2..].to_vec(),
+                };
+                let extra = match current {
+                    Some((interface, alt, true)) => {
+                        let alt = &mut config.interfaces[interface].alt_settings[alt];
+                        &mut alt
+                            .endpoints
+                            .last_mut()
+                            .expect("endpoint was just parsed")
+                            .extra
+                    }
+                    Some((interface, alt, false)) => {
+                        &mut config.interfaces[interface].alt_settings[alt].extra
+                    }
+                    None => &mut config.extra,
+                };
+                extra.push(raw);
+            }
+        }
+        offset += len;
+    }
+    Ok(config)
+}
+impl TryFrom<&[u8]> for ParsedConfig {
+    type Error = ParseError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        parse_config_descriptor(buf)
+    }
+}
+/// Splits a run of descriptors (like libusb's `extra` bytes) without interpreting them.
+pub fn parse_raw_descriptors(buf: &[u8]) -> Result<Vec<RawDescriptor>, ParseError> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        let rest = &buf[offset..];
+        if rest.len() < 2 {
+            return Err(ParseError::Truncated { offset });
+        }
+        let len = usize::from(rest[0]);
+        if len < 2 {
+            return Err(ParseError::BadLength { offset });
+        }
+        if len > rest.len() {
+            return Err(ParseError::Truncated { offset });
+        }
+        out.push(RawDescriptor {
+            descriptor_type: rest[1],
+            data: rest[2..len].to_vec(),
+        });
+        offset += len;
+    }
+    Ok(out)
+}
+
+impl Descriptor {
+    /// Appends the 18 byte device descriptor to `out`.
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[DEVICE_DESCRIPTOR_SIZE as u8, DEVICE_DESCRIPTOR_TYPE]);
+        out.extend_from_slice(&self.usb_version.0.to_le_bytes());
+        out.extend_from_slice(&[
+            self.codes.class,
+            self.codes.sub_class,
+            self.codes.protocol,
+            self.max_packet_size,
+        ]);
+        out.extend_from_slice(&self.device_identifier.vendor_id.0.to_le_bytes());
+        out.extend_from_slice(&self.device_identifier.product_id.0.to_le_bytes());
+        out.extend_from_slice(&self.device_version.0.to_le_bytes());
+        out.extend_from_slice(&[
+            self.string_indices.manufacturer.unwrap_or(0),
+            self.string_indices.product.unwrap_or(0),
+            self.string_indices.serial_number.unwrap_or(0),
+            self.num_configurations,
+        ]);
+    }
+}
+impl RawDescriptor {
+    /// # Panics
+    /// Panics if `data` is longer than 253 bytes (`bLength` wouldn't fit in a `u8`).
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.push(header_length(2 + self.data.len()));
+        out.push(self.descriptor_type);
+        out.extend_from_slice(&self.data);
+    }
+}
+impl ParsedEndpoint {
+    /// Appends the endpoint descriptor and the descriptors following it.
+    /// # Panics
+    /// Panics if a descriptor is too long for `bLength`.
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            header_length(ENDPOINT_DESCRIPTOR_SIZE + self.trailing.len()),
+            ENDPOINT_DESCRIPTOR_TYPE,
+            self.address,
+            self.attributes,
+        ]);
+        out.extend_from_slice(&self.max_packet_size.to_le_bytes());
+        out.push(self.interval);
+        out.extend_from_slice(&self.trailing);
+        serialize_all(&self.extra, out);
+    }
+}
+impl ParsedAltSetting {
+    /// Appends the interface descriptor (with `bNumEndpoints` from `endpoints`), its extra
+    /// descriptors and its endpoints.
+    /// # Panics
+    /// Panics if there are more than 255 endpoints or a descriptor is too long for `bLength`.
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            INTERFACE_DESCRIPTOR_SIZE as u8,
+            INTERFACE_DESCRIPTOR_TYPE,
+            self.interface_number,
+            self.alternate_setting,
+            u8::try_from(self.endpoints.len()).expect("more than 255 endpoints"),
+            self.codes.class,
+            self.codes.sub_class,
+            self.codes.protocol,
+            self.string_index.unwrap_or(0),
+        ]);
+        serialize_all(&self.extra, out);
+        for endpoint in &self.endpoints {
+            endpoint.serialize(out);
+        }
+    }
+}
+impl ParsedConfig {
+    /// All the descriptors the parser didn't know, wherever they ended up (the configuration's,
+    /// the alt settings' and the endpoints' `extra`).
+    pub fn raw_descriptors(&self) -> impl Iterator<Item = &RawDescriptor> {
+        self.extra
+            .iter()
+            .chain(self.interfaces.iter().flat_map(|interface| {
+                interface.alt_settings.iter().flat_map(|alt| {
+                    alt.extra
+                        .iter()
+                        .chain(alt.endpoints.iter().flat_map(|e| e.extra.iter()))
+                })
+            }))
+    }
+    /// The interface associations in the order they appear. Too short ones are skipped.
+    pub fn interface_associations(&self) -> impl Iterator<Item = InterfaceAssociation> + '_ {
+        self.raw_descriptors()
+            .filter_map(|d| InterfaceAssociation::from_descriptor(d.descriptor_type, &d.data))
+    }
+    /// The interfaces of `association`'s function.
+    pub fn function_interfaces(&self, association: &InterfaceAssociation) -> Vec<&ParsedInterface> {
+        self.interfaces
+            .iter()
+            .filter(|i| association.contains(i.number))
+            .collect()
+    }
+    /// Serializes the whole configuration. `wTotalLength` and `bNumInterfaces` are computed from
+    /// the descriptors instead of being stored. Alt settings are written grouped by interface.
+    /// # Panics
+    /// Panics if the configuration is longer than `u16::MAX` bytes, has more than 255
+    /// interfaces or a descriptor is too long for `bLength`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&[CONFIG_DESCRIPTOR_SIZE as u8, CONFIG_DESCRIPTOR_TYPE, 0, 0]);
+        out.extend_from_slice(&[
+            u8::try_from(self.interfaces.len()).expect("more than 255 interfaces"),
+            self.configuration_value,
+            self.string_index.unwrap_or(0),
+            self.attributes,
+            self.max_power,
+        ]);
+        serialize_all(&self.extra, &mut out);
+        for alt in self.interfaces.iter().flat_map(|i| i.alt_settings.iter()) {
+            alt.serialize(&mut out);
+        }
+        let total_len = u16::try_from(out.len()).expect("wTotalLength overflow");
+        out[2..4].copy_from_slice(&total_len.to_le_bytes());
+        out
+    }
+}
+fn header_length(len: usize) -> u8 {
+    u8::try_from(len).expect("descriptor too long for bLength")
+}
+fn serialize_all(descriptors: &[RawDescriptor], out: &mut Vec<u8>) {
+    for descriptor in descriptors {
+        descriptor.serialize(out);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::descriptors::{
+        parse_config_descriptor, parse_device_descriptor, parse_raw_descriptors,
+        InterfaceAssociation, ParseError, ParsedAltSetting, ParsedInterface,
+    };
+    use crate::device::{Codes, Descriptor, DeviceIdentifier, ProductID, VendorID};
+    use crate::version::Version;
+    use core::convert::TryFrom;
+
+    const RAW: [u8; 18] = [
+        18, 1, 0x10, 0x02, 0xE0, 0x01, 0x01, 64, 0x12, 0x0A, 0x01, 0x00, 0x91, 0x88, 0, 2, 3, 1,
+    ];
+    /// Bluetooth dongle: HCI interface with 3 endpoints and a SCO interface with 2 alt settings.
+    const BLUETOOTH_CONFIG: [u8; 85] = [
+        9, 2, 85, 0, 2, 1, 0, 0xE0, 50, // config
+        9, 4, 0, 0, 3, 0xE0, 1, 1, 0, // interface 0
+        7, 5, 0x81, 3, 16, 0, 1, // event endpoint
+        7, 5, 0x82, 2, 64, 0, 1, // ACL in
+        7, 5, 0x02, 2, 64, 0, 1, // ACL out
+        9, 4, 1, 0, 2, 0xE0, 1, 1, 0, // interface 1 alt 0
+        7, 5, 0x83, 1, 0, 0, 1, // SCO in
+        7, 5, 0x03, 1, 0, 0, 1, // SCO out
+        9, 4, 1, 1, 2, 0xE0, 1, 1, 0, // interface 1 alt 1
+        7, 5, 0x83, 1, 9, 0, 1, // SCO in
+        7, 5, 0x03, 1, 9, 0, 1, // SCO out
+    ];
+    /// HID keyboard: a class specific (HID) descriptor between the interface and endpoint.
+    const HID_CONFIG: [u8; 34] = [
+        9, 2, 34, 0, 1, 1, 0, 0xA0, 50, // config
+        9, 4, 0, 0, 1, 3, 1, 1, 0, // interface
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0, // HID
+        7, 5, 0x81, 3, 8, 0, 10, // endpoint
+    ];
+    /// Flash drive: one bulk only mass storage interface.
+    const MASS_STORAGE_CONFIG: [u8; 32] = [
+        9, 2, 32, 0, 1, 1, 0, 0x80, 100, // config
+        9, 4, 0, 0, 2, 8, 6, 0x50, 0, // interface
+        7, 5, 0x81, 2, 0, 2, 0, // bulk in
+        7, 5, 0x02, 2, 0, 2, 0, // bulk out
+    ];
+    /// Webcam style: an interface association before the first interface and a class specific
+    /// descriptor after an endpoint.
+    const ASSOCIATION_CONFIG: [u8; 49] = [
+        9, 2, 49, 0, 2, 1, 0, 0x80, 250, // config
+        8, 0x0B, 0, 2, 0x0E, 3, 0, 0, // interface association
+        9, 4, 0, 0, 1, 0x0E, 1, 0, 0, // control interface
+        7, 5, 0x83, 3, 16, 0, 6, // interrupt endpoint
+        5, 0x25, 3, 16, 0, // class specific endpoint
+        9, 4, 1, 0, 0, 0x0E, 2, 0, 0, // streaming interface
+        2, 0x24, // empty class specific descriptor
+    ];
+    /// Composite device: two functions, the second association lands after an endpoint, plus a
+    /// descriptor claiming to be an association that's too short.
+    const COMPOSITE_CONFIG: [u8; 70] = [
+        9, 2, 70, 0, 3, 1, 0, 0x80, 50, // config
+        8, 0x0B, 0, 2, 0x02, 0x02, 0x01, 4, // interface association 0-1
+        9, 4, 0, 0, 1, 0x02, 0x02, 0x01, 0, // communication interface
+        7, 5, 0x83, 3, 16, 0, 16, // notification endpoint
+        9, 4, 1, 0, 0, 0x0A, 0, 0, 0, // data interface
+        8, 0x0B, 2, 1, 0x03, 0, 0, 0, // interface association 2
+        4, 0x0B, 3, 1, // too short
+        9, 4, 2, 0, 1, 0x03, 0, 0, 0, // HID interface
+        7, 5, 0x81, 3, 8, 0, 10, // endpoint
+    ];
+    #[test]
+    pub fn test_parse_device_descriptor() {
+        let d = parse_device_descriptor(&RAW).expect("valid descriptor");
+        assert_eq!(d.usb_version, Version::from_bcd(0x0210));
+        assert_eq!(
+            (d.codes.class, d.codes.sub_class, d.codes.protocol),
+            (0xE0, 0x01, 0x01)
+        );
+        assert_eq!(d.max_packet_size, 64);
+        assert_eq!(
+            d.device_identifier,
+            DeviceIdentifier {
+                vendor_id: VendorID(0x0A12),
+                product_id: ProductID(0x0001),
+            }
+        );
+        assert_eq!(d.device_version, Version(0x8891));
+        assert_eq!(d.string_indices.manufacturer, None);
+        assert_eq!(d.string_indices.product, Some(2));
+        assert_eq!(d.string_indices.serial_number, Some(3));
+        assert_eq!(d.num_configurations, 1);
+        assert_eq!(Descriptor::try_from(&RAW[..]), Ok(d));
+    }
+    #[test]
+    pub fn test_parse_bad_device_descriptor() {
+        assert_eq!(
+            parse_device_descriptor(&RAW[..17]),
+            Err(ParseError::Truncated { offset: 0 })
+        );
+        let mut wrong_type = RAW;
+        wrong_type[1] = 0x02;
+        assert_eq!(
+            parse_device_descriptor(&wrong_type),
+            Err(ParseError::UnexpectedType {
+                offset: 0,
+                descriptor_type: 0x02
+            })
+        );
+        let mut short_length = RAW;
+        short_length[0] = 9;
+        assert_eq!(
+            parse_device_descriptor(&short_length),
+            Err(ParseError::BadLength { offset: 0 })
+        );
+        // Trailing bytes are fine
+        let mut long = [0_u8; 20];
+        long[..18].copy_from_slice(&RAW);
+        assert!(parse_device_descriptor(&long).is_ok());
+    }
+    #[test]
+    pub fn test_parse_config_descriptor() {
+        let config = parse_config_descriptor(&BLUETOOTH_CONFIG).expect("valid config");
+        assert_eq!(config.configuration_value, 1);
+        assert_eq!(config.attributes, 0xE0);
+        assert_eq!(config.max_power, 50);
+        assert_eq!(config.interfaces.len(), 2);
+        let hci = &config.interfaces[0];
+        assert_eq!(hci.number, 0);
+        assert_eq!(hci.alt_settings.len(), 1);
+        let addresses: Vec<u8> = hci.alt_settings[0]
+            .endpoints
+            .iter()
+            .map(|e| e.address)
+            .collect();
+        assert_eq!(addresses, vec![0x81, 0x82, 0x02]);
+        assert_eq!(hci.alt_settings[0].endpoints[1].max_packet_size, 64);
+        let sco = &config.interfaces[1];
+        assert_eq!(sco.alt_settings.len(), 2);
+        assert_eq!(sco.alt_settings[1].alternate_setting, 1);
+        assert_eq!(sco.alt_settings[1].endpoints[0].max_packet_size, 9);
+        assert_eq!(sco.alt_settings[1].codes.class, 0xE0);
+    }
+    #[test]
+    pub fn test_parse_config_class_specific() {
+        let config = parse_config_descriptor(&HID_CONFIG).expect("valid config");
+        let alt = &config.interfaces[0].alt_settings[0];
+        assert_eq!(alt.extra.len(), 1);
+        assert_eq!(alt.extra[0].descriptor_type, 0x21);
+        assert_eq!(alt.extra[0].data, HID_CONFIG[20..27].to_vec());
+        assert_eq!(alt.endpoints.len(), 1);
+        assert_eq!(alt.endpoints[0].interval, 10);
+        assert!(alt.endpoints[0].extra.is_empty());
+    }
+    #[test]
+    pub fn test_interface_associations() {
+        let config = parse_config_descriptor(&COMPOSITE_CONFIG).expect("valid config");
+        let associations: Vec<InterfaceAssociation> = config.interface_associations().collect();
+        assert_eq!(
+            associations,
+            vec![
+                InterfaceAssociation {
+                    first_interface: 0,
+                    interface_count: 2,
+                    function_class: 0x02,
+                    function_sub_class: 0x02,
+                    function_protocol: 0x01,
+                    function_string_index: Some(4),
+                },
+                InterfaceAssociation {
+                    first_interface: 2,
+                    interface_count: 1,
+                    function_class: 0x03,
+                    function_sub_class: 0,
+                    function_protocol: 0,
+                    function_string_index: None,
+                },
+            ]
+        );
+        let numbers: Vec<u8> = config
+            .function_interfaces(&associations[0])
+            .iter()
+            .map(|i| i.number)
+            .collect();
+        assert_eq!(numbers, vec![0, 1]);
+        assert_eq!(config.function_interfaces(&associations[1]).len(), 1);
+        let one = parse_config_descriptor(&ASSOCIATION_CONFIG).expect("valid config");
+        assert_eq!(one.interface_associations().count(), 1);
+        let last = InterfaceAssociation {
+            first_interface: 255,
+            interface_count: 2,
+            ..associations[0]
+        };
+        assert!(last.contains(255) && !last.contains(0));
+    }
+    #[test]
+    pub fn test_parse_raw_descriptors() {
+        let raw = parse_raw_descriptors(&HID_CONFIG[18..]).expect("valid descriptors");
+        let types: Vec<u8> = raw.iter().map(|d| d.descriptor_type).collect();
+        assert_eq!(types, vec![0x21, 0x05]);
+        assert_eq!(
+            parse_raw_descriptors(&HID_CONFIG[18..26]),
+            Err(ParseError::Truncated { offset: 0 })
+        );
+        assert_eq!(parse_raw_descriptors(&[]), Ok(Vec::new()));
+    }
+    #[test]
+    pub fn test_parse_config_truncated() {
+        assert_eq!(
+            parse_config_descriptor(&BLUETOOTH_CONFIG[..84]),
+            Err(ParseError::Truncated { offset: 0 })
+        );
+        // wTotalLength cuts the last endpoint in half
+        let mut cut = BLUETOOTH_CONFIG;
+        cut[2] = 82;
+        assert_eq!(
+            parse_config_descriptor(&cut),
+            Err(ParseError::Truncated { offset: 78 })
+        );
+        let mut zero_length = HID_CONFIG;
+        zero_length[18] = 0;
+        assert_eq!(
+            parse_config_descriptor(&zero_length),
+            Err(ParseError::BadLength { offset: 18 })
+        );
+    }
+    #[test]
+    pub fn test_device_descriptor_round_trip() {
+        let mut out = Vec::new();
+        parse_device_descriptor(&RAW)
+            .expect("valid descriptor")
+            .serialize(&mut out);
+        assert_eq!(out, RAW.to_vec());
+    }
+    #[test]
+    pub fn test_config_round_trip() {
+        for blob in &[
+            &BLUETOOTH_CONFIG[..],
+            &HID_CONFIG[..],
+            &MASS_STORAGE_CONFIG[..],
+            &ASSOCIATION_CONFIG[..],
+            &COMPOSITE_CONFIG[..],
+        ] {
+            let config = parse_config_descriptor(blob).expect("valid config");
+            assert_eq!(config.serialize(), blob.to_vec());
+        }
+    }
+    #[test]
+    pub fn test_config_serialize_recomputes_lengths() {
+        let mut config = parse_config_descriptor(&MASS_STORAGE_CONFIG).expect("valid config");
+        config.interfaces.push(ParsedInterface {
+            number: 1,
+            alt_settings: vec![ParsedAltSetting {
+                interface_number: 1,
+                alternate_setting: 0,
+                codes: Codes {
+                    class: 0xFF,
+                    sub_class: 0,
+                    protocol: 0,
+                },
+                string_index: Some(4),
+                endpoints: Vec::new(),
+                extra: Vec::new(),
+            }],
+        });
+        let out = config.serialize();
+        assert_eq!(out.len(), 41);
+        assert_eq!(&out[2..5], &[41, 0, 2]);
+        assert_eq!(&out[32..], &[9, 4, 1, 0, 0, 0xFF, 0, 0, 4]);
+        assert_eq!(parse_config_descriptor(&out), Ok(config));
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn test_serde_round_trip() {
+        let config = parse_config_descriptor(&HID_CONFIG).expect("valid config");
+        let json = serde_json::to_string(&config).expect("serializes");
+        assert_eq!(
+            serde_json::from_str::<crate::descriptors::ParsedConfig>(&json).expect("deserializes"),
+            config
+        );
+        let descriptor = parse_device_descriptor(&RAW).expect("valid descriptor");
+        let json = serde_json::to_value(descriptor).expect("serializes");
+        assert_eq!(json["usb_version"], "2.1.0");
+        assert_eq!(json["device_identifier"], "0a12:0001");
+        assert_eq!(
+            serde_json::from_value::<Descriptor>(json).expect("deserializes"),
+            descriptor
+        );
+    }
+    /// xorshift so the mutations are the same every run.
+    fn next_random(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+    #[test]
+    pub fn test_parse_mutated_blobs() {
+        for blob in &[&BLUETOOTH_CONFIG[..], &HID_CONFIG[..], &RAW[..]] {
+            for len in 0..=blob.len() {
+                let _ = parse_config_descriptor(&blob[..len]);
+                let _ = parse_device_descriptor(&blob[..len]);
+            }
+            let mut state = 0x1234_5678;
+            for _ in 0..10_000 {
+                let mut mutated = blob.to_vec();
+                for _ in 0..=next_random(&mut state) % 4 {
+                    let i = next_random(&mut state) as usize % mutated.len();
+                    mutated[i] = next_random(&mut state) as u8;
+                }
+                let len = next_random(&mut state) as usize % (mutated.len() + 1);
+                let _ = parse_config_descriptor(&mutated);
+                let _ = parse_config_descriptor(&mutated[..len]);
+                let _ = parse_device_descriptor(&mutated);
+            }
+        }
+    }
+}
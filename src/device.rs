@@ -1,10 +1,17 @@
+use crate::error::ConversionError;
 use crate::version::Version;
+use alloc::string::String;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VendorID(pub u16);
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ProductID(pub u16);
+/// Serializes as `vvvv:pppp` in hex (like `lsusb` prints it).
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 pub struct DeviceIdentifier {
     pub vendor_id: VendorID,
@@ -19,16 +26,119 @@ impl core::fmt::Display for DeviceIdentifier {
         )
     }
 }
+impl DeviceIdentifier {
+    pub const fn new(vendor_id: u16, product_id: u16) -> DeviceIdentifier {
+        DeviceIdentifier {
+            vendor_id: VendorID(vendor_id),
+            product_id: ProductID(product_id),
+        }
+    }
+}
+/// Parses 1 to 4 hex digits with an optional `0x` prefix.
+fn parse_hex_id(s: &str, target: &'static str) -> Result<u16, ConversionError> {
+    let err = ConversionError::new(target);
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if digits.is_empty() || digits.len() > 4 {
+        return Err(err.with_detail("bad length"));
+    }
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(err.with_detail("bad hex"));
+    }
+    u16::from_str_radix(digits, 16).map_err(|_| err.with_detail("bad hex"))
+}
+/// Parses hex with an optional `0x` prefix (`0a12`, `0x0A12`).
+impl core::str::FromStr for VendorID {
+    type Err = ConversionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex_id(s, "vendor ID").map(VendorID)
+    }
+}
+/// Parses hex with an optional `0x` prefix (`0001`, `0x1`).
+impl core::str::FromStr for ProductID {
+    type Err = ConversionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex_id(s, "product ID").map(ProductID)
+    }
+}
+/// Parses `vid:pid` where both IDs are parsed like `VendorID`/`ProductID` (`0a12:0001`).
+impl core::str::FromStr for DeviceIdentifier {
+    type Err = ConversionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colon = s.find(':').ok_or_else(|| {
+            ConversionError::new("device identifier").with_detail("missing colon")
+        })?;
+        Ok(DeviceIdentifier {
+            vendor_id: s[..colon].parse()?,
+            product_id: s[colon + 1..].parse()?,
+        })
+    }
+}
+impl core::fmt::LowerHex for VendorID {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+impl core::fmt::UpperHex for VendorID {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+impl core::fmt::LowerHex for ProductID {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+impl core::fmt::UpperHex for ProductID {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+/// `vvvv:pppp` (`0a12:0001`).
+impl core::fmt::LowerHex for DeviceIdentifier {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)
+    }
+}
+/// `VVVV:PPPP` (`0A12:0001`).
+impl core::fmt::UpperHex for DeviceIdentifier {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04X}:{:04X}", self.vendor_id, self.product_id)
+    }
+}
+#[cfg(feature = "serde")]
+impl Serialize for DeviceIdentifier {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:x}", self))
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for DeviceIdentifier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::serde_str::deserialize(deserializer, "a device identifier like 0a12:0001")
+    }
+}
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StringIndices {
     pub manufacturer: Option<u8>,
     pub product: Option<u8>,
     pub serial_number: Option<u8>,
 }
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Codes {
     pub class: u8,
     pub sub_class: u8,
     pub protocol: u8,
 }
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Descriptor {
     pub usb_version: Version,
     pub codes: Codes,
@@ -38,3 +148,181 @@ pub struct Descriptor {
     pub string_indices: StringIndices,
     pub num_configurations: u8,
 }
+/// Unset filter fields match anything.
+fn field_matches<T: PartialEq>(filter: Option<T>, value: T) -> bool {
+    match filter {
+        Some(expected) => expected == value,
+        None => true,
+    }
+}
+/// Matches devices on their IDs, class codes and serial number. Unset fields match anything.
+/// ```
+/// use usbw::device::DeviceFilter;
+/// // Bluetooth adapters
+/// let filter = DeviceFilter::new().class(0xE0).subclass(0x01).protocol(0x01);
+/// ```
+#[derive(Clone, Eq, PartialEq, Debug, Default, Hash)]
+pub struct DeviceFilter {
+    vendor_id: Option<VendorID>,
+    product_id: Option<ProductID>,
+    class: Option<u8>,
+    sub_class: Option<u8>,
+    protocol: Option<u8>,
+    serial_number: Option<String>,
+}
+impl DeviceFilter {
+    pub fn new() -> DeviceFilter {
+        DeviceFilter::default()
+    }
+    pub fn vendor(mut self, vendor_id: VendorID) -> DeviceFilter {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+    pub fn product(mut self, product_id: ProductID) -> DeviceFilter {
+        self.product_id = Some(product_id);
+        self
+    }
+    pub fn device_identifier(self, id: DeviceIdentifier) -> DeviceFilter {
+        self.vendor(id.vendor_id).product(id.product_id)
+    }
+    pub fn class(mut self, class: u8) -> DeviceFilter {
+        self.class = Some(class);
+        self
+    }
+    pub fn subclass(mut self, sub_class: u8) -> DeviceFilter {
+        self.sub_class = Some(sub_class);
+        self
+    }
+    pub fn protocol(mut self, protocol: u8) -> DeviceFilter {
+        self.protocol = Some(protocol);
+        self
+    }
+    pub fn serial(mut self, serial_number: impl Into<String>) -> DeviceFilter {
+        self.serial_number = Some(serial_number.into());
+        self
+    }
+    pub fn vendor_id(&self) -> Option<VendorID> {
+        self.vendor_id
+    }
+    pub fn product_id(&self) -> Option<ProductID> {
+        self.product_id
+    }
+    pub fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_deref()
+    }
+    /// Returns if the filter has a class, subclass or protocol.
+    pub fn has_codes(&self) -> bool {
+        self.class.is_some() || self.sub_class.is_some() || self.protocol.is_some()
+    }
+    pub fn matches_ids(&self, id: DeviceIdentifier) -> bool {
+        field_matches(self.vendor_id, id.vendor_id) && field_matches(self.product_id, id.product_id)
+    }
+    /// Matches device or interface class codes.
+    pub fn matches_codes(&self, codes: &Codes) -> bool {
+        field_matches(self.class, codes.class)
+            && field_matches(self.sub_class, codes.sub_class)
+            && field_matches(self.protocol, codes.protocol)
+    }
+    /// Matches the IDs and the device class codes. Composite devices (class codes in the
+    /// interfaces) and the serial number need the device, see `libusb::Device` for that.
+    pub fn matches_descriptor(&self, descriptor: &Descriptor) -> bool {
+        self.matches_ids(descriptor.device_identifier) && self.matches_codes(&descriptor.codes)
+    }
+    pub fn matches_serial(&self, serial_number: Option<&str>) -> bool {
+        match (self.serial_number(), serial_number) {
+            (None, _) => true,
+            (Some(expected), Some(serial_number)) => expected == serial_number,
+            (Some(_), None) => false,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::device::{Codes, DeviceFilter, DeviceIdentifier, ProductID, VendorID};
+
+    const BLUETOOTH: DeviceIdentifier = DeviceIdentifier::new(0x0A12, 0x0001);
+    #[test]
+    pub fn test_device_identifier_from_str() {
+        assert_eq!("0a12:0001".parse(), Ok(BLUETOOTH));
+        assert_eq!("0A12:1".parse(), Ok(BLUETOOTH));
+        assert_eq!("0x0a12:0X0001".parse(), Ok(BLUETOOTH));
+        let detail = |s: &str| {
+            s.parse::<DeviceIdentifier>()
+                .map_err(|e| (e.target(), e.detail()))
+        };
+        assert_eq!(
+            detail(""),
+            Err(("device identifier", Some("missing colon")))
+        );
+        assert_eq!(
+            detail("0a12"),
+            Err(("device identifier", Some("missing colon")))
+        );
+        assert_eq!(detail("0a12:"), Err(("product ID", Some("bad length"))));
+        assert_eq!(detail(":0001"), Err(("vendor ID", Some("bad length"))));
+        assert_eq!(detail("0x:0001"), Err(("vendor ID", Some("bad length"))));
+        assert_eq!(detail("00a12:0001"), Err(("vendor ID", Some("bad length"))));
+        assert_eq!(
+            detail("0a12:0001:0"),
+            Err(("product ID", Some("bad length")))
+        );
+        assert_eq!(detail("0a12:000:"), Err(("product ID", Some("bad hex"))));
+        assert_eq!(detail("+a12:0001"), Err(("vendor ID", Some("bad hex"))));
+        assert_eq!(detail("0g12:0001"), Err(("vendor ID", Some("bad hex"))));
+        assert_eq!(detail("0a12:é"), Err(("product ID", Some("bad hex"))));
+    }
+    #[test]
+    pub fn test_id_from_str() {
+        assert_eq!("ffff".parse(), Ok(VendorID(0xFFFF)));
+        assert_eq!("0x1".parse(), Ok(ProductID(0x0001)));
+        assert!("-1".parse::<VendorID>().is_err());
+        assert!("0x".parse::<ProductID>().is_err());
+    }
+    #[test]
+    pub fn test_hex_format() {
+        assert_eq!(format!("{:x}", BLUETOOTH), "0a12:0001");
+        assert_eq!(format!("{:X}", BLUETOOTH), "0A12:0001");
+        assert_eq!(format!("{:04x}", VendorID(0xA12)), "0a12");
+        assert_eq!(format!("{:#06X}", ProductID(1)), "0x0001");
+        assert_eq!(format!("{:x}", BLUETOOTH).parse(), Ok(BLUETOOTH));
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn test_device_identifier_serde() {
+        let json = serde_json::to_string(&BLUETOOTH).expect("serializes");
+        assert_eq!(json, "\"0a12:0001\"");
+        assert_eq!(
+            serde_json::from_str::<DeviceIdentifier>(&json).expect("deserializes"),
+            BLUETOOTH
+        );
+        assert!(serde_json::from_str::<DeviceIdentifier>("\"0a12\"").is_err());
+    }
+    #[test]
+    pub fn test_device_filter() {
+        let codes = Codes {
+            class: 0xE0,
+            sub_class: 0x01,
+            protocol: 0x01,
+        };
+        assert!(DeviceFilter::new().matches_ids(BLUETOOTH));
+        assert!(DeviceFilter::new().matches_codes(&codes));
+        assert!(DeviceFilter::new().matches_serial(None));
+        let filter = DeviceFilter::new()
+            .device_identifier(BLUETOOTH)
+            .class(0xE0)
+            .protocol(0x01);
+        assert!(filter.has_codes());
+        assert!(filter.matches_ids(BLUETOOTH));
+        assert!(!filter.matches_ids(DeviceIdentifier::new(0x0A12, 0x0002)));
+        assert!(filter.matches_codes(&codes));
+        assert!(!filter.matches_codes(&Codes {
+            protocol: 0x02,
+            ..codes
+        }));
+        let filter = DeviceFilter::new().serial("ABC123");
+        assert!(!filter.has_codes());
+        assert!(filter.matches_serial(Some("ABC123")));
+        assert!(!filter.matches_serial(Some("ABC124")));
+        assert!(!filter.matches_serial(None));
+    }
+}
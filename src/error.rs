@@ -0,0 +1,176 @@
+//! Crate level errors. Each backend has its own `Error`, this wraps them for backend agnostic
+//! code.
+use crate::descriptors::ParseError;
+use core::fmt;
+
+/// A value that doesn't map to the type it was converted into (an unknown transfer type, a
+/// malformed version string, ...).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConversionError {
+    target: &'static str,
+    detail: Option<&'static str>,
+}
+impl ConversionError {
+    /// `target` names what the value was being converted into (`"USB version"`).
+    pub const fn new(target: &'static str) -> ConversionError {
+        ConversionError {
+            target,
+            detail: None,
+        }
+    }
+    /// Adds why the conversion failed (`"bad hex"`).
+    pub const fn with_detail(self, detail: &'static str) -> ConversionError {
+        ConversionError {
+            target: self.target,
+            detail: Some(detail),
+        }
+    }
+    pub const fn target(&self) -> &'static str {
+        self.target
+    }
+    pub const fn detail(&self) -> Option<&'static str> {
+        self.detail
+    }
+}
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.detail {
+            Some(detail) => write!(f, "invalid {}: {}", self.target, detail),
+            None => write!(f, "invalid {}", self.target),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for ConversionError {}
+
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(feature = "libusb")]
+    Libusb(crate::libusb::error::Error),
+    #[cfg(feature = "rusb")]
+    Rusb(rusb::Error),
+    #[cfg(all(feature = "winusb", windows))]
+    WinUsb(crate::winusb::error::Error),
+    Conversion(ConversionError),
+    Parse(ParseError),
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "libusb")]
+            Error::Libusb(e) => write!(f, "libusb: {}", e),
+            #[cfg(feature = "rusb")]
+            Error::Rusb(e) => write!(f, "rusb: {}", e),
+            #[cfg(all(feature = "winusb", windows))]
+            Error::WinUsb(e) => write!(f, "WinUSB: {}", e),
+            Error::Conversion(e) => fmt::Display::fmt(e, f),
+            Error::Parse(e) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "std")]
+            Error::Io(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "libusb")]
+            Error::Libusb(e) => Some(e),
+            #[cfg(feature = "rusb")]
+            Error::Rusb(e) => Some(e),
+            #[cfg(all(feature = "winusb", windows))]
+            Error::WinUsb(e) => Some(e),
+            Error::Conversion(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+#[cfg(feature = "libusb")]
+impl From<crate::libusb::error::Error> for Error {
+    fn from(e: crate::libusb::error::Error) -> Self {
+        Error::Libusb(e)
+    }
+}
+#[cfg(feature = "rusb")]
+impl From<rusb::Error> for Error {
+    fn from(e: rusb::Error) -> Self {
+        Error::Rusb(e)
+    }
+}
+#[cfg(all(feature = "winusb", windows))]
+impl From<crate::winusb::error::Error> for Error {
+    fn from(e: crate::winusb::error::Error) -> Self {
+        Error::WinUsb(e)
+    }
+}
+impl From<ConversionError> for Error {
+    fn from(e: ConversionError) -> Self {
+        Error::Conversion(e)
+    }
+}
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            #[cfg(feature = "libusb")]
+            Error::Libusb(e) => e.into(),
+            #[cfg(all(feature = "winusb", windows))]
+            Error::WinUsb(e) => e.into(),
+            Error::Conversion(e) => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            Error::Parse(e) => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            Error::Io(e) => e,
+            #[allow(unreachable_patterns)]
+            e => std::io::Error::other(e),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::error::{ConversionError, Error};
+
+    #[test]
+    pub fn test_conversion_error() {
+        let e = ConversionError::new("USB version");
+        assert_eq!(e.target(), "USB version");
+        assert_eq!(e.to_string(), "invalid USB version");
+        let e = Error::from(e);
+        assert_eq!(e.to_string(), "invalid USB version");
+        let e = ConversionError::new("vendor ID").with_detail("bad hex");
+        assert_eq!(e.detail(), Some("bad hex"));
+        assert_eq!(e.to_string(), "invalid vendor ID: bad hex");
+    }
+    #[cfg(feature = "std")]
+    #[test]
+    pub fn test_conversion_error_to_io() {
+        let e = Error::from(ConversionError::new("USB version"));
+        let io = std::io::Error::from(e);
+        assert_eq!(io.kind(), std::io::ErrorKind::InvalidData);
+    }
+    #[cfg(feature = "libusb")]
+    #[test]
+    pub fn test_libusb_error() {
+        use std::error::Error as _;
+        let e = Error::from(crate::libusb::error::Error::NoDevice);
+        assert!(matches!(
+            e,
+            Error::Libusb(crate::libusb::error::Error::NoDevice)
+        ));
+        assert!(e.source().is_some());
+        let io = std::io::Error::from(e);
+        assert_eq!(io.kind(), std::io::ErrorKind::NotConnected);
+    }
+}
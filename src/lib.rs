@@ -1,11 +1,24 @@
-#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 extern crate alloc;
 
+#[macro_use]
+mod trace;
+
+pub mod descriptors;
 pub mod device;
 pub mod error;
+pub use error::{ConversionError, Error};
 #[cfg(feature = "libusb")]
 pub mod libusb;
+#[cfg(feature = "rusb")]
 pub mod manager;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "replay")]
+pub mod replay;
+#[cfg(feature = "serde")]
+mod serde_str;
+pub mod traits;
 pub mod version;
-#[cfg(feature = "winusb")]
+#[cfg(all(feature = "winusb", windows))]
 pub mod winusb;
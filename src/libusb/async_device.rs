@@ -1,15 +1,102 @@
+use crate::device::DeviceFilter;
+use crate::libusb::buffer::{scatter, Pool, PooledDevice};
+use crate::libusb::bulk_io::{BulkReader, BulkWriter};
+use crate::libusb::context::Context;
+use crate::libusb::control::{
+    control_len, DescriptorType, DeviceQualifier, DeviceStatus, Direction, Recipient,
+};
 use crate::libusb::device::Device;
+use crate::libusb::device_descriptor::DeviceDescriptor;
 use crate::libusb::device_handle::DeviceHandle;
+use crate::libusb::error;
 use crate::libusb::error::Error;
-use crate::libusb::safe_transfer::{SafeTransfer, SafeTransferAsyncLink};
-use crate::libusb::transfer::{ControlSetup, Transfer, TransferType};
+use crate::libusb::in_flight::InFlight;
+use crate::libusb::interface_guard::AsyncInterfaceGuard;
+use crate::libusb::interrupt_stream::InterruptStream;
+#[cfg(feature = "metrics")]
+use crate::libusb::metrics::DeviceMetrics;
+use crate::libusb::retry::StallRecovery;
+use crate::libusb::runtime;
+use crate::libusb::safe_transfer::{SafeTransfer, SafeTransferAsyncLink, TransferOptions};
+use crate::libusb::shared_device::SharedAsyncDevice;
+use crate::libusb::string_cache::{CachedStrings, StringCache};
+use crate::libusb::string_descriptor;
+use crate::libusb::string_descriptor::{DeviceStrings, Language};
+use crate::libusb::transfer::{
+    ControlSetup, IsoPacket, Transfer, TransferError, TransferResult, TransferType,
+};
+use crate::libusb::transfer_builder::{BuiltTransfer, Completion, NoBuffer, Out, TransferBuilder};
+use core::borrow::BorrowMut;
+use futures_util::future::Either;
 use libusb1_sys::constants::{LIBUSB_DT_STRING, LIBUSB_ENDPOINT_IN, LIBUSB_REQUEST_GET_DESCRIPTOR};
 use std::convert::TryInto;
+use std::io::{IoSlice, IoSliceMut};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// How long a dropped `AsyncDevice` waits for its cancelled transfers to complete.
+const DROP_DRAIN_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(1);
+/// How often `AsyncDevice::reset_and_reopen` looks for the re-enumerated device.
+const REOPEN_POLL_INTERVAL: core::time::Duration = core::time::Duration::from_millis(100);
+
+/// A blocking task's reference to the handle. `AsyncDevice::handle_mut` waits for them to be
+/// dropped, which happens under the `idle` lock so the wait can't miss it.
+struct BlockingRef {
+    handle: Option<Arc<DeviceHandle>>,
+    idle: Arc<(Mutex<()>, Condvar)>,
+}
+impl Drop for BlockingRef {
+    fn drop(&mut self) {
+        let (lock, idle) = &*self.idle;
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        drop(self.handle.take());
+        idle.notify_all();
+    }
+}
 
 /// The Synchronous libusb interface converted to rust async. Warning, each function will
-/// allocate a `Transfer` and a buffer for any data + `ControlSetup::SIZE`.
+/// allocate a `Transfer` and a buffer for any data + `ControlSetup::SIZE` (writes copy `data` into
+/// it, reads copy out of it, libusb is never given a pointer to a borrowed slice). See
+/// `bulk_read_buf` for reads without the copy and `with_pool` for bulk IO without allocations.
+/// Timeouts of zero (`transfer::NO_TIMEOUT`) wait forever, the methods without a timeout argument
+/// (`bulk_in`, `control_out`, ...) use `default_timeout`.
 pub struct AsyncDevice {
-    pub(crate) handle: DeviceHandle,
+    /// Shared with the blocking tasks of `unblock_handle`, which might outlive their futures.
+    pub(crate) handle: Arc<DeviceHandle>,
+    idle: Arc<(Mutex<()>, Condvar)>,
+    default_timeout: core::time::Duration,
+    auto_clear_halt: bool,
+    strings: CachedStrings,
+    in_flight: InFlight,
+    #[cfg(feature = "metrics")]
+    metrics: DeviceMetrics,
+}
+/// Transfers dropped in flight (like by dropping their future) can still be running, they're
+/// cancelled and the handle is only closed once their callbacks ran. The context's events are
+/// handled meanwhile, for up to `DROP_DRAIN_TIMEOUT` after which the handle is leaked instead.
+impl Drop for AsyncDevice {
+    fn drop(&mut self) {
+        if self.in_flight.len() == 0 {
+            return;
+        }
+        if let Err(_e) = self.in_flight.cancel_all() {
+            usb_debug!(error = ?_e, "cancelling the transfers of a dropped device failed");
+        }
+        let context = self.handle.context_inner().map(|context| &**context);
+        if !self.in_flight.drain(context, DROP_DRAIN_TIMEOUT) {
+            usb_debug!(
+                in_flight = self.in_flight.len(),
+                "transfers of a dropped device didn't complete, leaking its handle"
+            );
+            // Their callbacks still use the handle
+            core::mem::forget(self.handle.clone());
+            return;
+        }
+        debug_assert_eq!(
+            self.in_flight.len(),
+            0,
+            "closing a handle with transfers in flight"
+        );
+    }
 }
 #[derive(Copy, Clone, Debug)]
 pub enum BulkType {
@@ -30,17 +117,294 @@ impl From<BulkType> for TransferType {
     }
 }
 impl AsyncDevice {
+    /// Timeout used for standard requests (string descriptors, `get_configuration`, etc).
+    pub const DEFAULT_CONTROL_TIMEOUT: core::time::Duration =
+        core::time::Duration::from_millis(1000);
     /// # Safety
     /// Will block if a `AsyncContext` is running with the device's context
     pub unsafe fn from_device(handle: DeviceHandle) -> AsyncDevice {
-        AsyncDevice { handle }
+        AsyncDevice::new(handle)
+    }
+    pub(crate) fn new(handle: DeviceHandle) -> AsyncDevice {
+        AsyncDevice {
+            handle: Arc::new(handle),
+            idle: Arc::default(),
+            default_timeout: Self::DEFAULT_CONTROL_TIMEOUT,
+            auto_clear_halt: false,
+            strings: CachedStrings::new(),
+            in_flight: InFlight::new(),
+            #[cfg(feature = "metrics")]
+            metrics: DeviceMetrics::new(),
+        }
+    }
+    /// Timeout of the methods without a timeout argument. Starts as `DEFAULT_CONTROL_TIMEOUT`.
+    pub fn default_timeout(&self) -> core::time::Duration {
+        self.default_timeout
+    }
+    /// `NO_TIMEOUT` makes the methods without a timeout argument wait forever.
+    pub fn set_default_timeout(&mut self, timeout: core::time::Duration) {
+        self.default_timeout = timeout
+    }
+    /// If stalled bulk/interrupt transfers clear the endpoint halt and retry once.
+    pub fn auto_clear_halt(&self) -> bool {
+        self.auto_clear_halt
+    }
+    /// When enabled, a bulk/interrupt transfer that stalls sends `CLEAR_FEATURE(ENDPOINT_HALT)`
+    /// and is retried once, all within the original timeout. If the clear fails, the retry stalls
+    /// again or the time runs out, the stall is returned (`Error::Pipe`). Off by default.
+    pub fn set_auto_clear_halt(&mut self, enabled: bool) {
+        self.auto_clear_halt = enabled
+    }
+    /// Amount of transfers submitted on this device that haven't completed yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+    pub(crate) fn in_flight_ref(&self) -> &InFlight {
+        &self.in_flight
+    }
+    /// Requests every transfer in flight on this device to be cancelled, without waiting for
+    /// them (see `quiesce`). Returns how many cancels were issued, transfers already completing
+    /// aren't counted.
+    pub fn cancel_all(&self) -> Result<usize, Error> {
+        self.in_flight.cancel_all()
+    }
+    /// Waits until no transfer is in flight on this device, like after `cancel_all` before
+    /// closing it. The completions come from the libusb events so they have to be handled.
+    pub async fn quiesce(&self) {
+        self.in_flight.quiesce().await
+    }
+    /// Transfer counters of this device (a handle to them, they aren't copied).
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> DeviceMetrics {
+        self.metrics.clone()
+    }
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics_ref(&self) -> &DeviceMetrics {
+        &self.metrics
     }
 
+    /// Bulk/interrupt IO that reuses the `Transfer`s and buffers of `pool`.
+    pub fn with_pool<'a>(&'a self, pool: &'a Pool) -> PooledDevice<'a> {
+        PooledDevice::new(self, pool)
+    }
+    /// `AsyncRead` over a bulk IN endpoint, reading `buf_size` bytes per transfer.
+    pub fn bulk_reader(&self, endpoint: u8, buf_size: usize) -> BulkReader<'_> {
+        BulkReader::new(self, endpoint, buf_size)
+    }
+    /// `AsyncWrite` over a bulk OUT endpoint.
+    pub fn bulk_writer(&self, endpoint: u8) -> BulkWriter<'_> {
+        BulkWriter::new(self, endpoint)
+    }
+    /// `Stream` of the packets received on an interrupt IN endpoint.
+    pub fn interrupt_stream(&self, endpoint: u8, packet_size: usize) -> InterruptStream<'_> {
+        InterruptStream::new(self, endpoint, packet_size)
+    }
+    /// Makes the device cloneable so IO can run from several tasks at once.
+    pub fn shared(self) -> SharedAsyncDevice {
+        SharedAsyncDevice::new(self)
+    }
     pub fn handle_ref(&self) -> &DeviceHandle {
         &self.handle
     }
+    /// Blocks until the blocking tasks left behind by dropped futures (like a `claim_interface`
+    /// future dropped before it finished) are done with the handle.
     pub fn handle_mut(&mut self) -> &mut DeviceHandle {
-        &mut self.handle
+        let (lock, idle) = &*self.idle;
+        let mut guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        while Arc::strong_count(&self.handle) > 1 {
+            guard = idle.wait(guard).unwrap_or_else(|e| e.into_inner());
+        }
+        drop(guard);
+        Arc::get_mut(&mut self.handle).expect("blocking tasks are done")
+    }
+    /// `handle_mut` for async code, the wait runs on a blocking thread instead of stalling the
+    /// executor.
+    pub(crate) async fn handle_mut_async(&mut self) -> &mut DeviceHandle {
+        if Arc::strong_count(&self.handle) > 1 {
+            let handle = Arc::downgrade(&self.handle);
+            let idle = self.idle.clone();
+            blocking::unblock(move || {
+                let (lock, idle) = &*idle;
+                let mut guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                while handle.strong_count() > 1 {
+                    guard = idle.wait(guard).unwrap_or_else(|e| e.into_inner());
+                }
+            })
+            .await;
+        }
+        Arc::get_mut(&mut self.handle).expect("blocking tasks are done")
+    }
+    /// Runs `f` with the handle on a blocking thread so the async executor and the `AsyncContext`
+    /// event thread aren't blocked. The blocking task keeps its own reference to the handle so
+    /// dropping the future early is fine.
+    async fn unblock<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&DeviceHandle) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let handle = BlockingRef {
+            handle: Some(self.handle.clone()),
+            idle: self.idle.clone(),
+        };
+        blocking::unblock(move || {
+            let res = f(handle.handle.as_ref().expect("only taken on drop"));
+            drop(handle);
+            res
+        })
+        .await
+    }
+    /// `unblock` with the raw libusb handle, for the libusb functions returning an error code.
+    async fn unblock_handle<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(*mut libusb1_sys::libusb_device_handle) -> i32 + Send + 'static,
+    {
+        match self.unblock(move |handle| f(handle.inner().as_ptr())).await {
+            0 => Ok(()),
+            err => Err(error::from_libusb(err)),
+        }
+    }
+    /// Async version of `DeviceHandle::clear_halt`.
+    pub async fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        self.unblock(move |handle| handle.clear_halt(endpoint))
+            .await
+    }
+    /// Async version of `DeviceHandle::claim_interface`. The future should be run to completion
+    /// or else the claimed interface tracking might miss the claim.
+    pub async fn claim_interface(&mut self, interface: u8) -> Result<(), Error> {
+        if self.handle.interfaces_ref().is_claimed(interface) {
+            return Ok(());
+        }
+        self.unblock_handle(move |handle| unsafe {
+            libusb1_sys::libusb_claim_interface(handle, interface.into())
+        })
+        .await?;
+        self.handle_mut_async()
+            .await
+            .interfaces_mut()
+            .claim(interface);
+        Ok(())
+    }
+    /// Async version of `DeviceHandle::claim_interface_guard`.
+    pub async fn claim_interface_guard(
+        &mut self,
+        interface: u8,
+    ) -> Result<AsyncInterfaceGuard<'_>, Error> {
+        AsyncInterfaceGuard::claim(self, interface, false).await
+    }
+    /// Async version of `DeviceHandle::claim_interface_guard_detached`.
+    pub async fn claim_interface_guard_detached(
+        &mut self,
+        interface: u8,
+    ) -> Result<AsyncInterfaceGuard<'_>, Error> {
+        AsyncInterfaceGuard::claim(self, interface, true).await
+    }
+    /// Async version of `DeviceHandle::release_interface`.
+    pub async fn release_interface(&mut self, interface: u8) -> Result<(), Error> {
+        if !self.handle.interfaces_ref().is_claimed(interface) {
+            return Ok(());
+        }
+        self.unblock_handle(move |handle| unsafe {
+            libusb1_sys::libusb_release_interface(handle, interface.into())
+        })
+        .await?;
+        self.handle_mut_async()
+            .await
+            .interfaces_mut()
+            .release(interface);
+        Ok(())
+    }
+    /// Async version of `DeviceHandle::set_alternate_setting`.
+    pub async fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> Result<(), Error> {
+        if !self.handle.interfaces_ref().is_claimed(interface) {
+            return Err(Error::NotFound);
+        }
+        self.unblock_handle(move |handle| unsafe {
+            libusb1_sys::libusb_set_interface_alt_setting(handle, interface.into(), setting.into())
+        })
+        .await
+    }
+    /// Async version of `DeviceHandle::set_active_configuration`.
+    pub async fn set_active_configuration(&mut self, config: u8) -> Result<(), Error> {
+        self.unblock_handle(move |handle| unsafe {
+            libusb1_sys::libusb_set_configuration(handle, config.into())
+        })
+        .await
+    }
+    /// Resets the device and gives back an `AsyncDevice` for it with the same interfaces claimed
+    /// (and kernel drivers detached). If the device has to re-enumerate, `context`'s device list
+    /// is polled until a device with the same bus, port path, IDs and serial number shows up
+    /// (`Error::NoDevice` if that takes longer than `timeout`) and it's reopened. The reset runs on
+    /// a blocking task with its own reference to the handle, so the future can be dropped any time.
+    pub async fn reset_and_reopen(
+        self,
+        context: &Context,
+        timeout: core::time::Duration,
+    ) -> Result<AsyncDevice, Error> {
+        let device = self.device();
+        let location = device.location()?;
+        let old_address = device.device_address();
+        let id = device.device_descriptor()?.device_identifier();
+        let serial = self.strings().serial_number().await.ok().flatten();
+        let claimed: Vec<u8> = self.handle.claimed_interfaces().collect();
+        let detached: Vec<u8> = self.handle.detached_interfaces().collect();
+        let (default_timeout, auto_clear_halt) = (self.default_timeout, self.auto_clear_halt);
+        let auto_detach = self.handle.auto_detach_kernel_driver();
+
+        match self
+            .unblock_handle(|handle| unsafe { libusb1_sys::libusb_reset_device(handle) })
+            .await
+        {
+            // Same address, the handle and its claims are still good
+            Ok(()) => {
+                self.strings.invalidate();
+                return Ok(self);
+            }
+            Err(Error::NotFound) => drop(self),
+            Err(e) => return Err(e),
+        }
+        let start = std::time::Instant::now();
+        let mut reopened = 'poll: loop {
+            let candidates = context
+                .device_list()
+                .find_all(&DeviceFilter::new().device_identifier(id));
+            for candidate in candidates {
+                // The old device can still be listed until the disconnect is processed
+                if candidate.device_address() == old_address
+                    || candidate.location().as_ref() != Ok(&location)
+                {
+                    continue;
+                }
+                if let Ok(handle) = candidate.open() {
+                    let reopened = AsyncDevice::new(handle);
+                    // The serial number read can take a while, don't start it after the timeout
+                    if start.elapsed() >= timeout {
+                        return Err(Error::NoDevice);
+                    }
+                    if serial.is_none()
+                        || reopened.strings().serial_number().await.ok().flatten() == serial
+                    {
+                        break 'poll reopened;
+                    }
+                }
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::NoDevice);
+            }
+            runtime::sleep(REOPEN_POLL_INTERVAL).await;
+        };
+        reopened.default_timeout = default_timeout;
+        reopened.auto_clear_halt = auto_clear_halt;
+        reopened.handle.set_auto_detach_kernel_driver(auto_detach)?;
+        for interface in claimed {
+            if detached.contains(&interface) && reopened.handle.kernel_driver_active(interface)? {
+                reopened
+                    .handle_mut_async()
+                    .await
+                    .detach_kernel_driver(interface)?;
+            }
+            reopened.claim_interface(interface).await?;
+        }
+        Ok(reopened)
     }
 
     pub async fn control_read(
@@ -52,19 +416,23 @@ impl AsyncDevice {
         data: &mut [u8],
         timeout: core::time::Duration,
     ) -> Result<usize, Error> {
-        let mut transfer = SafeTransfer::from_buf(vec![0_u8; data.len() + ControlSetup::SIZE]);
-        transfer.set_timeout(timeout);
-        // Fill transfer with control parameters
-        transfer.set_control_setup(ControlSetup {
+        let setup = ControlSetup {
             request_type,
             request,
             value,
             index,
-            len: data.len().try_into().expect("too much data"),
-        })?;
-        let len = transfer.submit_write(self).await?;
-        data[..len].copy_from_slice(&transfer.control_data_ref()[..len]);
-        Ok(len)
+            len: control_len(data.len())?,
+        };
+        let completion = self
+            .submit(
+                TransferBuilder::control_in(setup)
+                    .timeout(timeout)
+                    .build()?,
+            )
+            .await?;
+        let read = completion.data();
+        data[..read.len()].copy_from_slice(read);
+        Ok(read.len())
     }
     pub async fn control_write(
         &self,
@@ -75,18 +443,195 @@ impl AsyncDevice {
         data: &[u8],
         timeout: core::time::Duration,
     ) -> Result<usize, Error> {
-        let mut transfer = SafeTransfer::from_buf(vec![0_u8; data.len() + ControlSetup::SIZE]);
-        transfer.set_timeout(timeout);
-        transfer.control_data_mut()[..data.len()].copy_from_slice(data);
-        // Fill transfer with control parameters
-        transfer.set_control_setup(ControlSetup {
+        let setup = ControlSetup {
             request_type,
             request,
             value,
             index,
-            len: data.len().try_into().expect("too much data"),
-        })?;
-        transfer.submit_write(self).await
+            len: control_len(data.len())?,
+        };
+        let transfer = TransferBuilder::control_out(setup)
+            .timeout(timeout)
+            .data(data)
+            .build()?;
+        Ok(self.submit(transfer).await?.transferred())
+    }
+    /// Sends `setup` as is, the direction bit of `setup.request_type` decides if `data` is read
+    /// into (up to `setup.len` bytes) or written (exactly `setup.len` bytes). Returns
+    /// `Error::InvalidParam` if `data` doesn't fit `setup.len`.
+    pub async fn control_raw(
+        &self,
+        setup: ControlSetup,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let len = usize::from(setup.len);
+        if setup.is_read() {
+            if data.len() < len {
+                return Err(Error::InvalidParam);
+            }
+            let transfer = TransferBuilder::control_in(setup)
+                .timeout(timeout)
+                .build()?;
+            let completion = self.submit(transfer).await?;
+            let read = completion.data();
+            data[..read.len()].copy_from_slice(read);
+            Ok(read.len())
+        } else {
+            if data.len() != len {
+                return Err(Error::InvalidParam);
+            }
+            let transfer = TransferBuilder::control_out(setup)
+                .timeout(timeout)
+                .data(data)
+                .build()?;
+            Ok(self.submit(transfer).await?.transferred())
+        }
+    }
+    /// `control_raw` with the setup packet as it goes on the wire (`u16`s in Little-Endian).
+    pub async fn control_raw_bytes(
+        &self,
+        setup: [u8; ControlSetup::SIZE],
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.control_raw(ControlSetup::try_deserialize(&setup)?, data, timeout)
+            .await
+    }
+    /// Submits a transfer made with a `TransferBuilder` and waits for it. A transfer that ran but
+    /// didn't complete is an `Error` (see `submit_partial`). Bulk stalls aren't recovered from,
+    /// unlike `bulk_read` and `bulk_write`.
+    pub async fn submit<Buf: AsMut<[u8]> + AsRef<[u8]>>(
+        &self,
+        transfer: BuiltTransfer<Buf>,
+    ) -> Result<Completion<Buf>, Error> {
+        let completion = self.submit_partial(transfer).await?;
+        completion.result().completed()?;
+        Ok(completion)
+    }
+    /// `submit` but a transfer that ran (timed out, stalled, ...) is `Ok`, with its status and
+    /// the bytes moved before it stopped in `Completion::result`.
+    pub async fn submit_partial<Buf: AsMut<[u8]> + AsRef<[u8]>>(
+        &self,
+        transfer: BuiltTransfer<Buf>,
+    ) -> Result<Completion<Buf>, Error> {
+        let BuiltTransfer {
+            mut transfer,
+            is_read,
+        } = transfer;
+        let result = transfer.submit_with_result(self, is_read).await?;
+        Ok(Completion { transfer, result })
+    }
+    /// `submit` with the error keeping the status, for `StallRecovery`.
+    async fn submit_checked<Buf: AsMut<[u8]> + AsRef<[u8]>>(
+        &self,
+        transfer: BuiltTransfer<Buf>,
+    ) -> Result<usize, TransferError> {
+        self.submit_partial(transfer).await?.result().checked()
+    }
+    /// Vendor request to the device with an IN data stage.
+    pub async fn vendor_read(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::vendor(
+            Direction::In,
+            request,
+            value,
+            index,
+            control_len(data.len())?,
+        );
+        self.control_read(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+        .await
+    }
+    /// Vendor request to the device with an OUT data stage (`data` can be empty).
+    pub async fn vendor_write(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::vendor(
+            Direction::Out,
+            request,
+            value,
+            index,
+            control_len(data.len())?,
+        );
+        self.control_write(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+        .await
+    }
+    /// Class request to `interface` with an IN data stage.
+    pub async fn class_read(
+        &self,
+        request: u8,
+        value: u16,
+        interface: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::class_interface(
+            Direction::In,
+            request,
+            value,
+            interface,
+            control_len(data.len())?,
+        );
+        self.control_read(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+        .await
+    }
+    /// Class request to `interface` with an OUT data stage (`data` can be empty).
+    pub async fn class_write(
+        &self,
+        request: u8,
+        value: u16,
+        interface: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::class_interface(
+            Direction::Out,
+            request,
+            value,
+            interface,
+            control_len(data.len())?,
+        );
+        self.control_write(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+        .await
     }
     pub async fn bulk_type_write(
         &self,
@@ -95,11 +640,84 @@ impl AsyncDevice {
         data: &[u8],
         timeout: core::time::Duration,
     ) -> Result<usize, Error> {
-        let mut transfer = SafeTransfer::from_buf(data);
-        transfer.set_type(bulk_type.into());
-        transfer.set_endpoint(endpoint);
-        transfer.set_timeout(timeout);
-        transfer.submit_write(self).await
+        self.bulk_type_write_with(
+            bulk_type,
+            endpoint,
+            data,
+            timeout,
+            TransferOptions::default(),
+        )
+        .await
+    }
+    pub async fn bulk_type_write_with(
+        &self,
+        bulk_type: BulkType,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+        options: TransferOptions,
+    ) -> Result<usize, Error> {
+        self.bulk_type_write_staged(bulk_type, endpoint, |b| b.data(data), timeout, options)
+            .await
+    }
+    /// `bulk_write` of `bufs` one after the other. They're copied into one buffer since a
+    /// transfer needs contiguous memory, see `PooledDevice::bulk_write_vectored` to reuse it.
+    pub async fn bulk_write_vectored(
+        &self,
+        endpoint: u8,
+        bufs: &[IoSlice<'_>],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.bulk_type_write_staged(
+            BulkType::Bulk,
+            endpoint,
+            |b| b.data_vectored(bufs),
+            timeout,
+            TransferOptions::default(),
+        )
+        .await
+    }
+    /// Bulk or interrupt write with the data copied in by `stage` for every attempt.
+    async fn bulk_type_write_staged(
+        &self,
+        bulk_type: BulkType,
+        endpoint: u8,
+        stage: impl Fn(TransferBuilder<Out, NoBuffer>) -> TransferBuilder<Out, Vec<u8>>,
+        timeout: core::time::Duration,
+        options: TransferOptions,
+    ) -> Result<usize, Error> {
+        let mut recovery = StallRecovery::new(self.auto_clear_halt, timeout);
+        let mut attempt_timeout = timeout;
+        loop {
+            let builder = match bulk_type {
+                BulkType::Bulk => TransferBuilder::bulk_out(endpoint),
+                BulkType::Interrupt => TransferBuilder::interrupt_out(endpoint),
+            };
+            let transfer = stage(builder.timeout(attempt_timeout).options(options)).build()?;
+            let result = self.submit_checked(transfer).await;
+            if !recovery.should_recover(&result) {
+                return result.map_err(Error::from);
+            }
+            attempt_timeout = self.recover_stall(endpoint, &recovery).await?;
+        }
+    }
+    /// Clears the halt of `endpoint` after a stall and returns the timeout left for the retry.
+    /// Fails with the original stall if the clear fails or there's no time left.
+    async fn recover_stall(
+        &self,
+        endpoint: u8,
+        recovery: &StallRecovery,
+    ) -> Result<core::time::Duration, Error> {
+        let remaining = recovery.remaining()?;
+        // libusb's clear has its own timeout, only `remaining` of ours is waited for
+        let clear = self.clear_halt(endpoint);
+        futures_util::pin_mut!(clear);
+        let timer = runtime::sleep(remaining);
+        futures_util::pin_mut!(timer);
+        match futures_util::future::select(clear, timer).await {
+            Either::Left((Ok(()), _)) => Ok(recovery.remaining()?),
+            _ => Err(recovery.stall().into()),
+        }
     }
 
     pub async fn bulk_type_read(
@@ -109,11 +727,66 @@ impl AsyncDevice {
         data: &mut [u8],
         timeout: core::time::Duration,
     ) -> Result<usize, Error> {
-        let mut transfer = SafeTransfer::from_buf(data);
-        transfer.set_type(bulk_type.into());
-        transfer.set_endpoint(endpoint);
-        transfer.set_timeout(timeout);
-        transfer.submit_read(self).await
+        self.bulk_type_read_with(
+            bulk_type,
+            endpoint,
+            data,
+            timeout,
+            TransferOptions::default(),
+        )
+        .await
+    }
+    pub async fn bulk_type_read_with(
+        &self,
+        bulk_type: BulkType,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+        options: TransferOptions,
+    ) -> Result<usize, Error> {
+        let (buf, read) = self
+            .bulk_type_read_buf_with(
+                bulk_type,
+                endpoint,
+                vec![0_u8; data.len()],
+                timeout,
+                options,
+            )
+            .await?;
+        data[..read].copy_from_slice(&buf[..read]);
+        Ok(read)
+    }
+    /// Reads into `buf` (its whole length) without copying and gives it back with how many bytes
+    /// were read. libusb only ever has the owned `buf`, so the future can be dropped (or leaked)
+    /// mid-transfer without libusb writing into memory that's been given back.
+    pub async fn bulk_type_read_buf_with(
+        &self,
+        bulk_type: BulkType,
+        endpoint: u8,
+        mut buf: Vec<u8>,
+        timeout: core::time::Duration,
+        options: TransferOptions,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        let mut recovery = StallRecovery::new(self.auto_clear_halt, timeout);
+        let mut attempt_timeout = timeout;
+        loop {
+            let builder = match bulk_type {
+                BulkType::Bulk => TransferBuilder::bulk_in(endpoint),
+                BulkType::Interrupt => TransferBuilder::interrupt_in(endpoint),
+            };
+            let transfer = builder
+                .timeout(attempt_timeout)
+                .options(options)
+                .buffer(buf)
+                .build()?;
+            let completion = self.submit_partial(transfer).await?;
+            let result = completion.result().checked();
+            buf = completion.into_buf().await;
+            if !recovery.should_recover(&result) {
+                return result.map(|read| (buf, read)).map_err(Error::from);
+            }
+            attempt_timeout = self.recover_stall(endpoint, &recovery).await?;
+        }
     }
     pub async fn bulk_write(
         &self,
@@ -124,6 +797,17 @@ impl AsyncDevice {
         self.bulk_type_write(BulkType::Bulk, endpoint, data, timeout)
             .await
     }
+    /// `bulk_write` but with `TransferOptions` (like sending a trailing zero length packet).
+    pub async fn bulk_write_with(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+        options: TransferOptions,
+    ) -> Result<usize, Error> {
+        self.bulk_type_write_with(BulkType::Bulk, endpoint, data, timeout, options)
+            .await
+    }
     pub async fn interrupt_write(
         &self,
         endpoint: u8,
@@ -142,6 +826,32 @@ impl AsyncDevice {
         self.bulk_type_read(BulkType::Bulk, endpoint, data, timeout)
             .await
     }
+    /// `bulk_read` into an owned buffer, see `bulk_type_read_buf_with`.
+    pub async fn bulk_read_buf(
+        &self,
+        endpoint: u8,
+        buf: Vec<u8>,
+        timeout: core::time::Duration,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        self.bulk_type_read_buf_with(
+            BulkType::Bulk,
+            endpoint,
+            buf,
+            timeout,
+            TransferOptions::default(),
+        )
+        .await
+    }
+    pub async fn bulk_read_with(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+        options: TransferOptions,
+    ) -> Result<usize, Error> {
+        self.bulk_type_read_with(BulkType::Bulk, endpoint, data, timeout, options)
+            .await
+    }
     pub async fn interrupt_read(
         &self,
         endpoint: u8,
@@ -151,9 +861,341 @@ impl AsyncDevice {
         self.bulk_type_read(BulkType::Interrupt, endpoint, data, timeout)
             .await
     }
+    /// `interrupt_read` into an owned buffer, see `bulk_type_read_buf_with`.
+    pub async fn interrupt_read_buf(
+        &self,
+        endpoint: u8,
+        buf: Vec<u8>,
+        timeout: core::time::Duration,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        self.bulk_type_read_buf_with(
+            BulkType::Interrupt,
+            endpoint,
+            buf,
+            timeout,
+            TransferOptions::default(),
+        )
+        .await
+    }
+    /// `control_read` with `default_timeout`.
+    pub async fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.control_read(
+            request_type,
+            request,
+            value,
+            index,
+            data,
+            self.default_timeout,
+        )
+        .await
+    }
+    /// `control_write` with `default_timeout`.
+    pub async fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        self.control_write(
+            request_type,
+            request,
+            value,
+            index,
+            data,
+            self.default_timeout,
+        )
+        .await
+    }
+    /// `bulk_read` with `default_timeout`.
+    pub async fn bulk_in(&self, endpoint: u8, data: &mut [u8]) -> Result<usize, Error> {
+        self.bulk_read(endpoint, data, self.default_timeout).await
+    }
+    /// `bulk_write` with `default_timeout`.
+    pub async fn bulk_out(&self, endpoint: u8, data: &[u8]) -> Result<usize, Error> {
+        self.bulk_write(endpoint, data, self.default_timeout).await
+    }
+    /// `interrupt_read` with `default_timeout`.
+    pub async fn interrupt_in(&self, endpoint: u8, data: &mut [u8]) -> Result<usize, Error> {
+        self.interrupt_read(endpoint, data, self.default_timeout)
+            .await
+    }
+    /// `interrupt_write` with `default_timeout`.
+    pub async fn interrupt_out(&self, endpoint: u8, data: &[u8]) -> Result<usize, Error> {
+        self.interrupt_write(endpoint, data, self.default_timeout)
+            .await
+    }
+    /// Reads up to the total length of `bufs` into one buffer and copies it into `bufs` in order.
+    /// Returns the bytes read. See `PooledDevice::bulk_read_vectored` to reuse the buffer.
+    pub async fn bulk_read_vectored(
+        &self,
+        endpoint: u8,
+        bufs: &mut [IoSliceMut<'_>],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        if let [buf] = bufs {
+            return self.bulk_read(endpoint, buf, timeout).await;
+        }
+        let staging = vec![0_u8; bufs.iter().map(|b| b.len()).sum()];
+        let (staging, read) = self.bulk_read_buf(endpoint, staging, timeout).await?;
+        Ok(scatter(&staging[..read], bufs))
+    }
+    /// `bulk_read` that keeps the bytes read before a timeout. `TransferResult::transferred`
+    /// bytes of `data` are valid whatever the status.
+    pub async fn bulk_read_partial(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<TransferResult, Error> {
+        let transfer = TransferBuilder::bulk_in(endpoint)
+            .timeout(timeout)
+            .buffer(vec![0_u8; data.len()])
+            .build()?;
+        let completion = self.submit_partial(transfer).await?;
+        let read = completion.data();
+        data[..read.len()].copy_from_slice(read);
+        Ok(completion.result())
+    }
+    /// Writes to a USB 3.0 bulk stream. The stream must first be allocated with
+    /// `DeviceHandle::alloc_streams`.
+    pub async fn stream_write(
+        &self,
+        endpoint: u8,
+        stream_id: u32,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let transfer = TransferBuilder::stream_out(endpoint, stream_id)
+            .timeout(timeout)
+            .data(data)
+            .build()?;
+        Ok(self.submit(transfer).await?.transferred())
+    }
+    /// Reads from a USB 3.0 bulk stream. The stream must first be allocated with
+    /// `DeviceHandle::alloc_streams`.
+    pub async fn stream_read(
+        &self,
+        endpoint: u8,
+        stream_id: u32,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let transfer = TransferBuilder::stream_in(endpoint, stream_id)
+            .timeout(timeout)
+            .buffer(vec![0_u8; data.len()])
+            .build()?;
+        let completion = self.submit(transfer).await?;
+        let read = completion.data();
+        data[..read.len()].copy_from_slice(read);
+        Ok(read.len())
+    }
+    /// Reads `num_packets` isochronous packets of up to `packet_len` bytes each. Packets that
+    /// didn't complete successfully are dropped.
+    pub async fn iso_read(
+        &self,
+        endpoint: u8,
+        num_packets: usize,
+        packet_len: usize,
+        timeout: core::time::Duration,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let transfer = TransferBuilder::iso_in(endpoint, num_packets, packet_len)?
+            .timeout(timeout)
+            .build()?;
+        let completion = self.submit(transfer).await?;
+        Ok(completed_iso_packets(completion.iso_packets()))
+    }
+    /// Writes each slice in `packets` as its own isochronous packet. Returns the total amount of
+    /// bytes sent by the packets that completed successfully.
+    pub async fn iso_write(
+        &self,
+        endpoint: u8,
+        packets: &[&[u8]],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let transfer = TransferBuilder::iso_out(endpoint, packets)
+            .timeout(timeout)
+            .build()?;
+        let completion = self.submit(transfer).await?;
+        Ok(completed_iso_len(completion.iso_packets()))
+    }
     pub fn device(&self) -> Device {
         self.handle.device()
     }
+    /// Reads a response that must be exactly `setup.len` bytes long. Short responses are
+    /// `Error::BadDescriptor`.
+    async fn control_read_exact(
+        &self,
+        setup: ControlSetup,
+        timeout: core::time::Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0_u8; usize::from(setup.len)];
+        let len = self
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &mut data,
+                timeout,
+            )
+            .await?;
+        if len != data.len() {
+            return Err(Error::BadDescriptor);
+        }
+        Ok(data)
+    }
+    async fn control_write_no_data(
+        &self,
+        setup: ControlSetup,
+        timeout: core::time::Duration,
+    ) -> Result<(), Error> {
+        self.control_write(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            &[],
+            timeout,
+        )
+        .await
+        .map(|_| ())
+    }
+    /// Returns the current configuration value (0 means unconfigured).
+    pub async fn get_configuration(&self, timeout: core::time::Duration) -> Result<u8, Error> {
+        let config = self
+            .control_read_exact(ControlSetup::get_configuration(), timeout)
+            .await?;
+        Ok(config[0])
+    }
+    pub async fn get_device_status(
+        &self,
+        timeout: core::time::Duration,
+    ) -> Result<DeviceStatus, Error> {
+        let status = self
+            .control_read_exact(ControlSetup::get_status(Recipient::Device, 0), timeout)
+            .await?;
+        DeviceStatus::from_bytes(&status)
+    }
+    /// Returns the alternate setting currently selected on `interface`.
+    pub async fn get_interface_alt_setting(
+        &self,
+        interface: u8,
+        timeout: core::time::Duration,
+    ) -> Result<u8, Error> {
+        let setting = self
+            .control_read_exact(ControlSetup::get_interface(interface), timeout)
+            .await?;
+        Ok(setting[0])
+    }
+    /// Returns the frame number reported by an isochronous endpoint.
+    pub async fn synch_frame(
+        &self,
+        endpoint: u8,
+        timeout: core::time::Duration,
+    ) -> Result<u16, Error> {
+        let frame = self
+            .control_read_exact(ControlSetup::synch_frame(endpoint), timeout)
+            .await?;
+        Ok(u16::from_le_bytes([frame[0], frame[1]]))
+    }
+    /// `index` is the interface or endpoint number (0 for `Recipient::Device`). See
+    /// `control::feature` for the standard feature selectors.
+    pub async fn set_feature(
+        &self,
+        recipient: Recipient,
+        feature: u16,
+        index: u16,
+        timeout: core::time::Duration,
+    ) -> Result<(), Error> {
+        self.control_write_no_data(
+            ControlSetup::set_feature(recipient, feature, index),
+            timeout,
+        )
+        .await
+    }
+    pub async fn clear_feature(
+        &self,
+        recipient: Recipient,
+        feature: u16,
+        index: u16,
+        timeout: core::time::Duration,
+    ) -> Result<(), Error> {
+        self.control_write_no_data(
+            ControlSetup::clear_feature(recipient, feature, index),
+            timeout,
+        )
+        .await
+    }
+    /// Reads descriptor `index` of `descriptor_type` into `buf` and returns how many bytes the
+    /// device sent. `langid` is only used for string descriptors (0 otherwise).
+    pub async fn get_descriptor(
+        &self,
+        descriptor_type: u8,
+        index: u8,
+        langid: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::get_descriptor_from(
+            Recipient::Device,
+            descriptor_type,
+            index,
+            langid,
+            control_len(buf.len())?,
+        );
+        self.control_read(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            buf,
+            self.default_timeout,
+        )
+        .await
+    }
+    /// Reads the HID report descriptor of `interface`. Its length is `wDescriptorLength` in the
+    /// HID descriptor (in the interface's `extra` bytes).
+    pub async fn get_hid_report_descriptor(
+        &self,
+        interface: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::get_descriptor_from(
+            Recipient::Interface,
+            DescriptorType::Report.into(),
+            0,
+            interface.into(),
+            control_len(buf.len())?,
+        );
+        self.control_read(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            buf,
+            self.default_timeout,
+        )
+        .await
+    }
+    /// Full speed only devices stall the request (`Error::Pipe`).
+    pub async fn get_device_qualifier(&self) -> Result<DeviceQualifier, Error> {
+        let setup = ControlSetup::get_descriptor(
+            DescriptorType::DeviceQualifier,
+            0,
+            0,
+            DeviceQualifier::SIZE as u16,
+        );
+        let qualifier = self.control_read_exact(setup, self.default_timeout).await?;
+        DeviceQualifier::from_bytes(&qualifier)
+    }
 
     pub async fn get_string_descriptor_bytes(
         &self,
@@ -170,36 +1212,122 @@ impl AsyncDevice {
             u16::from(LIBUSB_DT_STRING) << 8 | u16::from(desc_index),
             langid,
             data,
-            core::time::Duration::from_millis(1000),
+            Self::DEFAULT_CONTROL_TIMEOUT,
         )
         .await
     }
-    pub async fn get_string_descriptor(
+    /// Reads the whole string descriptor `desc_index` (header included).
+    async fn get_string_descriptor_raw(
         &self,
         desc_index: u8,
         langid: u16,
-    ) -> Result<String, Error> {
-        let mut buf = vec![0_u8; 255];
+    ) -> Result<Vec<u8>, Error> {
+        let setup = ControlSetup::get_descriptor(DescriptorType::String, desc_index, langid, 255);
+        let mut buf = vec![0_u8; usize::from(setup.len)];
         let len = self
-            .get_string_descriptor_bytes(desc_index, langid, buf.as_mut_slice())
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &mut buf,
+                Self::DEFAULT_CONTROL_TIMEOUT,
+            )
             .await?;
-        buf.resize(len, 0_u8);
-        String::from_utf8(buf).map_err(|_| Error::Other)
+        buf.truncate(len);
+        Ok(buf)
+    }
+    /// Returns the languages the device's strings are available in (string descriptor 0).
+    pub async fn get_languages(&self) -> Result<Vec<Language>, Error> {
+        string_descriptor::parse_languages(&self.get_string_descriptor_raw(0, 0).await?)
+    }
+    pub async fn get_string_descriptor(
+        &self,
+        desc_index: u8,
+        langid: u16,
+    ) -> Result<String, Error> {
+        if desc_index == 0 {
+            return Err(Error::InvalidParam);
+        }
+        string_descriptor::parse_string(&self.get_string_descriptor_raw(desc_index, langid).await?)
     }
+    /// Reads string `desc_index` in the first language the device supports.
     pub async fn get_string_descriptor_ascii(&self, desc_index: u8) -> Result<String, Error> {
-        let mut langid_bytes = [0_u8; 2];
-        if self
-            .get_string_descriptor_bytes(0, 0, &mut langid_bytes[..])
+        let language = *self
+            .get_languages()
             .await?
-            != 2
-        {
-            return Err(Error::BadDescriptor);
+            .first()
+            .ok_or(Error::BadDescriptor)?;
+        self.get_string_descriptor(desc_index, language.into())
+            .await
+    }
+    /// String descriptors cached for the lifetime of this `AsyncDevice` (the `get_string_*`
+    /// methods always read them from the device).
+    pub fn strings(&self) -> StringCache<'_> {
+        StringCache::new(self, &self.strings)
+    }
+    /// Reads the manufacturer, product and serial number strings in `language`.
+    pub async fn get_device_strings(
+        &self,
+        descriptor: &DeviceDescriptor,
+        language: Language,
+    ) -> Result<DeviceStrings, Error> {
+        Ok(DeviceStrings {
+            manufacturer: self
+                .get_optional_string(descriptor.manufacturer_string_index(), language)
+                .await?,
+            product: self
+                .get_optional_string(descriptor.product_string_index(), language)
+                .await?,
+            serial: self
+                .get_optional_string(descriptor.serial_number_string_index(), language)
+                .await?,
+        })
+    }
+    async fn get_optional_string(
+        &self,
+        desc_index: Option<u8>,
+        language: Language,
+    ) -> Result<Option<String>, Error> {
+        match desc_index {
+            Some(index) => Ok(Some(
+                self.get_string_descriptor(index, language.into()).await?,
+            )),
+            None => Ok(None),
         }
-        let langid = u16::from_le_bytes(langid_bytes);
-        self.get_string_descriptor(desc_index, langid).await
     }
 }
 
+fn completed_iso_packets(packets: Vec<IsoPacket<'_>>) -> Vec<Vec<u8>> {
+    packets
+        .into_iter()
+        .filter(IsoPacket::is_completed)
+        .map(|p| p.data.to_vec())
+        .collect()
+}
+fn completed_iso_len(packets: Vec<IsoPacket<'_>>) -> usize {
+    packets
+        .into_iter()
+        .filter(IsoPacket::is_completed)
+        .map(|p| p.data.len())
+        .sum()
+}
+fn set_iso_write_lengths<Buf: AsRef<[u8]>>(
+    transfer: &mut SafeTransfer<
+        Buf,
+        impl BorrowMut<Transfer>,
+        impl BorrowMut<SafeTransferAsyncLink>,
+    >,
+    packets: &[&[u8]],
+) -> Result<(), Error> {
+    transfer.set_iso_packets(packets.len(), 0)?;
+    for (i, packet) in packets.iter().enumerate() {
+        transfer
+            .set_iso_packet_length(i, packet.len().try_into().map_err(|_| Error::InvalidParam)?)?;
+    }
+    Ok(())
+}
+
 struct InactiveTransfer {
     buf: Vec<u8>,
     transfer: Transfer,
@@ -219,16 +1347,51 @@ impl InactiveTransfer {
     ) -> SafeTransfer<TempBuf, &mut Transfer, &mut SafeTransferAsyncLink> {
         SafeTransfer::from_parts(buf, &mut self.transfer, &mut self.link)
     }
-    fn control_transfer(
+    /// Copies `data` into the reused buffer.
+    fn staged_transfer(
         &mut self,
         data: &[u8],
-        setup: ControlSetup,
     ) -> SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink> {
-        self.buf.resize(data.len() + ControlSetup::SIZE, 0_u8);
-        setup.serialize(self.buf.as_mut_slice());
-        self.buf.as_mut_slice()[ControlSetup::SIZE..].copy_from_slice(data);
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
         SafeTransfer::from_parts(self.buf.as_mut_slice(), &mut self.transfer, &mut self.link)
     }
+    /// Sizes the buffer for `setup` followed by `capacity` (or `data.len()` if bigger) bytes and
+    /// copies `data` in after the setup. Reads pass empty `data` and the length to read.
+    fn control_transfer(
+        &mut self,
+        data: &[u8],
+        capacity: usize,
+        setup: ControlSetup,
+    ) -> Result<SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink>, Error> {
+        self.buf
+            .resize(ControlSetup::SIZE + data.len().max(capacity), 0_u8);
+        setup.try_serialize(self.buf.as_mut_slice())?;
+        self.buf[ControlSetup::SIZE..ControlSetup::SIZE + data.len()].copy_from_slice(data);
+        let mut transfer =
+            SafeTransfer::from_parts(self.buf.as_mut_slice(), &mut self.transfer, &mut self.link);
+        // The `Transfer` might have been used for a bulk transfer before
+        transfer.set_type(TransferType::Control);
+        transfer.set_endpoint(0);
+        Ok(transfer)
+    }
+    /// Makes sure the `Transfer` has room for `num_packets` iso packets and the buffer is `len`
+    /// long. Fails like `Transfer::try_new` if it has to be reallocated.
+    fn iso_transfer(
+        &mut self,
+        num_packets: usize,
+        len: usize,
+    ) -> Result<SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink>, Error> {
+        if self.transfer.max_iso_packets() < num_packets {
+            self.transfer = Transfer::try_new(num_packets)?;
+        }
+        self.buf.resize(len, 0_u8);
+        Ok(SafeTransfer::from_parts(
+            self.buf.as_mut_slice(),
+            &mut self.transfer,
+            &mut self.link,
+        ))
+    }
 }
 
 /// A [`AsyncDevice`] but reusing a `Vec<u8>` underneath to save allocations. While
@@ -289,16 +1452,17 @@ impl SingleTransferDevice {
     ) -> Result<usize, Error> {
         let mut transfer = self.transfer.control_transfer(
             &[],
+            data.len(),
             ControlSetup {
                 request_type,
                 request,
                 value,
                 index,
-                len: data.len().try_into().expect("too much data"),
+                len: control_len(data.len())?,
             },
-        );
+        )?;
         transfer.set_timeout(timeout);
-        let len = transfer.submit_write(&self.device).await?;
+        let len = transfer.submit_read(&self.device).await?;
         data[..len].copy_from_slice(&transfer.control_data_ref()[..len]);
         Ok(len)
     }
@@ -313,17 +1477,21 @@ impl SingleTransferDevice {
     ) -> Result<usize, Error> {
         let mut transfer = self.transfer.control_transfer(
             data,
+            data.len(),
             ControlSetup {
                 request_type,
                 request,
                 value,
                 index,
-                len: data.len().try_into().expect("too much data"),
+                len: control_len(data.len())?,
             },
-        );
+        )?;
         transfer.set_timeout(timeout);
         // Fill transfer with control parameters
-        transfer.submit_write(&self.device).await
+        transfer
+            .submit_write(&self.device)
+            .await
+            .map_err(Error::from)
     }
     pub async fn bulk_type_write(
         &mut self,
@@ -332,11 +1500,14 @@ impl SingleTransferDevice {
         data: &[u8],
         timeout: core::time::Duration,
     ) -> Result<usize, Error> {
-        let mut transfer = self.transfer.safe_transfer(data);
+        let mut transfer = self.transfer.staged_transfer(data);
         transfer.set_type(bulk_type.into());
         transfer.set_endpoint(endpoint);
         transfer.set_timeout(timeout);
-        transfer.submit_write(&self.device).await
+        transfer
+            .submit_write(&self.device)
+            .await
+            .map_err(Error::from)
     }
 
     pub async fn bulk_type_read(
@@ -350,7 +1521,10 @@ impl SingleTransferDevice {
         transfer.set_type(bulk_type.into());
         transfer.set_endpoint(endpoint);
         transfer.set_timeout(timeout);
-        transfer.submit_read(&self.device).await
+        transfer
+            .submit_read(&self.device)
+            .await
+            .map_err(Error::from)
     }
     pub async fn bulk_write(
         &mut self,
@@ -388,9 +1562,241 @@ impl SingleTransferDevice {
         self.bulk_type_read(BulkType::Interrupt, endpoint, data, timeout)
             .await
     }
+    pub async fn iso_read(
+        &mut self,
+        endpoint: u8,
+        num_packets: usize,
+        packet_len: usize,
+        timeout: core::time::Duration,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let packet_len_u32 = packet_len.try_into().map_err(|_| Error::InvalidParam)?;
+        let len = num_packets
+            .checked_mul(packet_len)
+            .ok_or(Error::InvalidParam)?;
+        let mut transfer = self.transfer.iso_transfer(num_packets, len)?;
+        transfer.set_type(TransferType::Isochronous);
+        transfer.set_endpoint(endpoint);
+        transfer.set_timeout(timeout);
+        transfer.set_iso_packets(num_packets, packet_len_u32)?;
+        transfer.submit_read(&self.device).await?;
+        Ok(completed_iso_packets(transfer.iso_packets()))
+    }
+    pub async fn iso_write(
+        &mut self,
+        endpoint: u8,
+        packets: &[&[u8]],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let len = packets.iter().map(|p| p.len()).sum();
+        let mut transfer = self.transfer.iso_transfer(packets.len(), len)?;
+        let mut offset = 0;
+        for packet in packets {
+            transfer.buf_mut()[offset..offset + packet.len()].copy_from_slice(packet);
+            offset += packet.len();
+        }
+        transfer.set_type(TransferType::Isochronous);
+        transfer.set_endpoint(endpoint);
+        transfer.set_timeout(timeout);
+        set_iso_write_lengths(&mut transfer, packets)?;
+        transfer.submit_write(&self.device).await?;
+        Ok(completed_iso_len(transfer.iso_packets()))
+    }
 }
 impl From<AsyncDevice> for SingleTransferDevice {
     fn from(device: AsyncDevice) -> Self {
         SingleTransferDevice::new(device)
     }
 }
+#[cfg(test)]
+mod tests {
+    use crate::libusb::async_device::{
+        AsyncDevice, BlockingRef, InactiveTransfer, SingleTransferDevice,
+    };
+    use crate::libusb::device_handle::DeviceHandle;
+    use crate::libusb::error::Error;
+    use crate::libusb::transfer::{ControlSetup, TransferType};
+    use core::time::Duration;
+
+    fn setup(request_type: u8, len: u16) -> ControlSetup {
+        ControlSetup {
+            request_type,
+            request: 0x06,
+            value: 0x0100,
+            index: 0,
+            len,
+        }
+    }
+    #[test]
+    pub fn test_control_transfer_read_capacity() {
+        let mut inactive = InactiveTransfer::new();
+        let transfer = inactive
+            .control_transfer(&[], 18, setup(0x80, 18))
+            .expect("fits");
+        assert_eq!(transfer.buf_ref().len(), ControlSetup::SIZE + 18);
+        assert_eq!(transfer.calculated_control_data_len(), 18);
+        assert_eq!(transfer.control_setup_len_field(), Ok(18));
+        assert_eq!(transfer.get_type(), TransferType::Control);
+        assert_eq!(transfer.get_endpoint(), 0);
+    }
+    #[test]
+    pub fn test_control_transfer_write_data() {
+        let mut inactive = InactiveTransfer::new();
+        let data = [1_u8, 2, 3];
+        let transfer = inactive
+            .control_transfer(&data, data.len(), setup(0x00, 3))
+            .expect("fits");
+        assert_eq!(transfer.control_data_ref(), &data);
+        assert_eq!(transfer.control_setup_len_field(), Ok(3));
+        drop(transfer);
+        // Shorter data than capacity doesn't panic and the rest is left for the device
+        let transfer = inactive
+            .control_transfer(&data, 8, setup(0x00, 8))
+            .expect("fits");
+        assert_eq!(transfer.calculated_control_data_len(), 8);
+        assert_eq!(&transfer.control_data_ref()[..3], &data);
+        drop(transfer);
+        // Reusing the buffer for a smaller transfer shrinks it
+        let transfer = inactive
+            .control_transfer(&[], 1, setup(0x80, 1))
+            .expect("fits");
+        assert_eq!(transfer.buf_ref().len(), ControlSetup::SIZE + 1);
+    }
+    #[test]
+    pub fn test_staged_transfer_static_buf() {
+        static DATA: &[u8] = &[0x03, 0x0C, 0x00];
+        let mut inactive = InactiveTransfer::new();
+        let transfer = inactive.staged_transfer(DATA);
+        assert_eq!(transfer.buf_ref(), &DATA);
+        assert_ne!(transfer.buf_ref().as_ptr(), DATA.as_ptr());
+        drop(transfer);
+        // Staging a smaller write after a bigger one only keeps the new data
+        let transfer = inactive.staged_transfer(&DATA[..1]);
+        assert_eq!(transfer.buf_ref(), &&DATA[..1]);
+    }
+    #[test]
+    pub fn test_control_setup_staging() {
+        let mut inactive = InactiveTransfer::new();
+        let mut setup = setup(0x40, 2);
+        setup.value = 0xBEEF;
+        let transfer = inactive
+            .control_transfer(&[0xAA, 0x55], 2, setup)
+            .expect("fits");
+        let buf = transfer.buf_ref();
+        assert_eq!(buf.len(), ControlSetup::SIZE + 2);
+        let staged = ControlSetup::deserialize(buf);
+        assert_eq!(
+            (
+                staged.request_type,
+                staged.request,
+                staged.value,
+                staged.index,
+                staged.len
+            ),
+            (0x40, 0x06, 0xBEEF, 0, 2)
+        );
+        assert_eq!(&buf[ControlSetup::SIZE..], &[0xAA, 0x55]);
+    }
+    #[test]
+    pub fn test_control_oversized() {
+        // The lengths are checked before the handle is passed to libusb.
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let device = AsyncDevice::new(handle);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can't make async runtime");
+        let mut data = vec![0_u8; usize::from(u16::MAX) + 1];
+        let timeout = Duration::from_millis(100);
+        runtime.block_on(async {
+            assert_eq!(
+                device
+                    .control_read(0x80, 0x06, 0, 0, &mut data, timeout)
+                    .await,
+                Err(Error::InvalidParam)
+            );
+            assert_eq!(
+                device.control_write(0x00, 0x09, 0, 0, &data, timeout).await,
+                Err(Error::InvalidParam)
+            );
+            // `data` doesn't match `setup.len`
+            assert_eq!(
+                device.control_raw(setup(0x00, 2), &mut [0], timeout).await,
+                Err(Error::InvalidParam)
+            );
+            assert_eq!(
+                device.control_raw(setup(0x80, 2), &mut [0], timeout).await,
+                Err(Error::InvalidParam)
+            );
+        });
+        let mut single = SingleTransferDevice::new(device);
+        runtime.block_on(async {
+            assert_eq!(
+                single
+                    .control_read(0x80, 0x06, 0, 0, &mut data, timeout)
+                    .await,
+                Err(Error::InvalidParam)
+            );
+            assert_eq!(
+                single.control_write(0x00, 0x09, 0, 0, &data, timeout).await,
+                Err(Error::InvalidParam)
+            );
+        });
+        core::mem::forget(single.into_device());
+    }
+    #[test]
+    pub fn test_iso_read_oversized() {
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let device = AsyncDevice::new(handle);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can't make async runtime");
+        let read = runtime.block_on(device.iso_read(0x81, usize::MAX, 2, Duration::from_millis(1)));
+        assert_eq!(read, Err(Error::InvalidParam));
+        core::mem::forget(device);
+    }
+    #[test]
+    pub fn test_handle_mut_waits_for_blocking_tasks() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let mut device = AsyncDevice::new(handle);
+        // Like the blocking task of a `claim_interface` future that was dropped
+        let stray = BlockingRef {
+            handle: Some(device.handle.clone()),
+            idle: device.idle.clone(),
+        };
+        let done = Arc::new(AtomicBool::new(false));
+        let task_done = done.clone();
+        let task = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            task_done.store(true, Ordering::SeqCst);
+            drop(stray);
+        });
+        device.handle_mut();
+        assert!(done.load(Ordering::SeqCst));
+        task.join().expect("task panicked");
+        core::mem::forget(device);
+    }
+    #[test]
+    pub fn test_handle_mut_async_yields() {
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let mut device = AsyncDevice::new(handle);
+        let stray = BlockingRef {
+            handle: Some(device.handle.clone()),
+            idle: device.idle.clone(),
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("can't make async runtime");
+        runtime.block_on(async {
+            // Only runs if waiting for the handle lets the (only) executor thread go
+            let task = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(stray);
+            });
+            device.handle_mut_async().await;
+            task.await.expect("task panicked");
+        });
+        core::mem::forget(device);
+    }
+}
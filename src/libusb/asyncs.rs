@@ -1,13 +1,21 @@
 use crate::libusb::async_device::AsyncDevice;
 use crate::libusb::context::Context;
+use crate::libusb::device::Device;
 use crate::libusb::device_handle::DeviceHandle;
-use std::sync::atomic::AtomicBool;
+use crate::libusb::error::Error;
+use crate::libusb::hotplug::{self, HotplugStream};
+use crate::libusb::retry::RetryPolicy;
+use std::sync::atomic::AtomicI32;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
+type ErrorSubscribers = Arc<Mutex<Vec<mpsc::Sender<Error>>>>;
 pub struct AsyncContext {
     context: Arc<Context>,
-    running_atomic: Arc<AtomicBool>,
+    /// Non-zero once the event thread should stop. Used as libusb's `completed` flag.
+    stopped: Arc<AtomicI32>,
+    error_subscribers: ErrorSubscribers,
     thread: Option<std::thread::JoinHandle<()>>,
 }
 impl AsyncContext {
@@ -16,40 +24,106 @@ impl AsyncContext {
     }
     pub fn with_arc(context: Arc<Context>) -> AsyncContext {
         let job_context = context.clone();
-        let is_running = Arc::new(AtomicBool::new(true));
-        let running_atomic = is_running.clone();
+        let stopped = Arc::new(AtomicI32::new(0));
+        let job_stopped = stopped.clone();
+        let error_subscribers = ErrorSubscribers::default();
+        let job_subscribers = error_subscribers.clone();
         let job = move || {
-            while is_running.load(Ordering::Relaxed) {
-                job_context
-                    .handle_events_timeout(std::time::Duration::from_secs(1))
-                    .expect("libusb handle events error");
+            while job_stopped.load(Ordering::SeqCst) == 0 {
+                match job_context.handle_events_completed(&job_stopped, None) {
+                    Ok(()) | Err(Error::Interrupted) => (),
+                    Err(e) => Self::publish_error(&job_subscribers, e),
+                }
             }
         };
         let handle = std::thread::spawn(job);
         AsyncContext {
             context,
-            running_atomic,
+            stopped,
+            error_subscribers,
             thread: Some(handle),
         }
     }
+    fn publish_error(subscribers: &ErrorSubscribers, error: Error) {
+        let mut subscribers = subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget about subscribers that dropped their `Receiver`
+        subscribers.retain(|s| s.send(error).is_ok());
+    }
+    /// Subscribes to the errors the event thread runs into (other than `Error::Interrupted`).
+    /// The event thread keeps running after an error.
+    pub fn errors(&self) -> mpsc::Receiver<Error> {
+        let (sender, receiver) = mpsc::channel();
+        self.error_subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sender);
+        receiver
+    }
+    /// `Stream` of devices arriving and leaving, polling `device_list` every `poll_interval` on
+    /// platforms without hotplug support (Windows).
+    pub fn hotplug_stream(
+        &self,
+        flag: hotplug::Flags,
+        poll_interval: core::time::Duration,
+    ) -> Result<HotplugStream, Error> {
+        HotplugStream::new(self.context_arc(), flag, poll_interval)
+    }
     pub fn context_ref(&self) -> &Context {
         &self.context
     }
     pub fn context_arc(&self) -> Arc<Context> {
         self.context.clone()
     }
-    /// WARNING!!: If the device belongs to another context, async operations on that device will
-    /// just block. This function is a no-op just to make sure a `AsyncContext` is running. It does
-    /// not check to make sure it owns the handle. Proceed at own risk.
-    pub fn make_async_device(&self, handle: DeviceHandle) -> AsyncDevice {
-        AsyncDevice { handle }
+    /// Fails with `Error::InvalidParam` if `handle` belongs to another context (its transfers
+    /// would never complete). Handles made with `DeviceHandle::from_libusb` aren't checked.
+    pub fn make_async_device(&self, handle: DeviceHandle) -> Result<AsyncDevice, Error> {
+        self.context.check_owns(&handle)?;
+        Ok(AsyncDevice::new(handle))
+    }
+    /// Opens `device` on a blocking task, retrying with `policy` while the open fails with
+    /// `Error::Access` or `Error::Busy` (like right after a hotplug arrival).
+    pub async fn open_async_with_retry(
+        &self,
+        device: &Device,
+        policy: RetryPolicy,
+    ) -> Result<AsyncDevice, Error> {
+        let handle = policy
+            .retry_async(|| {
+                let device = device.clone();
+                blocking::unblock(move || device.open())
+            })
+            .await?;
+        self.make_async_device(handle)
+    }
+    /// Stops and joins the event thread. Returns the thread's panic (if it panicked).
+    fn stop(&mut self) -> std::thread::Result<()> {
+        self.stopped.store(1, Ordering::SeqCst);
+        match self.thread.take() {
+            Some(handle) => {
+                // `handle_events_completed` only checks `stopped` once it wakes up
+                self.context.interrupt_event_handler();
+                handle.join()
+            }
+            None => Ok(()),
+        }
+    }
+    /// Stops the event thread and returns the `Context`. Returns `Error::Busy` if the
+    /// `Context` is still shared (see `context_arc`). Panics if the event thread panicked.
+    pub fn shutdown(mut self) -> Result<Context, Error> {
+        if let Err(panic) = self.stop() {
+            std::panic::resume_unwind(panic)
+        }
+        let context = self.context.clone();
+        drop(self);
+        Arc::try_unwrap(context).map_err(|_| Error::Busy)
     }
 }
 impl Drop for AsyncContext {
     fn drop(&mut self) {
-        self.running_atomic.store(false, Ordering::SeqCst);
-        if let Some(handle) = self.thread.take() {
-            handle.join().expect("async context panicked")
+        if let Err(panic) = self.stop() {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(panic)
+            }
         }
     }
 }
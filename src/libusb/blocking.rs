@@ -0,0 +1,455 @@
+//! Transfers for synchronous code without an executor. They're submitted with libusb's
+//! asynchronous API and completed by handling the context's events on the calling thread. Unlike
+//! libusb's synchronous API (`DeviceHandle::bulk_read`, ...) they can be cancelled while waiting,
+//! like by a Ctrl-C handler setting the flag given to `BlockingDevice::cancel_on`.
+use crate::libusb::context::Context;
+use crate::libusb::control::{control_len, ControlSetup};
+use crate::libusb::device_handle::DeviceHandle;
+use crate::libusb::error::Error;
+use crate::libusb::transfer::{transfer_len, Transfer, TransferResult, TransferType};
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Longest `Context::handle_events_timeout` call, the cancel flag and the deadline are checked
+/// at least this often.
+const EVENT_SLICE: Duration = Duration::from_millis(50);
+/// How long a dropped in flight transfer waits for its cancellation before leaking its buffer
+/// and `Transfer` instead.
+const DROP_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn is_set(flag: Option<&AtomicBool>) -> bool {
+    flag.is_some_and(|f| f.load(Ordering::SeqCst))
+}
+extern "system" fn completion_callback(transfer: *mut libusb1_sys::libusb_transfer) {
+    // # Safety
+    // `user_data` is the reference leaked by `BlockingTransfer::submit`.
+    unsafe {
+        let completed = Arc::from_raw((*transfer).user_data as *const AtomicBool);
+        (*transfer).user_data = core::ptr::null_mut();
+        completed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A transfer and the buffer it owns. Dropping it while it's in flight cancels it and handles
+/// events until libusb is done with it. If that fails (or takes longer than `DROP_TIMEOUT`) both
+/// are leaked, libusb could still use them.
+pub struct BlockingTransfer<'c, 'h> {
+    context: &'c Context,
+    /// libusb uses the handle until the transfer completes, it can't be closed before.
+    handle: PhantomData<&'h DeviceHandle>,
+    /// Only dropped once libusb is done with it.
+    transfer: ManuallyDrop<Transfer>,
+    buf: Vec<u8>,
+    completed: Arc<AtomicBool>,
+    in_flight: bool,
+}
+impl<'c, 'h> BlockingTransfer<'c, 'h> {
+    /// Bulk or interrupt transfer of `buf`, read into if `endpoint` is an IN endpoint.
+    pub fn new(
+        context: &'c Context,
+        handle: &'h DeviceHandle,
+        transfer_type: TransferType,
+        endpoint: u8,
+        buf: Vec<u8>,
+        timeout: Duration,
+    ) -> BlockingTransfer<'c, 'h> {
+        let mut transfer = Transfer::new(0);
+        transfer.set_device(handle);
+        transfer.set_type(transfer_type);
+        transfer.set_endpoint(endpoint);
+        transfer.set_timeout(timeout);
+        BlockingTransfer {
+            context,
+            handle: PhantomData,
+            transfer: ManuallyDrop::new(transfer),
+            buf,
+            completed: Arc::new(AtomicBool::new(false)),
+            in_flight: false,
+        }
+    }
+    /// Control transfer. `data` is the data stage of an OUT request (`setup.len` has to match),
+    /// an IN request reads `setup.len` bytes.
+    pub fn control(
+        context: &'c Context,
+        handle: &'h DeviceHandle,
+        setup: ControlSetup,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<BlockingTransfer<'c, 'h>, Error> {
+        let mut buf = vec![0_u8; ControlSetup::SIZE + usize::from(setup.len)];
+        if setup.is_write() {
+            if data.len() != usize::from(setup.len) {
+                return Err(Error::InvalidParam);
+            }
+            buf[ControlSetup::SIZE..].copy_from_slice(data);
+        }
+        setup.serialize(&mut buf);
+        Ok(BlockingTransfer::new(
+            context,
+            handle,
+            TransferType::Control,
+            0,
+            buf,
+            timeout,
+        ))
+    }
+    pub fn submit(&mut self) -> Result<(), Error> {
+        if self.in_flight {
+            return Err(Error::Busy);
+        }
+        let (ptr, len) = (self.buf.as_mut_ptr(), self.buf.len());
+        transfer_len(len)?;
+        self.completed.store(false, Ordering::SeqCst);
+        self.transfer.set_buffer(ptr, len);
+        self.transfer.set_callback(completion_callback);
+        let completed = Arc::into_raw(self.completed.clone());
+        self.transfer.set_user_data(completed as *mut AtomicBool);
+        match unsafe { self.transfer.submit() } {
+            Ok(()) => {
+                self.in_flight = true;
+                Ok(())
+            }
+            Err(e) => {
+                // The callback won't run
+                self.transfer
+                    .set_user_data(core::ptr::null_mut::<AtomicBool>());
+                drop(unsafe { Arc::from_raw(completed) });
+                Err(e)
+            }
+        }
+    }
+    /// If the transfer completed. It only does while events are handled (`wait`, or any other
+    /// thread handling the context's events).
+    pub fn is_completed(&self) -> bool {
+        self.completed.load(Ordering::SeqCst)
+    }
+    /// Requests the transfer to be cancelled, `wait` still has to be called. A transfer that's
+    /// already completing isn't an error.
+    pub fn cancel(&self) -> Result<(), Error> {
+        match unsafe { self.transfer.cancel() } {
+            Err(Error::NotFound) | Ok(()) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    /// Handles events on this thread until the transfer completes. Once `deadline` has passed
+    /// or `cancel` is set, the transfer is cancelled (and still waited for, it then completes
+    /// with `Status::Cancelled`).
+    pub fn wait(
+        &mut self,
+        deadline: Option<Duration>,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<TransferResult, Error> {
+        let start = Instant::now();
+        let mut cancelled = false;
+        while self.in_flight && !self.is_completed() {
+            let expired = deadline.is_some_and(|d| start.elapsed() >= d);
+            let requested = is_set(cancel);
+            if !cancelled && (expired || requested) {
+                self.cancel()?;
+                cancelled = true;
+            }
+            match self.context.handle_events_timeout(EVENT_SLICE) {
+                Ok(()) | Err(Error::Interrupted) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.in_flight = false;
+        self.transfer.try_result()
+    }
+    /// The bytes transferred, after the setup packet for control transfers.
+    pub fn data(&self) -> &[u8] {
+        let start = match self.transfer.get_type() {
+            TransferType::Control => ControlSetup::SIZE,
+            _ => 0,
+        };
+        let len = usize::try_from(self.transfer.actual_length()).unwrap_or(0);
+        &self.buf[start..(start + len).min(self.buf.len())]
+    }
+    pub fn transfer_ref(&self) -> &Transfer {
+        &self.transfer
+    }
+}
+impl<'c, 'h> Drop for BlockingTransfer<'c, 'h> {
+    fn drop(&mut self) {
+        if self.in_flight && !self.is_completed() {
+            // Errors can't be returned, libusb is done with the buffer either way once it
+            // completes.
+            self.cancel().ok();
+            let start = Instant::now();
+            while !self.is_completed() && start.elapsed() < DROP_TIMEOUT {
+                match self.context.handle_events_timeout(EVENT_SLICE) {
+                    Ok(()) | Err(Error::Interrupted) => (),
+                    Err(_e) => {
+                        usb_debug!(error = ?_e, "handling events for a dropped transfer failed");
+                        break;
+                    }
+                }
+            }
+            if !self.is_completed() {
+                usb_debug!("dropped transfer never completed, leaking it");
+                core::mem::forget(core::mem::take(&mut self.buf));
+                return;
+            }
+        }
+        // # Safety
+        // Never submitted or completed, libusb is done with it. It isn't used after this.
+        unsafe { ManuallyDrop::drop(&mut self.transfer) }
+    }
+}
+
+/// Blocking IO on a `DeviceHandle` through `BlockingTransfer`s, see `DeviceHandle::blocking`.
+/// The timeouts are the transfers' (`transfer::NO_TIMEOUT` waits forever).
+#[derive(Copy, Clone)]
+pub struct BlockingDevice<'a> {
+    context: &'a Context,
+    handle: &'a DeviceHandle,
+    cancel: Option<&'a AtomicBool>,
+}
+impl<'a> BlockingDevice<'a> {
+    pub fn new(context: &'a Context, handle: &'a DeviceHandle) -> BlockingDevice<'a> {
+        BlockingDevice {
+            context,
+            handle,
+            cancel: None,
+        }
+    }
+    /// Transfers are cancelled (failing with `Error::Cancelled`) once `cancel` is set. It isn't
+    /// cleared.
+    pub fn cancel_on(mut self, cancel: &'a AtomicBool) -> BlockingDevice<'a> {
+        self.cancel = Some(cancel);
+        self
+    }
+    fn run(
+        &self,
+        mut transfer: BlockingTransfer<'a, 'a>,
+    ) -> Result<BlockingTransfer<'a, 'a>, Error> {
+        if is_set(self.cancel) {
+            return Err(Error::Cancelled);
+        }
+        transfer.submit()?;
+        transfer.wait(None, self.cancel)?.completed()?;
+        Ok(transfer)
+    }
+    fn read(
+        &self,
+        transfer_type: TransferType,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let buf = vec![0_u8; data.len()];
+        let transfer = BlockingTransfer::new(
+            self.context,
+            self.handle,
+            transfer_type,
+            endpoint,
+            buf,
+            timeout,
+        );
+        let transfer = self.run(transfer)?;
+        let read = transfer.data();
+        data[..read.len()].copy_from_slice(read);
+        Ok(read.len())
+    }
+    fn write(
+        &self,
+        transfer_type: TransferType,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let transfer = BlockingTransfer::new(
+            self.context,
+            self.handle,
+            transfer_type,
+            endpoint,
+            data.to_vec(),
+            timeout,
+        );
+        Ok(self.run(transfer)?.data().len())
+    }
+    pub fn bulk_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        self.read(TransferType::Bulk, endpoint, data, timeout)
+    }
+    pub fn bulk_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize, Error> {
+        self.write(TransferType::Bulk, endpoint, data, timeout)
+    }
+    pub fn interrupt_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        self.read(TransferType::Interrupt, endpoint, data, timeout)
+    }
+    pub fn interrupt_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        self.write(TransferType::Interrupt, endpoint, data, timeout)
+    }
+    pub fn control_read(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup {
+            request_type,
+            request,
+            value,
+            index,
+            len: control_len(data.len())?,
+        };
+        if !setup.is_read() {
+            return Err(Error::InvalidParam);
+        }
+        let transfer = BlockingTransfer::control(self.context, self.handle, setup, &[], timeout)?;
+        let transfer = self.run(transfer)?;
+        let read = transfer.data();
+        data[..read.len()].copy_from_slice(read);
+        Ok(read.len())
+    }
+    pub fn control_write(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup {
+            request_type,
+            request,
+            value,
+            index,
+            len: control_len(data.len())?,
+        };
+        if !setup.is_write() {
+            return Err(Error::InvalidParam);
+        }
+        let transfer = BlockingTransfer::control(self.context, self.handle, setup, data, timeout)?;
+        Ok(self.run(transfer)?.data().len())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::blocking::{completion_callback, BlockingDevice, BlockingTransfer};
+    use crate::libusb::context::Context;
+    use crate::libusb::device_handle::DeviceHandle;
+    use crate::libusb::error::Error;
+    use crate::libusb::transfer::{Status, TransferType};
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::time::Duration;
+    use std::sync::Arc;
+
+    /// Marks `transfer` as in flight like a successful `submit`, without passing it to libusb.
+    fn fake_submit(transfer: &mut BlockingTransfer) {
+        let completed = Arc::into_raw(transfer.completed.clone());
+        transfer
+            .transfer
+            .set_user_data(completed as *mut AtomicBool);
+        transfer.in_flight = true;
+    }
+    /// Completes `transfer` like libusb would, with `data` read into the buffer.
+    fn complete(transfer: &mut BlockingTransfer, data: &[u8]) {
+        transfer.buf[..data.len()].copy_from_slice(data);
+        let inner = transfer.transfer.libusb_mut();
+        inner.status = Status::Completed as i32;
+        inner.actual_length = data.len() as i32;
+        completion_callback(transfer.transfer.libusb_inner().as_ptr());
+    }
+    #[test]
+    pub fn test_completion_callback() {
+        // The handle and context are never passed to libusb: the transfer isn't submitted and
+        // it's already completed when it's waited for.
+        let context = Context::unowned();
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let mut transfer = BlockingTransfer::new(
+            &context,
+            &handle,
+            TransferType::Bulk,
+            0x81,
+            vec![0_u8; 8],
+            Duration::from_millis(100),
+        );
+        fake_submit(&mut transfer);
+        assert_eq!(transfer.submit(), Err(Error::Busy));
+        assert!(!transfer.is_completed());
+        complete(&mut transfer, &[1, 2, 3]);
+        assert!(transfer.is_completed());
+        // The callback took back the reference given to libusb
+        assert!(transfer.transfer.libusb_ref().user_data.is_null());
+        assert_eq!(Arc::strong_count(&transfer.completed), 1);
+        let result = transfer.wait(None, None).expect("completed");
+        assert_eq!(result.completed(), Ok(3));
+        assert_eq!(transfer.data(), &[1, 2, 3]);
+        drop(transfer);
+        core::mem::forget(handle);
+    }
+    #[test]
+    pub fn test_drop_completed() {
+        let context = Context::unowned();
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let new = || {
+            BlockingTransfer::new(
+                &context,
+                &handle,
+                TransferType::Bulk,
+                0x01,
+                vec![0_u8; 4],
+                Duration::from_millis(100),
+            )
+        };
+        // Never submitted
+        drop(new());
+        // In flight but its completion was already handled, it's neither cancelled nor are
+        // events handled for it
+        let mut transfer = new();
+        fake_submit(&mut transfer);
+        complete(&mut transfer, &[]);
+        drop(transfer);
+        core::mem::forget(handle);
+    }
+    #[test]
+    pub fn test_cancel_flag() {
+        // A set flag fails the transfer before it's submitted.
+        let context = Context::unowned();
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let cancel = AtomicBool::new(true);
+        let device = BlockingDevice::new(&context, &handle).cancel_on(&cancel);
+        let timeout = Duration::from_millis(100);
+        let mut buf = [0_u8; 4];
+        assert_eq!(
+            device.bulk_read(0x81, &mut buf, timeout),
+            Err(Error::Cancelled)
+        );
+        assert_eq!(
+            device.interrupt_write(0x02, &buf, timeout),
+            Err(Error::Cancelled)
+        );
+        assert_eq!(
+            device.control_read(0x80, 0x06, 0x0100, 0, &mut buf, timeout),
+            Err(Error::Cancelled)
+        );
+        // Not cleared
+        assert!(cancel.load(Ordering::SeqCst));
+        core::mem::forget(handle);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::libusb::error::Error;
+
+/// `bDevCapabilityType` of a USB 2.0 Extension capability.
+pub const USB_2_0_EXTENSION: u8 = 0x02;
+/// `bDevCapabilityType` of a SuperSpeed USB device capability.
+pub const SS_USB_DEVICE_CAPABILITY: u8 = 0x03;
+/// `bDevCapabilityType` of a Container ID capability.
+pub const CONTAINER_ID: u8 = 0x04;
+
+/// Binary Object Store descriptor. Owns the libusb allocation and frees it on drop.
+pub struct BosDescriptor(core::ptr::NonNull<libusb1_sys::libusb_bos_descriptor>);
+impl BosDescriptor {
+    /// # Safety
+    /// Assumes the pointer is a valid pointer to a `libusb_bos_descriptor` allocated by
+    /// `libusb`.
+    pub unsafe fn from_libusb(
+        ptr: core::ptr::NonNull<libusb1_sys::libusb_bos_descriptor>,
+    ) -> BosDescriptor {
+        BosDescriptor(ptr)
+    }
+    pub fn total_length(&self) -> u16 {
+        self.inner_ref().wTotalLength
+    }
+    pub fn num_device_caps(&self) -> u8 {
+        self.inner_ref().bNumDeviceCaps
+    }
+    pub fn capabilities(&self) -> DeviceCapabilities<'_> {
+        // libusb declares `dev_capability` as a flexible array of *pointers* to capability
+        // descriptors (`libusb1_sys` types it as an array of the descriptors themselves).
+        let ptr = self.inner_ref().dev_capability.as_ptr()
+            as *const *mut libusb1_sys::libusb_bos_dev_capability_descriptor;
+        let len = self.num_device_caps();
+        DeviceCapabilities(unsafe { core::slice::from_raw_parts(ptr, len.into()) }.iter())
+    }
+    pub fn inner_ref(&self) -> &libusb1_sys::libusb_bos_descriptor {
+        unsafe { self.0.as_ref() }
+    }
+}
+impl Drop for BosDescriptor {
+    fn drop(&mut self) {
+        unsafe { libusb1_sys::libusb_free_bos_descriptor(self.0.as_ptr()) }
+    }
+}
+impl core::fmt::Debug for BosDescriptor {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        let mut debug = fmt.debug_struct("BosDescriptor");
+
+        let descriptor = self.inner_ref();
+
+        debug.field("bLength", &descriptor.bLength);
+        debug.field("bDescriptorType", &descriptor.bDescriptorType);
+        debug.field("wTotalLength", &descriptor.wTotalLength);
+        debug.field("bNumDeviceCaps", &descriptor.bNumDeviceCaps);
+        debug.field(
+            "capabilities",
+            &self.capabilities().collect::<Vec<DeviceCapability<'_>>>(),
+        );
+
+        debug.finish()
+    }
+}
+unsafe impl Sync for BosDescriptor {}
+unsafe impl Send for BosDescriptor {}
+
+pub struct DeviceCapabilities<'a>(
+    core::slice::Iter<'a, *mut libusb1_sys::libusb_bos_dev_capability_descriptor>,
+);
+impl<'a> Iterator for DeviceCapabilities<'a> {
+    type Item = DeviceCapability<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|&ptr| unsafe { DeviceCapability::from_libusb(ptr) })
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+impl<'a> ExactSizeIterator for DeviceCapabilities<'a> {}
+
+/// A single device capability in a `BosDescriptor`. Capabilities libusb can't parse (or that
+/// aren't known) are `Raw`.
+#[derive(Copy, Clone, Debug)]
+pub enum DeviceCapability<'a> {
+    Usb2Extension(Usb2Extension),
+    SuperSpeed(SuperSpeedCapability),
+    ContainerId(ContainerId),
+    Raw(RawCapability<'a>),
+}
+impl<'a> DeviceCapability<'a> {
+    /// # Safety
+    /// `ptr` must point to a capability descriptor inside a live `libusb_bos_descriptor`.
+    unsafe fn from_libusb(
+        ptr: *mut libusb1_sys::libusb_bos_dev_capability_descriptor,
+    ) -> DeviceCapability<'a> {
+        let raw = RawCapability::from_libusb(&*ptr);
+        let parsed = match raw.capability_type() {
+            USB_2_0_EXTENSION => {
+                Usb2Extension::from_libusb(ptr).map(DeviceCapability::Usb2Extension)
+            }
+            SS_USB_DEVICE_CAPABILITY => {
+                SuperSpeedCapability::from_libusb(ptr).map(DeviceCapability::SuperSpeed)
+            }
+            CONTAINER_ID => ContainerId::from_libusb(ptr).map(DeviceCapability::ContainerId),
+            _ => Err(Error::NotSupported),
+        };
+        parsed.unwrap_or(DeviceCapability::Raw(raw))
+    }
+}
+/// Unparsed device capability. `data` is everything after the 3 byte capability header.
+#[derive(Copy, Clone, Debug)]
+pub struct RawCapability<'a> {
+    capability_type: u8,
+    data: &'a [u8],
+}
+impl<'a> RawCapability<'a> {
+    const HEADER_SIZE: usize = 3;
+    fn from_libusb(
+        descriptor: &'a libusb1_sys::libusb_bos_dev_capability_descriptor,
+    ) -> RawCapability<'a> {
+        let len = usize::from(descriptor.bLength).saturating_sub(Self::HEADER_SIZE);
+        RawCapability {
+            capability_type: descriptor.bDevCapabilityType,
+            data: unsafe {
+                core::slice::from_raw_parts(descriptor.dev_capability_data.as_ptr(), len)
+            },
+        }
+    }
+    pub fn capability_type(&self) -> u8 {
+        self.capability_type
+    }
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+/// USB 2.0 Extension capability.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Usb2Extension {
+    pub attributes: u32,
+}
+impl Usb2Extension {
+    unsafe fn from_libusb(
+        ptr: *mut libusb1_sys::libusb_bos_dev_capability_descriptor,
+    ) -> Result<Usb2Extension, Error> {
+        let mut out: *const libusb1_sys::libusb_usb_2_0_extension_descriptor =
+            core::ptr::null_mut();
+        // The context is only used for logging so the default one is fine.
+        try_unsafe!(libusb1_sys::libusb_get_usb_2_0_extension_descriptor(
+            core::ptr::null_mut(),
+            ptr,
+            &mut out as *mut _
+        ));
+        let extension = Usb2Extension {
+            attributes: (*out).bmAttributes,
+        };
+        libusb1_sys::libusb_free_usb_2_0_extension_descriptor(out as *mut _);
+        Ok(extension)
+    }
+    /// Link Power Management support.
+    pub fn supports_lpm(&self) -> bool {
+        self.attributes & 0x02 != 0
+    }
+}
+/// SuperSpeed USB device capability.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct SuperSpeedCapability {
+    pub attributes: u8,
+    /// Bitmap of supported speeds (bit 0 low, 1 full, 2 high, 3 super speed).
+    pub speed_supported: u16,
+    pub functionality_support: u8,
+    pub u1_dev_exit_lat: u8,
+    pub u2_dev_exit_lat: u16,
+}
+impl SuperSpeedCapability {
+    unsafe fn from_libusb(
+        ptr: *mut libusb1_sys::libusb_bos_dev_capability_descriptor,
+    ) -> Result<SuperSpeedCapability, Error> {
+        let mut out: *const libusb1_sys::libusb_ss_usb_device_capability_descriptor =
+            core::ptr::null_mut();
+        try_unsafe!(libusb1_sys::libusb_get_ss_usb_device_capability_descriptor(
+            core::ptr::null_mut(),
+            ptr,
+            &mut out as *mut _
+        ));
+        let descriptor = &*out;
+        let capability = SuperSpeedCapability {
+            attributes: descriptor.bmAttributes,
+            speed_supported: descriptor.wSpeedSupported,
+            functionality_support: descriptor.bFunctionalitySupport,
+            u1_dev_exit_lat: descriptor.bU1DevExitLat,
+            u2_dev_exit_lat: descriptor.bU2DevExitLat.into(),
+        };
+        libusb1_sys::libusb_free_ss_usb_device_capability_descriptor(out as *mut _);
+        Ok(capability)
+    }
+    /// Latency Tolerance Messages support.
+    pub fn supports_ltm(&self) -> bool {
+        self.attributes & 0x02 != 0
+    }
+}
+/// Container ID capability. Uniquely identifies the device across all of its USB ports.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct ContainerId(pub [u8; 16]);
+impl ContainerId {
+    unsafe fn from_libusb(
+        ptr: *mut libusb1_sys::libusb_bos_dev_capability_descriptor,
+    ) -> Result<ContainerId, Error> {
+        let mut out: *const libusb1_sys::libusb_container_id_descriptor = core::ptr::null_mut();
+        try_unsafe!(libusb1_sys::libusb_get_container_id_descriptor(
+            core::ptr::null_mut(),
+            ptr,
+            &mut out as *mut _
+        ));
+        let id = ContainerId((*out).ContainerId);
+        libusb1_sys::libusb_free_container_id_descriptor(out as *mut _);
+        Ok(id)
+    }
+}
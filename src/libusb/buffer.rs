@@ -1,23 +1,74 @@
-#![allow(unused)]
+//! Reusable `Transfer`s and buffers for high throughput streaming. Every `AsyncDevice` IO call
+//! allocates a `Transfer` (and its completion link) which adds up at high transfer rates.
+use crate::libusb::async_device::{AsyncDevice, BulkType};
+use crate::libusb::error::Error;
+use crate::libusb::safe_transfer::{SafeTransfer, SafeTransferAsyncLink};
 use crate::libusb::transfer::Transfer;
-#[derive(Clone, Debug)]
-struct Inner {}
-impl Inner {
-    unsafe fn alloc(&self, _len: usize) -> *mut u8 {
-        unimplemented!()
+use std::io::{IoSlice, IoSliceMut};
+use std::sync::Mutex;
+
+/// Total length of `bufs`.
+pub fn io_slices_len(bufs: &[IoSlice<'_>]) -> usize {
+    bufs.iter().map(|b| b.len()).sum()
+}
+/// Copies `bufs` one after the other into `dst`, which has to be `io_slices_len(bufs)` long.
+pub(crate) fn gather(bufs: &[IoSlice<'_>], dst: &mut [u8]) {
+    let mut offset = 0;
+    for buf in bufs {
+        dst[offset..offset + buf.len()].copy_from_slice(buf);
+        offset += buf.len();
     }
 }
-pub struct Pool {
-    transfers: Vec<Transfer>,
+/// `bufs` copied into one `Vec` allocated once.
+pub(crate) fn gathered(bufs: &[IoSlice<'_>]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(io_slices_len(bufs));
+    for buf in bufs {
+        data.extend_from_slice(buf);
+    }
+    data
 }
+/// Copies `src` into `bufs` in order until it runs out. Returns the bytes copied.
+pub(crate) fn scatter(mut src: &[u8], bufs: &mut [IoSliceMut<'_>]) -> usize {
+    let mut copied = 0;
+    for buf in bufs {
+        let len = buf.len().min(src.len());
+        buf[..len].copy_from_slice(&src[..len]);
+        src = &src[len..];
+        copied += len;
+    }
+    copied
+}
+
+/// Heap buffer aligned to `Allocation::ALIGN`. Freed on drop.
 #[derive(Debug)]
 pub struct Allocation {
-    ptr: *mut u8,
+    ptr: core::ptr::NonNull<u8>,
     len: usize,
 }
 impl Allocation {
+    /// Cache line alignment.
+    pub const ALIGN: usize = 64;
+    fn layout(len: usize) -> core::alloc::Layout {
+        core::alloc::Layout::from_size_align(len, Self::ALIGN).expect("bad alloc layout")
+    }
+    /// Allocates `len` zeroed bytes.
+    pub fn new(len: usize) -> Allocation {
+        if len == 0 {
+            return Allocation {
+                ptr: core::ptr::NonNull::dangling(),
+                len,
+            };
+        }
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        Allocation {
+            ptr: core::ptr::NonNull::new(ptr)
+                .unwrap_or_else(|| alloc::alloc::handle_alloc_error(layout)),
+            len,
+        }
+    }
     pub fn ptr(&mut self) -> *mut u8 {
-        self.ptr
+        self.ptr.as_ptr()
     }
     pub fn len(&self) -> usize {
         self.len
@@ -26,21 +77,360 @@ impl Allocation {
         self.len == 0
     }
 }
+impl AsRef<[u8]> for Allocation {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+impl AsMut<[u8]> for Allocation {
+    fn as_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe { alloc::alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+        }
+    }
+}
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+/// A `Transfer`, its completion link and a buffer. Only ever stored in the pool while inactive.
+struct Entry {
+    transfer: Transfer,
+    link: SafeTransferAsyncLink,
+    buf: Allocation,
+}
+// # Safety
+// Entries in the pool are inactive so libusb (and the event thread) isn't using the `Transfer`.
+unsafe impl Send for Entry {}
+impl Entry {
+    fn new(buf_size: usize) -> Entry {
+        Entry {
+            transfer: Transfer::new(0),
+            link: SafeTransferAsyncLink::new(),
+            buf: Allocation::new(buf_size),
+        }
+    }
+}
+/// Pool of pre-allocated `Transfer`s and `buf_size` byte buffers. `acquire` hands them out and
+/// they return to the pool when the `PooledTransfer` is dropped. If the pool runs dry, `acquire`
+/// allocates a new one (which then stays in the pool).
+pub struct Pool {
+    buf_size: usize,
+    free: Mutex<Vec<Entry>>,
+}
 impl Pool {
-    fn layout(len: usize) -> core::alloc::Layout {
-        unsafe { core::alloc::Layout::from_size_align_unchecked(len, 2) }
+    pub fn new(transfer_count: usize, buf_size: usize) -> Pool {
+        Pool {
+            buf_size,
+            free: Mutex::new((0..transfer_count).map(|_| Entry::new(buf_size)).collect()),
+        }
+    }
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
     }
-    unsafe fn allocate(&mut self, len: usize) -> *mut u8 {
-        alloc::alloc::alloc(core::alloc::Layout::from_size_align(len, 2).expect("bad alloc layout"))
+    /// Amount of transfers currently in the pool (not acquired).
+    pub fn available(&self) -> usize {
+        self.free_list().len()
     }
-    unsafe fn deallocate(&mut self, ptr: *mut u8, len: usize) {
-        alloc::alloc::dealloc(ptr, Self::layout(len))
+    fn free_list(&self) -> std::sync::MutexGuard<'_, Vec<Entry>> {
+        // The list is never left half updated so a poisoned lock is still fine to use.
+        self.free.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    pub fn acquire(&self) -> PooledTransfer<'_> {
+        let entry = self.free_list().pop();
+        PooledTransfer {
+            pool: self,
+            entry: Some(entry.unwrap_or_else(|| Entry::new(self.buf_size))),
+            len: 0,
+        }
+    }
+    fn release(&self, entry: Entry) {
+        self.free_list().push(entry)
+    }
+}
+/// A `Transfer` and buffer borrowed from a `Pool`. Returns them to the pool on drop.
+pub struct PooledTransfer<'p> {
+    pool: &'p Pool,
+    entry: Option<Entry>,
+    /// Length of the data in the buffer.
+    len: usize,
+}
+impl<'p> PooledTransfer<'p> {
+    fn entry_ref(&self) -> &Entry {
+        self.entry.as_ref().expect("entry is only taken on drop")
     }
-    pub fn pop_transfer(&mut self) -> Transfer {
-        // TODO: iso packets
-        self.transfers.pop().unwrap_or_else(|| Transfer::new(0))
+    fn entry_mut(&mut self) -> &mut Entry {
+        self.entry.as_mut().expect("entry is only taken on drop")
+    }
+    /// The data from the last `PooledDevice` read.
+    pub fn data(&self) -> &[u8] {
+        &self.entry_ref().buf.as_ref()[..self.len]
+    }
+    /// The whole buffer (`Pool::buf_size` bytes).
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        self.entry_mut().buf.as_mut()
+    }
+    /// `SafeTransfer` using the pooled `Transfer` and buffer.
+    pub fn safe_transfer(
+        &mut self,
+    ) -> SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink> {
+        let entry = self.entry_mut();
+        SafeTransfer::from_parts(entry.buf.as_mut(), &mut entry.transfer, &mut entry.link)
+    }
+    /// `SafeTransfer` over the first `len` bytes of the pooled buffer. `len` must fit in it.
+    fn sized_transfer(
+        &mut self,
+        len: usize,
+    ) -> SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink> {
+        let entry = self.entry_mut();
+        SafeTransfer::from_parts(
+            &mut entry.buf.as_mut()[..len],
+            &mut entry.transfer,
+            &mut entry.link,
+        )
+    }
+    /// `SafeTransfer` over the first `data.len()` bytes of the pooled buffer with `data` copied
+    /// in. `data` must fit in the buffer.
+    fn staged_transfer(
+        &mut self,
+        data: &[u8],
+    ) -> SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink> {
+        let mut transfer = self.sized_transfer(data.len());
+        transfer.buf_mut().copy_from_slice(data);
+        transfer
+    }
+    /// `staged_transfer` of `bufs` one after the other. `None` if they don't fit in the buffer.
+    pub fn staged_vectored(
+        &mut self,
+        bufs: &[IoSlice<'_>],
+    ) -> Option<SafeTransfer<&mut [u8], &mut Transfer, &mut SafeTransferAsyncLink>> {
+        let len = io_slices_len(bufs);
+        if len > self.pool.buf_size() {
+            return None;
+        }
+        let mut transfer = self.sized_transfer(len);
+        gather(bufs, transfer.buf_mut());
+        Some(transfer)
+    }
+    /// `SafeTransfer` using the pooled `Transfer` but with another buffer.
+    pub fn safe_transfer_with<Buf>(
+        &mut self,
+        buf: Buf,
+    ) -> SafeTransfer<Buf, &mut Transfer, &mut SafeTransferAsyncLink> {
+        let entry = self.entry_mut();
+        SafeTransfer::from_parts(buf, &mut entry.transfer, &mut entry.link)
+    }
+}
+impl<'p> Drop for PooledTransfer<'p> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.pool.release(entry)
+        }
+    }
+}
+/// `AsyncDevice` IO that draws its `Transfer`s and buffers from a `Pool` instead of allocating.
+/// See `AsyncDevice::with_pool`.
+pub struct PooledDevice<'a> {
+    device: &'a AsyncDevice,
+    pool: &'a Pool,
+}
+impl<'a> PooledDevice<'a> {
+    pub fn new(device: &'a AsyncDevice, pool: &'a Pool) -> PooledDevice<'a> {
+        PooledDevice { device, pool }
+    }
+    pub fn device(&self) -> &'a AsyncDevice {
+        self.device
+    }
+    pub fn pool(&self) -> &'a Pool {
+        self.pool
+    }
+    /// Reads up to `Pool::buf_size` bytes into a pooled buffer. The data is in
+    /// `PooledTransfer::data` and the buffer goes back to the pool once it's dropped.
+    pub async fn bulk_type_read(
+        &self,
+        bulk_type: BulkType,
+        endpoint: u8,
+        timeout: core::time::Duration,
+    ) -> Result<PooledTransfer<'a>, Error> {
+        let mut pooled = self.pool.acquire();
+        let mut transfer = pooled.safe_transfer();
+        transfer.set_type(bulk_type.into());
+        transfer.set_endpoint(endpoint);
+        transfer.set_timeout(timeout);
+        let len = transfer.submit_read(self.device).await?;
+        drop(transfer);
+        pooled.len = len;
+        Ok(pooled)
+    }
+    /// Writes `data` with a pooled `Transfer`. `data` is copied into the pooled buffer, or a
+    /// temporary `Vec` if it's bigger than `Pool::buf_size`.
+    pub async fn bulk_type_write(
+        &self,
+        bulk_type: BulkType,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let mut pooled = self.pool.acquire();
+        if data.len() <= self.pool.buf_size() {
+            let transfer = pooled.staged_transfer(data);
+            self.submit_write(transfer, bulk_type, endpoint, timeout)
+                .await
+        } else {
+            let transfer = pooled.safe_transfer_with(data.to_vec());
+            self.submit_write(transfer, bulk_type, endpoint, timeout)
+                .await
+        }
+    }
+    /// `bulk_write` of `bufs` one after the other, gathered in the pooled buffer (or a temporary
+    /// `Vec` if they don't fit).
+    pub async fn bulk_write_vectored(
+        &self,
+        endpoint: u8,
+        bufs: &[IoSlice<'_>],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let mut pooled = self.pool.acquire();
+        if io_slices_len(bufs) <= self.pool.buf_size() {
+            let transfer = pooled.staged_vectored(bufs).expect("checked it fits");
+            self.submit_write(transfer, BulkType::Bulk, endpoint, timeout)
+                .await
+        } else {
+            let transfer = pooled.safe_transfer_with(gathered(bufs));
+            self.submit_write(transfer, BulkType::Bulk, endpoint, timeout)
+                .await
+        }
+    }
+    /// Reads up to the total length of `bufs` into the pooled buffer (or a temporary `Vec` if
+    /// they don't fit) and copies it into `bufs` in order. Returns the bytes read.
+    pub async fn bulk_read_vectored(
+        &self,
+        endpoint: u8,
+        bufs: &mut [IoSliceMut<'_>],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        let mut pooled = self.pool.acquire();
+        if len <= self.pool.buf_size() {
+            let transfer = pooled.sized_transfer(len);
+            let read = self
+                .submit_read(transfer, BulkType::Bulk, endpoint, timeout)
+                .await?;
+            Ok(scatter(&pooled.buf_mut()[..read], bufs))
+        } else {
+            let mut staging = vec![0_u8; len];
+            let transfer = pooled.safe_transfer_with(staging.as_mut_slice());
+            let read = self
+                .submit_read(transfer, BulkType::Bulk, endpoint, timeout)
+                .await?;
+            Ok(scatter(&staging[..read], bufs))
+        }
+    }
+    async fn submit_read<Buf: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        mut transfer: SafeTransfer<Buf, &mut Transfer, &mut SafeTransferAsyncLink>,
+        bulk_type: BulkType,
+        endpoint: u8,
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        transfer.set_type(bulk_type.into());
+        transfer.set_endpoint(endpoint);
+        transfer.set_timeout(timeout);
+        transfer.submit_read(self.device).await.map_err(Error::from)
+    }
+    async fn submit_write<Buf: AsRef<[u8]>>(
+        &self,
+        mut transfer: SafeTransfer<Buf, &mut Transfer, &mut SafeTransferAsyncLink>,
+        bulk_type: BulkType,
+        endpoint: u8,
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        transfer.set_type(bulk_type.into());
+        transfer.set_endpoint(endpoint);
+        transfer.set_timeout(timeout);
+        transfer
+            .submit_write(self.device)
+            .await
+            .map_err(Error::from)
+    }
+    pub async fn bulk_read(
+        &self,
+        endpoint: u8,
+        timeout: core::time::Duration,
+    ) -> Result<PooledTransfer<'a>, Error> {
+        self.bulk_type_read(BulkType::Bulk, endpoint, timeout).await
+    }
+    pub async fn bulk_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.bulk_type_write(BulkType::Bulk, endpoint, data, timeout)
+            .await
+    }
+    pub async fn interrupt_read(
+        &self,
+        endpoint: u8,
+        timeout: core::time::Duration,
+    ) -> Result<PooledTransfer<'a>, Error> {
+        self.bulk_type_read(BulkType::Interrupt, endpoint, timeout)
+            .await
+    }
+    pub async fn interrupt_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.bulk_type_write(BulkType::Interrupt, endpoint, data, timeout)
+            .await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::buffer::{gathered, io_slices_len, scatter, Allocation, Pool};
+    use std::io::{IoSlice, IoSliceMut};
+
+    #[test]
+    pub fn test_pool_reuses_transfers() {
+        let pool = Pool::new(2, 512);
+        assert_eq!(pool.available(), 2);
+        {
+            let mut first = pool.acquire();
+            let second = pool.acquire();
+            assert_eq!(pool.available(), 0);
+            assert_eq!(first.buf_mut().len(), 512);
+            assert_eq!(
+                first.buf_mut().as_ptr() as usize % Allocation::ALIGN,
+                0,
+                "unaligned buffer"
+            );
+            assert!(second.data().is_empty());
+            // Empty pool allocates instead of blocking
+            let _third = pool.acquire();
+        }
+        assert_eq!(pool.available(), 3);
     }
-    pub fn push_transfer(&mut self, transfer: Transfer) {
-        self.transfers.push(transfer)
+    #[test]
+    pub fn test_vectored() {
+        let bufs = [IoSlice::new(&[1, 2]), IoSlice::new(&[]), IoSlice::new(&[3])];
+        assert_eq!(io_slices_len(&bufs), 3);
+        assert_eq!(gathered(&bufs), vec![1, 2, 3]);
+
+        let pool = Pool::new(1, 2);
+        let mut pooled = pool.acquire();
+        assert!(pooled.staged_vectored(&bufs).is_none());
+        let transfer = pooled.staged_vectored(&bufs[..2]).unwrap();
+        assert_eq!(*transfer.buf_ref(), &[1, 2]);
+
+        let (mut first, mut second) = ([0_u8; 1], [0_u8; 3]);
+        let mut bufs = [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)];
+        assert_eq!(scatter(&[4, 5, 6], &mut bufs), 3);
+        assert_eq!((first, second), ([4], [5, 6, 0]));
     }
 }
@@ -0,0 +1,213 @@
+//! `AsyncRead`/`AsyncWrite` adapters over bulk endpoints (`futures-io` and `tokio` features).
+//!
+//! Each adapter reuses a single transfer and buffer (like `SingleTransferDevice`). Timeouts
+//! just resubmit the transfer and `Error::NoDevice` becomes `ErrorKind::NotConnected`.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::error::Error;
+use crate::libusb::safe_transfer::SafeTransfer;
+use crate::libusb::transfer::TransferType;
+#[cfg(any(feature = "futures-io", feature = "tokio"))]
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Reads a bulk IN endpoint `buf_size` bytes at a time. Bytes the caller didn't have room for
+/// are kept for the next read.
+pub struct BulkReader<'d> {
+    device: &'d AsyncDevice,
+    transfer: SafeTransfer<Vec<u8>>,
+    in_flight: bool,
+    /// Start and end of the unread bytes in the transfer buffer.
+    unread: (usize, usize),
+}
+impl<'d> BulkReader<'d> {
+    pub fn new(device: &'d AsyncDevice, endpoint: u8, buf_size: usize) -> BulkReader<'d> {
+        let mut transfer = SafeTransfer::from_buf(vec![0_u8; buf_size]);
+        transfer.set_type(TransferType::Bulk);
+        transfer.set_endpoint(endpoint);
+        BulkReader {
+            device,
+            transfer,
+            in_flight: false,
+            unread: (0, 0),
+        }
+    }
+    /// Per transfer timeout. A timed out transfer is just resubmitted.
+    pub fn set_timeout(&mut self, timeout: core::time::Duration) {
+        self.transfer.set_timeout(timeout)
+    }
+    /// Fills the unread bytes from the device.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            if !self.in_flight {
+                self.transfer.start_read(self.device)?;
+                self.in_flight = true;
+            }
+            let result = match self.transfer.poll_completion(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.in_flight = false;
+            let len = match result {
+                Ok(len) => len,
+                // Keep whatever arrived before the timeout
+                Err(Error::Timeout) => self.transfer.transfer_ref().actual_length() as usize,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            // A zero length packet isn't EOF, keep reading
+            if len > 0 {
+                self.unread = (0, len);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+    pub fn poll_read_bytes(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.unread.0 == self.unread.1 {
+            match self.poll_fill(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let (start, end) = self.unread;
+        let len = buf.len().min(end - start);
+        buf[..len].copy_from_slice(&self.transfer.buf_ref()[start..start + len]);
+        self.unread.0 += len;
+        Poll::Ready(Ok(len))
+    }
+}
+/// Writes to a bulk OUT endpoint. Each write is copied into the adapter's buffer (up to
+/// `MAX_WRITE` bytes) and submitted right away. The next write (or flush) waits for it to
+/// finish.
+pub struct BulkWriter<'d> {
+    device: &'d AsyncDevice,
+    transfer: SafeTransfer<Vec<u8>>,
+    in_flight: bool,
+}
+impl<'d> BulkWriter<'d> {
+    /// Most bytes accepted by a single write.
+    pub const MAX_WRITE: usize = 64 * 1024;
+    pub fn new(device: &'d AsyncDevice, endpoint: u8) -> BulkWriter<'d> {
+        let mut transfer = SafeTransfer::from_buf(Vec::new());
+        transfer.set_type(TransferType::Bulk);
+        transfer.set_endpoint(endpoint);
+        BulkWriter {
+            device,
+            transfer,
+            in_flight: false,
+        }
+    }
+    /// Per transfer timeout. A timed out transfer is resubmitted with the bytes that weren't
+    /// sent.
+    pub fn set_timeout(&mut self, timeout: core::time::Duration) {
+        self.transfer.set_timeout(timeout)
+    }
+    /// Waits for the in flight transfer. Resubmits whatever wasn't sent (short or timed out).
+    pub fn poll_flush_transfer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.in_flight {
+            let result = match self.transfer.poll_completion(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.in_flight = false;
+            let sent = match result {
+                Ok(len) => len,
+                Err(Error::Timeout) => self.transfer.transfer_ref().actual_length() as usize,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            if sent < self.transfer.buf_ref().len() {
+                self.transfer.buf_mut().drain(..sent);
+                self.transfer.start_write(self.device)?;
+                self.in_flight = true;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+    pub fn poll_write_bytes(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.poll_flush_transfer(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(Self::MAX_WRITE);
+        let transfer_buf = self.transfer.buf_mut();
+        transfer_buf.clear();
+        transfer_buf.extend_from_slice(&buf[..len]);
+        self.transfer.start_write(self.device)?;
+        self.in_flight = true;
+        Poll::Ready(Ok(len))
+    }
+}
+#[cfg(feature = "futures-io")]
+impl<'d> futures_io::AsyncRead for BulkReader<'d> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().poll_read_bytes(cx, buf).map_err(Into::into)
+    }
+}
+#[cfg(feature = "futures-io")]
+impl<'d> futures_io::AsyncWrite for BulkWriter<'d> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().poll_write_bytes(cx, buf).map_err(Into::into)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_flush_transfer(cx).map_err(Into::into)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_flush_transfer(cx).map_err(Into::into)
+    }
+}
+#[cfg(feature = "tokio")]
+impl<'d> tokio::io::AsyncRead for BulkReader<'d> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_read_bytes(cx, buf.initialize_unfilled()) {
+            Poll::Ready(Ok(len)) => {
+                buf.advance(len);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+#[cfg(feature = "tokio")]
+impl<'d> tokio::io::AsyncWrite for BulkWriter<'d> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().poll_write_bytes(cx, buf).map_err(Into::into)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_flush_transfer(cx).map_err(Into::into)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_flush_transfer(cx).map_err(Into::into)
+    }
+}
@@ -0,0 +1,533 @@
+//! CDC-ACM (USB serial) devices.
+//!
+//! An ACM function is a communication interface (class 0x02, subclass 0x02) with an optional
+//! interrupt IN notification endpoint and a data interface (class 0x0A) with a bulk IN and OUT
+//! endpoint. Composite devices can have several, `acm_functions` lists them so the caller can
+//! pick one for `CdcAcmDevice::open_function`.
+use crate::descriptors::{InterfaceAssociation, ParsedAltSetting, ParsedConfig};
+use crate::libusb::async_device::{AsyncDevice, BulkType};
+use crate::libusb::control::Direction;
+use crate::libusb::error::Error;
+use crate::libusb::safe_transfer::TransferOptions;
+use crate::libusb::transfer::{ControlSetup, NO_TIMEOUT};
+use futures_util::stream::{Stream, StreamExt};
+
+pub const COMMUNICATIONS_CLASS: u8 = 0x02;
+pub const ACM_SUBCLASS: u8 = 0x02;
+pub const DATA_CLASS: u8 = 0x0A;
+const CS_INTERFACE_TYPE: u8 = 0x24;
+const UNION_SUBTYPE: u8 = 0x06;
+
+/// Class requests of the communication interface.
+pub mod request {
+    pub const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+    pub const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+    pub const SET_LINE_CODING: u8 = 0x20;
+    pub const GET_LINE_CODING: u8 = 0x21;
+    pub const SET_CONTROL_LINE_STATE: u8 = 0x22;
+    pub const SEND_BREAK: u8 = 0x23;
+}
+/// `bNotification` of the serial state notification.
+pub const SERIAL_STATE_NOTIFICATION: u8 = 0x20;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum StopBits {
+    One = 0,
+    OnePointFive = 1,
+    Two = 2,
+}
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum Parity {
+    None = 0,
+    Odd = 1,
+    Even = 2,
+    Mark = 3,
+    Space = 4,
+}
+/// The 7 byte line coding structure of `SET_LINE_CODING`/`GET_LINE_CODING`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct LineCoding {
+    pub baud: u32,
+    pub stop_bits: StopBits,
+    pub parity: Parity,
+    /// 5, 6, 7, 8 or 16.
+    pub data_bits: u8,
+}
+impl LineCoding {
+    pub const SIZE: usize = 7;
+    pub fn new(baud: u32, stop_bits: StopBits, parity: Parity, data_bits: u8) -> LineCoding {
+        LineCoding {
+            baud,
+            stop_bits,
+            parity,
+            data_bits,
+        }
+    }
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let baud = self.baud.to_le_bytes();
+        [
+            baud[0],
+            baud[1],
+            baud[2],
+            baud[3],
+            self.stop_bits as u8,
+            self.parity as u8,
+            self.data_bits,
+        ]
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<LineCoding, Error> {
+        if bytes.len() < Self::SIZE {
+            return Err(Error::Overflow);
+        }
+        let stop_bits = match bytes[4] {
+            0 => StopBits::One,
+            1 => StopBits::OnePointFive,
+            2 => StopBits::Two,
+            _ => return Err(Error::BadDescriptor),
+        };
+        let parity = match bytes[5] {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => return Err(Error::BadDescriptor),
+        };
+        Ok(LineCoding {
+            baud: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            stop_bits,
+            parity,
+            data_bits: bytes[6],
+        })
+    }
+}
+/// 115200 8N1.
+impl Default for LineCoding {
+    fn default() -> Self {
+        LineCoding::new(115_200, StopBits::One, Parity::None, 8)
+    }
+}
+/// `SET_LINE_CODING` to the communication interface (the line coding is the data stage).
+pub fn set_line_coding_setup(interface: u8) -> ControlSetup {
+    ControlSetup::class_interface(
+        Direction::Out,
+        request::SET_LINE_CODING,
+        0,
+        interface,
+        LineCoding::SIZE as u16,
+    )
+}
+pub fn get_line_coding_setup(interface: u8) -> ControlSetup {
+    ControlSetup::class_interface(
+        Direction::In,
+        request::GET_LINE_CODING,
+        0,
+        interface,
+        LineCoding::SIZE as u16,
+    )
+}
+/// `SET_CONTROL_LINE_STATE`. DTR is bit 0 of `wValue`, RTS bit 1.
+pub fn set_control_line_state_setup(interface: u8, dtr: bool, rts: bool) -> ControlSetup {
+    let value = u16::from(dtr) | u16::from(rts) << 1;
+    ControlSetup::class_interface(
+        Direction::Out,
+        request::SET_CONTROL_LINE_STATE,
+        value,
+        interface,
+        0,
+    )
+}
+/// `SEND_BREAK` for `millis` milliseconds (0xFFFF until another `SEND_BREAK` of 0).
+pub fn send_break_setup(interface: u8, millis: u16) -> ControlSetup {
+    ControlSetup::class_interface(Direction::Out, request::SEND_BREAK, millis, interface, 0)
+}
+
+/// UART state bitmap of a serial state notification.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct SerialState(pub u16);
+impl SerialState {
+    /// DCD.
+    pub fn rx_carrier(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+    /// DSR.
+    pub fn tx_carrier(self) -> bool {
+        self.0 & 0x02 != 0
+    }
+    pub fn break_detected(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+    pub fn ring(self) -> bool {
+        self.0 & 0x08 != 0
+    }
+    pub fn framing_error(self) -> bool {
+        self.0 & 0x10 != 0
+    }
+    pub fn parity_error(self) -> bool {
+        self.0 & 0x20 != 0
+    }
+    pub fn overrun(self) -> bool {
+        self.0 & 0x40 != 0
+    }
+    /// Parses a notification packet (8 byte header and the data). `None` for other
+    /// notifications or a short packet.
+    pub fn from_notification(packet: &[u8]) -> Option<SerialState> {
+        match packet {
+            [0xA1, SERIAL_STATE_NOTIFICATION, _, _, _, _, _, _, low, high, ..] => {
+                Some(SerialState(u16::from_le_bytes([*low, *high])))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The interfaces and endpoints of one ACM function.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct AcmFunction {
+    pub control_interface: u8,
+    pub data_interface: u8,
+    /// Alt setting of the data interface with the bulk endpoints.
+    pub data_alt_setting: u8,
+    /// Interrupt IN endpoint for notifications and its max packet size.
+    pub notification_endpoint: Option<(u8, u16)>,
+    pub bulk_in: u8,
+    pub bulk_in_packet_size: u16,
+    pub bulk_out: u8,
+    /// First interface of the interface association the function is in.
+    pub association: Option<u8>,
+}
+const ENDPOINT_IN: u8 = 0x80;
+const BULK: u8 = 2;
+const INTERRUPT: u8 = 3;
+fn first_alt(config: &ParsedConfig, interface: u8) -> Option<&ParsedAltSetting> {
+    config
+        .interfaces
+        .iter()
+        .find(|i| i.number == interface)?
+        .alt_settings
+        .first()
+}
+fn is_data_interface(config: &ParsedConfig, interface: u8) -> bool {
+    first_alt(config, interface).map(|alt| alt.codes.class) == Some(DATA_CLASS)
+}
+/// Data interface of the communication interface `control`: from its union functional
+/// descriptor, else the data interface in its interface association, else the next interface.
+fn data_interface(
+    config: &ParsedConfig,
+    control: &ParsedAltSetting,
+    association: Option<&InterfaceAssociation>,
+) -> Option<u8> {
+    let union = control.extra.iter().find_map(|d| match d.data.as_slice() {
+        [UNION_SUBTYPE, master, subordinate, ..]
+            if d.descriptor_type == CS_INTERFACE_TYPE && *master == control.interface_number =>
+        {
+            Some(*subordinate)
+        }
+        _ => None,
+    });
+    let in_association = association.and_then(|association| {
+        config
+            .function_interfaces(association)
+            .into_iter()
+            .map(|i| i.number)
+            .find(|&i| i != control.interface_number && is_data_interface(config, i))
+    });
+    union
+        .or(in_association)
+        .or_else(|| control.interface_number.checked_add(1))
+        .filter(|&i| is_data_interface(config, i))
+}
+/// The ACM functions of `config` in interface order.
+pub fn acm_functions(config: &ParsedConfig) -> Vec<AcmFunction> {
+    let associations: Vec<InterfaceAssociation> = config.interface_associations().collect();
+    config
+        .interfaces
+        .iter()
+        .filter_map(|interface| {
+            let control = interface.alt_settings.first()?;
+            if (control.codes.class, control.codes.sub_class)
+                != (COMMUNICATIONS_CLASS, ACM_SUBCLASS)
+            {
+                return None;
+            }
+            let association = associations.iter().find(|a| a.contains(interface.number));
+            let data_interface = data_interface(config, control, association)?;
+            let notification_endpoint = control
+                .endpoints
+                .iter()
+                .find(|e| e.address & ENDPOINT_IN != 0 && e.attributes & 0x03 == INTERRUPT)
+                .map(|e| (e.address, e.max_packet_size));
+            // Some devices have an alt setting without endpoints before the one to use
+            config
+                .interfaces
+                .iter()
+                .find(|i| i.number == data_interface)?
+                .alt_settings
+                .iter()
+                .find_map(|alt| {
+                    let bulk = |input: bool| {
+                        alt.endpoints.iter().find(|e| {
+                            (e.address & ENDPOINT_IN != 0) == input && e.attributes & 0x03 == BULK
+                        })
+                    };
+                    let (bulk_in, bulk_out) = (bulk(true)?, bulk(false)?);
+                    Some(AcmFunction {
+                        control_interface: interface.number,
+                        data_interface,
+                        data_alt_setting: alt.alternate_setting,
+                        notification_endpoint,
+                        bulk_in: bulk_in.address,
+                        bulk_in_packet_size: bulk_in.max_packet_size,
+                        bulk_out: bulk_out.address,
+                        association: association.map(|a| a.first_interface),
+                    })
+                })
+        })
+        .collect()
+}
+
+/// An opened ACM function. Reads wait until data arrives, the other IO uses the `AsyncDevice`
+/// default timeout.
+pub struct CdcAcmDevice {
+    device: AsyncDevice,
+    function: AcmFunction,
+    read_buf: Vec<u8>,
+    /// Start and end of the unread bytes in `read_buf`.
+    unread: (usize, usize),
+}
+impl CdcAcmDevice {
+    /// Opens the first ACM function of the active configuration.
+    pub fn open(device: AsyncDevice) -> Result<CdcAcmDevice, Error> {
+        let config = ParsedConfig::from(&device.device().active_config_descriptor()?);
+        let function = *acm_functions(&config).first().ok_or(Error::NotFound)?;
+        CdcAcmDevice::open_function(device, function)
+    }
+    /// Detaches any kernel driver (reattached when the handle is dropped) and claims both
+    /// interfaces of `function`.
+    pub fn open_function(
+        mut device: AsyncDevice,
+        function: AcmFunction,
+    ) -> Result<CdcAcmDevice, Error> {
+        let handle = device.handle_mut();
+        for &interface in &[function.control_interface, function.data_interface] {
+            match handle.kernel_driver_active(interface) {
+                Ok(true) => handle.detach_kernel_driver(interface)?,
+                Ok(false) | Err(Error::NotSupported) => (),
+                Err(e) => return Err(e),
+            }
+            handle.claim_interface(interface)?;
+        }
+        if function.data_alt_setting != 0 {
+            handle.set_alternate_setting(function.data_interface, function.data_alt_setting)?;
+        }
+        let packet_size = usize::from(function.bulk_in_packet_size.max(64));
+        Ok(CdcAcmDevice {
+            device,
+            function,
+            read_buf: vec![0_u8; packet_size * 8],
+            unread: (0, 0),
+        })
+    }
+    pub fn function(&self) -> &AcmFunction {
+        &self.function
+    }
+    pub fn device(&self) -> &AsyncDevice {
+        &self.device
+    }
+    pub fn into_device(self) -> AsyncDevice {
+        self.device
+    }
+    async fn control_out(&self, setup: ControlSetup, data: &[u8]) -> Result<(), Error> {
+        self.device
+            .control_write(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                data,
+                self.device.default_timeout(),
+            )
+            .await?;
+        Ok(())
+    }
+    pub async fn set_line_coding(
+        &self,
+        baud: u32,
+        stop_bits: StopBits,
+        parity: Parity,
+        data_bits: u8,
+    ) -> Result<(), Error> {
+        let coding = LineCoding::new(baud, stop_bits, parity, data_bits);
+        let setup = set_line_coding_setup(self.function.control_interface);
+        self.control_out(setup, &coding.to_bytes()).await
+    }
+    pub async fn line_coding(&self) -> Result<LineCoding, Error> {
+        let setup = get_line_coding_setup(self.function.control_interface);
+        let mut buf = [0_u8; LineCoding::SIZE];
+        let len = self
+            .device
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &mut buf,
+                self.device.default_timeout(),
+            )
+            .await?;
+        LineCoding::from_bytes(&buf[..len])
+    }
+    pub async fn set_control_line_state(&self, dtr: bool, rts: bool) -> Result<(), Error> {
+        let setup = set_control_line_state_setup(self.function.control_interface, dtr, rts);
+        self.control_out(setup, &[]).await
+    }
+    pub async fn send_break(&self, millis: u16) -> Result<(), Error> {
+        self.control_out(
+            send_break_setup(self.function.control_interface, millis),
+            &[],
+        )
+        .await
+    }
+    /// Reads at least one byte (zero length packets are skipped). Bytes that didn't fit in `buf`
+    /// are returned by the next read.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.unread.0 == self.unread.1 {
+            let len = self
+                .device
+                .bulk_read(self.function.bulk_in, &mut self.read_buf, NO_TIMEOUT)
+                .await?;
+            self.unread = (0, len);
+        }
+        let (start, end) = self.unread;
+        let len = buf.len().min(end - start);
+        buf[..len].copy_from_slice(&self.read_buf[start..start + len]);
+        self.unread.0 += len;
+        Ok(len)
+    }
+    /// Writes all of `data`, ending with a zero length packet if it's a multiple of the max
+    /// packet size.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let options = TransferOptions {
+            add_zero_packet: true,
+            ..TransferOptions::default()
+        };
+        self.device
+            .bulk_type_write_with(
+                BulkType::Bulk,
+                self.function.bulk_out,
+                data,
+                self.device.default_timeout(),
+                options,
+            )
+            .await
+    }
+    /// Serial state changes from the notification endpoint. Other notifications are skipped.
+    /// `Error::NotFound` if the function has no notification endpoint.
+    pub fn serial_state_stream(
+        &self,
+    ) -> Result<impl Stream<Item = Result<SerialState, Error>> + '_, Error> {
+        let (endpoint, packet_size) = self.function.notification_endpoint.ok_or(Error::NotFound)?;
+        let packet_size = usize::from(packet_size).max(10);
+        Ok(self
+            .device
+            .interrupt_stream(endpoint, packet_size)
+            .filter_map(|packet| async move {
+                match packet {
+                    Ok(packet) => SerialState::from_notification(&packet).map(Ok),
+                    Err(e) => Some(Err(e)),
+                }
+            }))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::descriptors::parse_config_descriptor;
+    use crate::libusb::cdc_acm::{
+        acm_functions, send_break_setup, set_control_line_state_setup, set_line_coding_setup,
+        AcmFunction, LineCoding, Parity, SerialState, StopBits,
+    };
+
+    /// Two ACM functions, each in an interface association. The first has a union functional
+    /// descriptor, the second relies on its association.
+    const DUAL_ACM_CONFIG: [u8; 113] = [
+        9, 0x02, 113, 0, 4, 1, 0, 0x80, 50, // configuration
+        8, 0x0B, 0, 2, 0x02, 0x02, 0x01, 0, // interface association 0-1
+        9, 0x04, 0, 0, 1, 0x02, 0x02, 0x01, 0, // communication interface 0
+        5, 0x24, 0x00, 0x10, 0x01, // header functional descriptor
+        5, 0x24, 0x06, 0, 1, // union: control 0, data 1
+        7, 0x05, 0x83, 0x03, 16, 0, 16, // notification endpoint
+        9, 0x04, 1, 0, 2, 0x0A, 0, 0, 0, // data interface 1
+        7, 0x05, 0x81, 0x02, 0, 2, 0, // bulk IN
+        7, 0x05, 0x01, 0x02, 0, 2, 0, // bulk OUT
+        8, 0x0B, 2, 2, 0x02, 0x02, 0x01, 0, // interface association 2-3
+        9, 0x04, 2, 0, 1, 0x02, 0x02, 0x01, 0, // communication interface 2
+        7, 0x05, 0x84, 0x03, 16, 0, 16, // notification endpoint
+        9, 0x04, 3, 0, 2, 0x0A, 0, 0, 0, // data interface 3
+        7, 0x05, 0x82, 0x02, 64, 0, 0, // bulk IN
+        7, 0x05, 0x02, 0x02, 64, 0, 0, // bulk OUT
+    ];
+
+    #[test]
+    pub fn test_acm_functions() {
+        let config = parse_config_descriptor(&DUAL_ACM_CONFIG).expect("valid config");
+        let functions = acm_functions(&config);
+        assert_eq!(
+            functions,
+            vec![
+                AcmFunction {
+                    control_interface: 0,
+                    data_interface: 1,
+                    data_alt_setting: 0,
+                    notification_endpoint: Some((0x83, 16)),
+                    bulk_in: 0x81,
+                    bulk_in_packet_size: 512,
+                    bulk_out: 0x01,
+                    association: Some(0),
+                },
+                AcmFunction {
+                    control_interface: 2,
+                    data_interface: 3,
+                    data_alt_setting: 0,
+                    notification_endpoint: Some((0x84, 16)),
+                    bulk_in: 0x82,
+                    bulk_in_packet_size: 64,
+                    bulk_out: 0x02,
+                    association: Some(2),
+                },
+            ]
+        );
+    }
+    #[test]
+    pub fn test_class_requests() {
+        let setup = set_line_coding_setup(2);
+        assert_eq!(
+            (setup.request_type, setup.request, setup.index, setup.len),
+            (0x21, 0x20, 2, 7)
+        );
+        let coding = LineCoding::new(9600, StopBits::Two, Parity::Even, 7);
+        assert_eq!(coding.to_bytes(), [0x80, 0x25, 0, 0, 2, 2, 7]);
+        assert_eq!(LineCoding::from_bytes(&coding.to_bytes()), Ok(coding));
+        let setup = set_control_line_state_setup(0, true, true);
+        assert_eq!(
+            (setup.request_type, setup.request, setup.value, setup.len),
+            (0x21, 0x22, 0x03, 0)
+        );
+        assert_eq!(set_control_line_state_setup(0, false, true).value, 0x02);
+        assert_eq!(send_break_setup(0, 0xFFFF).value, 0xFFFF);
+    }
+    #[test]
+    pub fn test_serial_state() {
+        let notification = [0xA1, 0x20, 0, 0, 0, 0, 2, 0, 0x43, 0x00];
+        let state = SerialState::from_notification(&notification).expect("serial state");
+        assert!(state.rx_carrier() && state.tx_carrier() && state.overrun());
+        assert!(!state.ring());
+        // Network connection notification
+        assert_eq!(
+            SerialState::from_notification(&[0xA1, 0x00, 1, 0, 0, 0, 0, 0]),
+            None
+        );
+    }
+}
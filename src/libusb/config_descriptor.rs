@@ -1,4 +1,10 @@
-use crate::libusb::interface_descriptor::Interfaces;
+use crate::descriptors::{
+    parse_raw_descriptors, InterfaceAssociation, ParsedAltSetting, ParsedConfig, ParsedEndpoint,
+    ParsedInterface, RawDescriptor, ENDPOINT_DESCRIPTOR_SIZE,
+};
+use crate::device::Codes;
+use crate::libusb::endpoint_descriptor::EndpointDescriptor;
+use crate::libusb::interface_descriptor::{InterfaceDescriptor, Interfaces};
 
 pub struct ConfigDescriptor(core::ptr::NonNull<libusb1_sys::libusb_config_descriptor>);
 impl ConfigDescriptor {
@@ -52,15 +58,160 @@ impl ConfigDescriptor {
             }
         }
     }
+    /// Deep copy of the whole configuration (see `ParsedConfig::from`) that can outlive the
+    /// device, be cloned, compared and serialized.
+    pub fn to_owned(&self) -> ParsedConfig {
+        ParsedConfig::from(self)
+    }
     pub fn interfaces(&self) -> Interfaces<'_> {
         let ptr = self.inner_ref().interface;
         let len = self.inner_ref().bNumInterfaces;
         Interfaces(unsafe { core::slice::from_raw_parts(ptr, len.into()) })
     }
+    /// The interface associations in the order they appear. libusb leaves them in the `extra`
+    /// bytes of whatever descriptor precedes them (the configuration, an interface or an
+    /// endpoint). A malformed `extra` is only read up to the bad descriptor.
+    pub fn interface_associations(&self) -> impl Iterator<Item = InterfaceAssociation> {
+        let mut associations = Vec::new();
+        let mut add = |extra: Option<&[u8]>| {
+            associations.extend(split_descriptors(extra.unwrap_or(&[])).filter_map(
+                |(descriptor_type, data)| {
+                    InterfaceAssociation::from_descriptor(descriptor_type, data)
+                },
+            ))
+        };
+        add(self.extra());
+        for alt in self
+            .interfaces()
+            .iter()
+            .flat_map(|i| i.descriptors().iter())
+        {
+            add(alt.extra());
+            for endpoint in alt.endpoint_descriptors().iter() {
+                add(endpoint.extra());
+            }
+        }
+        associations.into_iter()
+    }
+    /// Every alt setting of the interfaces of `association`'s function.
+    pub fn function_interfaces(
+        &self,
+        association: &InterfaceAssociation,
+    ) -> Vec<InterfaceDescriptor<'_>> {
+        self.interfaces()
+            .iter()
+            .flat_map(|i| i.descriptors().iter())
+            .filter(|d| association.contains(d.interface_number()))
+            .collect()
+    }
+    /// Returns the first interface descriptor (of any alternate setting) matching `predicate`.
+    pub fn find_interface(
+        &self,
+        mut predicate: impl FnMut(&InterfaceDescriptor<'_>) -> bool,
+    ) -> Option<InterfaceDescriptor<'_>> {
+        self.interfaces()
+            .into_iter()
+            .flat_map(|i| i.descriptors())
+            .find(|d| predicate(d))
+    }
+    /// Iterates over the endpoints of every interface descriptor in this configuration.
+    pub fn endpoints(&self) -> impl Iterator<Item = EndpointDescriptor<'_>> {
+        self.interfaces()
+            .into_iter()
+            .flat_map(|i| i.descriptors())
+            .flat_map(|d| d.endpoint_descriptors())
+    }
     pub fn inner_ref(&self) -> &libusb1_sys::libusb_config_descriptor {
         unsafe { self.0.as_ref() }
     }
 }
+/// (`bDescriptorType`, the bytes after it) of the descriptors in `extra`, up to the first one
+/// that's malformed.
+fn split_descriptors(extra: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = extra;
+    core::iter::from_fn(move || {
+        let len = usize::from(*rest.first()?);
+        if len < 2 || len > rest.len() {
+            return None;
+        }
+        let (descriptor, tail) = rest.split_at(len);
+        rest = tail;
+        Some((descriptor[1], &descriptor[2..]))
+    })
+}
+/// Malformed `extra` bytes are dropped.
+fn raw_extra(extra: Option<&[u8]>) -> Vec<RawDescriptor> {
+    extra
+        .and_then(|extra| parse_raw_descriptors(extra).ok())
+        .unwrap_or_default()
+}
+impl From<&EndpointDescriptor<'_>> for ParsedEndpoint {
+    fn from(e: &EndpointDescriptor<'_>) -> Self {
+        // libusb always has `bRefresh` and `bSynchAddress`, `bLength` says if they were sent
+        let trailing = if usize::from(e.0.bLength) > ENDPOINT_DESCRIPTOR_SIZE {
+            vec![e.refresh(), e.synch_address()]
+        } else {
+            Vec::new()
+        };
+        ParsedEndpoint {
+            address: e.address(),
+            attributes: e.0.bmAttributes,
+            max_packet_size: e.max_packet_size(),
+            interval: e.interval(),
+            trailing,
+            extra: raw_extra(e.extra()),
+        }
+    }
+}
+impl From<&InterfaceDescriptor<'_>> for ParsedAltSetting {
+    fn from(d: &InterfaceDescriptor<'_>) -> Self {
+        ParsedAltSetting {
+            interface_number: d.interface_number(),
+            alternate_setting: d.setting_number(),
+            codes: Codes {
+                class: d.class_code(),
+                sub_class: d.sub_class_code(),
+                protocol: d.protocol_code(),
+            },
+            string_index: d.description_string_index(),
+            endpoints: d
+                .endpoint_descriptors()
+                .into_iter()
+                .map(|e| ParsedEndpoint::from(&e))
+                .collect(),
+            extra: raw_extra(d.extra()),
+        }
+    }
+}
+/// Owned copy of the configuration. Class specific descriptors end up in the `extra` lists like
+/// `parse_config_descriptor` puts them.
+impl From<&ConfigDescriptor> for ParsedConfig {
+    fn from(c: &ConfigDescriptor) -> Self {
+        let inner = c.inner_ref();
+        ParsedConfig {
+            configuration_value: c.number(),
+            string_index: c.description_string_index(),
+            attributes: inner.bmAttributes,
+            max_power: inner.bMaxPower,
+            interfaces: c
+                .interfaces()
+                .into_iter()
+                .filter_map(|interface| {
+                    let alt_settings: Vec<ParsedAltSetting> = interface
+                        .descriptors()
+                        .into_iter()
+                        .map(|d| ParsedAltSetting::from(&d))
+                        .collect();
+                    Some(ParsedInterface {
+                        number: alt_settings.first()?.interface_number,
+                        alt_settings,
+                    })
+                })
+                .collect(),
+            extra: raw_extra(c.extra()),
+        }
+    }
+}
 impl Drop for ConfigDescriptor {
     fn drop(&mut self) {
         unsafe { libusb1_sys::libusb_free_config_descriptor(self.0.as_ptr()) }
@@ -88,3 +239,140 @@ impl core::fmt::Debug for ConfigDescriptor {
 }
 unsafe impl Sync for ConfigDescriptor {}
 unsafe impl Send for ConfigDescriptor {}
+#[cfg(test)]
+mod tests {
+    use crate::descriptors::{parse_config_descriptor, InterfaceAssociation};
+    use crate::libusb::config_descriptor::ConfigDescriptor;
+    use core::mem::ManuallyDrop;
+    use core::ptr::{null, NonNull};
+    use libusb1_sys::{
+        libusb_config_descriptor, libusb_endpoint_descriptor, libusb_interface,
+        libusb_interface_descriptor,
+    };
+
+    /// Runs `f` with a configuration like libusb parses it: `extra` after the configuration
+    /// descriptor and an interface per `classes` (numbered in order) with one interrupt IN
+    /// endpoint each.
+    fn with_config(extra: &[u8], classes: &[u8], f: impl FnOnce(&ConfigDescriptor)) {
+        let endpoint = libusb_endpoint_descriptor {
+            bLength: 7,
+            bDescriptorType: 0x05,
+            bEndpointAddress: 0x81,
+            bmAttributes: 0x03,
+            wMaxPacketSize: 8,
+            bInterval: 10,
+            bRefresh: 0,
+            bSynchAddress: 0,
+            extra: null(),
+            extra_length: 0,
+        };
+        let alt_settings: Vec<libusb_interface_descriptor> = classes
+            .iter()
+            .enumerate()
+            .map(|(number, &class)| libusb_interface_descriptor {
+                bLength: 9,
+                bDescriptorType: 0x04,
+                bInterfaceNumber: number as u8,
+                bAlternateSetting: 0,
+                bNumEndpoints: 1,
+                bInterfaceClass: class,
+                bInterfaceSubClass: 0,
+                bInterfaceProtocol: 0,
+                iInterface: 0,
+                endpoint: &endpoint,
+                extra: null(),
+                extra_length: 0,
+            })
+            .collect();
+        let interfaces: Vec<libusb_interface> = alt_settings
+            .iter()
+            .map(|altsetting| libusb_interface {
+                altsetting,
+                num_altsetting: 1,
+            })
+            .collect();
+        let config = libusb_config_descriptor {
+            bLength: 9,
+            bDescriptorType: 0x02,
+            wTotalLength: 0,
+            bNumInterfaces: classes.len() as u8,
+            bConfigurationValue: 1,
+            iConfiguration: 0,
+            bmAttributes: 0x80,
+            bMaxPower: 50,
+            interface: interfaces.as_ptr(),
+            extra: extra.as_ptr(),
+            extra_length: extra.len() as i32,
+        };
+        // libusb didn't allocate it so it mustn't free it
+        let config =
+            ManuallyDrop::new(unsafe { ConfigDescriptor::from_libusb(NonNull::from(&config)) });
+        f(&config)
+    }
+    #[test]
+    pub fn test_to_owned() {
+        const VENDOR: [u8; 4] = [4, 0x41, 0x01, 0x02];
+        let mut owned = None;
+        with_config(&VENDOR, &[0x03], |config| owned = Some(config.to_owned()));
+        // The copy outlives libusb's descriptor and matches parsing the same bytes
+        let mut raw = vec![9, 0x02, 0, 0, 1, 1, 0, 0x80, 50];
+        raw.extend_from_slice(&VENDOR);
+        raw.extend_from_slice(&[9, 0x04, 0, 0, 1, 0x03, 0, 0, 0]);
+        raw.extend_from_slice(&[7, 0x05, 0x81, 0x03, 8, 0, 10]);
+        let len = raw.len() as u8;
+        raw[2] = len;
+        let owned = owned.expect("copied");
+        assert_eq!(owned, parse_config_descriptor(&raw).expect("valid config"));
+        assert_eq!(owned.clone(), owned);
+        assert_eq!(owned.extra[0].data, VENDOR[2..].to_vec());
+    }
+    #[test]
+    pub fn test_interface_associations() {
+        // Captured from a webcam with a microphone: video (interfaces 0-1) and audio (2-3)
+        const ASSOCIATIONS: [u8; 16] = [
+            8, 0x0B, 0, 2, 0x0E, 0x03, 0x00, 5, // video
+            8, 0x0B, 2, 2, 0x01, 0x02, 0x00, 0, // audio
+        ];
+        let video = InterfaceAssociation {
+            first_interface: 0,
+            interface_count: 2,
+            function_class: 0x0E,
+            function_sub_class: 0x03,
+            function_protocol: 0,
+            function_string_index: Some(5),
+        };
+        with_config(&ASSOCIATIONS, &[0x0E, 0x0E, 0x01, 0x01, 0xFF], |config| {
+            let associations: Vec<InterfaceAssociation> = config.interface_associations().collect();
+            assert_eq!(associations.len(), 2);
+            assert_eq!(associations[0], video);
+            assert_eq!(associations[1].first_interface, 2);
+            assert_eq!(associations[1].function_string_index, None);
+            let numbers = |a: &InterfaceAssociation| -> Vec<u8> {
+                config
+                    .function_interfaces(a)
+                    .iter()
+                    .map(|d| d.interface_number())
+                    .collect()
+            };
+            assert_eq!(numbers(&associations[0]), vec![0, 1]);
+            assert_eq!(numbers(&associations[1]), vec![2, 3]);
+        });
+        // Everything up to a malformed descriptor is kept
+        let mut truncated = ASSOCIATIONS[..8].to_vec();
+        truncated.extend_from_slice(&[8, 0x0B, 2, 2]);
+        with_config(&truncated, &[0x0E, 0x0E], |config| {
+            assert_eq!(
+                config.interface_associations().collect::<Vec<_>>(),
+                vec![video]
+            );
+        });
+        // Too short to be an association, but well formed
+        let short = [5, 0x0B, 0, 2, 0x0E, 4, 0x41, 0, 0];
+        with_config(&short, &[0x0E], |config| {
+            assert_eq!(config.interface_associations().count(), 0);
+        });
+        with_config(&[], &[0x03], |config| {
+            assert_eq!(config.interface_associations().count(), 0);
+        });
+    }
+}
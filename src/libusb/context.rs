@@ -1,11 +1,18 @@
-use crate::device::{ProductID, VendorID};
+use crate::device::{DeviceFilter, ProductID, VendorID};
 use crate::libusb::asyncs::AsyncContext;
 use crate::libusb::device::{Device, DeviceList};
+#[cfg(unix)]
+use crate::libusb::device_handle::DeviceHandle;
 use crate::libusb::error::Error;
+#[cfg(feature = "tokio")]
+use crate::libusb::event_pump::EventPump;
 use crate::libusb::hotplug;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::libusb::log_callback;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(i32)]
 pub enum LogLevel {
     None = 0,
@@ -14,7 +21,147 @@ pub enum LogLevel {
     Info = 3,
     Debug = 4,
 }
+/// What the libusb build and platform support (`libusb_has_capability`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[repr(u32)]
+pub enum Capability {
+    /// Always supported (libusb 1.0.9 or later).
+    HasCapability = 0x0000,
+    /// Hotplug callbacks. Not on Windows.
+    HasHotplug = 0x0001,
+    /// HID devices can be accessed without detaching a kernel driver.
+    HasHidAccess = 0x0100,
+    /// `detach_kernel_driver` works. Linux only.
+    SupportsDetachKernelDriver = 0x0101,
+}
+/// Capabilities don't depend on the context. Some platforms only report them correctly after
+/// libusb has been initialized (a `Context` exists).
+pub fn has_capability(capability: Capability) -> bool {
+    unsafe { libusb1_sys::libusb_has_capability(capability as u32) != 0 }
+}
+/// A libusb option (`libusb_set_option`). Options the platform doesn't support fail with
+/// `Error::NotSupported`.
+#[derive(Copy, Clone, Debug)]
+pub enum ContextOption {
+    LogLevel(LogLevel),
+    /// Use the UsbDk backend instead of WinUSB. Windows only.
+    UseUsbdk,
+    /// Skip device enumeration and hotplug. Linux only. Only has an effect when passed to
+    /// `Context::new_with` because it must be set before `libusb_init`.
+    NoDeviceDiscovery,
+}
+impl ContextOption {
+    /// # Safety
+    /// `context` must be null (the default/global options) or a valid context.
+    unsafe fn apply(self, context: *mut libusb1_sys::libusb_context) -> Result<(), Error> {
+        use libusb1_sys::constants::{
+            LIBUSB_OPTION_LOG_LEVEL, LIBUSB_OPTION_NO_DEVICE_DISCOVERY, LIBUSB_OPTION_USE_USBDK,
+        };
+        match self {
+            ContextOption::LogLevel(level) => try_unsafe!(libusb1_sys::libusb_set_option(
+                context,
+                LIBUSB_OPTION_LOG_LEVEL,
+                level as libc::c_int
+            )),
+            ContextOption::UseUsbdk => {
+                try_unsafe!(libusb1_sys::libusb_set_option(
+                    context,
+                    LIBUSB_OPTION_USE_USBDK
+                ))
+            }
+            ContextOption::NoDeviceDiscovery => try_unsafe!(libusb1_sys::libusb_set_option(
+                context,
+                LIBUSB_OPTION_NO_DEVICE_DISCOVERY
+            )),
+        }
+        Ok(())
+    }
+}
+/// Options applied while creating a `Context`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ContextOptions {
+    /// Skip device enumeration and hotplug. Required on Android where devices can only be
+    /// opened from a file descriptor (see `Context::wrap_sys_device`). Linux only.
+    pub no_device_discovery: bool,
+}
+impl LogLevel {
+    pub fn from_i32(i: i32) -> Option<LogLevel> {
+        match i {
+            0 => Some(LogLevel::None),
+            1 => Some(LogLevel::Error),
+            2 => Some(LogLevel::Warning),
+            3 => Some(LogLevel::Info),
+            4 => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+/// Longest timeout passed on to libusb. Any `timeval` can hold it and libusb won't overflow
+/// adding it to the current time.
+pub const MAX_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(i32::MAX as u64);
+/// Converts a timeout to a `timeval`, saturating at `MAX_TIMEOUT`. `timeval`'s fields are
+/// `c_long` on Windows so the conversion is checked instead of cast.
+#[allow(clippy::unnecessary_fallible_conversions)]
+pub(crate) fn timeval_from_duration(timeout: core::time::Duration) -> Result<libc::timeval, Error> {
+    use core::convert::TryInto;
+    let timeout = timeout.min(MAX_TIMEOUT);
+    Ok(libc::timeval {
+        tv_sec: timeout
+            .as_secs()
+            .try_into()
+            .map_err(|_| Error::InvalidParam)?,
+        tv_usec: timeout
+            .subsec_micros()
+            .try_into()
+            .map_err(|_| Error::InvalidParam)?,
+    })
+}
 static DEFAULT_CONTEXT_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Held while `DEFAULT_CONTEXT_COUNT` changes so exiting the default context can't interleave
+/// with the next `Context::default` initializing it again.
+static DEFAULT_CONTEXT_LOCK: Mutex<()> = Mutex::new(());
+/// Adds a reference to `count`, calling `init` first if it's the first one. A failed `init`
+/// doesn't count.
+fn acquire_ref(
+    lock: &Mutex<()>,
+    count: &AtomicUsize,
+    init: impl FnOnce() -> Result<(), Error>,
+) -> Result<(), Error> {
+    let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+    if count.load(Ordering::SeqCst) == 0 {
+        init()?;
+    }
+    count.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+/// Removes a reference from `count`, calling `exit` if it was the last one.
+fn release_ref(lock: &Mutex<()>, count: &AtomicUsize, exit: impl FnOnce()) {
+    let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+    if count.fetch_sub(1, Ordering::SeqCst) == 1 {
+        exit()
+    }
+}
+/// One reference to libusb's default context. The first guard initializes it and the last one
+/// exits it.
+#[derive(Debug)]
+struct DefaultContextGuard(());
+impl DefaultContextGuard {
+    fn acquire() -> Result<DefaultContextGuard, Error> {
+        acquire_ref(&DEFAULT_CONTEXT_LOCK, &DEFAULT_CONTEXT_COUNT, || {
+            try_unsafe!(libusb1_sys::libusb_init(core::ptr::null_mut()));
+            Ok(())
+        })?;
+        Ok(DefaultContextGuard(()))
+    }
+}
+impl Drop for DefaultContextGuard {
+    fn drop(&mut self) {
+        release_ref(&DEFAULT_CONTEXT_LOCK, &DEFAULT_CONTEXT_COUNT, || {
+            unsafe { libusb1_sys::libusb_exit(core::ptr::null_mut()) }
+            drop(log_callback::set(core::ptr::null_mut(), false, None));
+        })
+    }
+}
 /// Handle the default context reference counter
 /// # Safety
 /// Modication of the atomic could cause the default context to be prematurely or never freed.
@@ -24,123 +171,640 @@ pub unsafe fn default_context_reference_counter() -> &'static AtomicUsize {
 pub fn default_context() -> Result<Context, Error> {
     Context::default()
 }
+/// The libusb context shared by a `Context` and everything made from it (`DeviceList`,
+/// `Device`, `DeviceHandle`), so libusb isn't exited while any of them is still alive.
 #[derive(Debug)]
-pub struct Context(*mut libusb1_sys::libusb_context);
-unsafe impl Send for Context {}
-unsafe impl Sync for Context {}
+pub(crate) struct ContextInner {
+    ptr: *mut libusb1_sys::libusb_context,
+    /// `false` for contexts owned by someone else, which are never exited.
+    owned: bool,
+    /// Set for the default context (`ptr` is null), which it exits instead.
+    default: Option<DefaultContextGuard>,
+}
+unsafe impl Send for ContextInner {}
+unsafe impl Sync for ContextInner {}
+impl ContextInner {
+    pub(crate) fn ptr(&self) -> *mut libusb1_sys::libusb_context {
+        self.ptr
+    }
+}
 impl Context {
+    /// Fails with `Error::InvalidParam` if `handle` was opened in another context. Handles made
+    /// with `DeviceHandle::from_libusb` can't be checked and are accepted.
+    pub(crate) fn check_owns(&self, handle: &DeviceHandle) -> Result<(), Error> {
+        match handle.context_inner() {
+            Some(context) if context.ptr != self.0.ptr => Err(Error::InvalidParam),
+            _ => Ok(()),
+        }
+    }
+    /// A context that's never exited, for tests that never pass it to libusb.
+    #[cfg(test)]
+    pub(crate) fn unowned() -> Context {
+        Context(Arc::new(ContextInner {
+            ptr: core::ptr::null_mut(),
+            owned: false,
+            default: None,
+        }))
+    }
+}
+impl Drop for ContextInner {
+    fn drop(&mut self) {
+        if !self.owned || self.default.is_some() {
+            return;
+        }
+        unsafe { libusb1_sys::libusb_exit(self.ptr) }
+        // libusb won't log for this context anymore so the callback can go
+        drop(log_callback::set(self.ptr, false, None));
+    }
+}
+#[derive(Debug)]
+pub struct Context(Arc<ContextInner>);
+impl Context {
+    fn from_libusb(ptr: *mut libusb1_sys::libusb_context) -> Context {
+        Context(Arc::new(ContextInner {
+            ptr,
+            owned: true,
+            default: None,
+        }))
+    }
     pub fn new() -> Result<Context, Error> {
         let mut context = core::ptr::null_mut();
         try_unsafe!(libusb1_sys::libusb_init(&mut context));
-        Ok(Context(context))
+        Ok(Context::from_libusb(context))
+    }
+    /// Creates a new `Context` with `options`. Note that libusb only reads
+    /// `no_device_discovery` as a global default before `libusb_init`, so it also applies to
+    /// contexts created after this one.
+    pub fn with_options(options: ContextOptions) -> Result<Context, Error> {
+        if options.no_device_discovery {
+            Self::new_with(&[ContextOption::NoDeviceDiscovery])
+        } else {
+            Self::new()
+        }
+    }
+    /// Creates a new `Context` and applies `options` to it. `ContextOption::NoDeviceDiscovery`
+    /// is applied globally before `libusb_init` (libusb ignores it afterwards) and the rest right
+    /// after init.
+    pub fn new_with(options: &[ContextOption]) -> Result<Context, Error> {
+        for option in options {
+            if let ContextOption::NoDeviceDiscovery = option {
+                unsafe { option.apply(core::ptr::null_mut()) }?;
+            }
+        }
+        let context = Self::new()?;
+        for option in options {
+            match option {
+                ContextOption::NoDeviceDiscovery => (),
+                option => context.set_option(*option)?,
+            }
+        }
+        Ok(context)
+    }
+    pub fn set_option(&self, option: ContextOption) -> Result<(), Error> {
+        unsafe { option.apply(self.0.ptr) }
+    }
+    /// Wraps an already open OS device (a usbfs file descriptor, like the one Android's
+    /// `UsbManager` hands out) into a `DeviceHandle` without enumerating devices.
+    #[cfg(unix)]
+    pub fn wrap_sys_device(&self, fd: std::os::unix::io::RawFd) -> Result<DeviceHandle, Error> {
+        let mut out = core::ptr::null_mut();
+        // libusb takes the fd as an `intptr_t`
+        try_unsafe!(libusb1_sys::libusb_wrap_sys_device(
+            self.0.ptr,
+            fd as isize as *mut _,
+            &mut out
+        ));
+        debug_assert!(!out.is_null(), "null libusb device handle ptr");
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::new_unchecked(out)) };
+        Ok(handle.with_context(self.0.clone()))
     }
     pub fn leak(self) {
         core::mem::forget(self)
     }
+    #[deprecated(note = "use `set_option(ContextOption::LogLevel(level))`")]
     pub fn set_debug_level(&self, new_level: LogLevel) {
-        unsafe { libusb1_sys::libusb_set_debug(self.0, new_level as i32) }
+        // `libusb_set_debug` never reported errors either
+        self.set_option(ContextOption::LogLevel(new_level)).ok();
     }
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<Context, Error> {
-        // NOOP if default Context already exists
-        try_unsafe!(libusb1_sys::libusb_init(core::ptr::null_mut()));
-        DEFAULT_CONTEXT_COUNT.fetch_add(1, Ordering::SeqCst);
-        Ok(Context(core::ptr::null_mut()))
+        Ok(Context(Arc::new(ContextInner {
+            ptr: core::ptr::null_mut(),
+            owned: true,
+            default: Some(DefaultContextGuard::acquire()?),
+        })))
+    }
+    /// Routes libusb's log messages to `callback` instead of stderr. If `global` is true, the
+    /// callback gets the messages of every context (and ones not tied to a context). Replacing a
+    /// callback frees the old one once any in-progress call to it returns. Panics in `callback`
+    /// are caught and dropped.
+    pub fn set_log_callback(
+        &self,
+        callback: impl Fn(LogLevel, &str) + Send + Sync + 'static,
+        global: bool,
+    ) {
+        let old = log_callback::set(self.0.ptr, global, Some(Arc::new(callback)));
+        unsafe {
+            libusb1_sys::libusb_set_log_cb(
+                self.0.ptr,
+                Some(log_callback::system_callback),
+                Self::log_cb_mode(global),
+            )
+        }
+        drop(old)
+    }
+    /// Stops routing log messages to the callback set with `set_log_callback`.
+    pub fn clear_log_callback(&self, global: bool) {
+        unsafe { libusb1_sys::libusb_set_log_cb(self.0.ptr, None, Self::log_cb_mode(global)) }
+        drop(log_callback::set(self.0.ptr, global, None))
+    }
+    fn log_cb_mode(global: bool) -> libc::c_int {
+        if global {
+            libusb1_sys::constants::LIBUSB_LOG_CB_GLOBAL
+        } else {
+            libusb1_sys::constants::LIBUSB_LOG_CB_CONTEXT
+        }
+    }
+    /// Forwards this context's libusb log messages to the `log` crate (target `libusb`).
+    #[cfg(feature = "log")]
+    pub fn route_logs_to_log_crate(&self) {
+        self.set_log_callback(
+            |level, message| {
+                let level = match level {
+                    LogLevel::None => return,
+                    LogLevel::Error => log::Level::Error,
+                    LogLevel::Warning => log::Level::Warn,
+                    LogLevel::Info => log::Level::Info,
+                    LogLevel::Debug => log::Level::Debug,
+                };
+                log::log!(target: "libusb", level, "{}", message)
+            },
+            false,
+        )
+    }
+    /// Same as the free `has_capability`, but libusb is known to be initialized.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        has_capability(capability)
     }
     pub fn is_default(&self) -> bool {
-        self.0.is_null()
+        self.0.ptr.is_null()
+    }
+    pub fn libusb_ptr(&self) -> *mut libusb1_sys::libusb_context {
+        self.0.ptr
     }
     pub fn device_list(&self) -> DeviceList {
         let mut out = core::ptr::null();
-        let len = unsafe { libusb1_sys::libusb_get_device_list(self.0, &mut out) };
+        let len = unsafe { libusb1_sys::libusb_get_device_list(self.0.ptr, &mut out) };
         unsafe {
-            DeviceList::from_libusb(
+            DeviceList::from_libusb_in(
                 core::ptr::NonNull::new_unchecked(out as *mut *mut libusb1_sys::libusb_device),
                 len as usize,
+                &self.0,
             )
         }
     }
+    /// Returns every device matching `filter` (see `DeviceFilter::matches_device`). Devices that
+    /// can't be checked are skipped.
+    pub fn find_devices(&self, filter: &DeviceFilter) -> Vec<Device> {
+        self.device_list().find_all(filter)
+    }
     pub fn handle_events(&self) -> Result<(), Error> {
-        try_unsafe!(libusb1_sys::libusb_handle_events(self.0));
+        usb_trace!("handling libusb events");
+        try_unsafe!(libusb1_sys::libusb_handle_events(self.0.ptr));
         Ok(())
     }
+    /// Timeouts longer than `MAX_TIMEOUT` are treated as `MAX_TIMEOUT`.
     pub fn handle_events_timeout(&self, timeout: core::time::Duration) -> Result<(), Error> {
-        let time = libc::timeval {
-            tv_sec: timeout.as_secs() as _,
-            tv_usec: timeout.subsec_micros() as _,
-        };
-        try_unsafe!(libusb1_sys::libusb_handle_events_timeout(self.0, &time));
+        let time = timeval_from_duration(timeout)?;
+        usb_trace!(?timeout, "handling libusb events");
+        try_unsafe!(libusb1_sys::libusb_handle_events_timeout(self.0.ptr, &time));
         Ok(())
     }
+    /// `handle_events_timeout`, but `None` blocks until an event is handled (`handle_events`).
+    pub fn handle_events_for(&self, timeout: Option<core::time::Duration>) -> Result<(), Error> {
+        match timeout {
+            Some(timeout) => self.handle_events_timeout(timeout),
+            None => self.handle_events(),
+        }
+    }
+    /// Handles events until `completed` is non-zero (checked under libusb's event lock, so a
+    /// completion on another thread can't be missed) or `timeout` elapses. `None` waits
+    /// forever. Another thread setting `completed` should call `interrupt_event_handler` to wake
+    /// this one up.
+    pub fn handle_events_completed(
+        &self,
+        completed: &AtomicI32,
+        timeout: Option<core::time::Duration>,
+    ) -> Result<(), Error> {
+        // `AtomicI32` has the same layout as `c_int`. libusb only uses the pointer for the
+        // duration of the call, which `completed` outlives.
+        let completed = completed as *const AtomicI32 as *mut libc::c_int;
+        usb_trace!(?timeout, "handling libusb events until completed");
+        match timeout {
+            Some(timeout) => {
+                let time = timeval_from_duration(timeout)?;
+                try_unsafe!(libusb1_sys::libusb_handle_events_timeout_completed(
+                    self.0.ptr, &time, completed
+                ));
+            }
+            None => try_unsafe!(libusb1_sys::libusb_handle_events_completed(
+                self.0.ptr, completed
+            )),
+        }
+        Ok(())
+    }
+    /// Returns when libusb next needs `handle_events` to be called to handle a timeout. `None`
+    /// if nothing is pending (or the timeouts are handled through the pollfds, see
+    /// `pollfds_handle_timeouts`).
+    pub fn next_timeout(&self) -> Result<Option<core::time::Duration>, Error> {
+        let mut time = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        match unsafe { libusb1_sys::libusb_get_next_timeout(self.0.ptr, &mut time) } {
+            0 => Ok(None),
+            r if r < 0 => Err(crate::libusb::error::from_libusb(r)),
+            _ => Ok(Some(
+                core::time::Duration::from_secs(time.tv_sec as u64)
+                    + core::time::Duration::from_micros(time.tv_usec as u64),
+            )),
+        }
+    }
+    /// Returns if libusb's timeouts are signalled through its pollfds (timerfd on Linux).
+    pub fn pollfds_handle_timeouts(&self) -> bool {
+        unsafe { libusb1_sys::libusb_pollfds_handle_timeouts(self.0.ptr) != 0 }
+    }
+    /// Handles this context's events on the current async runtime instead of a thread.
+    /// The returned future must be polled for async transfers to complete.
+    #[cfg(feature = "tokio")]
+    pub fn event_pump(&self) -> EventPump<'_> {
+        EventPump::new(self)
+    }
+    /// Wakes up a thread blocked in `handle_events` (like the `AsyncContext` event thread).
+    pub fn interrupt_event_handler(&self) {
+        unsafe { libusb1_sys::libusb_interrupt_event_handler(self.0.ptr) }
+    }
     pub fn start_async(self) -> AsyncContext {
         AsyncContext::start(self)
     }
-    /// Register a hotplug callback. `F` must keep returning `true` for as long as it lives and then
-    /// either deregister the callback handle or return `false` from `F`.
+    /// Register a hotplug callback. It stays registered until `F` returns
+    /// `hotplug::Action::Deregister`, panics (see `CallbackHandle::take_panic`) or the handle is
+    /// deregistered. Dropping the returned `CallbackHandle` does not deregister the callback.
     pub fn hotplug_register_callback<F>(
         &self,
         callback: F,
-        events: hotplug::Event,
+        events: hotplug::EventMask,
         flag: hotplug::Flags,
         vendor_id: Option<VendorID>,
         product_id: Option<ProductID>,
         device_class: Option<u8>,
-    ) -> Result<(), Error>
+    ) -> Result<hotplug::CallbackHandle, Error>
     where
-        F: FnMut(&mut Context, &mut Device, hotplug::Event) -> bool + Send + 'static,
+        F: FnMut(&mut Context, &mut Device, hotplug::Event) -> hotplug::Action + Send + 'static,
     {
-        extern "system" fn call_closure<F>(
-            context: *mut libusb1_sys::libusb_context,
-            device: *mut libusb1_sys::libusb_device,
-            event: libusb1_sys::libusb_hotplug_event,
-            closure: *mut core::ffi::c_void,
-        ) -> i32
-        where
-            F: FnMut(&mut Context, &mut Device, hotplug::Event) -> bool + Send + 'static,
-        {
-            let event = match event {
-                1 => hotplug::Event::DeviceArrived,
-                2 => hotplug::Event::DeviceLeft,
-                _ => hotplug::Event::Both,
-            };
-            let mut context = Context(context);
-            let closure = closure as *mut F;
-            let mut device =
-                unsafe { Device::from_libusb(core::ptr::NonNull::new_unchecked(device)) };
-            let r = unsafe { &mut *closure }(&mut context, &mut device, event);
-            // We don't wanna libusb_unref_device the device pointer (hotplug callbacks aren't expected to)
-            device.leak();
-            context.leak();
-            if r {
-                0
-            } else {
-                // Drop the closure because we're done now
-                unsafe { Box::from_raw(closure) };
-                1
-            }
-        }
         const MATCH_ANY: i32 = -1;
-        let callback_ptr = Box::into_raw(Box::new(callback)) as *mut core::ffi::c_void;
-        try_unsafe!(libusb1_sys::libusb_hotplug_register_callback(
-            self.0,
-            events as i32,
-            flag as i32,
-            vendor_id.map(|v| i32::from(v.0)).unwrap_or(MATCH_ANY),
-            product_id.map(|p| i32::from(p.0)).unwrap_or(MATCH_ANY),
-            device_class.map(i32::from).unwrap_or(MATCH_ANY),
-            call_closure::<F>,
-            callback_ptr,
-            core::ptr::null_mut(),
-        ));
-        Ok(())
+        let registration = hotplug::Registration::new(Arc::downgrade(&self.0), callback);
+        // libusb's reference, released by `hotplug_callback` or `CallbackHandle::deregister`
+        let user_data = Arc::into_raw(registration.clone()) as *const core::ffi::c_void;
+        let mut handle: libusb1_sys::libusb_hotplug_callback_handle = 0;
+        let res = unsafe {
+            libusb1_sys::libusb_hotplug_register_callback(
+                self.0.ptr,
+                events.bits(),
+                flag as i32,
+                vendor_id.map(|v| i32::from(v.0)).unwrap_or(MATCH_ANY),
+                product_id.map(|p| i32::from(p.0)).unwrap_or(MATCH_ANY),
+                device_class.map(i32::from).unwrap_or(MATCH_ANY),
+                hotplug_callback::<F>,
+                user_data as *mut core::ffi::c_void,
+                &mut handle,
+            )
+        };
+        if res != 0 {
+            // Registration failed so libusb never saw the closure.
+            unsafe { hotplug::Registration::<F>::release(user_data) };
+            return Err(crate::libusb::error::from_libusb(res));
+        }
+        Ok(hotplug::CallbackHandle::new(
+            handle,
+            self.0.clone(),
+            registration,
+            user_data,
+        ))
+    }
+    /// `hotplug_register_callback` for the devices matching `filter`. libusb matches the IDs and
+    /// the class codes are checked on the device and its active config (so composite devices
+    /// match). A device that left is passed to `callback` if it matched when it arrived.
+    /// Fails with `Error::InvalidParam` if the filter has a serial number because devices can't
+    /// be opened in hotplug callbacks.
+    pub fn hotplug_register_filtered<F>(
+        &self,
+        mut callback: F,
+        events: hotplug::EventMask,
+        flag: hotplug::Flags,
+        filter: &DeviceFilter,
+    ) -> Result<hotplug::CallbackHandle, Error>
+    where
+        F: FnMut(&mut Context, &mut Device, hotplug::Event) -> hotplug::Action + Send + 'static,
+    {
+        if filter.serial_number().is_some() {
+            return Err(Error::InvalidParam);
+        }
+        let (vendor_id, product_id) = (filter.vendor_id(), filter.product_id());
+        let filter = filter.clone();
+        // (bus, address) of the devices that matched when they arrived. A device that left
+        // might not have a readable config descriptor anymore.
+        let mut matched = std::collections::HashSet::new();
+        self.hotplug_register_callback(
+            move |context, device, event| {
+                let key = (device.bus_number(), device.device_address());
+                let matches = match event {
+                    hotplug::Event::DeviceLeft => {
+                        matched.remove(&key)
+                            || filter.matches_device_descriptors(device).unwrap_or(false)
+                    }
+                    _ => {
+                        let matches = filter.matches_device_descriptors(device).unwrap_or(false);
+                        if matches {
+                            matched.insert(key);
+                        }
+                        matches
+                    }
+                };
+                if matches {
+                    callback(context, device, event)
+                } else {
+                    hotplug::Action::Keep
+                }
+            },
+            events,
+            flag,
+            vendor_id,
+            product_id,
+            None,
+        )
     }
 }
-impl Drop for Context {
-    fn drop(&mut self) {
-        if self.is_default() && DEFAULT_CONTEXT_COUNT.fetch_sub(1, Ordering::SeqCst) != 0 {
-            // Not ready to exit default context
-            return;
+/// The libusb hotplug callback of `Context::hotplug_register_callback`. `user_data` is a
+/// reference to a `hotplug::Registration<F>`.
+extern "system" fn hotplug_callback<F>(
+    _context: *mut libusb1_sys::libusb_context,
+    device: *mut libusb1_sys::libusb_device,
+    event: libusb1_sys::libusb_hotplug_event,
+    user_data: *mut core::ffi::c_void,
+) -> i32
+where
+    F: FnMut(&mut Context, &mut Device, hotplug::Event) -> hotplug::Action + Send + 'static,
+{
+    let event = match hotplug::Event::try_from(event) {
+        Ok(event) => event,
+        Err(_) => {
+            usb_debug!(event, "skipping unknown hotplug event");
+            return 0;
+        }
+    };
+    // # Safety
+    // libusb's reference keeps the registration alive until it's released below.
+    let registration = unsafe { &*(user_data as *const hotplug::Registration<F>) };
+    let context = match registration.context() {
+        Some(context) => context,
+        None => {
+            usb_debug!("skipping hotplug event, the context is being dropped");
+            return 0;
         }
+    };
+    // Hotplug callbacks don't own a reference to the device, only the context's is released
+    let mut device = core::mem::ManuallyDrop::new(unsafe {
+        Device::from_libusb_in(
+            core::ptr::NonNull::new_unchecked(device),
+            Some(context.clone()),
+        )
+    });
+    // Panics are caught by `call`, the context reference is always released
+    let action = registration.call(|closure| closure(&mut Context(context), &mut device, event));
+    drop(device.take_context());
+    match action {
+        hotplug::Action::Keep => 0,
+        hotplug::Action::Deregister => {
+            // libusb deregisters the callback once we return 1 so it won't be called again.
+            unsafe { hotplug::Registration::<F>::release(user_data) };
+            1
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::context::{
+        acquire_ref, hotplug_callback, release_ref, timeval_from_duration, Context, ContextInner,
+        MAX_TIMEOUT,
+    };
+    use crate::libusb::device::Device;
+    use crate::libusb::error::Error;
+    use crate::libusb::hotplug::{Action, CallbackHandle, Event, Registration};
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::time::Duration;
+    use std::sync::{Arc, Mutex};
 
-        unsafe { libusb1_sys::libusb_exit(self.0) }
+    #[test]
+    pub fn test_timeval_from_duration() {
+        let time = timeval_from_duration(Duration::from_micros(1_500_001)).expect("fits");
+        assert_eq!((time.tv_sec, time.tv_usec), (1, 500_001));
+        for timeout in [
+            Duration::from_secs(u64::MAX),
+            MAX_TIMEOUT + Duration::from_nanos(1),
+        ] {
+            let time = timeval_from_duration(timeout).expect("saturates");
+            assert_eq!(time.tv_sec as u64, MAX_TIMEOUT.as_secs());
+            assert_eq!(time.tv_usec, 0);
+        }
+    }
+    /// Needs libusb.
+    #[test]
+    #[ignore]
+    pub fn test_context_outlives_devices() {
+        let context = Context::new().expect("libusb context");
+        let inner = Arc::downgrade(&context.0);
+        let list = context.device_list();
+        let device = list.get(0);
+        drop(context);
+        // Not exited while the list and its devices are around
+        assert!(inner.upgrade().is_some());
+        drop(list);
+        assert_eq!(inner.upgrade().is_some(), device.is_some());
+        if let Some(device) = device {
+            assert!(device.device_descriptor().is_ok());
+        }
+        assert!(inner.upgrade().is_none());
+    }
+    #[test]
+    pub fn test_default_refcount() {
+        let (lock, count) = (Mutex::new(()), AtomicUsize::new(0));
+        let (inits, exits) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let init = || {
+            inits.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        };
+        let exit = || {
+            exits.fetch_add(1, Ordering::SeqCst);
+        };
+        assert_eq!(
+            acquire_ref(&lock, &count, || Err(Error::NoMem)),
+            Err(Error::NoMem)
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        acquire_ref(&lock, &count, init).expect("init");
+        acquire_ref(&lock, &count, || panic!("initialized twice")).expect("no init");
+        release_ref(&lock, &count, || panic!("exited while referenced"));
+        assert_eq!(exits.load(Ordering::SeqCst), 0);
+        release_ref(&lock, &count, exit);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+        assert_eq!(exits.load(Ordering::SeqCst), 1);
+    }
+    #[test]
+    pub fn test_default_refcount_threads() {
+        let lock = Arc::new(Mutex::new(()));
+        let count = Arc::new(AtomicUsize::new(0));
+        // Whether the fake context is initialized, and how many times it was
+        let alive = Arc::new(AtomicBool::new(false));
+        let inits = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (lock, count) = (lock.clone(), count.clone());
+                let (alive, inits) = (alive.clone(), inits.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        acquire_ref(&lock, &count, || {
+                            assert!(!alive.swap(true, Ordering::SeqCst), "double init");
+                            inits.fetch_add(1, Ordering::SeqCst);
+                            Ok(())
+                        })
+                        .expect("init");
+                        assert!(alive.load(Ordering::SeqCst), "used after exit");
+                        release_ref(&lock, &count, || {
+                            assert!(alive.swap(false, Ordering::SeqCst), "double exit")
+                        });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("thread panicked");
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert!(!alive.load(Ordering::SeqCst));
+        assert!(inits.load(Ordering::SeqCst) >= 1);
+    }
+    /// Needs libusb.
+    #[test]
+    #[ignore]
+    pub fn test_default_context_threads() {
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..100 {
+                        let context = Context::default().expect("default context");
+                        context.device_list();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("thread panicked");
+        }
+        // Usable again after every reference was dropped
+        assert!(Context::default().is_ok());
+    }
+    /// Calls the libusb trampoline like libusb would, with a device that's never touched.
+    fn deliver<F>(
+        registration: &Arc<Registration<F>>,
+        user_data: *const core::ffi::c_void,
+        event: i32,
+    ) -> i32
+    where
+        F: FnMut(&mut Context, &mut Device, Event) -> Action + Send + 'static,
+    {
+        assert!(Arc::strong_count(registration) > 1, "released");
+        let device = core::ptr::NonNull::dangling().as_ptr();
+        hotplug_callback::<F>(core::ptr::null_mut(), device, event, user_data as *mut _)
+    }
+    type Callback = fn(&mut Context, &mut Device, Event) -> Action;
+    /// A context that's never exited, for handles that are never deregistered.
+    fn unowned_context() -> Arc<ContextInner> {
+        Context::unowned().0
+    }
+    #[test]
+    pub fn test_hotplug_callback_actions() {
+        let context = unowned_context();
+        let registering = context.clone();
+        let mut calls = 0;
+        let closure = move |context: &mut Context, _: &mut Device, event| {
+            // The closure gets the registering context, not a copy of its pointer
+            assert!(Arc::ptr_eq(&context.0, &registering));
+            assert_eq!(event, Event::DeviceArrived);
+            calls += 1;
+            match calls {
+                1 => Action::Keep,
+                _ => Action::Deregister,
+            }
+        };
+        let registration = Registration::new(Arc::downgrade(&context), closure);
+        let user_data = Arc::into_raw(registration.clone()) as *const core::ffi::c_void;
+        let handle = CallbackHandle::new(0, context.clone(), registration.clone(), user_data);
+        // Unknown events are skipped, the closure never sees them
+        assert_eq!(deliver(&registration, user_data, 3), 0);
+        assert_eq!(deliver(&registration, user_data, 1), 0);
+        assert!(!handle.is_finished());
+        assert_eq!(deliver(&registration, user_data, 1), 1);
+        assert!(handle.is_finished());
+        // libusb's reference is gone, the handle's and this one are left
+        assert_eq!(Arc::strong_count(&registration), 2);
+        drop(handle);
+        assert_eq!(Arc::strong_count(&registration), 1);
+        // Neither the devices nor the dropped closure kept a reference to the context
+        assert_eq!(Arc::strong_count(&context), 1);
+    }
+    #[test]
+    pub fn test_hotplug_callback_dropped_context() {
+        let context = unowned_context();
+        let closure: Callback = |_, _, _| panic!("called without a context");
+        let registration = Registration::new(Arc::downgrade(&context), closure);
+        let user_data = Arc::into_raw(registration.clone()) as *const core::ffi::c_void;
+        let handle = CallbackHandle::new(0, context, registration.clone(), user_data);
+        // Dropping the handle drops the last reference to the context
+        drop(handle);
+        assert_eq!(deliver(&registration, user_data, 1), 0);
+        assert!(registration.context().is_none());
+        unsafe { Registration::<Callback>::release(user_data) };
+    }
+    #[test]
+    pub fn test_hotplug_callback_panic() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        struct SetOnDrop(Arc<AtomicBool>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst)
+            }
+        }
+        let guard = SetOnDrop(dropped.clone());
+        let context = unowned_context();
+        let closure = move |_: &mut Context, _: &mut Device, _| {
+            let _guard = &guard;
+            panic!("callback failed")
+        };
+        let registration = Registration::new(Arc::downgrade(&context), closure);
+        let user_data = Arc::into_raw(registration.clone()) as *const core::ffi::c_void;
+        let handle = CallbackHandle::new(0, context.clone(), registration.clone(), user_data);
+        assert_eq!(deliver(&registration, user_data, 1), 1);
+        assert!(handle.is_finished());
+        // The closure is dropped right away, not once the handle goes
+        assert!(dropped.load(Ordering::SeqCst));
+        let panic = handle.take_panic().expect("panic kept");
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"callback failed"));
+        assert!(handle.take_panic().is_none());
+        drop(handle);
+        assert_eq!(Arc::strong_count(&registration), 1);
+        // Released even though the closure panicked
+        assert_eq!(Arc::strong_count(&context), 1);
     }
 }
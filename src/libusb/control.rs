@@ -0,0 +1,628 @@
+//! Control transfer setup packet (`ControlSetup`) and the pieces of its `bmRequestType` field.
+use crate::device::Codes;
+use crate::libusb::error::Error;
+use crate::version::Version;
+use core::convert::TryFrom;
+
+/// Direction of the data stage of a control transfer. Bit 7 of `bmRequestType`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum Direction {
+    /// Host to Device
+    Out = 0x00,
+    /// Device to Host
+    In = 0x80,
+}
+impl Direction {
+    pub const MASK: u8 = 0x80;
+    pub fn from_request_type(request_type: u8) -> Direction {
+        if request_type & Self::MASK == 0 {
+            Direction::Out
+        } else {
+            Direction::In
+        }
+    }
+}
+impl From<Direction> for u8 {
+    fn from(d: Direction) -> Self {
+        d as u8
+    }
+}
+/// Bits 5..6 of `bmRequestType`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum RequestKind {
+    Standard = 0x00,
+    Class = 0x20,
+    Vendor = 0x40,
+    Reserved = 0x60,
+}
+impl RequestKind {
+    pub const MASK: u8 = 0x60;
+    pub fn from_request_type(request_type: u8) -> RequestKind {
+        match request_type & Self::MASK {
+            0x00 => RequestKind::Standard,
+            0x20 => RequestKind::Class,
+            0x40 => RequestKind::Vendor,
+            _ => RequestKind::Reserved,
+        }
+    }
+}
+impl From<RequestKind> for u8 {
+    fn from(k: RequestKind) -> Self {
+        k as u8
+    }
+}
+/// Bits 0..4 of `bmRequestType`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum Recipient {
+    Device = 0x00,
+    Interface = 0x01,
+    Endpoint = 0x02,
+    Other = 0x03,
+}
+impl Recipient {
+    pub const MASK: u8 = 0x1F;
+}
+impl From<Recipient> for u8 {
+    fn from(r: Recipient) -> Self {
+        r as u8
+    }
+}
+impl TryFrom<u8> for Recipient {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Recipient::Device),
+            0x01 => Ok(Recipient::Interface),
+            0x02 => Ok(Recipient::Endpoint),
+            0x03 => Ok(Recipient::Other),
+            _ => Err(()),
+        }
+    }
+}
+/// `bDescriptorType` values used by `GET_DESCRIPTOR`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum DescriptorType {
+    Device = 0x01,
+    Config = 0x02,
+    String = 0x03,
+    Interface = 0x04,
+    Endpoint = 0x05,
+    DeviceQualifier = 0x06,
+    Bos = 0x0F,
+    DeviceCapability = 0x10,
+    Hid = 0x21,
+    Report = 0x22,
+    Physical = 0x23,
+    Hub = 0x29,
+    SuperSpeedHub = 0x2A,
+    SuperSpeedEndpointCompanion = 0x30,
+}
+impl From<DescriptorType> for u8 {
+    fn from(d: DescriptorType) -> Self {
+        d as u8
+    }
+}
+impl TryFrom<u8> for DescriptorType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(DescriptorType::Device),
+            0x02 => Ok(DescriptorType::Config),
+            0x03 => Ok(DescriptorType::String),
+            0x04 => Ok(DescriptorType::Interface),
+            0x05 => Ok(DescriptorType::Endpoint),
+            0x06 => Ok(DescriptorType::DeviceQualifier),
+            0x0F => Ok(DescriptorType::Bos),
+            0x10 => Ok(DescriptorType::DeviceCapability),
+            0x21 => Ok(DescriptorType::Hid),
+            0x22 => Ok(DescriptorType::Report),
+            0x23 => Ok(DescriptorType::Physical),
+            0x29 => Ok(DescriptorType::Hub),
+            0x2A => Ok(DescriptorType::SuperSpeedHub),
+            0x30 => Ok(DescriptorType::SuperSpeedEndpointCompanion),
+            _ => Err(()),
+        }
+    }
+}
+/// `bRequest` values of the standard requests (USB 2.0 spec Table 9-4).
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum StandardRequest {
+    GetStatus = 0x00,
+    ClearFeature = 0x01,
+    SetFeature = 0x03,
+    SetAddress = 0x05,
+    GetDescriptor = 0x06,
+    SetDescriptor = 0x07,
+    GetConfiguration = 0x08,
+    SetConfiguration = 0x09,
+    GetInterface = 0x0A,
+    SetInterface = 0x0B,
+    SynchFrame = 0x0C,
+}
+impl From<StandardRequest> for u8 {
+    fn from(r: StandardRequest) -> Self {
+        r as u8
+    }
+}
+/// Feature selectors for `SET_FEATURE`/`CLEAR_FEATURE` (USB 2.0 spec Table 9-6).
+pub mod feature {
+    pub const ENDPOINT_HALT: u16 = 0;
+    pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+    pub const TEST_MODE: u16 = 2;
+}
+/// Response to a device `GET_STATUS` request.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Default)]
+pub struct DeviceStatus {
+    pub self_powered: bool,
+    pub remote_wakeup: bool,
+}
+impl DeviceStatus {
+    pub const SIZE: usize = 2;
+    /// Returns `Error::BadDescriptor` if `bytes` isn't the 2 byte status.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DeviceStatus, Error> {
+        if bytes.len() != Self::SIZE {
+            return Err(Error::BadDescriptor);
+        }
+        Ok(DeviceStatus {
+            self_powered: bytes[0] & 0x01 != 0,
+            remote_wakeup: bytes[0] & 0x02 != 0,
+        })
+    }
+}
+/// Device qualifier descriptor. What the device would look like at the other speed (full speed if
+/// it's running at high speed and the other way around). Only high speed capable devices have one.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct DeviceQualifier {
+    pub usb_version: Version,
+    pub codes: Codes,
+    pub max_packet_size: u8,
+    pub num_configurations: u8,
+}
+impl DeviceQualifier {
+    pub const SIZE: usize = 10;
+    /// Returns `Error::BadDescriptor` if `bytes` isn't a 10 byte device qualifier descriptor.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DeviceQualifier, Error> {
+        if bytes.len() != Self::SIZE
+            || usize::from(bytes[0]) != Self::SIZE
+            || bytes[1] != u8::from(DescriptorType::DeviceQualifier)
+        {
+            return Err(Error::BadDescriptor);
+        }
+        Ok(DeviceQualifier {
+            usb_version: Version(u16::from_le_bytes([bytes[2], bytes[3]])),
+            codes: Codes {
+                class: bytes[4],
+                sub_class: bytes[5],
+                protocol: bytes[6],
+            },
+            max_packet_size: bytes[7],
+            num_configurations: bytes[8],
+        })
+    }
+}
+/// `wLength` for a data stage of `len` bytes. `Error::InvalidParam` if it doesn't fit.
+pub(crate) fn control_len(len: usize) -> Result<u16, Error> {
+    u16::try_from(len).map_err(|_| Error::InvalidParam)
+}
+/// A control transfer setup packet. The fields are in host endianness, `try_serialize` writes
+/// them in the wire's Little-Endian.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct ControlSetup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub len: u16,
+}
+impl ControlSetup {
+    /// Size of a setup packet on the wire.
+    pub const SIZE: usize = 8;
+    pub fn builder() -> ControlSetupBuilder {
+        ControlSetupBuilder::default()
+    }
+    /// Writes the setup packet (`u16`s in Little-Endian) to the start of `buf`.
+    /// `Error::Overflow` if `buf` is shorter than `ControlSetup::SIZE`.
+    pub fn try_serialize(self, buf: &mut [u8]) -> Result<(), Error> {
+        let buf = buf.get_mut(..Self::SIZE).ok_or(Error::Overflow)?;
+        buf[0] = self.request_type;
+        buf[1] = self.request;
+        buf[2..4].copy_from_slice(&self.value.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.index.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.len.to_le_bytes());
+        Ok(())
+    }
+    /// Reads the setup packet at the start of `buf`. `Error::Overflow` if `buf` is shorter than
+    /// `ControlSetup::SIZE`.
+    pub fn try_deserialize(buf: &[u8]) -> Result<ControlSetup, Error> {
+        let buf = buf.get(..Self::SIZE).ok_or(Error::Overflow)?;
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        Ok(ControlSetup {
+            request_type: buf[0],
+            request: buf[1],
+            value: u16_at(2),
+            index: u16_at(4),
+            len: u16_at(6),
+        })
+    }
+    /// `try_serialize`, panics if `buf` is too small.
+    pub fn serialize(self, buf: &mut [u8]) {
+        self.try_serialize(buf).expect("ControlSetup buf too small")
+    }
+    /// `try_deserialize`, panics if `buf` is too small.
+    pub fn deserialize(buf: &[u8]) -> ControlSetup {
+        Self::try_deserialize(buf).expect("ControlSetup buf too small")
+    }
+    pub fn direction(&self) -> Direction {
+        Direction::from_request_type(self.request_type)
+    }
+    pub fn request_kind(&self) -> RequestKind {
+        RequestKind::from_request_type(self.request_type)
+    }
+    /// Returns `None` if the recipient bits are reserved.
+    pub fn recipient(&self) -> Option<Recipient> {
+        Recipient::try_from(self.request_type & Recipient::MASK).ok()
+    }
+    pub fn is_write(&self) -> bool {
+        self.direction() == Direction::Out
+    }
+    pub fn is_read(&self) -> bool {
+        self.direction() == Direction::In
+    }
+    /// `GET_DESCRIPTOR` request. `langid` is only used for string descriptors (0 otherwise).
+    pub fn get_descriptor(
+        descriptor_type: DescriptorType,
+        index: u8,
+        langid: u16,
+        len: u16,
+    ) -> ControlSetup {
+        Self::get_descriptor_from(
+            Recipient::Device,
+            descriptor_type.into(),
+            index,
+            langid,
+            len,
+        )
+    }
+    /// `GET_DESCRIPTOR` request for any descriptor type. Class descriptors (like the HID report
+    /// descriptor) are requested from `Recipient::Interface` with the interface number as
+    /// `w_index`.
+    pub fn get_descriptor_from(
+        recipient: Recipient,
+        descriptor_type: u8,
+        index: u8,
+        w_index: u16,
+        len: u16,
+    ) -> ControlSetup {
+        Self::builder()
+            .direction(Direction::In)
+            .recipient(recipient)
+            .request(StandardRequest::GetDescriptor.into())
+            .value(u16::from(descriptor_type) << 8 | u16::from(index))
+            .index(w_index)
+            .len(len)
+            .build()
+    }
+    /// `SET_CONFIGURATION` request.
+    pub fn set_configuration(value: u8) -> ControlSetup {
+        Self::builder()
+            .request(StandardRequest::SetConfiguration.into())
+            .value(value.into())
+            .build()
+    }
+    /// `GET_CONFIGURATION` request.
+    pub fn get_configuration() -> ControlSetup {
+        Self::builder()
+            .direction(Direction::In)
+            .request(StandardRequest::GetConfiguration.into())
+            .len(1)
+            .build()
+    }
+    /// `GET_INTERFACE` request.
+    pub fn get_interface(interface: u8) -> ControlSetup {
+        Self::builder()
+            .direction(Direction::In)
+            .recipient(Recipient::Interface)
+            .request(StandardRequest::GetInterface.into())
+            .index(interface.into())
+            .len(1)
+            .build()
+    }
+    /// `SYNCH_FRAME` request.
+    pub fn synch_frame(endpoint: u8) -> ControlSetup {
+        Self::builder()
+            .direction(Direction::In)
+            .recipient(Recipient::Endpoint)
+            .request(StandardRequest::SynchFrame.into())
+            .index(endpoint.into())
+            .len(2)
+            .build()
+    }
+    /// `GET_STATUS` request. `index` is the interface or endpoint number (0 for the device).
+    pub fn get_status(recipient: Recipient, index: u16) -> ControlSetup {
+        Self::builder()
+            .direction(Direction::In)
+            .recipient(recipient)
+            .request(StandardRequest::GetStatus.into())
+            .index(index)
+            .len(2)
+            .build()
+    }
+    /// `SET_FEATURE` request. `index` is the interface or endpoint number (0 for the device).
+    pub fn set_feature(recipient: Recipient, feature: u16, index: u16) -> ControlSetup {
+        Self::builder()
+            .recipient(recipient)
+            .request(StandardRequest::SetFeature.into())
+            .value(feature)
+            .index(index)
+            .build()
+    }
+    /// `CLEAR_FEATURE` request. `index` is the interface or endpoint number (0 for the device).
+    pub fn clear_feature(recipient: Recipient, feature: u16, index: u16) -> ControlSetup {
+        Self::builder()
+            .recipient(recipient)
+            .request(StandardRequest::ClearFeature.into())
+            .value(feature)
+            .index(index)
+            .build()
+    }
+    /// `CLEAR_FEATURE(ENDPOINT_HALT)` of `endpoint` (address with the direction bit).
+    pub fn clear_halt(endpoint: u8) -> ControlSetup {
+        Self::clear_feature(Recipient::Endpoint, feature::ENDPOINT_HALT, endpoint.into())
+    }
+    /// Vendor request to the device. `direction` must match the data stage (`In` for reads).
+    pub fn vendor(
+        direction: Direction,
+        request: u8,
+        value: u16,
+        index: u16,
+        len: u16,
+    ) -> ControlSetup {
+        Self::builder()
+            .direction(direction)
+            .request_type(RequestKind::Vendor)
+            .request(request)
+            .value(value)
+            .index(index)
+            .len(len)
+            .build()
+    }
+    /// Class request to an interface. The spec puts the interface number in the low byte of
+    /// `wIndex` (the high byte is 0 here).
+    pub fn class_interface(
+        direction: Direction,
+        request: u8,
+        value: u16,
+        interface: u8,
+        len: u16,
+    ) -> ControlSetup {
+        Self::builder()
+            .direction(direction)
+            .request_type(RequestKind::Class)
+            .recipient(Recipient::Interface)
+            .request(request)
+            .value(value)
+            .index(interface.into())
+            .len(len)
+            .build()
+    }
+}
+/// Builds a `ControlSetup` without having to remember the `bmRequestType` bit layout.
+/// Defaults to a standard, host to device request to the device.
+#[derive(Copy, Clone, Debug)]
+pub struct ControlSetupBuilder {
+    direction: Direction,
+    request_kind: RequestKind,
+    recipient: Recipient,
+    request: u8,
+    value: u16,
+    index: u16,
+    len: u16,
+}
+impl Default for ControlSetupBuilder {
+    fn default() -> Self {
+        ControlSetupBuilder {
+            direction: Direction::Out,
+            request_kind: RequestKind::Standard,
+            recipient: Recipient::Device,
+            request: 0,
+            value: 0,
+            index: 0,
+            len: 0,
+        }
+    }
+}
+impl ControlSetupBuilder {
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+    pub fn request_type(mut self, request_kind: RequestKind) -> Self {
+        self.request_kind = request_kind;
+        self
+    }
+    pub fn recipient(mut self, recipient: Recipient) -> Self {
+        self.recipient = recipient;
+        self
+    }
+    pub fn request(mut self, request: u8) -> Self {
+        self.request = request;
+        self
+    }
+    pub fn value(mut self, value: u16) -> Self {
+        self.value = value;
+        self
+    }
+    pub fn index(mut self, index: u16) -> Self {
+        self.index = index;
+        self
+    }
+    pub fn len(mut self, len: u16) -> Self {
+        self.len = len;
+        self
+    }
+    pub fn build(self) -> ControlSetup {
+        ControlSetup {
+            request_type: u8::from(self.direction)
+                | u8::from(self.request_kind)
+                | u8::from(self.recipient),
+            request: self.request,
+            value: self.value,
+            index: self.index,
+            len: self.len,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::control::{
+        ControlSetup, DescriptorType, DeviceQualifier, DeviceStatus, Direction, Recipient,
+        RequestKind,
+    };
+    use crate::libusb::error::Error;
+    use crate::version::Version;
+
+    fn round_trip(setup: ControlSetup) -> ControlSetup {
+        let mut buf = [0_u8; ControlSetup::SIZE];
+        setup.serialize(&mut buf);
+        ControlSetup::deserialize(&buf)
+    }
+    #[test]
+    pub fn test_builder_round_trip() {
+        let setup = ControlSetup::builder()
+            .direction(Direction::In)
+            .request_type(RequestKind::Vendor)
+            .recipient(Recipient::Interface)
+            .request(0x42)
+            .value(0x1234)
+            .index(0x0102)
+            .len(64)
+            .build();
+        assert_eq!(setup.request_type, 0xC1);
+        assert_eq!(round_trip(setup), setup);
+        assert!(setup.is_read());
+        assert_eq!(setup.request_kind(), RequestKind::Vendor);
+        assert_eq!(setup.recipient(), Some(Recipient::Interface));
+    }
+    #[test]
+    pub fn test_get_descriptor_wire_format() {
+        let setup = ControlSetup::get_descriptor(DescriptorType::String, 2, 0x0409, 255);
+        let mut buf = [0_u8; ControlSetup::SIZE];
+        setup.serialize(&mut buf);
+        assert_eq!(buf, [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0xFF, 0x00]);
+        assert_eq!(round_trip(setup), setup);
+        // HID report descriptor of interface 1
+        let setup = ControlSetup::get_descriptor_from(
+            Recipient::Interface,
+            DescriptorType::Report.into(),
+            0,
+            1,
+            0x3F,
+        );
+        setup.serialize(&mut buf);
+        assert_eq!(buf, [0x81, 0x06, 0x00, 0x22, 0x01, 0x00, 0x3F, 0x00]);
+    }
+    #[test]
+    pub fn test_spec_setup_packets() {
+        // USB 2.0 chapter 9 standard requests
+        let packets = [
+            (
+                ControlSetup::get_descriptor(DescriptorType::Device, 0, 0, 18),
+                [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+            ),
+            (
+                ControlSetup::set_configuration(1),
+                [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            (
+                ControlSetup::get_status(Recipient::Endpoint, 0x81),
+                [0x82, 0x00, 0x00, 0x00, 0x81, 0x00, 0x02, 0x00],
+            ),
+            (
+                ControlSetup {
+                    request_type: 0x21,
+                    request: 0x09,
+                    value: 0x0200,
+                    index: 0x1234,
+                    len: 0xABCD,
+                },
+                [0x21, 0x09, 0x00, 0x02, 0x34, 0x12, 0xCD, 0xAB],
+            ),
+        ];
+        for (setup, bytes) in packets.iter() {
+            // Stale bytes past the packet are left alone and not read
+            let mut buf = [0xEE_u8; ControlSetup::SIZE + 2];
+            setup.try_serialize(&mut buf).expect("fits");
+            assert_eq!(&buf[..ControlSetup::SIZE], bytes);
+            assert_eq!(buf[ControlSetup::SIZE..], [0xEE, 0xEE]);
+            assert_eq!(ControlSetup::try_deserialize(&buf), Ok(*setup));
+        }
+        let mut short = [0_u8; ControlSetup::SIZE - 1];
+        assert_eq!(packets[0].0.try_serialize(&mut short), Err(Error::Overflow));
+        assert_eq!(short, [0; ControlSetup::SIZE - 1]);
+        assert_eq!(ControlSetup::try_deserialize(&short), Err(Error::Overflow));
+    }
+    #[test]
+    pub fn test_device_qualifier() {
+        let qualifier = DeviceQualifier::from_bytes(&[10, 6, 0x00, 0x02, 0xEF, 2, 1, 64, 1, 0])
+            .expect("valid qualifier");
+        assert_eq!(qualifier.usb_version, Version(0x0200));
+        assert_eq!(qualifier.codes.class, 0xEF);
+        assert_eq!(qualifier.max_packet_size, 64);
+        assert_eq!(qualifier.num_configurations, 1);
+        assert_eq!(
+            DeviceQualifier::from_bytes(&[10, 1, 0x00, 0x02, 0xEF, 2, 1, 64, 1, 0]),
+            Err(Error::BadDescriptor)
+        );
+        assert_eq!(
+            DeviceQualifier::from_bytes(&[10, 6, 0x00, 0x02]),
+            Err(Error::BadDescriptor)
+        );
+    }
+    #[test]
+    pub fn test_standard_requests() {
+        let set_config = ControlSetup::set_configuration(1);
+        assert!(set_config.is_write());
+        assert_eq!((set_config.request, set_config.value), (0x09, 1));
+        let status = ControlSetup::get_status(Recipient::Endpoint, 0x81);
+        assert_eq!(status.request_type, 0x82);
+        assert_eq!(status.len, 2);
+        let clear = ControlSetup::clear_feature(Recipient::Endpoint, 0, 0x81);
+        assert_eq!((clear.request_type, clear.request), (0x02, 0x01));
+        assert_eq!(round_trip(clear), clear);
+        assert_eq!(ControlSetup::clear_halt(0x81), clear);
+        let mut buf = [0_u8; ControlSetup::SIZE];
+        ControlSetup::clear_halt(0x02).serialize(&mut buf);
+        assert_eq!(buf, [0x02, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+    }
+    #[test]
+    pub fn test_vendor_and_class_requests() {
+        let read = ControlSetup::vendor(Direction::In, 0x01, 0x1234, 0x5678, 64);
+        assert_eq!(read.request_type, 0xC0);
+        assert_eq!(read.request_kind(), RequestKind::Vendor);
+        assert_eq!(read.recipient(), Some(Recipient::Device));
+        assert_eq!(
+            (read.request, read.value, read.index, read.len),
+            (0x01, 0x1234, 0x5678, 64)
+        );
+        let write = ControlSetup::vendor(Direction::Out, 0x02, 0, 0, 4);
+        assert_eq!(write.request_type, 0x40);
+        assert!(write.is_write());
+        // HID SET_IDLE/GET_REPORT style requests
+        let write = ControlSetup::class_interface(Direction::Out, 0x0A, 0x0000, 2, 0);
+        assert_eq!(write.request_type, 0x21);
+        assert_eq!(write.index, 0x0002);
+        let read = ControlSetup::class_interface(Direction::In, 0x01, 0x0100, 0xFF, 8);
+        assert_eq!(read.request_type, 0xA1);
+        assert_eq!(read.recipient(), Some(Recipient::Interface));
+        assert_eq!((read.value, read.index, read.len), (0x0100, 0x00FF, 8));
+    }
+    #[test]
+    pub fn test_device_status() {
+        let status = DeviceStatus::from_bytes(&[0x03, 0x00]).expect("valid status");
+        assert!(status.self_powered && status.remote_wakeup);
+        assert_eq!(DeviceStatus::from_bytes(&[0x01]), Err(Error::BadDescriptor));
+    }
+}
@@ -1,99 +1,449 @@
+use crate::descriptors::ParsedConfig;
+use crate::device::{Codes, Descriptor, DeviceFilter, DeviceIdentifier, VendorID};
 use crate::libusb::config_descriptor::ConfigDescriptor;
+use crate::libusb::context::ContextInner;
 use crate::libusb::device_descriptor::DeviceDescriptor;
 use crate::libusb::device_handle::DeviceHandle;
-use crate::libusb::error::Error;
+use crate::libusb::error;
+use crate::libusb::error::{Error, ErrorContext, Operation};
+use crate::libusb::interface_descriptor::InterfaceDescriptor;
+use crate::libusb::report::DeviceReport;
+use crate::libusb::retry::RetryPolicy;
+use crate::libusb::speed::Speed;
+use core::convert::TryFrom;
+use std::sync::Arc;
+
+/// The USB 3.0 spec limits hub depth to 7.
+pub const PORT_NUMBERS_MAX_DEPTH: usize = 7;
+/// Physical location of a device on a bus. Displays like Linux sysfs paths (`1-4.2.1`).
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub struct DeviceLocation {
+    pub bus: u8,
+    pub ports: Vec<u8>,
+}
+impl core::fmt::Display for DeviceLocation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.bus)?;
+        for (i, port) in self.ports.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { '-' } else { '.' }, port)?;
+        }
+        Ok(())
+    }
+}
+/// Identifies a connected device for as long as it stays connected: where it is plugged in and
+/// the address the bus gave it (a new address is assigned each time a device connects).
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub struct DeviceSessionId {
+    pub location: DeviceLocation,
+    pub address: u8,
+}
+/// Displays as the location and address (`1-4.2@5`).
+impl core::fmt::Display for DeviceSessionId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}@{}", self.location, self.address)
+    }
+}
 
 #[derive(Debug)]
-pub struct Device(core::ptr::NonNull<libusb1_sys::libusb_device>);
+pub struct Device {
+    ptr: core::ptr::NonNull<libusb1_sys::libusb_device>,
+    /// Keeps the context alive. `None` for devices made with `from_libusb`.
+    context: Option<Arc<ContextInner>>,
+}
+// libusb devices are reference counted and locked internally.
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
 impl Device {
     /// # Safety
     /// Assumes the pointer is valid and pointers to a `libusb_device`
     pub const unsafe fn from_libusb(ptr: core::ptr::NonNull<libusb1_sys::libusb_device>) -> Device {
-        Device(ptr)
+        Device { ptr, context: None }
+    }
+    /// # Safety
+    /// Same as `from_libusb`, and the device has to belong to `context`.
+    pub(crate) unsafe fn from_libusb_in(
+        ptr: core::ptr::NonNull<libusb1_sys::libusb_device>,
+        context: Option<Arc<ContextInner>>,
+    ) -> Device {
+        Device { ptr, context }
+    }
+    /// Releases the context reference, for devices libusb only lends and that are never dropped.
+    pub(crate) fn take_context(&mut self) -> Option<Arc<ContextInner>> {
+        self.context.take()
     }
 
     pub fn active_config_descriptor(&self) -> Result<ConfigDescriptor, Error> {
         let mut out: *const libusb1_sys::libusb_config_descriptor = core::ptr::null_mut();
         try_unsafe!(libusb1_sys::libusb_get_active_config_descriptor(
-            self.0.as_ptr(),
+            self.ptr.as_ptr(),
+            &mut out as *mut _
+        ));
+        Ok(unsafe {
+            ConfigDescriptor::from_libusb(core::ptr::NonNull::new_unchecked(out as *mut _))
+        })
+    }
+    /// Returns the config descriptor at `index` (not the `bConfigurationValue`).
+    pub fn config_descriptor(&self, index: u8) -> Result<ConfigDescriptor, Error> {
+        let mut out: *const libusb1_sys::libusb_config_descriptor = core::ptr::null_mut();
+        try_unsafe!(libusb1_sys::libusb_get_config_descriptor(
+            self.ptr.as_ptr(),
+            index,
+            &mut out as *mut _
+        ));
+        Ok(unsafe {
+            ConfigDescriptor::from_libusb(core::ptr::NonNull::new_unchecked(out as *mut _))
+        })
+    }
+    /// Returns the config descriptor with the `bConfigurationValue` of `value`.
+    pub fn config_descriptor_by_value(&self, value: u8) -> Result<ConfigDescriptor, Error> {
+        let mut out: *const libusb1_sys::libusb_config_descriptor = core::ptr::null_mut();
+        try_unsafe!(libusb1_sys::libusb_get_config_descriptor_by_value(
+            self.ptr.as_ptr(),
+            value,
             &mut out as *mut _
         ));
         Ok(unsafe {
             ConfigDescriptor::from_libusb(core::ptr::NonNull::new_unchecked(out as *mut _))
         })
     }
+    /// Iterates over every config descriptor of the device (`bNumConfigurations` of them). If the
+    /// device descriptor can't be read, only that error is returned.
+    pub fn config_descriptors(&self) -> impl Iterator<Item = Result<ConfigDescriptor, Error>> + '_ {
+        let (num_configurations, err) = match self.device_descriptor() {
+            Ok(descriptor) => (descriptor.0.bNumConfigurations, None),
+            Err(e) => (0, Some(Err(e))),
+        };
+        err.into_iter()
+            .chain((0..num_configurations).map(move |i| self.config_descriptor(i)))
+    }
     pub fn device_address(&self) -> u8 {
-        unsafe { libusb1_sys::libusb_get_device_address(self.0.as_ptr()) }
+        unsafe { libusb1_sys::libusb_get_device_address(self.ptr.as_ptr()) }
+    }
+    /// Returns the negotiated connection speed of the device.
+    pub fn speed(&self) -> Speed {
+        let speed = unsafe { libusb1_sys::libusb_get_device_speed(self.ptr.as_ptr()) };
+        Speed::try_from(speed).unwrap_or(Speed::Unknown)
+    }
+    /// Returns the `wMaxPacketSize` of `endpoint` in the active configuration.
+    pub fn max_packet_size(&self, endpoint: u8) -> Result<usize, Error> {
+        let res = unsafe { libusb1_sys::libusb_get_max_packet_size(self.ptr.as_ptr(), endpoint) };
+        if res < 0 {
+            Err(error::from_libusb(res))
+        } else {
+            Ok(res as usize)
+        }
+    }
+    /// Returns the maximum amount of bytes `endpoint` can send/receive in one microframe.
+    pub fn max_iso_packet_size(&self, endpoint: u8) -> Result<usize, Error> {
+        let res =
+            unsafe { libusb1_sys::libusb_get_max_iso_packet_size(self.ptr.as_ptr(), endpoint) };
+        if res < 0 {
+            Err(error::from_libusb(res))
+        } else {
+            Ok(res as usize)
+        }
+    }
+    /// Returns the number of the bus the device is connected to.
+    pub fn bus_number(&self) -> u8 {
+        unsafe { libusb1_sys::libusb_get_bus_number(self.ptr.as_ptr()) }
+    }
+    /// Returns the number of the port the device is connected to. `0` means unavailable.
+    pub fn port_number(&self) -> u8 {
+        unsafe { libusb1_sys::libusb_get_port_number(self.ptr.as_ptr()) }
+    }
+    /// Returns the list of all port numbers from the root for the device.
+    pub fn port_numbers(&self) -> Result<Vec<u8>, Error> {
+        let mut ports = [0_u8; PORT_NUMBERS_MAX_DEPTH];
+        let len = unsafe {
+            libusb1_sys::libusb_get_port_numbers(
+                self.ptr.as_ptr(),
+                ports.as_mut_ptr(),
+                ports.len() as i32,
+            )
+        };
+        if len < 0 {
+            Err(error::from_libusb(len))
+        } else {
+            Ok(ports[..len as usize].to_vec())
+        }
+    }
+    /// Returns the bus number and port path of the device.
+    pub fn location(&self) -> Result<DeviceLocation, Error> {
+        Ok(DeviceLocation {
+            bus: self.bus_number(),
+            ports: self.port_numbers()?,
+        })
+    }
+    /// Returns the bus, port path and address of the device. Unlike `Device` equality this also
+    /// matches devices from different contexts.
+    pub fn session_id(&self) -> Result<DeviceSessionId, Error> {
+        Ok(DeviceSessionId {
+            location: self.location()?,
+            address: self.device_address(),
+        })
+    }
+    /// Returns the parent of the device (usually a hub) or `None` if its a root hub or the
+    /// parent isn't in the device list.
+    pub fn parent(&self) -> Option<Device> {
+        let ptr = unsafe { libusb1_sys::libusb_get_parent(self.ptr.as_ptr()) };
+        core::ptr::NonNull::new(ptr).map(|ptr| unsafe {
+            // `libusb_get_parent` doesn't increase the reference count
+            libusb1_sys::libusb_ref_device(ptr.as_ptr());
+            Device::from_libusb_in(ptr, self.context.clone())
+        })
     }
 
     pub fn device_descriptor(&self) -> Result<DeviceDescriptor, Error> {
         let mut out: core::mem::MaybeUninit<libusb1_sys::libusb_device_descriptor> =
             core::mem::MaybeUninit::uninit();
         try_unsafe!(libusb1_sys::libusb_get_device_descriptor(
-            self.0.as_ptr() as *const _,
+            self.ptr.as_ptr() as *const _,
             out.as_mut_ptr()
         ));
         Ok(unsafe { DeviceDescriptor::from(out.assume_init()) })
     }
-    pub fn open(&self) -> Result<DeviceHandle, Error> {
+    /// `error` annotated with this device and `operation`.
+    pub(crate) fn error_context(&self, error: Error, operation: Operation) -> ErrorContext {
+        ErrorContext {
+            error,
+            operation,
+            device_identifier: self.device_descriptor().ok().map(|d| d.device_identifier()),
+            bus_number: self.bus_number(),
+            address: self.device_address(),
+        }
+    }
+    pub fn open(&self) -> Result<DeviceHandle, ErrorContext> {
         let mut out = core::ptr::null_mut();
-        try_unsafe!(libusb1_sys::libusb_open(self.0.as_ptr(), &mut out));
+        match unsafe { libusb1_sys::libusb_open(self.ptr.as_ptr(), &mut out) } {
+            0 => (),
+            err => return Err(self.error_context(error::from_libusb(err), Operation::Open)),
+        }
         debug_assert!(!out.is_null(), "null libusb device handle ptr");
-        Ok(unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::new_unchecked(out)) })
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::new_unchecked(out)) };
+        Ok(match &self.context {
+            Some(context) => handle.with_context(context.clone()),
+            None => handle,
+        })
+    }
+    /// Tries to open the device `attempts` times, sleeping `backoff` in between, while it fails
+    /// with an error that might go away (see `RetryPolicy::is_retryable`).
+    pub fn open_with_retry(
+        &self,
+        attempts: u32,
+        backoff: core::time::Duration,
+    ) -> Result<DeviceHandle, ErrorContext> {
+        RetryPolicy::constant(attempts, backoff).retry(|| self.open())
+    }
+    /// Gathers everything about the device into a `DeviceReport`. Only fails if the device
+    /// descriptor can't be read, anything else (like not having permission to open the device for
+    /// the strings) leaves that part of the report empty.
+    pub fn probe(&self) -> Result<DeviceReport, Error> {
+        let descriptor = Descriptor::from(&self.device_descriptor()?);
+        let mut report = DeviceReport {
+            descriptor,
+            bus_number: self.bus_number(),
+            address: self.device_address(),
+            port_numbers: self.port_numbers().unwrap_or_default(),
+            speed: self.speed(),
+            configs: self
+                .config_descriptors()
+                .filter_map(Result::ok)
+                .map(|c| ParsedConfig::from(&c))
+                .collect(),
+            strings: Default::default(),
+            open_error: None,
+        };
+        match self.open() {
+            Ok(handle) => report.read_strings(&handle),
+            Err(e) => report.open_error = Some(e.error),
+        }
+        Ok(report)
     }
     /// Leak the `Device` without calling `libusb_unref_device`.
     pub fn leak(self) {
         core::mem::forget(self)
     }
     pub fn libusb_ptr(&self) -> core::ptr::NonNull<libusb1_sys::libusb_device> {
-        self.0
+        self.ptr
+    }
+}
+impl DeviceFilter {
+    pub fn matches_interface(&self, interface: &InterfaceDescriptor<'_>) -> bool {
+        self.matches_codes(&Codes {
+            class: interface.class_code(),
+            sub_class: interface.sub_class_code(),
+            protocol: interface.protocol_code(),
+        })
+    }
+    /// `matches_device` without the serial number (so without opening the device).
+    pub(crate) fn matches_device_descriptors(&self, device: &Device) -> Result<bool, Error> {
+        let descriptor = Descriptor::from(&device.device_descriptor()?);
+        if !self.matches_ids(descriptor.device_identifier) {
+            return Ok(false);
+        }
+        if !self.has_codes() || self.matches_codes(&descriptor.codes) {
+            return Ok(true);
+        }
+        match device.active_config_descriptor() {
+            Ok(config) => Ok(config
+                .find_interface(|i| self.matches_interface(i))
+                .is_some()),
+            // Unconfigured
+            Err(Error::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    /// Checks the IDs, the class codes (of the device or any interface in the active config, so
+    /// composite devices match) and the serial number. The device is only opened to read the
+    /// serial number if the filter has one.
+    pub fn matches_device(&self, device: &Device) -> Result<bool, Error> {
+        if !self.matches_device_descriptors(device)? {
+            return Ok(false);
+        }
+        if self.serial_number().is_none() {
+            return Ok(true);
+        }
+        let serial_number = match device.device_descriptor()?.serial_number_string_index() {
+            Some(index) => Some(device.open()?.read_string_descriptor_ascii(index)?),
+            None => None,
+        };
+        Ok(self.matches_serial(serial_number.as_deref()))
+    }
+}
+/// Devices are equal if they're the same `libusb_device`. A context keeps one `libusb_device` per
+/// connected device so every `DeviceList` and hotplug callback hands out the same pointer for
+/// it, while a device that reconnects (or the same device seen by another context) is not
+/// equal. Use `session_id` to compare across contexts.
+impl PartialEq for Device {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+impl Eq for Device {}
+impl core::hash::Hash for Device {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state)
+    }
+}
+/// Another reference to the same device (`libusb_ref_device`).
+impl Clone for Device {
+    fn clone(&self) -> Self {
+        unsafe {
+            libusb1_sys::libusb_ref_device(self.ptr.as_ptr());
+            Device::from_libusb_in(self.ptr, self.context.clone())
+        }
     }
 }
 impl Drop for Device {
     fn drop(&mut self) {
-        unsafe { libusb1_sys::libusb_unref_device(self.0.as_ptr()) }
+        unsafe { libusb1_sys::libusb_unref_device(self.ptr.as_ptr()) }
     }
 }
 
+/// The devices of a `libusb_get_device_list` call. The list itself is freed right away, the
+/// devices keep their own references.
 #[derive(Debug)]
 pub struct DeviceList {
-    ptr: core::ptr::NonNull<*mut libusb1_sys::libusb_device>,
-    len: usize,
+    devices: Vec<Device>,
 }
 impl DeviceList {
     /// # Safety
-    /// Assumes the pointer is valid and pointers to a list of devices
-    pub const unsafe fn from_libusb(
+    /// Assumes the pointer is valid and pointers to a list of devices. Takes ownership of the
+    /// list (and its references to the devices).
+    pub unsafe fn from_libusb(
+        ptr: core::ptr::NonNull<*mut libusb1_sys::libusb_device>,
+        len: usize,
+    ) -> DeviceList {
+        Self::from_libusb_list(ptr, len, None)
+    }
+    /// # Safety
+    /// Same as `from_libusb`, and the list has to come from `context`.
+    pub(crate) unsafe fn from_libusb_in(
+        ptr: core::ptr::NonNull<*mut libusb1_sys::libusb_device>,
+        len: usize,
+        context: &Arc<ContextInner>,
+    ) -> DeviceList {
+        Self::from_libusb_list(ptr, len, Some(context))
+    }
+    unsafe fn from_libusb_list(
         ptr: core::ptr::NonNull<*mut libusb1_sys::libusb_device>,
         len: usize,
+        context: Option<&Arc<ContextInner>>,
     ) -> DeviceList {
-        DeviceList { ptr, len }
+        let devices = (0..len)
+            .map(|i| {
+                let device = *ptr.as_ptr().add(i);
+                debug_assert!(!device.is_null(), "null device ptr");
+                // The list's reference moves to the `Device`
+                Device::from_libusb_in(core::ptr::NonNull::new_unchecked(device), context.cloned())
+            })
+            .collect();
+        libusb1_sys::libusb_free_device_list(ptr.as_ptr(), 0);
+        DeviceList { devices }
     }
     pub fn is_empty(&self) -> bool {
-        self.len == 0
+        self.devices.is_empty()
     }
     pub fn len(&self) -> usize {
-        self.len
+        self.devices.len()
     }
     pub fn get(&self, pos: usize) -> Option<Device> {
-        if pos < self.len {
-            Some(unsafe {
-                let ptr = *self.ptr.as_ptr().add(pos);
-                debug_assert!(!ptr.is_null(), "null device ptr");
-                libusb1_sys::libusb_ref_device(ptr);
-                Device::from_libusb(core::ptr::NonNull::new_unchecked(ptr))
-            })
-        } else {
-            None
-        }
+        self.devices.get(pos).cloned()
     }
     pub fn iter(&self) -> DeviceListIter<'_> {
         DeviceListIter { list: self, pos: 0 }
     }
+    /// Borrows the devices without touching their reference counts. The list keeps them alive.
+    pub fn as_slice(&self) -> &[Device] {
+        &self.devices
+    }
+    /// Returns the first device matching `predicate`.
+    pub fn find_by(&self, mut predicate: impl FnMut(&Device) -> bool) -> Option<Device> {
+        self.iter().find(|d| predicate(d))
+    }
+    /// Returns the first device with the given vendor and product ID. Devices whose descriptor
+    /// can't be read are skipped.
+    pub fn find_by_id(&self, id: DeviceIdentifier) -> Option<Device> {
+        self.iter_descriptors()
+            .find(|(_, descriptor)| descriptor.device_identifier() == id)
+            .map(|(device, _)| device)
+    }
+    /// Returns every device from `vendor_id`. Devices whose descriptor can't be read are skipped.
+    pub fn find_all_by_vendor(&self, vendor_id: VendorID) -> Vec<Device> {
+        self.iter_descriptors()
+            .filter(|(_, descriptor)| descriptor.vendor_id() == vendor_id)
+            .map(|(device, _)| device)
+            .collect()
+    }
+    /// Returns every device matching `filter`. Devices that can't be checked (their descriptors
+    /// can't be read or they can't be opened to read the serial number) are skipped.
+    pub fn find_all(&self, filter: &DeviceFilter) -> Vec<Device> {
+        self.iter()
+            .filter(|device| filter.matches_device(device).unwrap_or(false))
+            .collect()
+    }
+    /// Iterates over every device with its `DeviceDescriptor`, silently skipping devices whose
+    /// descriptor can't be read.
+    pub fn iter_descriptors(&self) -> impl Iterator<Item = (Device, DeviceDescriptor)> + '_ {
+        self.iter().filter_map(|device| {
+            let descriptor = device.device_descriptor().ok()?;
+            Some((device, descriptor))
+        })
+    }
 }
-impl Drop for DeviceList {
-    fn drop(&mut self) {
-        unsafe { libusb1_sys::libusb_free_device_list(self.ptr.as_ptr(), 1) }
+impl core::ops::Index<usize> for DeviceList {
+    type Output = Device;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.as_slice()[index]
+    }
+}
+impl<'a> IntoIterator for &'a DeviceList {
+    type Item = Device;
+    type IntoIter = DeviceListIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 pub struct DeviceListIter<'a> {
@@ -113,3 +463,45 @@ impl<'a> core::iter::Iterator for DeviceListIter<'a> {
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use crate::libusb::context::Context;
+    use crate::libusb::device::{DeviceLocation, DeviceSessionId};
+    use std::collections::HashSet;
+
+    #[test]
+    pub fn test_session_id_display() {
+        let id = DeviceSessionId {
+            location: DeviceLocation {
+                bus: 1,
+                ports: vec![4, 2],
+            },
+            address: 5,
+        };
+        assert_eq!(id.to_string(), "1-4.2@5");
+    }
+    /// Needs libusb and at least one attached device (root hubs count).
+    #[test]
+    #[ignore]
+    pub fn test_clone_drop_stress() {
+        let context = Context::new().expect("libusb context");
+        let list = context.device_list();
+        let device = list.get(0).expect("an attached device");
+        let mut set = HashSet::new();
+        for _ in 0..100_000 {
+            let clone = device.clone();
+            assert_eq!(clone, device);
+            set.insert(clone);
+        }
+        assert_eq!(set.len(), 1);
+        drop(set);
+        drop(list);
+        // The original reference is still valid after every clone and the list are gone
+        assert!(device.device_descriptor().is_ok());
+        assert_eq!(
+            context.device_list().iter().find(|d| *d == device),
+            Some(device.clone())
+        );
+        assert!(device.session_id().is_ok());
+    }
+}
@@ -1,4 +1,5 @@
-use crate::device::{DeviceIdentifier, ProductID, VendorID};
+use crate::device::{Codes, Descriptor, DeviceIdentifier, ProductID, StringIndices, VendorID};
+use crate::version::Version;
 
 pub struct DeviceDescriptor(pub libusb1_sys::libusb_device_descriptor);
 impl Clone for DeviceDescriptor {
@@ -58,6 +59,51 @@ impl DeviceDescriptor {
             product_id: ProductID(self.0.idProduct),
         }
     }
+
+    /// Returns the USB version the device supports (`bcdUSB`).
+    pub fn usb_version(&self) -> Version {
+        Version(self.0.bcdUSB)
+    }
+
+    /// Returns the device's release number (`bcdDevice`).
+    pub fn device_version(&self) -> Version {
+        Version(self.0.bcdDevice)
+    }
+
+    /// Returns the max packet size of endpoint 0.
+    pub fn max_packet_size_ep0(&self) -> u8 {
+        self.0.bMaxPacketSize0
+    }
+
+    /// The portable owned copy, same as `Descriptor::from`. Not `to_owned` since
+    /// `DeviceDescriptor` is `Clone` itself.
+    pub fn to_descriptor(&self) -> Descriptor {
+        Descriptor::from(self)
+    }
+    pub fn num_configurations(&self) -> u8 {
+        self.0.bNumConfigurations
+    }
+}
+impl From<&DeviceDescriptor> for Descriptor {
+    fn from(d: &DeviceDescriptor) -> Self {
+        Descriptor {
+            usb_version: d.usb_version(),
+            codes: Codes {
+                class: d.class_code(),
+                sub_class: d.sub_class_code(),
+                protocol: d.protocol_code(),
+            },
+            max_packet_size: d.max_packet_size_ep0(),
+            device_identifier: d.device_identifier(),
+            device_version: d.device_version(),
+            string_indices: StringIndices {
+                manufacturer: d.manufacturer_string_index(),
+                product: d.product_string_index(),
+                serial_number: d.serial_number_string_index(),
+            },
+            num_configurations: d.num_configurations(),
+        }
+    }
 }
 impl From<libusb1_sys::libusb_device_descriptor> for DeviceDescriptor {
     fn from(d: libusb1_sys::libusb_device_descriptor) -> Self {
@@ -85,3 +131,69 @@ impl core::fmt::Debug for DeviceDescriptor {
             .finish()
     }
 }
+#[cfg(test)]
+mod tests {
+    use crate::device::Descriptor;
+    use crate::libusb::device_descriptor::DeviceDescriptor;
+    use crate::version::Version;
+
+    fn raw_descriptor() -> libusb1_sys::libusb_device_descriptor {
+        libusb1_sys::libusb_device_descriptor {
+            bLength: 18,
+            bDescriptorType: 1,
+            bcdUSB: 0x0210,
+            bDeviceClass: 0xE0,
+            bDeviceSubClass: 0x01,
+            bDeviceProtocol: 0x01,
+            bMaxPacketSize0: 64,
+            idVendor: 0x0A12,
+            idProduct: 0x0001,
+            bcdDevice: 0x8891,
+            iManufacturer: 0,
+            iProduct: 2,
+            iSerialNumber: 3,
+            bNumConfigurations: 1,
+        }
+    }
+    #[test]
+    pub fn test_descriptor_from_device_descriptor() {
+        let device_descriptor = DeviceDescriptor(raw_descriptor());
+        let d = Descriptor::from(&device_descriptor);
+        assert_eq!(d.usb_version, Version(0x0210));
+        assert_eq!(
+            (
+                d.usb_version.major(),
+                d.usb_version.minor(),
+                d.usb_version.sub_minor()
+            ),
+            (2, 1, 0)
+        );
+        assert_eq!(
+            (
+                d.device_version.major(),
+                d.device_version.minor(),
+                d.device_version.sub_minor()
+            ),
+            (0x88, 9, 1)
+        );
+        assert_eq!(
+            (d.codes.class, d.codes.sub_class, d.codes.protocol),
+            (0xE0, 0x01, 0x01)
+        );
+        assert_eq!(d.max_packet_size, 64);
+        assert_eq!(d.device_identifier, device_descriptor.device_identifier());
+        assert_eq!(d.string_indices.manufacturer, None);
+        assert_eq!(d.string_indices.product, Some(2));
+        assert_eq!(d.string_indices.serial_number, Some(3));
+        assert_eq!(d.num_configurations, 1);
+        assert_eq!(device_descriptor.to_descriptor(), d);
+    }
+    #[test]
+    pub fn test_device_descriptor_accessors() {
+        let d = DeviceDescriptor(raw_descriptor());
+        assert_eq!(u16::from(d.usb_version()), d.0.bcdUSB);
+        assert_eq!(u16::from(d.device_version()), d.0.bcdDevice);
+        assert_eq!(d.max_packet_size_ep0(), d.0.bMaxPacketSize0);
+        assert_eq!(d.num_configurations(), d.0.bNumConfigurations);
+    }
+}
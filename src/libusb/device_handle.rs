@@ -1,22 +1,45 @@
+use crate::libusb::blocking::BlockingDevice;
+use crate::libusb::bos_descriptor::BosDescriptor;
+use crate::libusb::context::{Context, ContextInner};
+use crate::libusb::control::{control_len, ControlSetup, Direction};
 use crate::libusb::device::Device;
+use crate::libusb::dma::DevMem;
 use crate::libusb::error;
-use crate::libusb::error::Error;
+use crate::libusb::error::{Error, ErrorContext, Operation};
+use crate::libusb::interface_guard::{DetachedDriverGuard, InterfaceGuard};
 use crate::libusb::interfaces::ClaimedInterfaces;
+use crate::libusb::string_descriptor;
+use crate::libusb::string_descriptor::Language;
+use crate::libusb::transfer::{timeout_millis, transfer_len};
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+/// Timeouts of zero (`transfer::NO_TIMEOUT`) wait forever, see `transfer::timeout_millis`.
 #[derive(Debug)]
 pub struct DeviceHandle {
     handle: core::ptr::NonNull<libusb1_sys::libusb_device_handle>,
     interfaces: ClaimedInterfaces,
+    /// Interfaces whose kernel driver was detached with `detach_kernel_driver`. Drivers libusb
+    /// detached itself (auto detach) aren't in here, libusb reattaches those on release.
+    detached: ClaimedInterfaces,
+    auto_detach: AtomicBool,
+    /// Keeps the context alive. `None` for handles made with `from_libusb`.
+    context: Option<Arc<ContextInner>>,
 }
 unsafe impl Send for DeviceHandle {}
 unsafe impl Sync for DeviceHandle {}
+/// Best effort. Releases the claimed interfaces, then reattaches the kernel drivers detached with
+/// `detach_kernel_driver`. Errors are ignored, use `DeviceHandle::close_checked` to see them.
 impl Drop for DeviceHandle {
     fn drop(&mut self) {
         unsafe {
-            while let Some(i) = self.interfaces.next() {
+            for i in self.interfaces.iter() {
                 libusb1_sys::libusb_release_interface(self.handle.as_ptr(), i.into());
             }
+            for i in self.detached.iter() {
+                libusb1_sys::libusb_attach_kernel_driver(self.handle.as_ptr(), i.into());
+            }
             libusb1_sys::libusb_close(self.handle.as_ptr())
         }
     }
@@ -27,12 +50,31 @@ impl DeviceHandle {
         unsafe {
             let ptr = libusb1_sys::libusb_get_device(self.handle.as_ptr());
             libusb1_sys::libusb_ref_device(ptr);
-            Device::from_libusb(core::ptr::NonNull::new_unchecked(ptr))
+            Device::from_libusb_in(core::ptr::NonNull::new_unchecked(ptr), self.context.clone())
         }
     }
     pub fn inner(&self) -> core::ptr::NonNull<libusb1_sys::libusb_device_handle> {
         self.handle
     }
+    /// Allocates device memory for zero-copy transfers (see `DevMem::new`).
+    pub fn alloc_dev_mem(&self, len: usize) -> Result<DevMem<'_>, Error> {
+        DevMem::new(self, len)
+    }
+    /// Reads the Binary Object Store descriptor. Only USB 2.01+ devices have one.
+    pub fn bos_descriptor(&self) -> Result<BosDescriptor, Error> {
+        let mut out: *const libusb1_sys::libusb_bos_descriptor = core::ptr::null_mut();
+        try_unsafe!(libusb1_sys::libusb_get_bos_descriptor(
+            self.handle.as_ptr(),
+            &mut out as *mut _
+        ));
+        Ok(unsafe { BosDescriptor::from_libusb(core::ptr::NonNull::new_unchecked(out as *mut _)) })
+    }
+    pub(crate) fn interfaces_ref(&self) -> &ClaimedInterfaces {
+        &self.interfaces
+    }
+    pub(crate) fn interfaces_mut(&mut self) -> &mut ClaimedInterfaces {
+        &mut self.interfaces
+    }
 
     /// Returns the active configuration number.
     pub fn active_configuration(&self) -> Result<u8, Error> {
@@ -45,20 +87,30 @@ impl DeviceHandle {
     }
 
     /// Sets the device's active configuration.
-    pub fn set_active_configuration(&mut self, config: u8) -> Result<(), Error> {
-        try_unsafe!(libusb1_sys::libusb_set_configuration(
-            self.handle.as_ptr(),
-            config.into()
-        ));
-        Ok(())
+    pub fn set_active_configuration(&mut self, config: u8) -> Result<(), ErrorContext> {
+        match unsafe { libusb1_sys::libusb_set_configuration(self.handle.as_ptr(), config.into()) }
+        {
+            0 => Ok(()),
+            err => Err(self.device().error_context(
+                error::from_libusb(err),
+                Operation::SetActiveConfiguration(config),
+            )),
+        }
     }
+    /// With auto detach libusb detaches the kernel driver when an interface is claimed and
+    /// reattaches it when it's released. Drivers detached with `detach_kernel_driver` are still
+    /// reattached when the handle is dropped.
     pub fn set_auto_detach_kernel_driver(&self, enabled: bool) -> Result<(), Error> {
         try_unsafe!(libusb1_sys::libusb_set_auto_detach_kernel_driver(
             self.handle.as_ptr(),
             enabled.into()
         ));
+        self.auto_detach.store(enabled, Ordering::SeqCst);
         Ok(())
     }
+    pub fn auto_detach_kernel_driver(&self) -> bool {
+        self.auto_detach.load(Ordering::SeqCst)
+    }
     pub fn control_read(
         &self,
         request_type: u8,
@@ -73,6 +125,7 @@ impl DeviceHandle {
         {
             return Err(Error::InvalidParam);
         }
+        let len = control_len(data.len())?;
         let res = unsafe {
             libusb1_sys::libusb_control_transfer(
                 self.handle.as_ptr(),
@@ -81,13 +134,8 @@ impl DeviceHandle {
                 value,
                 index,
                 data.as_mut_ptr(),
-                data.len()
-                    .try_into()
-                    .expect("libusb control transfer len overflow"),
-                timeout
-                    .as_millis()
-                    .try_into()
-                    .expect("libusb control transfer timeout overflow"),
+                len,
+                timeout_millis(timeout),
             )
         };
         if res < 0 {
@@ -97,6 +145,107 @@ impl DeviceHandle {
         }
     }
 
+    /// Vendor request to the device with an IN data stage.
+    pub fn vendor_read(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::vendor(
+            Direction::In,
+            request,
+            value,
+            index,
+            control_len(data.len())?,
+        );
+        self.control_read(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+    }
+    /// Vendor request to the device with an OUT data stage (`data` can be empty).
+    pub fn vendor_write(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::vendor(
+            Direction::Out,
+            request,
+            value,
+            index,
+            control_len(data.len())?,
+        );
+        self.control_write(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+    }
+    /// Class request to `interface` with an IN data stage.
+    pub fn class_read(
+        &self,
+        request: u8,
+        value: u16,
+        interface: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::class_interface(
+            Direction::In,
+            request,
+            value,
+            interface,
+            control_len(data.len())?,
+        );
+        self.control_read(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+    }
+    /// Class request to `interface` with an OUT data stage (`data` can be empty).
+    pub fn class_write(
+        &self,
+        request: u8,
+        value: u16,
+        interface: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let setup = ControlSetup::class_interface(
+            Direction::Out,
+            request,
+            value,
+            interface,
+            control_len(data.len())?,
+        );
+        self.control_write(
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+    }
+    /// Copies `data` into a temporary buffer, see `control_write_mut`.
     pub fn control_write(
         &self,
         request_type: u8,
@@ -105,12 +254,33 @@ impl DeviceHandle {
         index: u16,
         data: &[u8],
         timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.control_write_mut(
+            request_type,
+            request,
+            value,
+            index,
+            &mut data.to_vec(),
+            timeout,
+        )
+    }
+    /// Writes straight from `data`. libusb takes a mutable pointer even for OUT transfers (it
+    /// doesn't write to them) so `data` has to be mutable.
+    pub fn control_write_mut(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: core::time::Duration,
     ) -> Result<usize, Error> {
         if request_type & libusb1_sys::constants::LIBUSB_ENDPOINT_DIR_MASK
             != libusb1_sys::constants::LIBUSB_ENDPOINT_OUT
         {
             return Err(Error::InvalidParam);
         }
+        let len = control_len(data.len())?;
         let res = unsafe {
             libusb1_sys::libusb_control_transfer(
                 self.handle.as_ptr(),
@@ -118,14 +288,9 @@ impl DeviceHandle {
                 request,
                 value,
                 index,
-                data.as_ptr() as *mut u8,
-                data.len()
-                    .try_into()
-                    .expect("libusb control transfer len overflow"),
-                timeout
-                    .as_millis()
-                    .try_into()
-                    .expect("libusb control transfer timeout overflow"),
+                data.as_mut_ptr(),
+                len,
+                timeout_millis(timeout),
             )
         };
         if res < 0 {
@@ -135,26 +300,38 @@ impl DeviceHandle {
         }
     }
 
+    /// Copies `data` into a temporary buffer, see `bulk_write_mut`.
     pub fn bulk_write(
         &self,
         endpoint: u8,
         data: &[u8],
         timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.bulk_write_mut(endpoint, &mut data.to_vec(), timeout)
+    }
+    /// Writes straight from `data`. libusb takes a mutable pointer even for OUT transfers (it
+    /// doesn't write to them) so `data` has to be mutable.
+    pub fn bulk_write_mut(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
     ) -> Result<usize, Error> {
         if endpoint & libusb1_sys::constants::LIBUSB_ENDPOINT_DIR_MASK
             != libusb1_sys::constants::LIBUSB_ENDPOINT_OUT
         {
             return Err(Error::InvalidParam);
         }
+        let len = transfer_len(data.len())?;
         let mut transferred = 0;
         unsafe {
             match libusb1_sys::libusb_bulk_transfer(
                 self.handle.as_ptr(),
                 endpoint,
-                data.as_ptr() as *mut u8,
-                data.len() as i32,
+                data.as_mut_ptr(),
+                len,
                 &mut transferred as *mut i32,
-                timeout.as_millis() as u32,
+                timeout_millis(timeout),
             ) {
                 0 => Ok(transferred as usize),
                 err if err == libusb1_sys::constants::LIBUSB_ERROR_INTERRUPTED
@@ -171,6 +348,12 @@ impl DeviceHandle {
         }
     }
 
+    /// Blocking transfers that handle `context`'s events on the calling thread and can be
+    /// cancelled, see `blocking::BlockingDevice`. `context` has to be the handle's context.
+    pub fn blocking<'a>(&'a self, context: &'a Context) -> BlockingDevice<'a> {
+        BlockingDevice::new(context, self)
+    }
+
     pub fn bulk_read(
         &self,
         endpoint: u8,
@@ -182,15 +365,16 @@ impl DeviceHandle {
         {
             return Err(Error::InvalidParam);
         }
+        let len = transfer_len(data.len())?;
         let mut transferred = 0;
         unsafe {
             match libusb1_sys::libusb_bulk_transfer(
                 self.handle.as_ptr(),
                 endpoint,
                 data.as_mut_ptr(),
-                data.len() as i32,
+                len,
                 &mut transferred as *mut i32,
-                timeout.as_millis() as u32,
+                timeout_millis(timeout),
             ) {
                 0 => Ok(transferred as usize),
                 err if err == libusb1_sys::constants::LIBUSB_ERROR_INTERRUPTED
@@ -206,26 +390,38 @@ impl DeviceHandle {
             }
         }
     }
+    /// Copies `data` into a temporary buffer, see `interrupt_write_mut`.
     pub fn interrupt_write(
         &self,
         endpoint: u8,
         data: &[u8],
         timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.interrupt_write_mut(endpoint, &mut data.to_vec(), timeout)
+    }
+    /// Writes straight from `data`. libusb takes a mutable pointer even for OUT transfers (it
+    /// doesn't write to them) so `data` has to be mutable.
+    pub fn interrupt_write_mut(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
     ) -> Result<usize, Error> {
         if endpoint & libusb1_sys::constants::LIBUSB_ENDPOINT_DIR_MASK
             != libusb1_sys::constants::LIBUSB_ENDPOINT_OUT
         {
             return Err(Error::InvalidParam);
         }
+        let len = transfer_len(data.len())?;
         let mut transferred = 0;
         unsafe {
             match libusb1_sys::libusb_interrupt_transfer(
                 self.handle.as_ptr(),
                 endpoint,
-                data.as_ptr() as *mut u8,
-                data.len() as i32,
+                data.as_mut_ptr(),
+                len,
                 &mut transferred as *mut i32,
-                timeout.as_millis() as u32,
+                timeout_millis(timeout),
             ) {
                 0 => Ok(transferred as usize),
                 err if err == libusb1_sys::constants::LIBUSB_ERROR_INTERRUPTED => {
@@ -250,15 +446,16 @@ impl DeviceHandle {
         {
             return Err(Error::InvalidParam);
         }
+        let len = transfer_len(data.len())?;
         let mut transferred = 0;
         unsafe {
             match libusb1_sys::libusb_interrupt_transfer(
                 self.handle.as_ptr(),
                 endpoint,
                 data.as_mut_ptr(),
-                data.len() as i32,
+                len,
                 &mut transferred as *mut i32,
-                timeout.as_millis() as u32,
+                timeout_millis(timeout),
             ) {
                 0 => Ok(transferred as usize),
                 err if err == libusb1_sys::constants::LIBUSB_ERROR_INTERRUPTED => {
@@ -272,17 +469,35 @@ impl DeviceHandle {
             }
         }
     }
-    pub fn claim_interface(&mut self, interface: u8) -> Result<(), Error> {
+    pub fn claim_interface(&mut self, interface: u8) -> Result<(), ErrorContext> {
         if self.interfaces.is_claimed(interface) {
             return Ok(());
         }
-        try_unsafe!(libusb1_sys::libusb_claim_interface(
-            self.handle.as_ptr(),
-            interface.into()
-        ));
+        match unsafe { libusb1_sys::libusb_claim_interface(self.handle.as_ptr(), interface.into()) }
+        {
+            0 => (),
+            err => {
+                return Err(self.device().error_context(
+                    error::from_libusb(err),
+                    Operation::ClaimInterface(interface),
+                ))
+            }
+        }
         self.interfaces.claim(interface);
         Ok(())
     }
+    /// Claims `interface` and releases it when the guard is dropped.
+    pub fn claim_interface_guard(&mut self, interface: u8) -> Result<InterfaceGuard<'_>, Error> {
+        InterfaceGuard::claim(self, interface, false)
+    }
+    /// Like `claim_interface_guard` but detaches the kernel driver first (if one is bound) and
+    /// reattaches it when the guard is dropped.
+    pub fn claim_interface_guard_detached(
+        &mut self,
+        interface: u8,
+    ) -> Result<InterfaceGuard<'_>, Error> {
+        InterfaceGuard::claim(self, interface, true)
+    }
     pub fn release_interface(&mut self, interface: u8) -> Result<(), Error> {
         if !self.interfaces.is_claimed(interface) {
             return Ok(());
@@ -294,10 +509,108 @@ impl DeviceHandle {
         self.interfaces.release(interface);
         Ok(())
     }
+    /// Activates an alternate setting of a claimed interface. Returns `Error::NotFound` if
+    /// `interface` isn't claimed.
+    pub fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> Result<(), Error> {
+        if !self.interfaces.is_claimed(interface) {
+            return Err(Error::NotFound);
+        }
+        try_unsafe!(libusb1_sys::libusb_set_interface_alt_setting(
+            self.handle.as_ptr(),
+            interface.into(),
+            setting.into()
+        ));
+        Ok(())
+    }
+    /// Clears the halt/stall condition of `endpoint` (and resets its data toggle).
+    pub fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        try_unsafe!(libusb1_sys::libusb_clear_halt(
+            self.handle.as_ptr(),
+            endpoint
+        ));
+        Ok(())
+    }
+    /// Returns if a kernel driver is bound to `interface`.
+    pub fn kernel_driver_active(&self, interface: u8) -> Result<bool, Error> {
+        match unsafe {
+            libusb1_sys::libusb_kernel_driver_active(self.handle.as_ptr(), interface.into())
+        } {
+            0 => Ok(false),
+            1 => Ok(true),
+            err => Err(error::from_libusb(err)),
+        }
+    }
+    /// The driver is reattached when the handle is dropped (unless `attach_kernel_driver` is
+    /// called first).
+    pub fn detach_kernel_driver(&mut self, interface: u8) -> Result<(), Error> {
+        try_unsafe!(libusb1_sys::libusb_detach_kernel_driver(
+            self.handle.as_ptr(),
+            interface.into()
+        ));
+        self.detached.claim(interface);
+        Ok(())
+    }
+    pub fn attach_kernel_driver(&mut self, interface: u8) -> Result<(), Error> {
+        try_unsafe!(libusb1_sys::libusb_attach_kernel_driver(
+            self.handle.as_ptr(),
+            interface.into()
+        ));
+        self.detached.release(interface);
+        Ok(())
+    }
+    /// Detaches the kernel driver of `interface` (if one is bound) and reattaches it when the
+    /// guard is dropped. With `allow_unsupported`, platforms without kernel drivers to detach
+    /// (`Error::NotSupported`) give a guard that does nothing.
+    pub fn detach_kernel_driver_guard(
+        &mut self,
+        interface: u8,
+        allow_unsupported: bool,
+    ) -> Result<DetachedDriverGuard<'_>, Error> {
+        DetachedDriverGuard::detach(self, interface, allow_unsupported)
+    }
+    /// Interfaces with a kernel driver detached by `detach_kernel_driver` in ascending order.
+    pub fn detached_interfaces(&self) -> impl Iterator<Item = u8> + '_ {
+        self.detached.iter()
+    }
+    /// Allocates up to `num_streams` USB 3.0 bulk streams on each of the `endpoints`. Returns the
+    /// number of streams actually allocated (stream ids start at 1).
+    pub fn alloc_streams(&mut self, num_streams: u32, endpoints: &[u8]) -> Result<u32, Error> {
+        let mut endpoints = endpoints.to_vec();
+        let res = unsafe {
+            libusb1_sys::libusb_alloc_streams(
+                self.handle.as_ptr(),
+                num_streams,
+                endpoints.as_mut_ptr(),
+                endpoints
+                    .len()
+                    .try_into()
+                    .map_err(|_| Error::InvalidParam)?,
+            )
+        };
+        if res < 0 {
+            Err(error::from_libusb(res))
+        } else {
+            Ok(res as u32)
+        }
+    }
+    /// Frees the bulk streams previously allocated on `endpoints` with `alloc_streams`.
+    pub fn free_streams(&mut self, endpoints: &[u8]) -> Result<(), Error> {
+        let mut endpoints = endpoints.to_vec();
+        let len = endpoints
+            .len()
+            .try_into()
+            .map_err(|_| Error::InvalidParam)?;
+        try_unsafe!(libusb1_sys::libusb_free_streams(
+            self.handle.as_ptr(),
+            endpoints.as_mut_ptr(),
+            len
+        ));
+        Ok(())
+    }
     pub fn read_string_descriptor_ascii(&self, index: u8) -> Result<String, Error> {
         let mut out = Vec::<u8>::with_capacity(255);
 
-        let ptr = out.as_mut_ptr() as *mut u8;
+        let ptr = out.as_mut_ptr();
         let capacity = out.capacity() as i32;
 
         let res = unsafe {
@@ -319,6 +632,36 @@ impl DeviceHandle {
 
         String::from_utf8(out).map_err(|_| Error::Other)
     }
+    /// Reads the whole string descriptor `index` (header included) in `langid`.
+    fn read_string_descriptor_raw(&self, langid: u16, index: u8) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0_u8; 255];
+        let res = unsafe {
+            libusb1_sys::libusb_get_string_descriptor(
+                self.handle.as_ptr(),
+                index,
+                langid,
+                out.as_mut_ptr(),
+                out.len() as i32,
+            )
+        };
+        if res < 0 {
+            return Err(error::from_libusb(res));
+        }
+        out.truncate(res as usize);
+        Ok(out)
+    }
+    /// Returns the languages the device's strings are available in (string descriptor 0).
+    pub fn read_languages(&self) -> Result<Vec<Language>, Error> {
+        string_descriptor::parse_languages(&self.read_string_descriptor_raw(0, 0)?)
+    }
+    /// Reads and UTF-16 decodes string descriptor `index`. Unlike
+    /// `read_string_descriptor_ascii`, non-ASCII characters are kept.
+    pub fn read_string_descriptor(&self, langid: u16, index: u8) -> Result<String, Error> {
+        if index == 0 {
+            return Err(Error::InvalidParam);
+        }
+        string_descriptor::parse_string(&self.read_string_descriptor_raw(langid, index)?)
+    }
     /// # Safety
     /// Assumes the handle is valid.
     pub const unsafe fn from_libusb(
@@ -327,13 +670,71 @@ impl DeviceHandle {
         DeviceHandle {
             handle: ptr,
             interfaces: ClaimedInterfaces::DEFAULT,
+            detached: ClaimedInterfaces::DEFAULT,
+            auto_detach: AtomicBool::new(false),
+            context: None,
         }
     }
+    pub(crate) fn with_context(mut self, context: Arc<ContextInner>) -> DeviceHandle {
+        self.context = Some(context);
+        self
+    }
+    /// The context the handle was opened in, `None` if it was made with `from_libusb`.
+    pub(crate) fn context_inner(&self) -> Option<&Arc<ContextInner>> {
+        self.context.as_ref()
+    }
     pub fn close(self) {
         drop(self)
     }
+    /// Releases every claimed interface and then closes the handle. If a release fails (for
+    /// example the device is gone), the handle is given back with the interfaces that are still
+    /// claimed.
+    pub fn close_checked(mut self) -> Result<(), (DeviceHandle, Error)> {
+        while let Some(interface) = self.interfaces.iter().next() {
+            if let Err(e) = self.release_interface(interface) {
+                return Err((self, e));
+            }
+        }
+        drop(self);
+        Ok(())
+    }
+    /// Interfaces claimed through this handle in ascending order.
+    pub fn claimed_interfaces(&self) -> impl Iterator<Item = u8> + '_ {
+        self.interfaces.iter()
+    }
     pub fn reset(&self) -> Result<(), Error> {
         try_unsafe!(libusb1_sys::libusb_reset_device(self.handle.as_ptr()));
         Ok(())
     }
 }
+#[cfg(test)]
+mod tests {
+    use crate::libusb::device_handle::DeviceHandle;
+    use crate::libusb::error::Error;
+    use core::time::Duration;
+
+    #[test]
+    pub fn test_alternate_setting_unclaimed() {
+        // The handle is never passed to libusb because the interface isn't claimed.
+        let mut handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        assert_eq!(handle.set_alternate_setting(0, 1), Err(Error::NotFound));
+        assert_eq!(handle.set_alternate_setting(255, 0), Err(Error::NotFound));
+        core::mem::forget(handle);
+    }
+    #[test]
+    pub fn test_control_oversized() {
+        // The length is checked before the handle is passed to libusb.
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let mut data = vec![0_u8; usize::from(u16::MAX) + 1];
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            handle.control_read(0x80, 0x06, 0, 0, &mut data, timeout),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            handle.control_write(0x00, 0x09, 0, 0, &data, timeout),
+            Err(Error::InvalidParam)
+        );
+        core::mem::forget(handle);
+    }
+}
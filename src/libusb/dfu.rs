@@ -0,0 +1,548 @@
+//! USB Device Firmware Upgrade (DFU 1.1) class requests and the download state machine.
+use crate::descriptors::parse_raw_descriptors;
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::control::{control_len, Direction};
+use crate::libusb::error::Error;
+use crate::libusb::runtime;
+use crate::libusb::transfer::ControlSetup;
+use core::fmt;
+use core::time::Duration;
+
+pub const DFU_CLASS: u8 = 0xFE;
+pub const DFU_SUBCLASS: u8 = 0x01;
+/// `bDescriptorType` of the DFU functional descriptor.
+pub const FUNCTIONAL_DESCRIPTOR_TYPE: u8 = 0x21;
+
+/// Class requests of a DFU interface.
+pub mod request {
+    pub const DETACH: u8 = 0x00;
+    pub const DNLOAD: u8 = 0x01;
+    pub const UPLOAD: u8 = 0x02;
+    pub const GETSTATUS: u8 = 0x03;
+    pub const CLRSTATUS: u8 = 0x04;
+    pub const GETSTATE: u8 = 0x05;
+    pub const ABORT: u8 = 0x06;
+}
+
+/// The DFU functional descriptor from the interface's extra descriptors.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct FunctionalDescriptor {
+    pub attributes: u8,
+    /// Milliseconds the device waits for a reset after `DETACH`.
+    pub detach_timeout: u16,
+    /// Most bytes per `DNLOAD`/`UPLOAD`.
+    pub transfer_size: u16,
+    /// `bcdDFUVersion`, 0 if the descriptor is the short DFU 1.0 one.
+    pub dfu_version: u16,
+}
+impl FunctionalDescriptor {
+    pub fn can_download(&self) -> bool {
+        self.attributes & 0x01 != 0
+    }
+    pub fn can_upload(&self) -> bool {
+        self.attributes & 0x02 != 0
+    }
+    /// The device still answers after manifestation (else it waits for a reset).
+    pub fn manifestation_tolerant(&self) -> bool {
+        self.attributes & 0x04 != 0
+    }
+    /// The device detaches by itself after `DETACH` (else the host resets it).
+    pub fn will_detach(&self) -> bool {
+        self.attributes & 0x08 != 0
+    }
+    /// Finds the functional descriptor in an interface's `extra` bytes.
+    pub fn from_extra(extra: &[u8]) -> Result<FunctionalDescriptor, Error> {
+        let descriptor = parse_raw_descriptors(extra)
+            .map_err(|_| Error::BadDescriptor)?
+            .into_iter()
+            .find(|d| d.descriptor_type == FUNCTIONAL_DESCRIPTOR_TYPE)
+            .ok_or(Error::NotFound)?;
+        match descriptor.data.as_slice() {
+            [attributes, detach_low, detach_high, size_low, size_high, rest @ ..] => {
+                Ok(FunctionalDescriptor {
+                    attributes: *attributes,
+                    detach_timeout: u16::from_le_bytes([*detach_low, *detach_high]),
+                    transfer_size: u16::from_le_bytes([*size_low, *size_high]),
+                    dfu_version: match rest {
+                        [low, high, ..] => u16::from_le_bytes([*low, *high]),
+                        _ => 0,
+                    },
+                })
+            }
+            _ => Err(Error::BadDescriptor),
+        }
+    }
+}
+
+/// `bStatus` of `GETSTATUS`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum StatusCode {
+    Ok = 0x00,
+    Target = 0x01,
+    File = 0x02,
+    Write = 0x03,
+    Erase = 0x04,
+    CheckErased = 0x05,
+    Prog = 0x06,
+    Verify = 0x07,
+    Address = 0x08,
+    NotDone = 0x09,
+    Firmware = 0x0A,
+    Vendor = 0x0B,
+    UsbReset = 0x0C,
+    PowerOnReset = 0x0D,
+    Unknown = 0x0E,
+    StalledPacket = 0x0F,
+}
+impl StatusCode {
+    pub fn from_u8(status: u8) -> Option<StatusCode> {
+        Some(match status {
+            0x00 => StatusCode::Ok,
+            0x01 => StatusCode::Target,
+            0x02 => StatusCode::File,
+            0x03 => StatusCode::Write,
+            0x04 => StatusCode::Erase,
+            0x05 => StatusCode::CheckErased,
+            0x06 => StatusCode::Prog,
+            0x07 => StatusCode::Verify,
+            0x08 => StatusCode::Address,
+            0x09 => StatusCode::NotDone,
+            0x0A => StatusCode::Firmware,
+            0x0B => StatusCode::Vendor,
+            0x0C => StatusCode::UsbReset,
+            0x0D => StatusCode::PowerOnReset,
+            0x0E => StatusCode::Unknown,
+            0x0F => StatusCode::StalledPacket,
+            _ => return None,
+        })
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StatusCode::Ok => "no error",
+            StatusCode::Target => "file is not targeted for this device",
+            StatusCode::File => "file failed a vendor check",
+            StatusCode::Write => "unable to write memory",
+            StatusCode::Erase => "memory erase failed",
+            StatusCode::CheckErased => "memory erase check failed",
+            StatusCode::Prog => "program memory failed",
+            StatusCode::Verify => "programmed memory failed verification",
+            StatusCode::Address => "address out of range",
+            StatusCode::NotDone => "download ended before it was complete",
+            StatusCode::Firmware => "firmware is corrupt",
+            StatusCode::Vendor => "vendor specific error",
+            StatusCode::UsbReset => "unexpected USB reset",
+            StatusCode::PowerOnReset => "unexpected power on reset",
+            StatusCode::Unknown => "unknown error",
+            StatusCode::StalledPacket => "unexpected request",
+        }
+    }
+}
+/// `bState` of `GETSTATUS`/`GETSTATE`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum State {
+    AppIdle = 0,
+    AppDetach = 1,
+    Idle = 2,
+    DownloadSync = 3,
+    DownloadBusy = 4,
+    DownloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+impl State {
+    pub fn from_u8(state: u8) -> Option<State> {
+        Some(match state {
+            0 => State::AppIdle,
+            1 => State::AppDetach,
+            2 => State::Idle,
+            3 => State::DownloadSync,
+            4 => State::DownloadBusy,
+            5 => State::DownloadIdle,
+            6 => State::ManifestSync,
+            7 => State::Manifest,
+            8 => State::ManifestWaitReset,
+            9 => State::UploadIdle,
+            10 => State::Error,
+            _ => return None,
+        })
+    }
+}
+/// Reply to `GETSTATUS`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct DfuStatus {
+    pub status: StatusCode,
+    /// How long to wait before the next `GETSTATUS`.
+    pub poll_timeout: Duration,
+    pub state: State,
+    pub string_index: Option<u8>,
+}
+impl DfuStatus {
+    pub const SIZE: usize = 6;
+    pub fn from_bytes(bytes: &[u8]) -> Result<DfuStatus, Error> {
+        match bytes {
+            [status, t0, t1, t2, state, string, ..] => Ok(DfuStatus {
+                status: StatusCode::from_u8(*status).ok_or(Error::BadDescriptor)?,
+                poll_timeout: Duration::from_millis(u32::from_le_bytes([*t0, *t1, *t2, 0]).into()),
+                state: State::from_u8(*state).ok_or(Error::BadDescriptor)?,
+                string_index: match *string {
+                    0 => None,
+                    n => Some(n),
+                },
+            }),
+            _ => Err(Error::Overflow),
+        }
+    }
+    /// `Err` with the status if it isn't `StatusCode::Ok`.
+    pub fn check(self) -> Result<DfuStatus, DfuError> {
+        match self.status {
+            StatusCode::Ok => Ok(self),
+            status => Err(DfuError::Status {
+                status,
+                state: self.state,
+            }),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DfuError {
+    Usb(Error),
+    /// The device reported an error status.
+    Status {
+        status: StatusCode,
+        state: State,
+    },
+    /// The device went into a state the state machine doesn't expect.
+    UnexpectedState(State),
+    /// The functional descriptor doesn't allow the operation (download or upload).
+    NotSupported,
+}
+impl From<Error> for DfuError {
+    fn from(e: Error) -> Self {
+        DfuError::Usb(e)
+    }
+}
+impl fmt::Display for DfuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DfuError::Usb(e) => fmt::Display::fmt(e, f),
+            DfuError::Status { status, state } => {
+                write!(
+                    f,
+                    "DFU error {:?} ({}) in {:?}",
+                    status,
+                    status.as_str(),
+                    state
+                )
+            }
+            DfuError::UnexpectedState(state) => write!(f, "unexpected DFU state {:?}", state),
+            DfuError::NotSupported => f.write_str("operation not supported by the DFU interface"),
+        }
+    }
+}
+impl std::error::Error for DfuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DfuError::Usb(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What to do after a `GETSTATUS` while downloading.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Poll {
+    /// Wait and ask again.
+    Wait(Duration),
+    Done,
+}
+/// Next step after a `GETSTATUS` following a block `DNLOAD`.
+pub fn download_poll(status: DfuStatus) -> Result<Poll, DfuError> {
+    match status.check()?.state {
+        State::DownloadSync | State::DownloadBusy => Ok(Poll::Wait(status.poll_timeout)),
+        State::DownloadIdle => Ok(Poll::Done),
+        state => Err(DfuError::UnexpectedState(state)),
+    }
+}
+/// Next step after a `GETSTATUS` following the final zero length `DNLOAD`.
+pub fn manifest_poll(status: DfuStatus) -> Result<Poll, DfuError> {
+    match status.check()?.state {
+        State::ManifestSync | State::Manifest => Ok(Poll::Wait(status.poll_timeout)),
+        State::Idle | State::ManifestWaitReset => Ok(Poll::Done),
+        state => Err(DfuError::UnexpectedState(state)),
+    }
+}
+pub fn detach_setup(interface: u8, timeout_ms: u16) -> ControlSetup {
+    ControlSetup::class_interface(Direction::Out, request::DETACH, timeout_ms, interface, 0)
+}
+pub fn download_setup(interface: u8, block_num: u16, len: u16) -> ControlSetup {
+    ControlSetup::class_interface(Direction::Out, request::DNLOAD, block_num, interface, len)
+}
+pub fn upload_setup(interface: u8, block_num: u16, len: u16) -> ControlSetup {
+    ControlSetup::class_interface(Direction::In, request::UPLOAD, block_num, interface, len)
+}
+pub fn get_status_setup(interface: u8) -> ControlSetup {
+    ControlSetup::class_interface(
+        Direction::In,
+        request::GETSTATUS,
+        0,
+        interface,
+        DfuStatus::SIZE as u16,
+    )
+}
+
+async fn sleep(duration: Duration) {
+    if duration > Duration::from_secs(0) {
+        runtime::sleep(duration).await
+    }
+}
+
+/// A claimed DFU interface (run time or DFU mode). Requests use the `AsyncDevice` default
+/// timeout.
+pub struct DfuDevice {
+    device: AsyncDevice,
+    interface: u8,
+    functional: FunctionalDescriptor,
+}
+impl DfuDevice {
+    /// Claims `interface` and reads its functional descriptor.
+    pub fn open(mut device: AsyncDevice, interface: u8) -> Result<DfuDevice, Error> {
+        let config = device.device().active_config_descriptor()?;
+        let descriptor = config
+            .find_interface(|d| {
+                d.interface_number() == interface
+                    && (d.class_code(), d.sub_class_code()) == (DFU_CLASS, DFU_SUBCLASS)
+            })
+            .ok_or(Error::NotFound)?;
+        let functional = FunctionalDescriptor::from_extra(descriptor.extra().unwrap_or(&[]))?;
+        device.handle_mut().claim_interface(interface)?;
+        Ok(DfuDevice {
+            device,
+            interface,
+            functional,
+        })
+    }
+    pub fn functional_descriptor(&self) -> &FunctionalDescriptor {
+        &self.functional
+    }
+    pub fn device(&self) -> &AsyncDevice {
+        &self.device
+    }
+    pub fn into_device(self) -> AsyncDevice {
+        self.device
+    }
+    async fn control_out(&self, setup: ControlSetup, data: &[u8]) -> Result<usize, Error> {
+        self.device
+            .control_write(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                data,
+                self.device.default_timeout(),
+            )
+            .await
+    }
+    async fn control_in(&self, setup: ControlSetup, buf: &mut [u8]) -> Result<usize, Error> {
+        self.device
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                buf,
+                self.device.default_timeout(),
+            )
+            .await
+    }
+    /// Asks a run time interface to switch to DFU mode. The device is reset afterwards unless it
+    /// detaches by itself (`will_detach`), either way it comes back as a new device.
+    pub async fn detach(&self) -> Result<(), Error> {
+        let setup = detach_setup(self.interface, self.functional.detach_timeout);
+        self.control_out(setup, &[]).await?;
+        if !self.functional.will_detach() {
+            match self.device.handle_ref().reset() {
+                // Already gone to re-enumerate
+                Ok(()) | Err(Error::NotFound) | Err(Error::NoDevice) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    /// `DNLOAD` of one block. `data` can't be longer than `transfer_size`, an empty block ends
+    /// the download.
+    pub async fn download(&self, block_num: u16, data: &[u8]) -> Result<(), Error> {
+        if data.len() > usize::from(self.functional.transfer_size) {
+            return Err(Error::InvalidParam);
+        }
+        let setup = download_setup(self.interface, block_num, control_len(data.len())?);
+        self.control_out(setup, data).await?;
+        Ok(())
+    }
+    /// `UPLOAD` of one block into `buf`. A short block is the last one.
+    pub async fn upload(&self, block_num: u16, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(usize::from(self.functional.transfer_size));
+        let setup = upload_setup(self.interface, block_num, control_len(len)?);
+        self.control_in(setup, &mut buf[..len]).await
+    }
+    pub async fn get_status(&self) -> Result<DfuStatus, Error> {
+        let mut buf = [0_u8; DfuStatus::SIZE];
+        let len = self
+            .control_in(get_status_setup(self.interface), &mut buf)
+            .await?;
+        DfuStatus::from_bytes(&buf[..len])
+    }
+    pub async fn get_state(&self) -> Result<State, Error> {
+        let setup =
+            ControlSetup::class_interface(Direction::In, request::GETSTATE, 0, self.interface, 1);
+        let mut buf = [0_u8; 1];
+        match self.control_in(setup, &mut buf).await? {
+            1 => State::from_u8(buf[0]).ok_or(Error::BadDescriptor),
+            _ => Err(Error::Overflow),
+        }
+    }
+    /// Leaves `State::Error`.
+    pub async fn clr_status(&self) -> Result<(), Error> {
+        let setup =
+            ControlSetup::class_interface(Direction::Out, request::CLRSTATUS, 0, self.interface, 0);
+        self.control_out(setup, &[]).await?;
+        Ok(())
+    }
+    /// Back to `State::Idle` from the download/upload idle states.
+    pub async fn abort(&self) -> Result<(), Error> {
+        let setup =
+            ControlSetup::class_interface(Direction::Out, request::ABORT, 0, self.interface, 0);
+        self.control_out(setup, &[]).await?;
+        Ok(())
+    }
+    /// Gets the device to `State::Idle`, clearing an error and aborting a transfer.
+    async fn make_idle(&self) -> Result<(), DfuError> {
+        let status = self.get_status().await?;
+        match status.state {
+            State::Idle => return Ok(()),
+            State::Error => self.clr_status().await?,
+            State::DownloadIdle | State::UploadIdle => self.abort().await?,
+            state => return Err(DfuError::UnexpectedState(state)),
+        }
+        match self.get_status().await?.check()?.state {
+            State::Idle => Ok(()),
+            state => Err(DfuError::UnexpectedState(state)),
+        }
+    }
+    /// Downloads `firmware` in `transfer_size` blocks, waiting out each block with `GETSTATUS`
+    /// polls, then manifests it. `progress` gets the bytes sent and the total after each block.
+    pub async fn flash(
+        &self,
+        firmware: &[u8],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), DfuError> {
+        if !self.functional.can_download() || self.functional.transfer_size == 0 {
+            return Err(DfuError::NotSupported);
+        }
+        self.make_idle().await?;
+        let mut block_num: u16 = 0;
+        let mut sent = 0;
+        for block in firmware.chunks(usize::from(self.functional.transfer_size)) {
+            self.download(block_num, block).await?;
+            while let Poll::Wait(delay) = download_poll(self.get_status().await?)? {
+                sleep(delay).await;
+            }
+            sent += block.len();
+            progress(sent, firmware.len());
+            block_num = block_num.wrapping_add(1);
+        }
+        self.download(block_num, &[]).await?;
+        loop {
+            let status = match self.get_status().await {
+                Ok(status) => status,
+                // Devices that aren't manifestation tolerant may not answer anymore
+                Err(_) if !self.functional.manifestation_tolerant() => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            match manifest_poll(status)? {
+                Poll::Wait(delay) => sleep(delay).await,
+                Poll::Done => return Ok(()),
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::dfu::{
+        download_poll, download_setup, get_status_setup, manifest_poll, upload_setup, DfuError,
+        DfuStatus, FunctionalDescriptor, Poll, State, StatusCode,
+    };
+    use crate::libusb::error::Error;
+    use core::time::Duration;
+
+    #[test]
+    pub fn test_functional_descriptor() {
+        // Some other class specific descriptor first
+        let extra = [
+            5, 0x24, 0, 0, 0, // ...
+            9, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x08, 0x1A, 0x01,
+        ];
+        let functional = FunctionalDescriptor::from_extra(&extra).expect("has one");
+        assert_eq!(functional.transfer_size, 2048);
+        assert_eq!(functional.detach_timeout, 255);
+        assert_eq!(functional.dfu_version, 0x011A);
+        assert!(functional.can_download() && functional.can_upload());
+        assert!(!functional.manifestation_tolerant() && functional.will_detach());
+        assert_eq!(
+            FunctionalDescriptor::from_extra(&extra[..5]),
+            Err(Error::NotFound)
+        );
+    }
+    #[test]
+    pub fn test_requests() {
+        let setup = download_setup(1, 7, 1024);
+        assert_eq!(
+            (
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                setup.len
+            ),
+            (0x21, 0x01, 7, 1, 1024)
+        );
+        let setup = upload_setup(0, 2, 64);
+        assert_eq!((setup.request_type, setup.request), (0xA1, 0x02));
+        let setup = get_status_setup(0);
+        assert_eq!(
+            (setup.request_type, setup.request, setup.len),
+            (0xA1, 0x03, 6)
+        );
+    }
+    #[test]
+    pub fn test_status() {
+        let status = DfuStatus::from_bytes(&[0, 0x2C, 0x01, 0, 4, 0]).expect("valid");
+        assert_eq!(status.poll_timeout, Duration::from_millis(300));
+        assert_eq!(status.state, State::DownloadBusy);
+        assert_eq!(download_poll(status), Ok(Poll::Wait(status.poll_timeout)));
+        let idle = DfuStatus::from_bytes(&[0, 0, 0, 0, 5, 0]).expect("valid");
+        assert_eq!(download_poll(idle), Ok(Poll::Done));
+        // Errors carry the status byte
+        let failed = DfuStatus::from_bytes(&[0x06, 0, 0, 0, 10, 0]).expect("valid");
+        assert_eq!(
+            download_poll(failed),
+            Err(DfuError::Status {
+                status: StatusCode::Prog,
+                state: State::Error
+            })
+        );
+        assert_eq!(
+            manifest_poll(idle),
+            Err(DfuError::UnexpectedState(State::DownloadIdle))
+        );
+        let manifest = DfuStatus::from_bytes(&[0, 10, 0, 0, 7, 0]).expect("valid");
+        assert_eq!(
+            manifest_poll(manifest),
+            Ok(Poll::Wait(Duration::from_millis(10)))
+        );
+        assert_eq!(DfuStatus::from_bytes(&[0; 5]), Err(Error::Overflow));
+    }
+}
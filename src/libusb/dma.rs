@@ -1,12 +1,118 @@
-#![allow(unused_variables, dead_code)]
+//! Device memory (`libusb_dev_mem_alloc`). On Linux this is memory the kernel can DMA into
+//! directly, so transfers using it skip a copy.
 use crate::libusb::device_handle::DeviceHandle;
+use crate::libusb::error::Error;
 
-pub struct DevMem {
+// `libusb1_sys` doesn't bind these (added in libusb 1.0.21).
+extern "system" {
+    fn libusb_dev_mem_alloc(
+        dev_handle: *mut libusb1_sys::libusb_device_handle,
+        length: libc::size_t,
+    ) -> *mut libc::c_uchar;
+    fn libusb_dev_mem_free(
+        dev_handle: *mut libusb1_sys::libusb_device_handle,
+        buffer: *mut libc::c_uchar,
+        length: libc::size_t,
+    ) -> libc::c_int;
+}
+
+/// Device memory for a `DeviceHandle`. Borrows the handle because the memory is mapped from the
+/// device's file descriptor and has to be freed before the handle is closed.
+pub struct DevMem<'h> {
+    handle: &'h DeviceHandle,
     ptr: core::ptr::NonNull<u8>,
     len: usize,
 }
-impl DevMem {
-    pub fn new(_device_handle: DeviceHandle, _len: usize) -> Option<DevMem> {
-        unimplemented!("libusb1_sys is missing dev_mem_alloc and free")
+impl<'h> DevMem<'h> {
+    /// Returns `Error::NotSupported` if the platform (or kernel) doesn't have device memory.
+    /// See `DevBuf` for falling back to heap memory.
+    pub fn new(handle: &'h DeviceHandle, len: usize) -> Result<DevMem<'h>, Error> {
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let ptr = unsafe { libusb_dev_mem_alloc(handle.inner().as_ptr(), len) };
+        Ok(DevMem {
+            handle,
+            ptr: core::ptr::NonNull::new(ptr).ok_or(Error::NotSupported)?,
+            len,
+        })
+    }
+    pub fn handle_ref(&self) -> &'h DeviceHandle {
+        self.handle
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+impl<'h> AsRef<[u8]> for DevMem<'h> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+impl<'h> AsMut<[u8]> for DevMem<'h> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+impl<'h> Drop for DevMem<'h> {
+    fn drop(&mut self) {
+        unsafe {
+            libusb_dev_mem_free(self.handle.inner().as_ptr(), self.ptr.as_ptr(), self.len);
+        }
+    }
+}
+impl<'h> core::fmt::Debug for DevMem<'h> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DevMem")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+// # Safety
+// Just memory. The `DeviceHandle` is `Sync`.
+unsafe impl<'h> Send for DevMem<'h> {}
+unsafe impl<'h> Sync for DevMem<'h> {}
+
+/// Device memory if the platform has it, otherwise a heap buffer.
+#[derive(Debug)]
+pub enum DevBuf<'h> {
+    DevMem(DevMem<'h>),
+    Heap(Vec<u8>),
+}
+impl<'h> DevBuf<'h> {
+    pub fn new(handle: &'h DeviceHandle, len: usize) -> DevBuf<'h> {
+        match DevMem::new(handle, len) {
+            Ok(mem) => DevBuf::DevMem(mem),
+            Err(_) => DevBuf::Heap(vec![0_u8; len]),
+        }
+    }
+    pub fn is_dev_mem(&self) -> bool {
+        matches!(self, DevBuf::DevMem(_))
+    }
+}
+impl<'h> AsRef<[u8]> for DevBuf<'h> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            DevBuf::DevMem(mem) => mem.as_slice(),
+            DevBuf::Heap(buf) => buf.as_slice(),
+        }
+    }
+}
+impl<'h> AsMut<[u8]> for DevBuf<'h> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        match self {
+            DevBuf::DevMem(mem) => mem.as_mut_slice(),
+            DevBuf::Heap(buf) => buf.as_mut_slice(),
+        }
     }
 }
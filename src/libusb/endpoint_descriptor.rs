@@ -1,4 +1,172 @@
+use crate::libusb::context::Context;
+use crate::libusb::control::Direction;
+use crate::libusb::error::Error;
+use crate::libusb::transfer::TransferType;
+
 #[derive(Copy, Clone)]
 pub struct EndpointDescriptors<'a>(pub &'a [libusb1_sys::libusb_endpoint_descriptor]);
 
-impl<'a> EndpointDescriptors<'a> {}
+impl<'a> EndpointDescriptors<'a> {
+    pub fn iter(&self) -> EndpointDescriptorsIter<'a> {
+        self.0.iter().map(EndpointDescriptor)
+    }
+}
+pub type EndpointDescriptorsIter<'a> = core::iter::Map<
+    core::slice::Iter<'a, libusb1_sys::libusb_endpoint_descriptor>,
+    fn(&'a libusb1_sys::libusb_endpoint_descriptor) -> EndpointDescriptor<'a>,
+>;
+impl<'a> IntoIterator for EndpointDescriptors<'a> {
+    type Item = EndpointDescriptor<'a>;
+    type IntoIter = EndpointDescriptorsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+/// Isochronous synchronization type. Bits 2..3 of `bmAttributes`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum SyncType {
+    NoSync = 0,
+    Asynchronous = 1,
+    Adaptive = 2,
+    Synchronous = 3,
+}
+/// Isochronous usage type. Bits 4..5 of `bmAttributes`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum UsageType {
+    Data = 0,
+    Feedback = 1,
+    ImplicitFeedbackData = 2,
+    Reserved = 3,
+}
+#[derive(Copy, Clone)]
+pub struct EndpointDescriptor<'a>(pub &'a libusb1_sys::libusb_endpoint_descriptor);
+
+impl<'a> EndpointDescriptor<'a> {
+    /// Returns the endpoint's address (number and direction bit).
+    pub fn address(&self) -> u8 {
+        self.0.bEndpointAddress
+    }
+
+    /// Returns the endpoint number.
+    pub fn number(&self) -> u8 {
+        self.0.bEndpointAddress & 0x0F
+    }
+
+    /// Returns the direction of the endpoint.
+    pub fn direction(&self) -> Direction {
+        Direction::from_request_type(self.0.bEndpointAddress)
+    }
+
+    /// Returns the endpoint's transfer type.
+    pub fn transfer_type(&self) -> TransferType {
+        match self.0.bmAttributes & 0x03 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+
+    /// Returns the endpoint's synchronisation type (only meaningful for isochronous endpoints).
+    pub fn sync_type(&self) -> SyncType {
+        match (self.0.bmAttributes >> 2) & 0x03 {
+            0 => SyncType::NoSync,
+            1 => SyncType::Asynchronous,
+            2 => SyncType::Adaptive,
+            _ => SyncType::Synchronous,
+        }
+    }
+
+    /// Returns the endpoint's usage type (only meaningful for isochronous endpoints).
+    pub fn usage_type(&self) -> UsageType {
+        match (self.0.bmAttributes >> 4) & 0x03 {
+            0 => UsageType::Data,
+            1 => UsageType::Feedback,
+            2 => UsageType::ImplicitFeedbackData,
+            _ => UsageType::Reserved,
+        }
+    }
+
+    /// Returns the endpoint's maximum packet size.
+    pub fn max_packet_size(&self) -> u16 {
+        self.0.wMaxPacketSize
+    }
+
+    /// Returns the endpoint's polling interval.
+    pub fn interval(&self) -> u8 {
+        self.0.bInterval
+    }
+
+    /// Returns the rate at which synchronization feedback is provided (audio endpoints only).
+    pub fn refresh(&self) -> u8 {
+        self.0.bRefresh
+    }
+
+    /// Returns the address of the synch endpoint (audio endpoints only).
+    pub fn synch_address(&self) -> u8 {
+        self.0.bSynchAddress
+    }
+
+    /// Returns the unknown 'extra' bytes that libusb does not understand.
+    pub fn extra(&self) -> Option<&[u8]> {
+        unsafe {
+            match self.0.extra_length {
+                len if len > 0 => Some(core::slice::from_raw_parts(self.0.extra, len as usize)),
+                _ => None,
+            }
+        }
+    }
+
+    /// Returns the SuperSpeed endpoint companion descriptor. Only SuperSpeed devices have one.
+    pub fn ss_companion(&self, context: &Context) -> Result<SsCompanion, Error> {
+        let mut out: *const libusb1_sys::libusb_ss_endpoint_companion_descriptor =
+            core::ptr::null_mut();
+        try_unsafe!(libusb1_sys::libusb_get_ss_endpoint_companion_descriptor(
+            context.libusb_ptr(),
+            self.0,
+            &mut out as *mut _
+        ));
+        let companion = unsafe {
+            let descriptor = &*out;
+            SsCompanion {
+                max_burst: descriptor.bMaxBurst,
+                attributes: descriptor.bmAttributes,
+                bytes_per_interval: descriptor.wBytesPerInterval,
+            }
+        };
+        unsafe { libusb1_sys::libusb_free_ss_endpoint_companion_descriptor(out as *mut _) };
+        Ok(companion)
+    }
+}
+
+impl<'a> core::fmt::Debug for EndpointDescriptor<'a> {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        let mut debug = fmt.debug_struct("EndpointDescriptor");
+
+        let descriptor = self.0;
+
+        debug.field("bLength", &descriptor.bLength);
+        debug.field("bDescriptorType", &descriptor.bDescriptorType);
+        debug.field("bEndpointAddress", &descriptor.bEndpointAddress);
+        debug.field("bmAttributes", &descriptor.bmAttributes);
+        debug.field("wMaxPacketSize", &descriptor.wMaxPacketSize);
+        debug.field("bInterval", &descriptor.bInterval);
+        debug.field("bRefresh", &descriptor.bRefresh);
+        debug.field("bSynchAddress", &descriptor.bSynchAddress);
+        debug.field("extra", &self.extra());
+
+        debug.finish()
+    }
+}
+
+/// SuperSpeed endpoint companion descriptor.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct SsCompanion {
+    /// Max packets per burst minus 1 (0..15).
+    pub max_burst: u8,
+    /// Max streams for bulk endpoints, `Mult` for isochronous endpoints.
+    pub attributes: u8,
+    /// Bytes per service interval for periodic endpoints.
+    pub bytes_per_interval: u16,
+}
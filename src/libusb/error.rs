@@ -1,3 +1,5 @@
+use crate::device::DeviceIdentifier;
+use core::convert::TryFrom;
 use core::fmt;
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Error {
@@ -40,24 +42,83 @@ pub enum Error {
     /// The device returned a malformed descriptor.
     BadDescriptor,
 
+    /// The transfer was cancelled (`Status::Cancelled`). Not a libusb error code.
+    Cancelled,
+
     /// Other error.
     Other,
 }
+/// What can be done about an `Error`, see `Error::classify`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum RetryClass {
+    /// Might work if tried again (`Timeout`, `Interrupted`, `Busy`).
+    Transient,
+    /// The device is gone (`NoDevice`), it has to be found and opened again.
+    Disconnect,
+    /// Not allowed (`Access`), can go away on its own while udev applies its rules.
+    Permission,
+    /// The endpoint stalled (`Pipe`), it has to be cleared (`AsyncDevice::clear_halt`) first.
+    ClearHalt,
+    /// Trying again won't help.
+    Fatal,
+}
 impl Error {
+    pub fn classify(self) -> RetryClass {
+        match self {
+            Error::Timeout | Error::Interrupted | Error::Busy => RetryClass::Transient,
+            Error::NoDevice => RetryClass::Disconnect,
+            Error::Access => RetryClass::Permission,
+            Error::Pipe => RetryClass::ClearHalt,
+            _ => RetryClass::Fatal,
+        }
+    }
+    pub fn is_transient(self) -> bool {
+        self.classify() == RetryClass::Transient
+    }
+    pub fn is_disconnect(self) -> bool {
+        self.classify() == RetryClass::Disconnect
+    }
+    pub fn is_permission(self) -> bool {
+        self.classify() == RetryClass::Permission
+    }
+    pub fn should_retry_after_clear_halt(self) -> bool {
+        self.classify() == RetryClass::ClearHalt
+    }
+    /// The `LIBUSB_ERROR_*` code, `None` for the errors libusb doesn't have (`BadDescriptor`,
+    /// `Cancelled`).
+    pub fn to_libusb_code(self) -> Option<i32> {
+        use libusb1_sys::constants::*;
+        match self {
+            Error::Io => Some(LIBUSB_ERROR_IO),
+            Error::InvalidParam => Some(LIBUSB_ERROR_INVALID_PARAM),
+            Error::Access => Some(LIBUSB_ERROR_ACCESS),
+            Error::NoDevice => Some(LIBUSB_ERROR_NO_DEVICE),
+            Error::NotFound => Some(LIBUSB_ERROR_NOT_FOUND),
+            Error::Busy => Some(LIBUSB_ERROR_BUSY),
+            Error::Timeout => Some(LIBUSB_ERROR_TIMEOUT),
+            Error::Overflow => Some(LIBUSB_ERROR_OVERFLOW),
+            Error::Pipe => Some(LIBUSB_ERROR_PIPE),
+            Error::Interrupted => Some(LIBUSB_ERROR_INTERRUPTED),
+            Error::NoMem => Some(LIBUSB_ERROR_NO_MEM),
+            Error::NotSupported => Some(LIBUSB_ERROR_NOT_SUPPORTED),
+            Error::Other => Some(LIBUSB_ERROR_OTHER),
+            Error::BadDescriptor | Error::Cancelled => None,
+        }
+    }
+    /// libusb's name for the error (`LIBUSB_ERROR_ACCESS`, ...), `as_str` for the errors libusb
+    /// doesn't have.
     pub fn libusb_name(self) -> &'static str {
-        unsafe {
-            let ptr = libusb1_sys::libusb_error_name(self as i32);
-            std::ffi::CStr::from_ptr(ptr)
-                .to_str()
-                .expect("libusb error name utf-8 error")
+        match self.to_libusb_code() {
+            Some(code) => unsafe { static_str(libusb1_sys::libusb_error_name(code)) },
+            None => self.as_str(),
         }
     }
+    /// libusb's (possibly translated, see `libusb_setlocale`) description of the error, `as_str`
+    /// for the errors libusb doesn't have.
     pub fn libusb_description(self) -> &'static str {
-        unsafe {
-            let ptr = libusb1_sys::libusb_strerror(self as i32);
-            std::ffi::CStr::from_ptr(ptr)
-                .to_str()
-                .expect("libusb error name utf-8 error")
+        match self.to_libusb_code() {
+            Some(code) => unsafe { static_str(libusb1_sys::libusb_strerror(code)) },
+            None => self.as_str(),
         }
     }
     pub fn as_str(self) -> &'static str {
@@ -75,10 +136,18 @@ impl Error {
             Error::NoMem => "Insufficient memory",
             Error::NotSupported => "Operation not supported or unimplemented on this platform",
             Error::BadDescriptor => "Malformed descriptor",
+            Error::Cancelled => "Transfer was cancelled",
             Error::Other => "Other error",
         }
     }
 }
+/// # Safety
+/// `ptr` has to be a nul terminated string that lives forever (libusb's error strings).
+unsafe fn static_str(ptr: *const std::os::raw::c_char) -> &'static str {
+    std::ffi::CStr::from_ptr(ptr)
+        .to_str()
+        .expect("libusb error name utf-8 error")
+}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(self.as_str())
@@ -86,6 +155,124 @@ impl fmt::Display for Error {
 }
 
 impl std::error::Error for Error {}
+/// The `Error` is kept as the `io::Error`'s inner error (`get_ref`, `into_inner`), `TryFrom` gets
+/// it back.
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        use std::io::ErrorKind;
+        let kind = match e {
+            Error::InvalidParam => ErrorKind::InvalidInput,
+            Error::Access => ErrorKind::PermissionDenied,
+            Error::NoDevice => ErrorKind::NotConnected,
+            Error::NotFound => ErrorKind::NotFound,
+            Error::Busy => ErrorKind::ResourceBusy,
+            Error::Timeout => ErrorKind::TimedOut,
+            Error::Interrupted => ErrorKind::Interrupted,
+            Error::NoMem => ErrorKind::OutOfMemory,
+            Error::NotSupported => ErrorKind::Unsupported,
+            Error::BadDescriptor => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+/// Best effort. The inner `Error` (or `ErrorContext`) if there's one, otherwise the `ErrorKind`
+/// mapped back. Gives the `io::Error` back if its kind has no `Error`.
+impl TryFrom<std::io::Error> for Error {
+    type Error = std::io::Error;
+    fn try_from(e: std::io::Error) -> Result<Self, Self::Error> {
+        use std::io::ErrorKind;
+        if let Some(inner) = e.get_ref() {
+            if let Some(&error) = inner.downcast_ref::<Error>() {
+                return Ok(error);
+            }
+            if let Some(context) = inner.downcast_ref::<ErrorContext>() {
+                return Ok(context.error);
+            }
+        }
+        Ok(match e.kind() {
+            ErrorKind::InvalidInput => Error::InvalidParam,
+            ErrorKind::PermissionDenied => Error::Access,
+            ErrorKind::NotConnected => Error::NoDevice,
+            ErrorKind::NotFound => Error::NotFound,
+            ErrorKind::ResourceBusy | ErrorKind::WouldBlock => Error::Busy,
+            ErrorKind::TimedOut => Error::Timeout,
+            ErrorKind::Interrupted => Error::Interrupted,
+            ErrorKind::OutOfMemory => Error::NoMem,
+            ErrorKind::Unsupported => Error::NotSupported,
+            ErrorKind::InvalidData => Error::BadDescriptor,
+            _ => return Err(e),
+        })
+    }
+}
+
+/// The device operation an `ErrorContext` happened in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operation {
+    Open,
+    ClaimInterface(u8),
+    SetActiveConfiguration(u8),
+}
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Open => f.write_str("open"),
+            Operation::ClaimInterface(interface) => write!(f, "claim_interface({})", interface),
+            Operation::SetActiveConfiguration(config) => {
+                write!(f, "set_active_configuration({})", config)
+            }
+        }
+    }
+}
+/// An `Error` with the device and the operation it failed on, like
+/// "Access denied (insufficient permissions) on 0a12:0001 (bus 3, addr 7) while
+/// claim_interface(0)". `source()` is the `Error`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ErrorContext {
+    pub error: Error,
+    pub operation: Operation,
+    /// `None` if the device descriptor couldn't be read.
+    pub device_identifier: Option<DeviceIdentifier>,
+    pub bus_number: u8,
+    pub address: u8,
+}
+impl ErrorContext {
+    pub fn error(&self) -> Error {
+        self.error
+    }
+}
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operation {
+            Operation::Open => write!(f, "{} opening ", self.error)?,
+            _ => write!(f, "{} on ", self.error)?,
+        }
+        if let Some(id) = self.device_identifier {
+            write!(f, "{:04x}:{:04x} ", id.vendor_id.0, id.product_id.0)?;
+        }
+        write!(f, "(bus {}, addr {})", self.bus_number, self.address)?;
+        match self.operation {
+            Operation::Open => Ok(()),
+            operation => write!(f, " while {}", operation),
+        }
+    }
+}
+impl std::error::Error for ErrorContext {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+impl From<ErrorContext> for Error {
+    fn from(e: ErrorContext) -> Self {
+        e.error
+    }
+}
+impl From<ErrorContext> for std::io::Error {
+    fn from(e: ErrorContext) -> Self {
+        let kind = std::io::Error::from(e.error).kind();
+        std::io::Error::new(kind, e)
+    }
+}
 
 pub fn from_libusb(err: i32) -> Error {
     match err {
@@ -113,3 +300,142 @@ macro_rules! try_unsafe {
         }
     };
 }
+#[cfg(test)]
+mod tests {
+    use crate::device::DeviceIdentifier;
+    use crate::libusb::error::{from_libusb, Error, ErrorContext, Operation, RetryClass};
+    use core::convert::TryFrom;
+    use std::error::Error as _;
+    use std::io::ErrorKind;
+
+    const ALL: [Error; 15] = [
+        Error::Io,
+        Error::InvalidParam,
+        Error::Access,
+        Error::NoDevice,
+        Error::NotFound,
+        Error::Busy,
+        Error::Timeout,
+        Error::Overflow,
+        Error::Pipe,
+        Error::Interrupted,
+        Error::NoMem,
+        Error::NotSupported,
+        Error::BadDescriptor,
+        Error::Cancelled,
+        Error::Other,
+    ];
+
+    #[test]
+    pub fn test_io_error() {
+        let kinds = [
+            ErrorKind::Other,
+            ErrorKind::InvalidInput,
+            ErrorKind::PermissionDenied,
+            ErrorKind::NotConnected,
+            ErrorKind::NotFound,
+            ErrorKind::ResourceBusy,
+            ErrorKind::TimedOut,
+            ErrorKind::Other,
+            ErrorKind::Other,
+            ErrorKind::Interrupted,
+            ErrorKind::OutOfMemory,
+            ErrorKind::Unsupported,
+            ErrorKind::InvalidData,
+            ErrorKind::Other,
+            ErrorKind::Other,
+        ];
+        for (&error, &kind) in ALL.iter().zip(kinds.iter()) {
+            let io = std::io::Error::from(error);
+            assert_eq!(io.kind(), kind, "{:?}", error);
+            assert_eq!(io.get_ref().and_then(|e| e.downcast_ref()), Some(&error));
+            assert_eq!(io.to_string(), error.to_string());
+            assert_eq!(Error::try_from(io).ok(), Some(error));
+            // Without the inner error only the kind is left
+            let bare = std::io::Error::from(kind);
+            match Error::try_from(bare) {
+                Ok(mapped) => assert_eq!(std::io::Error::from(mapped).kind(), kind),
+                Err(bare) => assert_eq!(bare.kind(), ErrorKind::Other),
+            }
+        }
+        let io = std::io::Error::from(ErrorContext {
+            error: Error::Access,
+            operation: Operation::Open,
+            device_identifier: None,
+            bus_number: 1,
+            address: 2,
+        });
+        assert_eq!(io.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(Error::try_from(io).ok(), Some(Error::Access));
+        assert_eq!(
+            Error::try_from(std::io::Error::from(ErrorKind::WouldBlock)).ok(),
+            Some(Error::Busy)
+        );
+    }
+    #[test]
+    pub fn test_classify() {
+        use RetryClass::*;
+        let classes = [
+            Fatal, Fatal, Permission, Disconnect, Fatal, Transient, Transient, Fatal, ClearHalt,
+            Transient, Fatal, Fatal, Fatal, Fatal, Fatal,
+        ];
+        for (&error, &class) in ALL.iter().zip(classes.iter()) {
+            assert_eq!(error.classify(), class, "{:?}", error);
+            assert_eq!(error.is_transient(), class == Transient);
+            assert_eq!(error.is_disconnect(), class == Disconnect);
+            assert_eq!(error.is_permission(), class == Permission);
+            assert_eq!(error.should_retry_after_clear_halt(), class == ClearHalt);
+        }
+    }
+    #[test]
+    pub fn test_libusb_codes() {
+        assert_eq!(Error::Io.libusb_name(), "LIBUSB_ERROR_IO");
+        assert_eq!(Error::Access.libusb_name(), "LIBUSB_ERROR_ACCESS");
+        assert_eq!(Error::Other.libusb_name(), "LIBUSB_ERROR_OTHER");
+        for &error in ALL.iter() {
+            match error.to_libusb_code() {
+                Some(code) => {
+                    assert_eq!(from_libusb(code), error);
+                    assert!(code < 0);
+                    assert!(error.libusb_name().starts_with("LIBUSB_ERROR_"));
+                }
+                None => {
+                    assert_eq!(error.libusb_name(), error.as_str());
+                    assert_eq!(error.libusb_description(), error.as_str());
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_error_context_display() {
+        let mut context = ErrorContext {
+            error: Error::Access,
+            operation: Operation::Open,
+            device_identifier: Some(DeviceIdentifier::new(0x0A12, 0x0001)),
+            bus_number: 3,
+            address: 7,
+        };
+        assert_eq!(
+            context.to_string(),
+            "Access denied (insufficient permissions) opening 0a12:0001 (bus 3, addr 7)"
+        );
+        context.error = Error::Busy;
+        context.operation = Operation::ClaimInterface(0);
+        assert_eq!(
+            context.to_string(),
+            "Resource busy on 0a12:0001 (bus 3, addr 7) while claim_interface(0)"
+        );
+        context.device_identifier = None;
+        context.operation = Operation::SetActiveConfiguration(1);
+        assert_eq!(
+            context.to_string(),
+            "Resource busy on (bus 3, addr 7) while set_active_configuration(1)"
+        );
+        assert_eq!(
+            context.source().map(|e| e.to_string()),
+            Some(Error::Busy.to_string())
+        );
+        assert_eq!(Error::from(context), Error::Busy);
+    }
+}
@@ -0,0 +1,215 @@
+//! Drives libusb's events from a tokio runtime instead of a dedicated event thread
+//! (`AsyncContext`). On Unix the pump waits on libusb's file descriptors through the runtime's
+//! reactor. Elsewhere it falls back to polling libusb on a short timer.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::context::Context;
+use crate::libusb::device_handle::DeviceHandle;
+use crate::libusb::error::Error;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Poll;
+use core::time::Duration;
+#[cfg(unix)]
+use futures_util::task::AtomicWaker;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::io::unix::AsyncFd;
+
+/// How often libusb gets polled when its file descriptors can't be waited on.
+#[cfg(not(unix))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Future that handles libusb events for a `Context` until an error occurs. Only one
+/// `EventPump` (or `AsyncContext`) should be handling a context's events at a time.
+pub struct EventPump<'a> {
+    context: &'a Context,
+    first_poll: bool,
+    timeout: Option<Pin<Box<tokio::time::Sleep>>>,
+    #[cfg(unix)]
+    fds: Vec<(AsyncFd<PollFd>, libc::c_short)>,
+    #[cfg(unix)]
+    notify: Arc<PollFdNotify>,
+}
+impl<'a> EventPump<'a> {
+    pub fn new(context: &'a Context) -> EventPump<'a> {
+        #[cfg(unix)]
+        let notify = {
+            let notify = Arc::new(PollFdNotify {
+                // Load the file descriptors on the first poll (inside the runtime)
+                changed: AtomicBool::new(true),
+                waker: AtomicWaker::new(),
+            });
+            unsafe {
+                libusb1_sys::libusb_set_pollfd_notifiers(
+                    context.libusb_ptr(),
+                    Some(pollfd_added),
+                    Some(pollfd_removed),
+                    &*notify as *const PollFdNotify as *mut core::ffi::c_void,
+                )
+            }
+            notify
+        };
+        EventPump {
+            context,
+            first_poll: true,
+            timeout: None,
+            #[cfg(unix)]
+            fds: Vec::new(),
+            #[cfg(unix)]
+            notify,
+        }
+    }
+    pub fn context_ref(&self) -> &Context {
+        self.context
+    }
+    /// Same as `AsyncContext::make_async_device`, checked against this pump's context.
+    pub fn make_async_device(&self, handle: DeviceHandle) -> Result<AsyncDevice, Error> {
+        self.context.check_owns(&handle)?;
+        Ok(AsyncDevice::new(handle))
+    }
+    #[cfg(unix)]
+    fn refresh_fds(&mut self) -> Result<(), Error> {
+        self.fds.clear();
+        let list = unsafe { libusb1_sys::libusb_get_pollfds(self.context.libusb_ptr()) };
+        if list.is_null() {
+            return Err(Error::NotSupported);
+        }
+        let mut result = Ok(());
+        let mut i = 0;
+        loop {
+            let pollfd = unsafe { *list.add(i) };
+            if pollfd.is_null() {
+                break;
+            }
+            let (fd, events) = unsafe { ((*pollfd).fd, (*pollfd).events) };
+            match AsyncFd::new(PollFd(fd)) {
+                Ok(async_fd) => self.fds.push((async_fd, events)),
+                Err(_) => {
+                    result = Err(Error::Io);
+                    break;
+                }
+            }
+            i += 1;
+        }
+        unsafe { libusb1_sys::libusb_free_pollfds(list) };
+        result
+    }
+    /// Polls the file descriptors. Returns if any of them are ready.
+    #[cfg(unix)]
+    fn poll_fds(&mut self, cx: &mut core::task::Context<'_>) -> Result<bool, Error> {
+        self.notify.waker.register(cx.waker());
+        let mut ready = false;
+        if self.notify.changed.swap(false, Ordering::SeqCst) {
+            self.refresh_fds()?;
+            ready = true;
+        }
+        for (fd, events) in &self.fds {
+            if events & libc::POLLIN != 0 {
+                if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+                    guard.map_err(|_| Error::Io)?.clear_ready();
+                    ready = true;
+                }
+            }
+            if events & libc::POLLOUT != 0 {
+                if let Poll::Ready(guard) = fd.poll_write_ready(cx) {
+                    guard.map_err(|_| Error::Io)?.clear_ready();
+                    ready = true;
+                }
+            }
+        }
+        Ok(ready)
+    }
+    #[cfg(not(unix))]
+    fn poll_fds(&mut self, _cx: &mut core::task::Context<'_>) -> Result<bool, Error> {
+        Ok(false)
+    }
+    #[cfg(unix)]
+    fn next_timeout(&self) -> Result<Option<Duration>, Error> {
+        self.context.next_timeout()
+    }
+    #[cfg(not(unix))]
+    fn next_timeout(&self) -> Result<Option<Duration>, Error> {
+        let timeout = self.context.next_timeout()?;
+        Ok(Some(
+            timeout.map_or(POLL_INTERVAL, |t| t.min(POLL_INTERVAL)),
+        ))
+    }
+}
+impl<'a> Future for EventPump<'a> {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let mut ready = core::mem::replace(&mut this.first_poll, false);
+            ready |= this.poll_fds(cx)?;
+            if let Some(timeout) = this.timeout.as_mut() {
+                if timeout.as_mut().poll(cx).is_ready() {
+                    this.timeout = None;
+                    ready = true;
+                }
+            }
+            if !ready {
+                return Poll::Pending;
+            }
+            // Something is ready so this won't block.
+            match this.context.handle_events_timeout(Duration::from_secs(0)) {
+                Ok(()) | Err(Error::Interrupted) => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            this.timeout = this
+                .next_timeout()?
+                .map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+        }
+    }
+}
+impl<'a> Drop for EventPump<'a> {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            // Stop libusb from using `notify` before it gets freed
+            libusb1_sys::libusb_set_pollfd_notifiers(
+                self.context.libusb_ptr(),
+                None,
+                None,
+                core::ptr::null_mut(),
+            )
+        }
+    }
+}
+
+/// libusb file descriptor. Doesn't close it on drop (libusb owns it).
+#[cfg(unix)]
+struct PollFd(std::os::unix::io::RawFd);
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for PollFd {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.0
+    }
+}
+#[cfg(unix)]
+struct PollFdNotify {
+    changed: AtomicBool,
+    waker: AtomicWaker,
+}
+#[cfg(unix)]
+fn notify_changed(user_data: *mut core::ffi::c_void) {
+    let notify = unsafe { &*(user_data as *const PollFdNotify) };
+    notify.changed.store(true, Ordering::SeqCst);
+    notify.waker.wake();
+}
+#[cfg(unix)]
+extern "system" fn pollfd_added(
+    _fd: libc::c_int,
+    _events: libc::c_short,
+    user_data: *mut core::ffi::c_void,
+) {
+    notify_changed(user_data)
+}
+#[cfg(unix)]
+extern "system" fn pollfd_removed(_fd: libc::c_int, user_data: *mut core::ffi::c_void) {
+    notify_changed(user_data)
+}
@@ -0,0 +1,265 @@
+//! Bluetooth HCI over USB (the Bluetooth Core spec's USB transport layer).
+//!
+//! Commands go out as class control requests, events come in on the interrupt endpoint and ACL
+//! data uses the bulk endpoints. Events and ACL packets longer than one transfer are put back
+//! together from their header's length field.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::control::{control_len, Direction, Recipient, RequestKind};
+use crate::libusb::error::Error;
+use crate::libusb::transfer::{ControlSetup, NO_TIMEOUT};
+use core::convert::TryFrom;
+
+/// The interface with the HCI endpoints (interface 1 has the isochronous SCO endpoints).
+pub const HCI_INTERFACE: u8 = 0;
+/// Event code + parameter length + 255 bytes of parameters.
+pub const EVENT_MAX_SIZE: usize = 257;
+/// Command complete event code.
+pub const COMMAND_COMPLETE_EVENT: u8 = 0x0E;
+/// Command status event code.
+pub const COMMAND_STATUS_EVENT: u8 = 0x0F;
+
+/// Fixed endpoint addresses of the HCI interface.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum EndpointAddress {
+    /// Interrupt IN.
+    HCIEvents = 0x81,
+    /// Bulk IN.
+    ACLDataIn = 0x82,
+    /// Bulk OUT.
+    ACLDataOut = 0x02,
+}
+impl From<EndpointAddress> for u8 {
+    fn from(endpoint: EndpointAddress) -> u8 {
+        endpoint as u8
+    }
+}
+impl TryFrom<u8> for EndpointAddress {
+    type Error = crate::error::ConversionError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x81 => Ok(EndpointAddress::HCIEvents),
+            0x82 => Ok(EndpointAddress::ACLDataIn),
+            0x02 => Ok(EndpointAddress::ACLDataOut),
+            _ => Err(crate::error::ConversionError::new("HCI endpoint address")),
+        }
+    }
+}
+
+/// `ControlSetup` of an HCI command (`bmRequestType` 0x20) with `len` bytes of opcode and
+/// parameters.
+pub fn command_setup(len: u16) -> ControlSetup {
+    ControlSetup::builder()
+        .direction(Direction::Out)
+        .request_type(RequestKind::Class)
+        .recipient(Recipient::Device)
+        .index(HCI_INTERFACE.into())
+        .len(len)
+        .build()
+}
+/// Checks `command` is a whole command packet (opcode, parameter length and the parameters).
+pub fn check_command(command: &[u8]) -> Result<(), Error> {
+    match command.get(2) {
+        Some(&len) if usize::from(len) == command.len() - 3 => Ok(()),
+        _ => Err(Error::InvalidParam),
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PacketKind {
+    Event,
+    Acl,
+}
+impl PacketKind {
+    /// Length of the whole packet or `None` if the header isn't complete yet.
+    fn packet_len(self, buf: &[u8]) -> Option<usize> {
+        match self {
+            PacketKind::Event => buf.get(1).map(|&len| 2 + usize::from(len)),
+            PacketKind::Acl => buf
+                .get(2..4)
+                .map(|len| 4 + usize::from(u16::from_le_bytes([len[0], len[1]]))),
+        }
+    }
+}
+/// Bytes read so far. Packets are taken out once they're complete.
+#[derive(Debug)]
+struct Assembler {
+    kind: PacketKind,
+    buf: Vec<u8>,
+}
+impl Assembler {
+    fn new(kind: PacketKind) -> Assembler {
+        Assembler {
+            kind,
+            buf: Vec::new(),
+        }
+    }
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes)
+    }
+    fn take_packet(&mut self) -> Option<Vec<u8>> {
+        let len = self.kind.packet_len(&self.buf)?;
+        if self.buf.len() < len {
+            return None;
+        }
+        let rest = self.buf.split_off(len);
+        Some(core::mem::replace(&mut self.buf, rest))
+    }
+}
+
+/// An HCI adapter. Reads wait forever (wrap them in a timeout if needed), writes use the
+/// `AsyncDevice` default timeout.
+pub struct HciTransport {
+    device: AsyncDevice,
+    events: Assembler,
+    acl: Assembler,
+}
+impl HciTransport {
+    /// Claims `HCI_INTERFACE` of `device`.
+    pub fn new(mut device: AsyncDevice) -> Result<HciTransport, Error> {
+        device.handle_mut().claim_interface(HCI_INTERFACE)?;
+        Ok(HciTransport {
+            device,
+            events: Assembler::new(PacketKind::Event),
+            acl: Assembler::new(PacketKind::Acl),
+        })
+    }
+    pub fn device(&self) -> &AsyncDevice {
+        &self.device
+    }
+    pub fn device_mut(&mut self) -> &mut AsyncDevice {
+        &mut self.device
+    }
+    pub fn into_device(self) -> AsyncDevice {
+        self.device
+    }
+    /// Sends a command packet (opcode, parameter length and the parameters).
+    pub async fn send_command(&self, command: &[u8]) -> Result<(), Error> {
+        check_command(command)?;
+        let setup = command_setup(control_len(command.len())?);
+        let written = self
+            .device
+            .control_write(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                command,
+                self.device.default_timeout(),
+            )
+            .await?;
+        if written != command.len() {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+    /// Reads the next whole event (code, parameter length and the parameters).
+    pub async fn read_event(&mut self) -> Result<Vec<u8>, Error> {
+        let mut buf = [0_u8; EVENT_MAX_SIZE];
+        loop {
+            if let Some(event) = self.events.take_packet() {
+                return Ok(event);
+            }
+            let len = self
+                .device
+                .interrupt_read(EndpointAddress::HCIEvents.into(), &mut buf, NO_TIMEOUT)
+                .await?;
+            self.events.push(&buf[..len]);
+        }
+    }
+    /// Sends an ACL data packet (handle and flags, data length and the data).
+    pub async fn send_acl(&self, packet: &[u8]) -> Result<(), Error> {
+        match PacketKind::Acl.packet_len(packet) {
+            Some(len) if len == packet.len() => (),
+            _ => return Err(Error::InvalidParam),
+        }
+        let written = self
+            .device
+            .bulk_write(
+                EndpointAddress::ACLDataOut.into(),
+                packet,
+                self.device.default_timeout(),
+            )
+            .await?;
+        if written != packet.len() {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+    /// Reads the next whole ACL data packet.
+    pub async fn read_acl(&mut self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0_u8; 1024];
+        loop {
+            if let Some(packet) = self.acl.take_packet() {
+                return Ok(packet);
+            }
+            let len = self
+                .device
+                .bulk_read(EndpointAddress::ACLDataIn.into(), &mut buf, NO_TIMEOUT)
+                .await?;
+            self.acl.push(&buf[..len]);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::error::Error;
+    use crate::libusb::hci::{
+        check_command, command_setup, Assembler, EndpointAddress, PacketKind,
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    pub fn test_command_setup() {
+        let setup = command_setup(3);
+        assert_eq!(
+            (
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                setup.len
+            ),
+            (0x20, 0, 0, 0, 3)
+        );
+        assert_eq!(check_command(&[0x03, 0x0C, 0x00]), Ok(()));
+        assert_eq!(
+            check_command(&[0x01, 0x0C, 0x02, 0xFF]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(check_command(&[0x03, 0x0C]), Err(Error::InvalidParam));
+        assert_eq!(
+            EndpointAddress::try_from(0x81),
+            Ok(EndpointAddress::HCIEvents)
+        );
+        assert!(EndpointAddress::try_from(0x83).is_err());
+    }
+    #[test]
+    pub fn test_event_assembly() {
+        let mut events = Assembler::new(PacketKind::Event);
+        // A 20 byte event split over 16 byte interrupt packets
+        let event: Vec<u8> = [0x0E, 18].iter().copied().chain(0..18).collect();
+        events.push(&event[..1]);
+        assert_eq!(events.take_packet(), None);
+        events.push(&event[1..16]);
+        assert_eq!(events.take_packet(), None);
+        // The rest and the start of the next event
+        events.push(&event[16..]);
+        events.push(&[0x0F, 4, 0]);
+        assert_eq!(events.take_packet(), Some(event));
+        assert_eq!(events.take_packet(), None);
+        events.push(&[1, 3, 0x0C]);
+        assert_eq!(events.take_packet(), Some(vec![0x0F, 4, 0, 1, 3, 0x0C]));
+    }
+    #[test]
+    pub fn test_acl_assembly() {
+        let mut acl = Assembler::new(PacketKind::Acl);
+        acl.push(&[0x01, 0x20, 0x02, 0x00, 0xAA, 0xBB, 0x01, 0x20, 0x00, 0x00]);
+        assert_eq!(
+            acl.take_packet(),
+            Some(vec![0x01, 0x20, 0x02, 0x00, 0xAA, 0xBB])
+        );
+        // An empty packet is still a packet
+        assert_eq!(acl.take_packet(), Some(vec![0x01, 0x20, 0x00, 0x00]));
+        assert_eq!(acl.take_packet(), None);
+    }
+}
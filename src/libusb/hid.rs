@@ -0,0 +1,330 @@
+//! HID class transport: the report descriptor, `GET_REPORT`/`SET_REPORT` and the interrupt
+//! endpoints. Report descriptors aren't parsed.
+use crate::descriptors::ParsedConfig;
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::control::{control_len, DescriptorType, Direction};
+use crate::libusb::error::Error;
+use crate::libusb::transfer::ControlSetup;
+
+/// Class requests of a HID interface.
+pub mod request {
+    pub const GET_REPORT: u8 = 0x01;
+    pub const GET_IDLE: u8 = 0x02;
+    pub const GET_PROTOCOL: u8 = 0x03;
+    pub const SET_REPORT: u8 = 0x09;
+    pub const SET_IDLE: u8 = 0x0A;
+    pub const SET_PROTOCOL: u8 = 0x0B;
+}
+pub const HID_CLASS: u8 = 0x03;
+
+/// High byte of the `wValue` of `GET_REPORT`/`SET_REPORT`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum ReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+fn report_value(report_type: ReportType, report_id: u8) -> u16 {
+    (report_type as u16) << 8 | u16::from(report_id)
+}
+/// `GET_REPORT` of `len` bytes. `report_id` 0 is for devices without report IDs.
+pub fn get_report_setup(
+    interface: u8,
+    report_type: ReportType,
+    report_id: u8,
+    len: u16,
+) -> ControlSetup {
+    ControlSetup::class_interface(
+        Direction::In,
+        request::GET_REPORT,
+        report_value(report_type, report_id),
+        interface,
+        len,
+    )
+}
+pub fn set_report_setup(
+    interface: u8,
+    report_type: ReportType,
+    report_id: u8,
+    len: u16,
+) -> ControlSetup {
+    ControlSetup::class_interface(
+        Direction::Out,
+        request::SET_REPORT,
+        report_value(report_type, report_id),
+        interface,
+        len,
+    )
+}
+/// `SET_IDLE` with the duration in 4 ms units (0 only reports changes).
+pub fn set_idle_setup(interface: u8, duration: u8, report_id: u8) -> ControlSetup {
+    let value = u16::from(duration) << 8 | u16::from(report_id);
+    ControlSetup::class_interface(Direction::Out, request::SET_IDLE, value, interface, 0)
+}
+/// `SET_PROTOCOL`, `false` selects the boot protocol.
+pub fn set_protocol_setup(interface: u8, report_protocol: bool) -> ControlSetup {
+    ControlSetup::class_interface(
+        Direction::Out,
+        request::SET_PROTOCOL,
+        report_protocol.into(),
+        interface,
+        0,
+    )
+}
+
+/// What the configuration descriptor says about a HID interface.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct HidInterface {
+    pub interface: u8,
+    /// `wDescriptorLength` of the report descriptor in the HID descriptor.
+    pub report_descriptor_len: u16,
+    /// Interrupt IN endpoint and its max packet size.
+    pub interrupt_in: (u8, u16),
+    /// Interrupt OUT endpoint. Without one, output reports go through `SET_REPORT`.
+    pub interrupt_out: Option<u8>,
+}
+impl HidInterface {
+    /// Finds `interface` (alt setting 0) in `config`. `Error::NotFound` if it isn't a HID
+    /// interface with an interrupt IN endpoint.
+    pub fn from_config(config: &ParsedConfig, interface: u8) -> Result<HidInterface, Error> {
+        let alt = config
+            .interfaces
+            .iter()
+            .find(|i| i.number == interface)
+            .and_then(|i| i.alt_settings.first())
+            .filter(|alt| alt.codes.class == HID_CLASS)
+            .ok_or(Error::NotFound)?;
+        // bcdHID, bCountryCode, bNumDescriptors then (bDescriptorType, wDescriptorLength)s
+        let hid = alt
+            .extra
+            .iter()
+            .find(|d| d.descriptor_type == u8::from(DescriptorType::Hid))
+            .ok_or(Error::BadDescriptor)?;
+        let report_descriptor_len = hid
+            .data
+            .get(4..)
+            .unwrap_or_default()
+            .chunks_exact(3)
+            .find(|d| d[0] == u8::from(DescriptorType::Report))
+            .map(|d| u16::from_le_bytes([d[1], d[2]]))
+            .ok_or(Error::BadDescriptor)?;
+        let interrupt = |input: bool| {
+            alt.endpoints
+                .iter()
+                .find(|e| (e.address & 0x80 != 0) == input && e.attributes & 0x03 == 3)
+        };
+        let interrupt_in = interrupt(true).ok_or(Error::NotFound)?;
+        Ok(HidInterface {
+            interface,
+            report_descriptor_len,
+            interrupt_in: (interrupt_in.address, interrupt_in.max_packet_size),
+            interrupt_out: interrupt(false).map(|e| e.address),
+        })
+    }
+}
+/// An interrupt OUT report: the report ID first unless it's 0 (no report IDs).
+pub fn output_report_packet(report_id: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 1);
+    if report_id != 0 {
+        packet.push(report_id);
+    }
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// A claimed HID interface. Control requests use the `AsyncDevice` default timeout.
+pub struct HidDevice {
+    device: AsyncDevice,
+    interface: HidInterface,
+    report_descriptor: Vec<u8>,
+}
+impl HidDevice {
+    /// Detaches the kernel HID driver (reattached when the handle is dropped), claims
+    /// `interface` and reads its report descriptor.
+    pub async fn open(mut device: AsyncDevice, interface: u8) -> Result<HidDevice, Error> {
+        let config = ParsedConfig::from(&device.device().active_config_descriptor()?);
+        let interface = HidInterface::from_config(&config, interface)?;
+        {
+            let mut detached = device
+                .handle_mut_async()
+                .await
+                .detach_kernel_driver_guard(interface.interface, true)?;
+            detached.claim_interface(interface.interface)?;
+            // The handle reattaches the driver once it's dropped
+            detached.forget();
+        }
+        let mut report_descriptor = vec![0_u8; interface.report_descriptor_len.into()];
+        let len = device
+            .get_hid_report_descriptor(interface.interface, &mut report_descriptor)
+            .await?;
+        report_descriptor.truncate(len);
+        Ok(HidDevice {
+            device,
+            interface,
+            report_descriptor,
+        })
+    }
+    pub fn interface(&self) -> &HidInterface {
+        &self.interface
+    }
+    pub fn report_descriptor(&self) -> &[u8] {
+        &self.report_descriptor
+    }
+    pub fn device(&self) -> &AsyncDevice {
+        &self.device
+    }
+    pub fn into_device(self) -> AsyncDevice {
+        self.device
+    }
+    /// `GET_REPORT` into `buf`. Returns the report length.
+    pub async fn get_report(
+        &self,
+        report_type: ReportType,
+        report_id: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let setup = get_report_setup(
+            self.interface.interface,
+            report_type,
+            report_id,
+            control_len(buf.len())?,
+        );
+        self.device
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                buf,
+                self.device.default_timeout(),
+            )
+            .await
+    }
+    /// `SET_REPORT` with `data` (including the report ID byte if the device uses them).
+    pub async fn set_report(
+        &self,
+        report_type: ReportType,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let setup = set_report_setup(
+            self.interface.interface,
+            report_type,
+            report_id,
+            control_len(data.len())?,
+        );
+        self.device
+            .control_write(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                data,
+                self.device.default_timeout(),
+            )
+            .await
+    }
+    pub async fn set_idle(&self, duration: u8, report_id: u8) -> Result<(), Error> {
+        let setup = set_idle_setup(self.interface.interface, duration, report_id);
+        self.device
+            .control_write(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &[],
+                self.device.default_timeout(),
+            )
+            .await?;
+        Ok(())
+    }
+    /// Reads one report from the interrupt IN endpoint. `buf` should hold the max packet size.
+    pub async fn read_input_report(
+        &mut self,
+        buf: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.device
+            .interrupt_read(self.interface.interrupt_in.0, buf, timeout)
+            .await
+    }
+    /// Sends an output report on the interrupt OUT endpoint or with `SET_REPORT` if there's
+    /// none. `data` doesn't include the report ID.
+    pub async fn write_output_report(
+        &mut self,
+        report_id: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let packet = output_report_packet(report_id, data);
+        match self.interface.interrupt_out {
+            Some(endpoint) => {
+                self.device
+                    .interrupt_write(endpoint, &packet, timeout)
+                    .await
+            }
+            None => {
+                self.set_report(ReportType::Output, report_id, &packet)
+                    .await
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::descriptors::parse_config_descriptor;
+    use crate::libusb::error::Error;
+    use crate::libusb::hid::{
+        get_report_setup, output_report_packet, set_idle_setup, set_report_setup, HidInterface,
+        ReportType,
+    };
+
+    /// Keyboard style interface 0 with only an interrupt IN endpoint.
+    const KEYBOARD_CONFIG: [u8; 34] = [
+        9, 0x02, 34, 0, 1, 1, 0, 0xA0, 50, // configuration
+        9, 0x04, 0, 0, 1, 0x03, 0x01, 0x01, 0, // interface 0
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0, // HID descriptor
+        7, 0x05, 0x81, 0x03, 8, 0, 10, // interrupt IN
+    ];
+
+    #[test]
+    pub fn test_hid_interface() {
+        let config = parse_config_descriptor(&KEYBOARD_CONFIG).expect("valid config");
+        assert_eq!(
+            HidInterface::from_config(&config, 0),
+            Ok(HidInterface {
+                interface: 0,
+                report_descriptor_len: 63,
+                interrupt_in: (0x81, 8),
+                interrupt_out: None,
+            })
+        );
+        assert_eq!(HidInterface::from_config(&config, 1), Err(Error::NotFound));
+    }
+    #[test]
+    pub fn test_report_requests() {
+        let setup = get_report_setup(2, ReportType::Feature, 5, 64);
+        assert_eq!(
+            (
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                setup.len
+            ),
+            (0xA1, 0x01, 0x0305, 2, 64)
+        );
+        let setup = set_report_setup(0, ReportType::Output, 0, 1);
+        assert_eq!(
+            (setup.request_type, setup.request, setup.value, setup.len),
+            (0x21, 0x09, 0x0200, 1)
+        );
+        let setup = set_idle_setup(1, 0, 0);
+        assert_eq!(
+            (setup.request_type, setup.request, setup.value, setup.index),
+            (0x21, 0x0A, 0, 1)
+        );
+        assert_eq!(output_report_packet(0, &[0x01]), vec![0x01]);
+        assert_eq!(output_report_packet(4, &[0x01]), vec![4, 0x01]);
+    }
+}
@@ -1,13 +1,465 @@
+use crate::libusb::context::{Capability, Context, ContextInner};
+use crate::libusb::device::Device;
+use crate::libusb::error::Error;
+use core::convert::TryFrom;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::task::Poll;
+use std::time::Duration;
+
+/// A hotplug event delivered to a callback.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 #[repr(i32)]
 pub enum Event {
     DeviceArrived = 1,
     DeviceLeft = 2,
-    Both = 3,
 }
+/// Fails with `Error::InvalidParam` for values libusb doesn't define.
+impl TryFrom<i32> for Event {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Event::DeviceArrived),
+            2 => Ok(Event::DeviceLeft),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+}
+/// The events a hotplug callback is registered for. Combine with `|`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct EventMask(i32);
+impl EventMask {
+    pub const ARRIVED: EventMask = EventMask(Event::DeviceArrived as i32);
+    pub const LEFT: EventMask = EventMask(Event::DeviceLeft as i32);
+    pub const BOTH: EventMask = EventMask(Self::ARRIVED.0 | Self::LEFT.0);
+    pub fn contains(self, event: Event) -> bool {
+        self.0 & event as i32 != 0
+    }
+    pub fn bits(self) -> i32 {
+        self.0
+    }
+}
+impl From<Event> for EventMask {
+    fn from(event: Event) -> Self {
+        EventMask(event as i32)
+    }
+}
+impl core::ops::BitOr for EventMask {
+    type Output = EventMask;
+
+    fn bitor(self, rhs: EventMask) -> EventMask {
+        EventMask(self.0 | rhs.0)
+    }
+}
+impl core::ops::BitOr<Event> for EventMask {
+    type Output = EventMask;
+
+    fn bitor(self, rhs: Event) -> EventMask {
+        self | EventMask::from(rhs)
+    }
+}
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
 pub enum Flags {
     NoFlags = 0,
     Enumerate = 1,
 }
-pub struct CallbackHandle(libusb1_sys::libusb_hotplug_callback_handle);
-impl CallbackHandle {}
+/// What a hotplug callback wants done after handling an event.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Action {
+    Keep,
+    /// Removes the callback. It isn't called again and the closure is dropped.
+    Deregister,
+}
+/// A registered callback. libusb's `user_data` holds one reference, released once the callback
+/// is removed (by returning `Action::Deregister`, panicking or `CallbackHandle::deregister`), and
+/// the `CallbackHandle` holds another.
+pub(crate) struct Registration<F> {
+    /// The registering context, handed to the closure. Weak because libusb's reference to the
+    /// registration is leaked if the context exits with the callback registered.
+    context: Weak<ContextInner>,
+    /// `None` once the callback is removed.
+    closure: Mutex<Option<F>>,
+    /// Set once libusb's reference has been released.
+    released: AtomicBool,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+impl<F> Registration<F> {
+    pub(crate) fn new(context: Weak<ContextInner>, closure: F) -> Arc<Registration<F>> {
+        Arc::new(Registration {
+            context,
+            closure: Mutex::new(Some(closure)),
+            released: AtomicBool::new(false),
+            panic: Mutex::new(None),
+        })
+    }
+    /// The registering context, `None` once it's being dropped.
+    pub(crate) fn context(&self) -> Option<Arc<ContextInner>> {
+        self.context.upgrade()
+    }
+    /// Calls the closure with `call`. A panic is caught (unwinding into libusb isn't allowed),
+    /// kept for `CallbackHandle::take_panic` and deregisters the callback. The closure is dropped
+    /// once the callback is deregistered.
+    pub(crate) fn call(&self, call: impl FnOnce(&mut F) -> Action) -> Action {
+        let mut closure = self.closure.lock().unwrap_or_else(|e| e.into_inner());
+        let action = match closure.as_mut() {
+            Some(f) => match std::panic::catch_unwind(AssertUnwindSafe(|| call(f))) {
+                Ok(action) => action,
+                Err(panic) => {
+                    usb_debug!("hotplug callback panicked, deregistering it");
+                    *self.panic.lock().unwrap_or_else(|e| e.into_inner()) = Some(panic);
+                    Action::Deregister
+                }
+            },
+            None => Action::Deregister,
+        };
+        if action == Action::Deregister {
+            *closure = None;
+        }
+        action
+    }
+    /// Releases libusb's reference, only the first call does anything.
+    /// # Safety
+    /// `ptr` must be the `Arc::into_raw` pointer given to libusb and libusb must no longer be able
+    /// to call the callback. `ptr` might be freed once this returns.
+    pub(crate) unsafe fn release(ptr: *const core::ffi::c_void) {
+        let ptr = ptr as *const Registration<F>;
+        if !(*ptr).released.swap(true, Ordering::SeqCst) {
+            drop(Arc::from_raw(ptr))
+        }
+    }
+}
+/// The type erased part of a `Registration` a `CallbackHandle` needs.
+trait Registered: Send + Sync {
+    fn is_released(&self) -> bool;
+    fn take_panic(&self) -> Option<Box<dyn Any + Send>>;
+    /// Drops the closure, waiting for a call in progress.
+    fn drop_closure(&self);
+}
+impl<F: Send> Registered for Registration<F> {
+    fn is_released(&self) -> bool {
+        self.released.load(Ordering::SeqCst)
+    }
+    fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.panic.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+    fn drop_closure(&self) {
+        drop(
+            self.closure
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take(),
+        )
+    }
+}
+/// Handle to a registered hotplug callback. Dropping the handle leaves the callback registered
+/// (until it returns `Action::Deregister`). Use `deregister` to remove it.
+pub struct CallbackHandle {
+    handle: libusb1_sys::libusb_hotplug_callback_handle,
+    /// The context the callback was registered with.
+    context: Arc<ContextInner>,
+    registration: Arc<dyn Registered>,
+    /// libusb's reference to `registration`.
+    user_data: *const core::ffi::c_void,
+    release: unsafe fn(*const core::ffi::c_void),
+}
+unsafe impl Send for CallbackHandle {}
+unsafe impl Sync for CallbackHandle {}
+impl CallbackHandle {
+    /// `user_data` is the reference to `registration` given to libusb.
+    pub(crate) fn new<F: Send + 'static>(
+        handle: libusb1_sys::libusb_hotplug_callback_handle,
+        context: Arc<ContextInner>,
+        registration: Arc<Registration<F>>,
+        user_data: *const core::ffi::c_void,
+    ) -> CallbackHandle {
+        CallbackHandle {
+            handle,
+            context,
+            registration,
+            user_data,
+            release: Registration::<F>::release,
+        }
+    }
+    pub fn libusb_handle(&self) -> libusb1_sys::libusb_hotplug_callback_handle {
+        self.handle
+    }
+    /// Returns if the callback has already been removed (it returned `Action::Deregister` or
+    /// panicked).
+    pub fn is_finished(&self) -> bool {
+        self.registration.is_released()
+    }
+    /// The panic of the callback, if it panicked (once).
+    pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.registration.take_panic()
+    }
+    /// Deregisters the callback from the context it was registered with and drops the closure.
+    /// Safe to call even if the callback already deregistered itself.
+    pub fn deregister(self) {
+        unsafe {
+            libusb1_sys::libusb_hotplug_deregister_callback(self.context.ptr(), self.handle);
+            // libusb won't call the callback after deregistering so its reference can go.
+            self.registration.drop_closure();
+            (self.release)(self.user_data)
+        }
+    }
+}
+impl core::fmt::Debug for CallbackHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CallbackHandle")
+            .field("handle", &self.handle)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+/// How `PollingHotplug` tells devices apart between snapshots: bus, address, vendor and
+/// product ID. Addresses get reused so the IDs catch most swaps between polls.
+type DeviceKey = (u8, u8, u16, u16);
+fn device_key(device: &Device) -> DeviceKey {
+    let (vendor_id, product_id) = match device.device_descriptor() {
+        Ok(descriptor) => {
+            let id = descriptor.device_identifier();
+            (id.vendor_id.0, id.product_id.0)
+        }
+        Err(_) => (0, 0),
+    };
+    (
+        device.bus_number(),
+        device.device_address(),
+        vendor_id,
+        product_id,
+    )
+}
+/// Returns the keys only in `new` (arrived) and the keys only in `old` (left).
+fn diff_snapshots<K: Copy + Eq + core::hash::Hash>(
+    old: &HashSet<K>,
+    new: &HashSet<K>,
+) -> (Vec<K>, Vec<K>) {
+    (
+        new.difference(old).copied().collect(),
+        old.difference(new).copied().collect(),
+    )
+}
+/// Hotplug for platforms without native support (`Capability::HasHotplug`). A thread diffs
+/// `device_list` snapshots every `interval` and calls `on_event` for each device that arrived
+/// or left. Dropping it stops the thread.
+pub struct PollingHotplug {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+impl PollingHotplug {
+    /// `Flags::Enumerate` reports the devices already connected as arrived first.
+    pub fn start<F>(
+        context: Arc<Context>,
+        interval: Duration,
+        flag: Flags,
+        mut on_event: F,
+    ) -> PollingHotplug
+    where
+        F: FnMut(Device, Event) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let snapshot = move || -> HashMap<DeviceKey, Device> {
+            context
+                .device_list()
+                .iter()
+                .map(|device| (device_key(&device), device))
+                .collect()
+        };
+        let job = move || {
+            let mut devices = snapshot();
+            if flag == Flags::Enumerate {
+                for device in devices.values() {
+                    on_event(device.clone(), Event::DeviceArrived);
+                }
+            }
+            // Sending never happens, the sender being dropped stops the thread
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let new_devices = snapshot();
+                let old_keys = devices.keys().copied().collect();
+                let new_keys = new_devices.keys().copied().collect();
+                let (arrived, left) = diff_snapshots(&old_keys, &new_keys);
+                for key in left {
+                    if let Some(device) = devices.remove(&key) {
+                        on_event(device, Event::DeviceLeft);
+                    }
+                }
+                for key in arrived {
+                    if let Some(device) = new_devices.get(&key) {
+                        on_event(device.clone(), Event::DeviceArrived);
+                    }
+                }
+                devices = new_devices;
+            }
+        };
+        PollingHotplug {
+            stop: Some(stop),
+            thread: Some(std::thread::spawn(job)),
+        }
+    }
+}
+impl Drop for PollingHotplug {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            // A panic in `on_event` already ended the thread
+            let _ = thread.join();
+        }
+    }
+}
+impl core::fmt::Debug for PollingHotplug {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PollingHotplug").finish()
+    }
+}
+
+#[derive(Default)]
+struct StreamShared {
+    events: Mutex<VecDeque<(Device, Event)>>,
+    waker: AtomicWaker,
+}
+impl StreamShared {
+    fn push(&self, device: Device, event: Event) {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_back((device, event));
+        self.waker.wake();
+    }
+}
+enum Source {
+    Native(Option<CallbackHandle>),
+    /// Only kept to be dropped with the stream, which stops the thread.
+    Polling {
+        _poller: PollingHotplug,
+    },
+}
+/// `Stream` of devices arriving and leaving. Uses native hotplug when libusb has it and
+/// `PollingHotplug` otherwise, so the items are the same on every platform. Native hotplug
+/// callbacks only run while the context's events are handled (like by an `AsyncContext`).
+pub struct HotplugStream {
+    shared: Arc<StreamShared>,
+    source: Source,
+}
+impl HotplugStream {
+    /// `poll_interval` is only used when polling.
+    pub fn new(
+        context: Arc<Context>,
+        flag: Flags,
+        poll_interval: Duration,
+    ) -> Result<HotplugStream, Error> {
+        let shared = Arc::new(StreamShared::default());
+        let source = if context.has_capability(Capability::HasHotplug) {
+            let weak = Arc::downgrade(&shared);
+            let handle = context.hotplug_register_callback(
+                move |_, device, event| match weak.upgrade() {
+                    Some(shared) => {
+                        shared.push(device.clone(), event);
+                        Action::Keep
+                    }
+                    None => Action::Deregister,
+                },
+                EventMask::BOTH,
+                flag,
+                None,
+                None,
+                None,
+            )?;
+            Source::Native(Some(handle))
+        } else {
+            let job_shared = shared.clone();
+            Source::Polling {
+                _poller: PollingHotplug::start(
+                    context,
+                    poll_interval,
+                    flag,
+                    move |device, event| job_shared.push(device, event),
+                ),
+            }
+        };
+        Ok(HotplugStream { shared, source })
+    }
+    /// Returns if the stream is polling instead of using native hotplug.
+    pub fn is_polling(&self) -> bool {
+        matches!(self.source, Source::Polling { .. })
+    }
+}
+impl Stream for HotplugStream {
+    type Item = (Device, Event);
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.shared.waker.register(cx.waker());
+        match self
+            .shared
+            .events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front()
+        {
+            Some(item) => Poll::Ready(Some(item)),
+            None => Poll::Pending,
+        }
+    }
+}
+impl Drop for HotplugStream {
+    fn drop(&mut self) {
+        if let Source::Native(handle) = &mut self.source {
+            if let Some(handle) = handle.take() {
+                handle.deregister();
+            }
+        }
+    }
+}
+impl core::fmt::Debug for HotplugStream {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HotplugStream")
+            .field("polling", &self.is_polling())
+            .finish()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::error::Error;
+    use crate::libusb::hotplug::{diff_snapshots, Event, EventMask};
+    use core::convert::TryFrom;
+    use std::collections::HashSet;
+
+    #[test]
+    pub fn test_events() {
+        assert_eq!(Event::try_from(1), Ok(Event::DeviceArrived));
+        assert_eq!(Event::try_from(2), Ok(Event::DeviceLeft));
+        assert_eq!(Event::try_from(3), Err(Error::InvalidParam));
+        assert_eq!(EventMask::ARRIVED | Event::DeviceLeft, EventMask::BOTH);
+        assert_eq!(EventMask::BOTH.bits(), 3);
+        assert!(EventMask::BOTH.contains(Event::DeviceLeft));
+        assert!(!EventMask::from(Event::DeviceArrived).contains(Event::DeviceLeft));
+    }
+
+    #[test]
+    pub fn test_diff_snapshots() {
+        let old: HashSet<_> = vec![(1, 2, 0x0A12, 1), (1, 3, 0x046D, 2), (2, 1, 0x1D6B, 3)]
+            .into_iter()
+            .collect();
+        // (1, 3) was swapped for another device with the same address
+        let new: HashSet<_> = vec![(1, 2, 0x0A12, 1), (1, 3, 0x0781, 4), (2, 4, 0x0A12, 1)]
+            .into_iter()
+            .collect();
+        let (mut arrived, mut left) = diff_snapshots(&old, &new);
+        arrived.sort_unstable();
+        left.sort_unstable();
+        assert_eq!(arrived, vec![(1, 3, 0x0781, 4), (2, 4, 0x0A12, 1)]);
+        assert_eq!(left, vec![(1, 3, 0x046D, 2), (2, 1, 0x1D6B, 3)]);
+        let (arrived, left) = diff_snapshots(&new, &new);
+        assert!(arrived.is_empty() && left.is_empty());
+    }
+}
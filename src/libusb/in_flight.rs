@@ -0,0 +1,214 @@
+//! Registry of the transfers an `AsyncDevice` has in flight, for `AsyncDevice::cancel_all` and
+//! `AsyncDevice::quiesce`.
+//!
+//! A transfer is added right before it's submitted and removed by the completion callback before
+//! it's marked inactive. An active `SafeTransfer` can't be freed (dropping it waits for the
+//! completion or leaves the freeing to the callback, after the removal) so every pointer in the
+//! registry is valid while the lock is held.
+use crate::libusb::context::{timeval_from_duration, ContextInner};
+use crate::libusb::error::{from_libusb, Error};
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use libusb1_sys::constants::LIBUSB_ERROR_NOT_FOUND;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// Longest a `drain` handles events (or sleeps) at a time.
+const DRAIN_SLICE: Duration = Duration::from_millis(100);
+/// How often `drain` checks the registry when it can't handle the events.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Default)]
+struct State {
+    transfers: Vec<NonNull<libusb1_sys::libusb_transfer>>,
+    /// `quiesce` futures waiting for `transfers` to be empty.
+    waiters: Vec<Waker>,
+}
+// # Safety
+// The transfer pointers are only used while the lock is held (see the module docs).
+unsafe impl Send for State {}
+
+#[derive(Clone, Default)]
+pub(crate) struct InFlight(Arc<Mutex<State>>);
+impl InFlight {
+    pub(crate) fn new() -> InFlight {
+        InFlight::default()
+    }
+    pub(crate) fn same_registry(&self, other: &InFlight) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+    fn state(&self) -> MutexGuard<'_, State> {
+        // The state is never left half updated so a poisoned lock is still fine to use.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.state().transfers.len()
+    }
+    pub(crate) fn insert(&self, transfer: NonNull<libusb1_sys::libusb_transfer>) {
+        self.state().transfers.push(transfer)
+    }
+    /// Removes `transfer` (if it's there) and wakes the `quiesce` futures if it was the last one.
+    pub(crate) fn remove(&self, transfer: NonNull<libusb1_sys::libusb_transfer>) {
+        let mut state = self.state();
+        if let Some(i) = state.transfers.iter().position(|&t| t == transfer) {
+            state.transfers.swap_remove(i);
+        }
+        if state.transfers.is_empty() {
+            state.waiters.drain(..).for_each(Waker::wake);
+        }
+    }
+    /// Requests every transfer to be cancelled. Returns how many were, the ones already
+    /// completing (or not submitted yet) aren't counted. A failed cancel doesn't stop the others,
+    /// the first error is returned once they were all tried.
+    pub(crate) fn cancel_all(&self) -> Result<usize, Error> {
+        // # Safety
+        // Registered transfers are valid while the lock is held.
+        self.cancel_each(|transfer| unsafe {
+            libusb1_sys::libusb_cancel_transfer(transfer.as_ptr())
+        })
+    }
+    fn cancel_each<F>(&self, mut cancel: F) -> Result<usize, Error>
+    where
+        F: FnMut(NonNull<libusb1_sys::libusb_transfer>) -> i32,
+    {
+        let state = self.state();
+        let mut cancelled = 0;
+        let mut first_error = None;
+        for &transfer in &state.transfers {
+            match cancel(transfer) {
+                0 => cancelled += 1,
+                LIBUSB_ERROR_NOT_FOUND => (),
+                err => {
+                    first_error.get_or_insert(from_libusb(err));
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(cancelled),
+        }
+    }
+    /// Waits up to `timeout` for the registry to be empty, handling the events of `context`
+    /// meanwhile (without one another thread has to). Returns if it's empty.
+    pub(crate) fn drain(&self, context: Option<&ContextInner>, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.len() > 0 {
+            let slice = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if remaining > Duration::from_secs(0) => remaining.min(DRAIN_SLICE),
+                _ => return false,
+            };
+            let handled = context.map(|context| {
+                let timeval = timeval_from_duration(slice).expect("fits");
+                // # Safety
+                // The context outlives the call.
+                unsafe {
+                    libusb1_sys::libusb_handle_events_timeout_completed(
+                        context.ptr(),
+                        &timeval,
+                        core::ptr::null_mut(),
+                    )
+                }
+            });
+            // Another thread handles the events (or this one already is, further up the stack)
+            if handled != Some(0) {
+                std::thread::sleep(slice.min(DRAIN_POLL_INTERVAL));
+            }
+        }
+        true
+    }
+    /// Resolves once there are no transfers in flight.
+    pub(crate) fn quiesce(&self) -> Quiesce<'_> {
+        Quiesce(self)
+    }
+}
+pub(crate) struct Quiesce<'a>(&'a InFlight);
+impl<'a> Future for Quiesce<'a> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.0.state();
+        if state.transfers.is_empty() {
+            Poll::Ready(())
+        } else {
+            if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::error::Error;
+    use crate::libusb::in_flight::InFlight;
+    use core::ptr::NonNull;
+    use core::time::Duration;
+    use futures_util::FutureExt;
+    use libusb1_sys::constants::{LIBUSB_ERROR_IO, LIBUSB_ERROR_NOT_FOUND, LIBUSB_ERROR_NO_DEVICE};
+
+    #[test]
+    pub fn test_quiesce() {
+        let in_flight = InFlight::new();
+        assert!(in_flight.quiesce().now_or_never().is_some());
+        // Never dereferenced, only compared
+        let first = NonNull::<libusb1_sys::libusb_transfer>::dangling();
+        let second = NonNull::new(first.as_ptr().wrapping_add(1)).unwrap();
+        in_flight.insert(first);
+        in_flight.insert(second);
+        assert_eq!(in_flight.len(), 2);
+        let mut quiesce = Box::pin(in_flight.quiesce());
+        assert!((&mut quiesce).now_or_never().is_none());
+        in_flight.remove(first);
+        in_flight.remove(first);
+        assert!((&mut quiesce).now_or_never().is_none());
+        in_flight.remove(second);
+        assert!(quiesce.now_or_never().is_some());
+        assert!(in_flight.same_registry(&in_flight.clone()));
+    }
+    #[test]
+    pub fn test_cancel_each_keeps_going() {
+        let in_flight = InFlight::new();
+        let first = NonNull::<libusb1_sys::libusb_transfer>::dangling();
+        let transfers = [
+            first,
+            NonNull::new(first.as_ptr().wrapping_add(1)).unwrap(),
+            NonNull::new(first.as_ptr().wrapping_add(2)).unwrap(),
+            NonNull::new(first.as_ptr().wrapping_add(3)).unwrap(),
+        ];
+        transfers.iter().for_each(|&t| in_flight.insert(t));
+        let results = [
+            LIBUSB_ERROR_IO,
+            0,
+            LIBUSB_ERROR_NO_DEVICE,
+            LIBUSB_ERROR_NOT_FOUND,
+        ];
+        let mut tried = Vec::new();
+        let cancelled = in_flight.cancel_each(|transfer| {
+            tried.push(transfer);
+            results[transfers.iter().position(|&t| t == transfer).unwrap()]
+        });
+        // Every transfer was tried, the first error is the one returned
+        assert_eq!(cancelled, Err(Error::Io));
+        assert_eq!(tried.len(), 4);
+        assert_eq!(in_flight.cancel_each(|_| 0), Ok(4));
+        assert_eq!(in_flight.cancel_each(|_| LIBUSB_ERROR_NOT_FOUND), Ok(0));
+    }
+    #[test]
+    pub fn test_drain_timeout() {
+        let in_flight = InFlight::new();
+        assert!(in_flight.drain(None, Duration::from_secs(0)));
+        let transfer = NonNull::<libusb1_sys::libusb_transfer>::dangling();
+        in_flight.insert(transfer);
+        assert!(!in_flight.drain(None, Duration::from_millis(20)));
+        // Completed from another thread, like the event thread would
+        let remover = in_flight.clone();
+        let completer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            remover.remove(NonNull::dangling());
+        });
+        assert!(in_flight.drain(None, Duration::from_secs(5)));
+        completer.join().expect("completer panicked");
+    }
+}
@@ -1,4 +1,6 @@
-use crate::libusb::endpoint_descriptor::EndpointDescriptors;
+use crate::libusb::control::Direction;
+use crate::libusb::endpoint_descriptor::{EndpointDescriptor, EndpointDescriptors};
+use crate::libusb::transfer::TransferType;
 
 #[derive(Copy, Clone)]
 pub struct Interfaces<'a>(pub &'a [libusb1_sys::libusb_interface]);
@@ -12,14 +14,27 @@ impl<'a> Interfaces<'a> {
         Interfaces(core::slice::from_raw_parts(ptr, len))
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = Interface<'a>> {
+    pub fn iter(&self) -> InterfacesIter<'a> {
         self.0.iter().map(Interface)
     }
 }
+pub type InterfacesIter<'a> = core::iter::Map<
+    core::slice::Iter<'a, libusb1_sys::libusb_interface>,
+    fn(&'a libusb1_sys::libusb_interface) -> Interface<'a>,
+>;
+impl<'a> IntoIterator for Interfaces<'a> {
+    type Item = Interface<'a>;
+    type IntoIter = InterfacesIter<'a>;
 
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Copy, Clone)]
 pub struct Interface<'a>(pub &'a libusb1_sys::libusb_interface);
 impl<'a> Interface<'a> {
-    pub fn descriptors(&self) -> InterfaceDescriptors<'_> {
+    pub fn descriptors(&self) -> InterfaceDescriptors<'a> {
         let ptr = self.0.altsetting;
         let len = self.0.num_altsetting as usize;
         InterfaceDescriptors(unsafe { core::slice::from_raw_parts(ptr, len) })
@@ -29,10 +44,22 @@ impl<'a> Interface<'a> {
 #[derive(Copy, Clone)]
 pub struct InterfaceDescriptors<'a>(pub &'a [libusb1_sys::libusb_interface_descriptor]);
 impl<'a> InterfaceDescriptors<'a> {
-    pub fn iter(&self) -> impl Iterator<Item = InterfaceDescriptor<'_>> {
+    pub fn iter(&self) -> InterfaceDescriptorsIter<'a> {
         self.0.iter().map(InterfaceDescriptor)
     }
 }
+pub type InterfaceDescriptorsIter<'a> = core::iter::Map<
+    core::slice::Iter<'a, libusb1_sys::libusb_interface_descriptor>,
+    fn(&'a libusb1_sys::libusb_interface_descriptor) -> InterfaceDescriptor<'a>,
+>;
+impl<'a> IntoIterator for InterfaceDescriptors<'a> {
+    type Item = InterfaceDescriptor<'a>;
+    type IntoIter = InterfaceDescriptorsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
 #[derive(Copy, Clone)]
 pub struct InterfaceDescriptor<'a>(pub &'a libusb1_sys::libusb_interface_descriptor);
 
@@ -76,18 +103,29 @@ impl<'a> InterfaceDescriptor<'a> {
     }
 
     /// Returns an iterator over the interface's endpoint descriptors.
-    pub fn endpoint_descriptors(&self) -> EndpointDescriptors<'_> {
+    pub fn endpoint_descriptors(&self) -> EndpointDescriptors<'a> {
         let endpoints =
             unsafe { core::slice::from_raw_parts(self.0.endpoint, self.0.bNumEndpoints as usize) };
 
         EndpointDescriptors(endpoints)
     }
 
+    /// Returns the first endpoint with the given direction and transfer type.
+    pub fn find_endpoint(
+        &self,
+        direction: Direction,
+        transfer_type: TransferType,
+    ) -> Option<EndpointDescriptor<'a>> {
+        self.endpoint_descriptors()
+            .into_iter()
+            .find(|e| e.direction() == direction && e.transfer_type() == transfer_type)
+    }
+
     /// Returns the unknown 'extra' bytes that libusb does not understand.
     pub fn extra(&self) -> Option<&[u8]> {
         unsafe {
-            match (*self.0).extra_length {
-                len if len > 0 => Some(core::slice::from_raw_parts((*self.0).extra, len as usize)),
+            match self.0.extra_length {
+                len if len > 0 => Some(core::slice::from_raw_parts(self.0.extra, len as usize)),
                 _ => None,
             }
         }
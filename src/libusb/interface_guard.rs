@@ -0,0 +1,219 @@
+//! Guards that release a claimed interface (or reattach a kernel driver) when dropped.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::device_handle::DeviceHandle;
+use crate::libusb::error::Error;
+
+/// Detaches the kernel driver from `interface` if one is bound. Returns if it was detached.
+fn detach_if_active(handle: &mut DeviceHandle, interface: u8) -> Result<bool, Error> {
+    match handle.kernel_driver_active(interface) {
+        Ok(true) => handle.detach_kernel_driver(interface).map(|_| true),
+        Ok(false) => Ok(false),
+        // Only Linux has kernel drivers to detach
+        Err(Error::NotSupported) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+/// Releases `interface` (if still claimed) and reattaches the kernel driver. Errors are ignored.
+fn release_on_drop(handle: &mut DeviceHandle, interface: u8, reattach: bool) {
+    let _ = handle.release_interface(interface);
+    if reattach {
+        let _ = handle.attach_kernel_driver(interface);
+    }
+}
+
+/// A claimed interface. Derefs to the `DeviceHandle` and releases the interface on drop (even
+/// if it was already claimed before the guard was made).
+#[derive(Debug)]
+pub struct InterfaceGuard<'h> {
+    handle: &'h mut DeviceHandle,
+    interface: u8,
+    reattach_kernel_driver: bool,
+}
+impl<'h> InterfaceGuard<'h> {
+    pub(crate) fn claim(
+        handle: &'h mut DeviceHandle,
+        interface: u8,
+        detach_kernel_driver: bool,
+    ) -> Result<InterfaceGuard<'h>, Error> {
+        let reattach_kernel_driver = detach_kernel_driver && detach_if_active(handle, interface)?;
+        if let Err(e) = handle.claim_interface(interface) {
+            if reattach_kernel_driver {
+                let _ = handle.attach_kernel_driver(interface);
+            }
+            return Err(e.into());
+        }
+        Ok(InterfaceGuard {
+            handle,
+            interface,
+            reattach_kernel_driver,
+        })
+    }
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+    /// Like dropping the guard but returns the errors.
+    pub fn release(mut self) -> Result<(), Error> {
+        self.handle.release_interface(self.interface)?;
+        if core::mem::replace(&mut self.reattach_kernel_driver, false) {
+            self.handle.attach_kernel_driver(self.interface)?;
+        }
+        Ok(())
+    }
+}
+impl<'h> core::ops::Deref for InterfaceGuard<'h> {
+    type Target = DeviceHandle;
+
+    fn deref(&self) -> &Self::Target {
+        self.handle
+    }
+}
+impl<'h> core::ops::DerefMut for InterfaceGuard<'h> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.handle
+    }
+}
+impl<'h> Drop for InterfaceGuard<'h> {
+    fn drop(&mut self) {
+        release_on_drop(self.handle, self.interface, self.reattach_kernel_driver)
+    }
+}
+
+/// A detached kernel driver. Derefs to the `DeviceHandle` and reattaches the driver on drop.
+#[derive(Debug)]
+pub struct DetachedDriverGuard<'h> {
+    handle: &'h mut DeviceHandle,
+    interface: u8,
+    /// `false` if no driver was bound (or detaching isn't supported), then there's nothing to
+    /// reattach.
+    detached: bool,
+}
+impl<'h> DetachedDriverGuard<'h> {
+    pub(crate) fn detach(
+        handle: &'h mut DeviceHandle,
+        interface: u8,
+        allow_unsupported: bool,
+    ) -> Result<DetachedDriverGuard<'h>, Error> {
+        let detached =
+            match handle
+                .kernel_driver_active(interface)
+                .and_then(|active| match active {
+                    true => handle.detach_kernel_driver(interface).map(|_| true),
+                    false => Ok(false),
+                }) {
+                Ok(detached) => detached,
+                Err(Error::NotSupported) if allow_unsupported => false,
+                Err(e) => return Err(e),
+            };
+        Ok(DetachedDriverGuard {
+            handle,
+            interface,
+            detached,
+        })
+    }
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+    /// If a kernel driver was detached by the guard.
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+    /// Like dropping the guard but returns the error.
+    pub fn reattach(mut self) -> Result<(), Error> {
+        if core::mem::replace(&mut self.detached, false) {
+            self.handle.attach_kernel_driver(self.interface)?;
+        }
+        Ok(())
+    }
+    /// Drops the guard without reattaching. The driver stays detached until
+    /// `attach_kernel_driver` is called or the `DeviceHandle` is dropped.
+    pub fn forget(mut self) {
+        self.detached = false;
+    }
+}
+impl<'h> core::ops::Deref for DetachedDriverGuard<'h> {
+    type Target = DeviceHandle;
+
+    fn deref(&self) -> &Self::Target {
+        self.handle
+    }
+}
+impl<'h> core::ops::DerefMut for DetachedDriverGuard<'h> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.handle
+    }
+}
+impl<'h> Drop for DetachedDriverGuard<'h> {
+    fn drop(&mut self) {
+        if self.detached {
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+/// `InterfaceGuard` for an `AsyncDevice`. Use `release` to release the interface on a blocking
+/// task, dropping the guard releases it on the current thread.
+pub struct AsyncInterfaceGuard<'d> {
+    device: &'d mut AsyncDevice,
+    interface: u8,
+    reattach_kernel_driver: bool,
+}
+impl<'d> AsyncInterfaceGuard<'d> {
+    pub(crate) async fn claim(
+        device: &'d mut AsyncDevice,
+        interface: u8,
+        detach_kernel_driver: bool,
+    ) -> Result<AsyncInterfaceGuard<'d>, Error> {
+        let reattach_kernel_driver =
+            detach_kernel_driver && detach_if_active(device.handle_mut_async().await, interface)?;
+        if let Err(e) = device.claim_interface(interface).await {
+            if reattach_kernel_driver {
+                let _ = device
+                    .handle_mut_async()
+                    .await
+                    .attach_kernel_driver(interface);
+            }
+            return Err(e);
+        }
+        Ok(AsyncInterfaceGuard {
+            device,
+            interface,
+            reattach_kernel_driver,
+        })
+    }
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+    /// Releases the interface on a blocking task and reattaches the kernel driver if it was
+    /// detached.
+    pub async fn release(mut self) -> Result<(), Error> {
+        self.device.release_interface(self.interface).await?;
+        if core::mem::replace(&mut self.reattach_kernel_driver, false) {
+            self.device
+                .handle_mut_async()
+                .await
+                .attach_kernel_driver(self.interface)?;
+        }
+        Ok(())
+    }
+}
+impl<'d> core::ops::Deref for AsyncInterfaceGuard<'d> {
+    type Target = AsyncDevice;
+
+    fn deref(&self) -> &Self::Target {
+        self.device
+    }
+}
+impl<'d> core::ops::DerefMut for AsyncInterfaceGuard<'d> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.device
+    }
+}
+impl<'d> Drop for AsyncInterfaceGuard<'d> {
+    fn drop(&mut self) {
+        release_on_drop(
+            self.device.handle_mut(),
+            self.interface,
+            self.reattach_kernel_driver,
+        )
+    }
+}
@@ -28,34 +28,54 @@ impl ClaimedInterfaces {
     pub fn none_claimed(&self) -> bool {
         self.0.iter().all(|&i| i == 0)
     }
+    pub fn iter(&self) -> ClaimedIter<'_> {
+        ClaimedIter {
+            bytes: &self.0,
+            byte_index: 0,
+            bits: self.0[0],
+        }
+    }
+    pub fn drain(&mut self) -> Drain<'_> {
+        Drain(self)
+    }
 }
-impl Iterator for ClaimedInterfaces {
+/// Claimed interfaces in ascending order. Doesn't change the `ClaimedInterfaces`.
+#[derive(Clone, Debug)]
+pub struct ClaimedIter<'a> {
+    bytes: &'a [u8; INTERFACES_BYTE_LEN],
+    byte_index: usize,
+    /// Bits of `bytes[byte_index]` not yielded yet.
+    bits: u8,
+}
+impl<'a> Iterator for ClaimedIter<'a> {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut index: u8 = 0;
-        for b in self.0.iter_mut() {
-            if *b == 0 {
-                if index >= 0xFF - 8 {
-                    break;
-                }
-                index += 8;
-                continue;
-            }
-            // Naive CLZ
-            for i in (0..8).rev() {
-                if *b > (1 << i) {
-                    *b -= 1 << i;
-                    return Some(index + i);
-                }
-            }
+        while self.bits == 0 {
+            self.byte_index += 1;
+            self.bits = *self.bytes.get(self.byte_index)?;
         }
-        None
+        let bit = self.bits.trailing_zeros() as u8;
+        // Clear the lowest set bit
+        self.bits &= self.bits - 1;
+        Some(self.byte_index as u8 * 8 + bit)
+    }
+}
+/// Releases (in the bitmap) and yields the claimed interfaces in ascending order.
+#[derive(Debug)]
+pub struct Drain<'a>(&'a mut ClaimedInterfaces);
+impl<'a> Iterator for Drain<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let interface = self.0.iter().next()?;
+        self.0.release(interface);
+        Some(interface)
     }
 }
 #[cfg(test)]
 mod tests {
-    use crate::libusb::interfaces::ClaimedInterfaces;
+    use crate::libusb::interfaces::{ClaimedInterfaces, INTERFACES_MAX};
 
     #[test]
     pub fn test_clean_claim_interfaces() {
@@ -76,7 +96,36 @@ mod tests {
         assert!(!c.is_claimed(5));
         assert!(!c.is_claimed(7));
         assert!(c.is_claimed(6));
-        assert_eq!(c.next(), Some(6));
+        assert_eq!(c.iter().next(), Some(6));
+        assert!(c.is_claimed(6));
+        assert_eq!(c.drain().next(), Some(6));
         assert!(!c.is_claimed(6))
     }
+    #[test]
+    pub fn test_claim_each_interface() {
+        for interface in 0..=INTERFACES_MAX {
+            let mut c = ClaimedInterfaces::new();
+            c.claim(interface);
+            assert!(c.is_claimed(interface));
+            assert_eq!(c.iter().collect::<Vec<_>>(), vec![interface]);
+            assert_eq!(c.drain().collect::<Vec<_>>(), vec![interface]);
+            assert!(c.none_claimed());
+        }
+    }
+    #[test]
+    pub fn test_iter_order() {
+        let mut c = ClaimedInterfaces::new();
+        for interface in (0..=INTERFACES_MAX).rev() {
+            c.claim(interface);
+        }
+        assert!((0..=INTERFACES_MAX).eq(c.iter()));
+        let odd = (0..=INTERFACES_MAX).filter(|i| i % 2 == 1);
+        for interface in (0..=INTERFACES_MAX).step_by(2) {
+            c.release(interface);
+        }
+        assert!(odd.clone().eq(c.iter()));
+        assert!(odd.eq(c.drain()));
+        assert!(c.none_claimed());
+        assert_eq!(c.iter().next(), None);
+    }
 }
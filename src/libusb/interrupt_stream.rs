@@ -0,0 +1,79 @@
+//! Interrupt IN endpoints (HID reports, HCI events, ...) as a `Stream` of packets.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::error::Error;
+use crate::libusb::safe_transfer::SafeTransfer;
+use crate::libusb::transfer::TransferType;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+
+/// Keeps an interrupt IN transfer submitted and yields every packet it receives. Timeouts just
+/// resubmit the transfer. Ends (`None`) once the device is gone (`Error::NoDevice`), other
+/// errors are yielded and then the transfer is resubmitted.
+pub struct InterruptStream<'d> {
+    device: &'d AsyncDevice,
+    transfer: SafeTransfer<Vec<u8>>,
+    in_flight: bool,
+    done: bool,
+}
+impl<'d> InterruptStream<'d> {
+    pub fn new(device: &'d AsyncDevice, endpoint: u8, packet_size: usize) -> InterruptStream<'d> {
+        let mut transfer = SafeTransfer::from_buf(vec![0_u8; packet_size]);
+        transfer.set_type(TransferType::Interrupt);
+        transfer.set_endpoint(endpoint);
+        InterruptStream {
+            device,
+            transfer,
+            in_flight: false,
+            done: false,
+        }
+    }
+    /// Per transfer timeout. Zero (the default) never times out.
+    pub fn set_timeout(&mut self, timeout: core::time::Duration) {
+        self.transfer.set_timeout(timeout)
+    }
+    pub fn get_timeout(&self) -> core::time::Duration {
+        self.transfer.get_timeout()
+    }
+    pub fn endpoint(&self) -> u8 {
+        self.transfer.get_endpoint()
+    }
+    fn poll_packet(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Vec<u8>, Error>>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+            if !self.in_flight {
+                match self.transfer.start_read(self.device) {
+                    Ok(()) => self.in_flight = true,
+                    Err(e) => return Poll::Ready(self.end_on_no_device(e)),
+                }
+            }
+            let result = match self.transfer.poll_completion(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.in_flight = false;
+            match result {
+                Ok(len) => return Poll::Ready(Some(Ok(self.transfer.buf_ref()[..len].to_vec()))),
+                Err(Error::Timeout) => (),
+                Err(e) => return Poll::Ready(self.end_on_no_device(e)),
+            }
+        }
+    }
+    fn end_on_no_device(&mut self, error: Error) -> Option<Result<Vec<u8>, Error>> {
+        if error == Error::NoDevice {
+            self.done = true;
+            None
+        } else {
+            Some(Err(error))
+        }
+    }
+}
+impl<'d> Stream for InterruptStream<'d> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_packet(cx)
+    }
+}
@@ -0,0 +1,116 @@
+//! Routes libusb's log messages into Rust closures (`libusb_set_log_cb`).
+//!
+//! `libusb_set_log_cb` has no user data pointer, so the closures live in a global registry keyed
+//! by context. The registry hands out `Arc` clones to the logging thread, so replacing a callback
+//! while another thread is inside it only frees the old closure once that call returns.
+use crate::libusb::context::LogLevel;
+use std::sync::{Arc, Mutex};
+
+pub type LogCallback = dyn Fn(LogLevel, &str) + Send + Sync + 'static;
+
+struct Registry {
+    global: Option<Arc<LogCallback>>,
+    /// (`libusb_context` address, callback)
+    contexts: Vec<(usize, Arc<LogCallback>)>,
+}
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    global: None,
+    contexts: Vec::new(),
+});
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    // A panic while holding the lock can't leave the registry half updated.
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+/// Returns the previous callback (if any) so it gets dropped outside of the lock.
+pub(crate) fn set(
+    context: *mut libusb1_sys::libusb_context,
+    global: bool,
+    callback: Option<Arc<LogCallback>>,
+) -> Option<Arc<LogCallback>> {
+    let mut registry = registry();
+    if global {
+        core::mem::replace(&mut registry.global, callback)
+    } else {
+        let key = context as usize;
+        let old = registry
+            .contexts
+            .iter()
+            .position(|(c, _)| *c == key)
+            .map(|i| registry.contexts.swap_remove(i).1);
+        if let Some(callback) = callback {
+            registry.contexts.push((key, callback));
+        }
+        old
+    }
+}
+/// libusb calls the global callback with a null context and context callbacks with the real
+/// context pointer. The default context is registered under null (that's all `Context` knows
+/// about it) so it's the fallback for unknown contexts.
+fn get(context: *mut libusb1_sys::libusb_context) -> Option<Arc<LogCallback>> {
+    let registry = registry();
+    if context.is_null() {
+        return registry.global.clone();
+    }
+    let find = |key: usize| {
+        registry
+            .contexts
+            .iter()
+            .find(|(c, _)| *c == key)
+            .map(|(_, callback)| callback.clone())
+    };
+    find(context as usize).or_else(|| find(0))
+}
+pub(crate) extern "system" fn system_callback(
+    context: *mut libusb1_sys::libusb_context,
+    level: libc::c_int,
+    message: *mut core::ffi::c_void,
+) {
+    // Never unwind into libusb. Panics from the user's callback are dropped.
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if message.is_null() {
+            return;
+        }
+        let callback = match get(context) {
+            Some(callback) => callback,
+            None => return,
+        };
+        let message = unsafe { std::ffi::CStr::from_ptr(message as *const libc::c_char) };
+        let message = message.to_string_lossy();
+        callback(
+            LogLevel::from_i32(level).unwrap_or(LogLevel::Debug),
+            message.trim_end_matches('\n'),
+        );
+    }));
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::context::LogLevel;
+    use crate::libusb::log_callback::{set, system_callback};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    pub fn test_callback_routing_and_panics() {
+        // Fake context pointer, never dereferenced.
+        let context = 0x1000 as *mut libusb1_sys::libusb_context;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        set(
+            context,
+            false,
+            Some(Arc::new(move |level, message: &str| {
+                assert_eq!(level, LogLevel::Warning);
+                assert_eq!(message, "libusb: warning");
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+        );
+        let message = b"libusb: warning\n\0";
+        system_callback(context, 2, message.as_ptr() as *mut _);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        set(context, false, Some(Arc::new(|_, _: &str| panic!("boom"))));
+        // Must not unwind out of the extern fn
+        system_callback(context, 2, message.as_ptr() as *mut _);
+        set(context, false, None);
+    }
+}
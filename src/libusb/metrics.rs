@@ -0,0 +1,301 @@
+//! Transfer counters of an `AsyncDevice` (`AsyncDevice::metrics`).
+//!
+//! Every transfer submitted through a `SafeTransfer` is counted on submission and again by the
+//! libusb completion callback. The counters are atomics so there's no locking on the transfer
+//! path, `snapshot` copies them into plain structs.
+use crate::libusb::transfer::{Status, TransferType};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::sync::Arc;
+
+/// Latency histogram buckets. Bucket `k` counts latencies up to 2^k µs, the last one everything
+/// longer than 2^(LATENCY_BUCKETS - 2) µs (about a second).
+pub const LATENCY_BUCKETS: usize = 22;
+const STATUSES: [Status; 7] = [
+    Status::Completed,
+    Status::Error,
+    Status::TimedOut,
+    Status::Cancelled,
+    Status::Stall,
+    Status::NoDevice,
+    Status::Overflow,
+];
+
+fn status_index(status: Status) -> usize {
+    match status {
+        Status::Completed => 0,
+        Status::Error => 1,
+        Status::TimedOut => 2,
+        Status::Cancelled => 3,
+        Status::Stall => 4,
+        Status::NoDevice => 5,
+        Status::Overflow => 6,
+    }
+}
+/// Endpoint number in the low 4 bits, direction in bit 4.
+fn endpoint_index(endpoint: u8) -> usize {
+    usize::from(endpoint & 0x0F) | usize::from(endpoint & 0x80) >> 3
+}
+fn endpoint_address(index: usize) -> u8 {
+    (index & 0x0F) as u8 | ((index & 0x10) << 3) as u8
+}
+/// Bucket of `latency`: the smallest `k` with `latency <= 2^k µs`.
+fn latency_bucket(latency: Duration) -> usize {
+    let micros = latency.as_micros();
+    if micros <= 1 {
+        return 0;
+    }
+    let bucket = (128 - (micros - 1).leading_zeros()) as usize;
+    bucket.min(LATENCY_BUCKETS - 1)
+}
+
+#[derive(Default)]
+struct EndpointCounters {
+    bytes: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+}
+#[derive(Default)]
+struct Counters {
+    submitted: AtomicU64,
+    submit_errors: AtomicU64,
+    statuses: [AtomicU64; 7],
+    endpoints: [EndpointCounters; 32],
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+/// Shared handle to the counters of one device. Cloning it doesn't copy the counters.
+#[derive(Clone, Default)]
+pub struct DeviceMetrics(Arc<Counters>);
+impl DeviceMetrics {
+    pub fn new() -> DeviceMetrics {
+        DeviceMetrics::default()
+    }
+    /// If `self` and `other` count into the same counters.
+    pub fn same_counters(&self, other: &DeviceMetrics) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+    /// Counts a submission. `accepted` is `false` when libusb refused the transfer.
+    pub fn record_submit(&self, accepted: bool) {
+        let counter = match accepted {
+            true => &self.0.submitted,
+            false => &self.0.submit_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Counts a completed transfer of `len` bytes that took `latency` from submission.
+    pub fn record_completion(&self, endpoint: u8, status: Status, len: usize, latency: Duration) {
+        let counters = &*self.0;
+        counters.statuses[status_index(status)].fetch_add(1, Ordering::Relaxed);
+        let endpoint = &counters.endpoints[endpoint_index(endpoint)];
+        endpoint.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        match status {
+            Status::Completed => &endpoint.completed,
+            _ => &endpoint.failed,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        counters.latency[latency_bucket(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+    /// Copies the counters. They keep changing while it's taken so the numbers might be off by
+    /// the transfers completing at the time.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = &*self.0;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            submitted: load(&counters.submitted),
+            submit_errors: load(&counters.submit_errors),
+            statuses: STATUSES
+                .iter()
+                .zip(counters.statuses.iter())
+                .map(|(&status, count)| (status, load(count)))
+                .collect(),
+            endpoints: counters
+                .endpoints
+                .iter()
+                .enumerate()
+                .map(|(i, e)| EndpointMetrics {
+                    endpoint: endpoint_address(i),
+                    bytes: load(&e.bytes),
+                    completed: load(&e.completed),
+                    failed: load(&e.failed),
+                })
+                .filter(|e| e.completed + e.failed > 0)
+                .collect(),
+            latency: counters.latency.iter().map(load).collect(),
+        }
+    }
+    /// Sets every counter back to 0.
+    pub fn reset(&self) {
+        let counters = &*self.0;
+        let reset = |counter: &AtomicU64| counter.store(0, Ordering::Relaxed);
+        reset(&counters.submitted);
+        reset(&counters.submit_errors);
+        counters.statuses.iter().for_each(reset);
+        counters.latency.iter().for_each(reset);
+        for endpoint in &counters.endpoints {
+            reset(&endpoint.bytes);
+            reset(&endpoint.completed);
+            reset(&endpoint.failed);
+        }
+    }
+}
+impl fmt::Debug for DeviceMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.snapshot(), f)
+    }
+}
+/// Endpoint of a completed transfer. Control transfers count as endpoint 0x80 or 0x00 depending
+/// on the direction in their setup packet.
+pub(crate) fn completion_endpoint(transfer_type: TransferType, endpoint: u8, setup: u8) -> u8 {
+    match transfer_type {
+        TransferType::Control => setup & 0x80,
+        _ => endpoint,
+    }
+}
+
+/// Traffic of one endpoint.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct EndpointMetrics {
+    /// Address (direction bit included).
+    pub endpoint: u8,
+    pub bytes: u64,
+    pub completed: u64,
+    /// Completed with any status other than `Status::Completed`.
+    pub failed: u64,
+}
+/// Counters of a `DeviceMetrics` at one point in time.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct MetricsSnapshot {
+    pub submitted: u64,
+    pub submit_errors: u64,
+    /// Completed transfers by status (every status, in `Status` order).
+    pub statuses: Vec<(Status, u64)>,
+    /// Endpoints that had at least one transfer, in address order (OUT endpoints first).
+    pub endpoints: Vec<EndpointMetrics>,
+    /// `LATENCY_BUCKETS` counts of the submit to complete latency, see `latency_upper_bound`.
+    pub latency: Vec<u64>,
+}
+impl MetricsSnapshot {
+    /// Upper bound of latency bucket `bucket` (`None` for the last one).
+    pub fn latency_upper_bound(bucket: usize) -> Option<Duration> {
+        if bucket + 1 >= LATENCY_BUCKETS {
+            None
+        } else {
+            Some(Duration::from_micros(1 << bucket))
+        }
+    }
+    /// Transfers that completed (with any status).
+    pub fn completed(&self) -> u64 {
+        self.statuses.iter().map(|(_, count)| count).sum()
+    }
+    pub fn failed(&self) -> u64 {
+        self.statuses
+            .iter()
+            .filter(|(status, _)| *status != Status::Completed)
+            .map(|(_, count)| count)
+            .sum()
+    }
+    pub fn bytes_in(&self) -> u64 {
+        self.endpoints
+            .iter()
+            .filter(|e| e.endpoint & 0x80 != 0)
+            .map(|e| e.bytes)
+            .sum()
+    }
+    pub fn bytes_out(&self) -> u64 {
+        self.endpoints
+            .iter()
+            .filter(|e| e.endpoint & 0x80 == 0)
+            .map(|e| e.bytes)
+            .sum()
+    }
+}
+/// A few lines: totals, the endpoints and the non-empty latency buckets.
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} submitted ({} refused), {} completed ({} failed), {} bytes in, {} bytes out",
+            self.submitted,
+            self.submit_errors,
+            self.completed(),
+            self.failed(),
+            self.bytes_in(),
+            self.bytes_out()
+        )?;
+        for e in &self.endpoints {
+            writeln!(
+                f,
+                "  endpoint 0x{:02x}: {} bytes, {} completed, {} failed",
+                e.endpoint, e.bytes, e.completed, e.failed
+            )?;
+        }
+        for (bucket, count) in self.latency.iter().enumerate().filter(|(_, c)| **c > 0) {
+            match Self::latency_upper_bound(bucket) {
+                Some(bound) => writeln!(f, "  <= {:?}: {}", bound, count)?,
+                None => writeln!(f, "  longer: {}", count)?,
+            }
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::metrics::{
+        completion_endpoint, endpoint_address, endpoint_index, latency_bucket, DeviceMetrics,
+        MetricsSnapshot, LATENCY_BUCKETS,
+    };
+    use crate::libusb::transfer::{Status, TransferType};
+    use core::time::Duration;
+
+    #[test]
+    pub fn test_buckets() {
+        assert_eq!(latency_bucket(Duration::from_nanos(10)), 0);
+        assert_eq!(latency_bucket(Duration::from_micros(1)), 0);
+        assert_eq!(latency_bucket(Duration::from_micros(2)), 1);
+        assert_eq!(latency_bucket(Duration::from_micros(3)), 2);
+        assert_eq!(latency_bucket(Duration::from_micros(125)), 7);
+        assert_eq!(latency_bucket(Duration::from_secs(60)), LATENCY_BUCKETS - 1);
+        assert_eq!(
+            MetricsSnapshot::latency_upper_bound(7),
+            Some(Duration::from_micros(128))
+        );
+        assert_eq!(
+            MetricsSnapshot::latency_upper_bound(LATENCY_BUCKETS - 1),
+            None
+        );
+        for &endpoint in &[0x00, 0x81, 0x0F, 0x8F, 0x02] {
+            assert_eq!(endpoint_address(endpoint_index(endpoint)), endpoint);
+        }
+        assert_eq!(completion_endpoint(TransferType::Control, 0, 0xC0), 0x80);
+        assert_eq!(completion_endpoint(TransferType::Bulk, 0x02, 0xC0), 0x02);
+    }
+    #[test]
+    pub fn test_counters() {
+        let metrics = DeviceMetrics::new();
+        let shared = metrics.clone();
+        metrics.record_submit(true);
+        metrics.record_submit(true);
+        metrics.record_submit(false);
+        shared.record_completion(0x81, Status::Completed, 64, Duration::from_micros(100));
+        shared.record_completion(0x02, Status::Stall, 0, Duration::from_millis(2));
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.submitted, snapshot.submit_errors), (2, 1));
+        assert_eq!((snapshot.completed(), snapshot.failed()), (2, 1));
+        assert_eq!((snapshot.bytes_in(), snapshot.bytes_out()), (64, 0));
+        assert_eq!(snapshot.endpoints.len(), 2);
+        assert_eq!(snapshot.endpoints[0].endpoint, 0x02);
+        assert_eq!(snapshot.endpoints[0].failed, 1);
+        assert_eq!(snapshot.latency[7], 1);
+        assert_eq!(snapshot.latency[11], 1);
+        assert_eq!(snapshot.latency.iter().sum::<u64>(), 2);
+
+        shared.reset();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.submitted + snapshot.completed(), 0);
+        assert!(snapshot.endpoints.is_empty());
+        assert!(metrics.same_counters(&shared));
+        assert!(!metrics.same_counters(&DeviceMetrics::new()));
+    }
+}
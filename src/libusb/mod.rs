@@ -2,18 +2,49 @@
 pub mod error;
 pub mod async_device;
 pub mod asyncs;
+pub mod blocking;
+pub mod bos_descriptor;
 pub mod buffer;
+pub mod bulk_io;
+#[cfg(feature = "cdc_acm")]
+pub mod cdc_acm;
 pub mod config_descriptor;
 pub mod context;
+pub mod control;
 pub mod device;
 pub mod device_descriptor;
 pub mod device_handle;
+#[cfg(feature = "dfu")]
+pub mod dfu;
 pub mod dma;
 pub mod endpoint_descriptor;
+#[cfg(feature = "tokio")]
+pub mod event_pump;
+#[cfg(feature = "hci")]
+pub mod hci;
+#[cfg(feature = "hid")]
+pub mod hid;
 pub mod hotplug;
+pub(crate) mod in_flight;
 pub mod interface_descriptor;
+pub mod interface_guard;
 pub mod interfaces;
+pub mod interrupt_stream;
+pub mod log_callback;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "msc")]
+pub mod msc;
+pub mod queue;
+pub mod reconnect;
+pub mod report;
+pub mod retry;
+pub(crate) mod runtime;
 pub mod safe_transfer;
+pub mod shared_device;
 pub mod speed;
+pub mod string_cache;
+pub mod string_descriptor;
 pub mod transfer;
+pub mod transfer_builder;
 pub mod version;
@@ -0,0 +1,665 @@
+//! USB Mass Storage Bulk-Only Transport (BOT) and a few SCSI commands.
+//!
+//! Each command is a Command Block Wrapper (CBW) on the bulk OUT endpoint, an optional data
+//! stage and a Command Status Wrapper (CSW) on the bulk IN endpoint. Stalls and invalid CSWs are
+//! recovered from like the spec says (clearing halts and the reset recovery).
+use crate::descriptors::ParsedConfig;
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::control::Direction;
+use crate::libusb::error::Error;
+use crate::libusb::transfer::ControlSetup;
+use core::convert::TryFrom;
+use core::fmt;
+
+pub const MASS_STORAGE_CLASS: u8 = 0x08;
+/// `bInterfaceSubClass` of SCSI transparent command set devices.
+pub const SCSI_SUBCLASS: u8 = 0x06;
+/// `bInterfaceProtocol` of Bulk-Only Transport.
+pub const BULK_ONLY_PROTOCOL: u8 = 0x50;
+/// Class request resetting the mass storage interface.
+pub const BULK_ONLY_RESET: u8 = 0xFF;
+pub const GET_MAX_LUN: u8 = 0xFE;
+
+/// `bmCBWFlags` direction of the data stage.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum CbwDirection {
+    Out,
+    In,
+}
+/// The 31 byte Command Block Wrapper.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Cbw {
+    pub tag: u32,
+    pub data_transfer_length: u32,
+    pub direction: CbwDirection,
+    pub lun: u8,
+    cb: [u8; 16],
+    cb_len: u8,
+}
+impl Cbw {
+    pub const SIZE: usize = 31;
+    pub const SIGNATURE: u32 = 0x4342_5355;
+    /// `Error::InvalidParam` for an empty or longer than 16 byte `cb` or a LUN over 15.
+    pub fn new(
+        tag: u32,
+        data_transfer_length: u32,
+        direction: CbwDirection,
+        lun: u8,
+        cb: &[u8],
+    ) -> Result<Cbw, Error> {
+        if cb.is_empty() || cb.len() > 16 || lun > 0x0F {
+            return Err(Error::InvalidParam);
+        }
+        let mut block = [0_u8; 16];
+        block[..cb.len()].copy_from_slice(cb);
+        Ok(Cbw {
+            tag,
+            data_transfer_length,
+            direction,
+            lun,
+            cb: block,
+            cb_len: cb.len() as u8,
+        })
+    }
+    /// The command block (SCSI CDB).
+    pub fn cb(&self) -> &[u8] {
+        &self.cb[..usize::from(self.cb_len)]
+    }
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0_u8; Self::SIZE];
+        out[0..4].copy_from_slice(&Self::SIGNATURE.to_le_bytes());
+        out[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        out[8..12].copy_from_slice(&self.data_transfer_length.to_le_bytes());
+        out[12] = match self.direction {
+            CbwDirection::Out => 0x00,
+            CbwDirection::In => 0x80,
+        };
+        out[13] = self.lun;
+        out[14] = self.cb_len;
+        out[15..31].copy_from_slice(&self.cb);
+        out
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cbw, MscError> {
+        if bytes.len() != Self::SIZE || bytes[0..4] != Self::SIGNATURE.to_le_bytes() {
+            return Err(MscError::InvalidWrapper);
+        }
+        let direction = match bytes[12] & 0x80 {
+            0 => CbwDirection::Out,
+            _ => CbwDirection::In,
+        };
+        let cb_len = usize::from(bytes[14]);
+        if cb_len > 16 {
+            return Err(MscError::InvalidWrapper);
+        }
+        Cbw::new(
+            u32_at(bytes, 4),
+            u32_at(bytes, 8),
+            direction,
+            bytes[13] & 0x0F,
+            &bytes[15..15 + cb_len],
+        )
+        .map_err(|_| MscError::InvalidWrapper)
+    }
+}
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+}
+/// `bCSWStatus`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum CswStatus {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+/// The 13 byte Command Status Wrapper.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Csw {
+    pub tag: u32,
+    /// Bytes of the data stage that weren't transferred.
+    pub residue: u32,
+    pub status: CswStatus,
+}
+impl Csw {
+    pub const SIZE: usize = 13;
+    pub const SIGNATURE: u32 = 0x5342_5355;
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0_u8; Self::SIZE];
+        out[0..4].copy_from_slice(&Self::SIGNATURE.to_le_bytes());
+        out[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        out[8..12].copy_from_slice(&self.residue.to_le_bytes());
+        out[12] = self.status as u8;
+        out
+    }
+    /// Parses a CSW without checking it against the command (`validate` does).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Csw, MscError> {
+        if bytes.len() != Self::SIZE || bytes[0..4] != Self::SIGNATURE.to_le_bytes() {
+            return Err(MscError::InvalidWrapper);
+        }
+        let status = match bytes[12] {
+            0 => CswStatus::Passed,
+            1 => CswStatus::Failed,
+            2 => CswStatus::PhaseError,
+            _ => return Err(MscError::InvalidWrapper),
+        };
+        Ok(Csw {
+            tag: u32_at(bytes, 4),
+            residue: u32_at(bytes, 8),
+            status,
+        })
+    }
+    /// Checks a CSW is valid and meaningful for `cbw` (same tag and a residue no larger than
+    /// the data length). Phase errors are errors.
+    pub fn validate(bytes: &[u8], cbw: &Cbw) -> Result<Csw, MscError> {
+        let csw = Csw::from_bytes(bytes)?;
+        if csw.tag != cbw.tag || csw.residue > cbw.data_transfer_length {
+            return Err(MscError::InvalidWrapper);
+        }
+        if csw.status == CswStatus::PhaseError {
+            return Err(MscError::PhaseError);
+        }
+        Ok(csw)
+    }
+}
+
+/// Data stage of a command.
+#[derive(Debug)]
+pub enum DataDirection<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+impl<'a> DataDirection<'a> {
+    fn len(&self) -> usize {
+        match self {
+            DataDirection::None => 0,
+            DataDirection::In(buf) => buf.len(),
+            DataDirection::Out(data) => data.len(),
+        }
+    }
+    fn cbw_direction(&self) -> CbwDirection {
+        match self {
+            DataDirection::In(_) => CbwDirection::In,
+            _ => CbwDirection::Out,
+        }
+    }
+}
+/// Result of a command the device answered with a valid CSW.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct CommandResult {
+    /// `false` for `CswStatus::Failed` (use REQUEST SENSE to find out why).
+    pub passed: bool,
+    pub residue: u32,
+    /// Bytes moved in the data stage.
+    pub transferred: usize,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MscError {
+    Usb(Error),
+    /// A CSW (or CBW) with a bad signature, length, tag or residue. The interface was reset.
+    InvalidWrapper,
+    /// The device reported a phase error. The interface was reset.
+    PhaseError,
+    /// A SCSI command returned `CswStatus::Failed`.
+    CommandFailed,
+}
+impl From<Error> for MscError {
+    fn from(e: Error) -> Self {
+        MscError::Usb(e)
+    }
+}
+impl fmt::Display for MscError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MscError::Usb(e) => fmt::Display::fmt(e, f),
+            MscError::InvalidWrapper => f.write_str("invalid command status wrapper"),
+            MscError::PhaseError => f.write_str("mass storage phase error"),
+            MscError::CommandFailed => f.write_str("SCSI command failed"),
+        }
+    }
+}
+impl std::error::Error for MscError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MscError::Usb(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Bulk-Only Mass Storage Reset of `interface`.
+pub fn reset_setup(interface: u8) -> ControlSetup {
+    ControlSetup::class_interface(Direction::Out, BULK_ONLY_RESET, 0, interface, 0)
+}
+pub fn get_max_lun_setup(interface: u8) -> ControlSetup {
+    ControlSetup::class_interface(Direction::In, GET_MAX_LUN, 0, interface, 1)
+}
+/// (bulk IN, bulk OUT) endpoints of the Bulk-Only `interface` in `config`.
+pub fn bulk_only_endpoints(config: &ParsedConfig, interface: u8) -> Result<(u8, u8), Error> {
+    let alt = config
+        .interfaces
+        .iter()
+        .find(|i| i.number == interface)
+        .and_then(|i| i.alt_settings.first())
+        .filter(|alt| {
+            (alt.codes.class, alt.codes.protocol) == (MASS_STORAGE_CLASS, BULK_ONLY_PROTOCOL)
+        })
+        .ok_or(Error::NotFound)?;
+    let bulk = |input: bool| {
+        alt.endpoints
+            .iter()
+            .find(|e| (e.address & 0x80 != 0) == input && e.attributes & 0x03 == 2)
+            .map(|e| e.address)
+            .ok_or(Error::NotFound)
+    };
+    Ok((bulk(true)?, bulk(false)?))
+}
+
+/// SCSI commands for `BulkOnly::transfer`.
+pub mod scsi {
+    pub const INQUIRY: u8 = 0x12;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    /// Standard INQUIRY data length.
+    pub const INQUIRY_LEN: u8 = 36;
+
+    pub fn inquiry(allocation_len: u8) -> [u8; 6] {
+        [INQUIRY, 0, 0, 0, allocation_len, 0]
+    }
+    pub fn request_sense(allocation_len: u8) -> [u8; 6] {
+        [REQUEST_SENSE, 0, 0, 0, allocation_len, 0]
+    }
+    pub fn test_unit_ready() -> [u8; 6] {
+        [TEST_UNIT_READY, 0, 0, 0, 0, 0]
+    }
+    pub fn read_capacity_10() -> [u8; 10] {
+        [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    }
+    /// READ(10) of `blocks` blocks starting at `lba`.
+    pub fn read_10(lba: u32, blocks: u16) -> [u8; 10] {
+        let lba = lba.to_be_bytes();
+        let blocks = blocks.to_be_bytes();
+        [
+            READ_10, 0, lba[0], lba[1], lba[2], lba[3], 0, blocks[0], blocks[1], 0,
+        ]
+    }
+    /// The interesting part of the standard INQUIRY data.
+    #[derive(Clone, Eq, PartialEq, Debug, Hash)]
+    pub struct InquiryData {
+        pub peripheral_device_type: u8,
+        pub removable: bool,
+        pub vendor: String,
+        pub product: String,
+        pub revision: String,
+    }
+    impl InquiryData {
+        pub fn from_bytes(bytes: &[u8]) -> Option<InquiryData> {
+            if bytes.len() < usize::from(INQUIRY_LEN) {
+                return None;
+            }
+            let text = |range: core::ops::Range<usize>| {
+                String::from_utf8_lossy(&bytes[range])
+                    .trim_end()
+                    .to_string()
+            };
+            Some(InquiryData {
+                peripheral_device_type: bytes[0] & 0x1F,
+                removable: bytes[1] & 0x80 != 0,
+                vendor: text(8..16),
+                product: text(16..32),
+                revision: text(32..36),
+            })
+        }
+    }
+    /// READ CAPACITY(10) data.
+    #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+    pub struct Capacity {
+        pub last_lba: u32,
+        pub block_len: u32,
+    }
+    impl Capacity {
+        pub const SIZE: usize = 8;
+        pub fn from_bytes(bytes: &[u8]) -> Option<Capacity> {
+            match bytes {
+                [a, b, c, d, e, f, g, h, ..] => Some(Capacity {
+                    last_lba: u32::from_be_bytes([*a, *b, *c, *d]),
+                    block_len: u32::from_be_bytes([*e, *f, *g, *h]),
+                }),
+                _ => None,
+            }
+        }
+        pub fn blocks(&self) -> u64 {
+            u64::from(self.last_lba) + 1
+        }
+        pub fn bytes(&self) -> u64 {
+            self.blocks() * u64::from(self.block_len)
+        }
+    }
+}
+
+/// Bulk-Only Transport over a claimed mass storage interface. Uses the `AsyncDevice` default
+/// timeout.
+pub struct BulkOnly {
+    device: AsyncDevice,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    lun: u8,
+    next_tag: u32,
+}
+impl BulkOnly {
+    /// Claims `interface` (detaching the kernel driver, reattached when the handle is dropped).
+    pub fn new(mut device: AsyncDevice, interface: u8) -> Result<BulkOnly, Error> {
+        let config = ParsedConfig::from(&device.device().active_config_descriptor()?);
+        let (bulk_in, bulk_out) = bulk_only_endpoints(&config, interface)?;
+        {
+            let mut detached = device
+                .handle_mut()
+                .detach_kernel_driver_guard(interface, true)?;
+            detached.claim_interface(interface)?;
+            detached.forget();
+        }
+        Ok(BulkOnly {
+            device,
+            interface,
+            bulk_in,
+            bulk_out,
+            lun: 0,
+            next_tag: 1,
+        })
+    }
+    pub fn device(&self) -> &AsyncDevice {
+        &self.device
+    }
+    pub fn into_device(self) -> AsyncDevice {
+        self.device
+    }
+    /// Logical unit the commands go to. Starts at 0.
+    pub fn set_lun(&mut self, lun: u8) {
+        self.lun = lun
+    }
+    /// Highest LUN. Devices with one LUN may stall the request, that's 0.
+    pub async fn max_lun(&self) -> Result<u8, Error> {
+        let setup = get_max_lun_setup(self.interface);
+        let mut buf = [0_u8; 1];
+        let read = self
+            .device
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &mut buf,
+                self.device.default_timeout(),
+            )
+            .await;
+        match read {
+            Ok(1) => Ok(buf[0]),
+            Ok(_) | Err(Error::Pipe) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+    /// `libusb_clear_halt` also resets the host side data toggle, which a raw
+    /// `CLEAR_FEATURE(ENDPOINT_HALT)` would leave out of sync with the device.
+    async fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        self.device.clear_halt(endpoint).await
+    }
+    /// Reset recovery: Bulk-Only Mass Storage Reset then clearing both halts.
+    pub async fn reset_recovery(&self) -> Result<(), Error> {
+        let setup = reset_setup(self.interface);
+        self.device
+            .control_write(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &[],
+                self.device.default_timeout(),
+            )
+            .await?;
+        self.clear_halt(self.bulk_in).await?;
+        self.clear_halt(self.bulk_out).await
+    }
+    /// Runs the reset recovery and returns `e`.
+    async fn recover(&self, e: MscError) -> MscError {
+        match self.reset_recovery().await {
+            Ok(()) => e,
+            Err(reset) => MscError::Usb(reset),
+        }
+    }
+    async fn read_csw(&self, buf: &mut [u8; Csw::SIZE]) -> Result<usize, Error> {
+        let timeout = self.device.default_timeout();
+        match self.device.bulk_read(self.bulk_in, buf, timeout).await {
+            // A stalled CSW read is retried once after clearing the halt
+            Err(Error::Pipe) => {
+                self.clear_halt(self.bulk_in).await?;
+                self.device.bulk_read(self.bulk_in, buf, timeout).await
+            }
+            result => result,
+        }
+    }
+    /// Sends the command block `cb` with the data stage `data` and checks the CSW.
+    pub async fn transfer(
+        &mut self,
+        cb: &[u8],
+        data: DataDirection<'_>,
+    ) -> Result<CommandResult, MscError> {
+        let data_len = u32::try_from(data.len()).map_err(|_| Error::InvalidParam)?;
+        let cbw = Cbw::new(self.next_tag, data_len, data.cbw_direction(), self.lun, cb)?;
+        self.next_tag = self.next_tag.wrapping_add(1);
+        let timeout = self.device.default_timeout();
+        match self
+            .device
+            .bulk_write(self.bulk_out, &cbw.to_bytes(), timeout)
+            .await
+        {
+            Ok(Cbw::SIZE) => (),
+            Ok(_) => return Err(self.recover(MscError::Usb(Error::Io)).await),
+            Err(e) => return Err(self.recover(MscError::Usb(e)).await),
+        }
+        let (endpoint, stage) = match data {
+            DataDirection::None => (self.bulk_in, Ok(0)),
+            DataDirection::In(buf) => (
+                self.bulk_in,
+                self.device.bulk_read(self.bulk_in, buf, timeout).await,
+            ),
+            DataDirection::Out(data) => (
+                self.bulk_out,
+                self.device.bulk_write(self.bulk_out, data, timeout).await,
+            ),
+        };
+        // A stalled data stage is cleared and the CSW still read, anything else (or a failed
+        // clear) leaves the device out of step and needs the reset recovery
+        let transferred = match stage {
+            Ok(len) => len,
+            Err(Error::Pipe) => match self.clear_halt(endpoint).await {
+                Ok(()) => 0,
+                Err(e) => return Err(self.recover(MscError::Usb(e)).await),
+            },
+            Err(e) => return Err(self.recover(MscError::Usb(e)).await),
+        };
+        let mut buf = [0_u8; Csw::SIZE];
+        let len = match self.read_csw(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => return Err(self.recover(MscError::Usb(e)).await),
+        };
+        match Csw::validate(&buf[..len], &cbw) {
+            Ok(csw) => Ok(CommandResult {
+                passed: csw.status == CswStatus::Passed,
+                residue: csw.residue,
+                transferred,
+            }),
+            Err(e) => Err(self.recover(e).await),
+        }
+    }
+    /// Runs a command with an IN data stage, `MscError::CommandFailed` if it doesn't pass.
+    async fn read_command(&mut self, cb: &[u8], buf: &mut [u8]) -> Result<usize, MscError> {
+        let result = self.transfer(cb, DataDirection::In(buf)).await?;
+        if !result.passed {
+            return Err(MscError::CommandFailed);
+        }
+        Ok(result.transferred)
+    }
+    pub async fn inquiry(&mut self) -> Result<scsi::InquiryData, MscError> {
+        let mut buf = [0_u8; scsi::INQUIRY_LEN as usize];
+        let len = self
+            .read_command(&scsi::inquiry(scsi::INQUIRY_LEN), &mut buf)
+            .await?;
+        scsi::InquiryData::from_bytes(&buf[..len]).ok_or(MscError::Usb(Error::Overflow))
+    }
+    pub async fn read_capacity(&mut self) -> Result<scsi::Capacity, MscError> {
+        let mut buf = [0_u8; scsi::Capacity::SIZE];
+        let len = self
+            .read_command(&scsi::read_capacity_10(), &mut buf)
+            .await?;
+        scsi::Capacity::from_bytes(&buf[..len]).ok_or(MscError::Usb(Error::Overflow))
+    }
+    /// READ(10) of `buf.len() / block_len` blocks starting at `lba`.
+    pub async fn read_blocks(
+        &mut self,
+        lba: u32,
+        block_len: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, MscError> {
+        if block_len == 0 || !buf.len().is_multiple_of(block_len as usize) {
+            return Err(Error::InvalidParam.into());
+        }
+        let blocks =
+            u16::try_from(buf.len() / block_len as usize).map_err(|_| Error::InvalidParam)?;
+        self.read_command(&scsi::read_10(lba, blocks), buf).await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::descriptors::parse_config_descriptor;
+    use crate::libusb::msc::scsi::{Capacity, InquiryData};
+    use crate::libusb::msc::{
+        bulk_only_endpoints, get_max_lun_setup, reset_setup, scsi, Cbw, CbwDirection, Csw,
+        CswStatus, MscError,
+    };
+
+    #[test]
+    pub fn test_cbw() {
+        let cbw =
+            Cbw::new(0x1234_5678, 36, CbwDirection::In, 0, &scsi::inquiry(36)).expect("valid CBW");
+        let bytes = cbw.to_bytes();
+        assert_eq!(&bytes[0..4], b"USBC");
+        assert_eq!(&bytes[4..8], &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(&bytes[8..12], &[36, 0, 0, 0]);
+        assert_eq!((bytes[12], bytes[13], bytes[14]), (0x80, 0, 6));
+        assert_eq!(&bytes[15..21], &[0x12, 0, 0, 0, 36, 0]);
+        assert!(bytes[21..].iter().all(|b| *b == 0));
+        assert_eq!(Cbw::from_bytes(&bytes), Ok(cbw));
+        assert_eq!(cbw.cb(), &scsi::inquiry(36));
+
+        let out = Cbw::new(1, 512, CbwDirection::Out, 3, &[0x2A; 10]).expect("valid CBW");
+        assert_eq!(out.to_bytes()[12..15], [0x00, 3, 10]);
+        assert!(Cbw::new(1, 0, CbwDirection::Out, 0, &[]).is_err());
+        assert!(Cbw::new(1, 0, CbwDirection::Out, 0, &[0; 17]).is_err());
+        assert!(Cbw::new(1, 0, CbwDirection::Out, 16, &[0]).is_err());
+        assert_eq!(Cbw::from_bytes(&bytes[..30]), Err(MscError::InvalidWrapper));
+    }
+    #[test]
+    pub fn test_csw_validation() {
+        let cbw = Cbw::new(7, 512, CbwDirection::In, 0, &scsi::read_10(0, 1)).expect("valid");
+        let csw = Csw {
+            tag: 7,
+            residue: 12,
+            status: CswStatus::Failed,
+        };
+        let bytes = csw.to_bytes();
+        assert_eq!(&bytes[..4], b"USBS");
+        assert_eq!(bytes, [0x55, 0x53, 0x42, 0x53, 7, 0, 0, 0, 12, 0, 0, 0, 1]);
+        assert_eq!(Csw::validate(&bytes, &cbw), Ok(csw));
+        // Wrong tag
+        let other = Csw { tag: 8, ..csw };
+        assert_eq!(
+            Csw::validate(&other.to_bytes(), &cbw),
+            Err(MscError::InvalidWrapper)
+        );
+        // Residue larger than the data stage
+        let residue = Csw {
+            residue: 513,
+            ..csw
+        };
+        assert_eq!(
+            Csw::validate(&residue.to_bytes(), &cbw),
+            Err(MscError::InvalidWrapper)
+        );
+        let phase = Csw {
+            status: CswStatus::PhaseError,
+            ..csw
+        };
+        assert_eq!(
+            Csw::validate(&phase.to_bytes(), &cbw),
+            Err(MscError::PhaseError)
+        );
+        // Short, long, bad signature and bad status
+        assert!(Csw::validate(&bytes[..12], &cbw).is_err());
+        assert!(Csw::validate(&[&bytes[..], &[0]].concat(), &cbw).is_err());
+        let mut bad = bytes;
+        bad[3] = b'C';
+        assert!(Csw::from_bytes(&bad).is_err());
+        let mut bad = bytes;
+        bad[12] = 3;
+        assert!(Csw::from_bytes(&bad).is_err());
+    }
+    #[test]
+    pub fn test_class_requests() {
+        let reset = reset_setup(1);
+        assert_eq!(
+            (
+                reset.request_type,
+                reset.request,
+                reset.value,
+                reset.index,
+                reset.len
+            ),
+            (0x21, 0xFF, 0, 1, 0)
+        );
+        let lun = get_max_lun_setup(0);
+        assert_eq!((lun.request_type, lun.request, lun.len), (0xA1, 0xFE, 1));
+    }
+    #[test]
+    pub fn test_scsi() {
+        assert_eq!(
+            scsi::read_10(0x0102_0304, 0x0506),
+            [0x28, 0, 1, 2, 3, 4, 0, 5, 6, 0]
+        );
+        let capacity = Capacity::from_bytes(&[0, 0x0F, 0xFF, 0xFF, 0, 0, 2, 0]).expect("8 bytes");
+        assert_eq!(capacity.block_len, 512);
+        assert_eq!(capacity.blocks(), 0x10_0000);
+        assert_eq!(capacity.bytes(), 512 * 1024 * 1024);
+        let mut inquiry = [0x20_u8; 36];
+        inquiry[0] = 0x00;
+        inquiry[1] = 0x80;
+        inquiry[8..14].copy_from_slice(b"Kingst");
+        inquiry[16..20].copy_from_slice(b"Data");
+        inquiry[32..36].copy_from_slice(b"1.00");
+        let data = InquiryData::from_bytes(&inquiry).expect("36 bytes");
+        assert_eq!(
+            (
+                data.vendor.as_str(),
+                data.product.as_str(),
+                data.revision.as_str()
+            ),
+            ("Kingst", "Data", "1.00")
+        );
+        assert!(data.removable);
+        assert_eq!(InquiryData::from_bytes(&inquiry[..35]), None);
+    }
+    #[test]
+    pub fn test_endpoints() {
+        let config = [
+            9, 0x02, 32, 0, 1, 1, 0, 0x80, 50, // configuration
+            9, 0x04, 0, 0, 2, 0x08, 0x06, 0x50, 0, // mass storage interface
+            7, 0x05, 0x81, 0x02, 0, 2, 0, // bulk IN
+            7, 0x05, 0x02, 0x02, 0, 2, 0, // bulk OUT
+        ];
+        let config = parse_config_descriptor(&config).expect("valid config");
+        assert_eq!(bulk_only_endpoints(&config, 0), Ok((0x81, 0x02)));
+        assert!(bulk_only_endpoints(&config, 1).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Pipelined bulk IO. A single `SafeTransfer` at a time leaves the endpoint idle between
+//! transfers, so these keep several transfers in flight at once.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::error::Error;
+use crate::libusb::safe_transfer::SafeTransfer;
+use crate::libusb::transfer::{Status, TransferType};
+use std::collections::VecDeque;
+
+/// Keeps `depth` bulk IN transfers submitted at all times and hands out their buffers in
+/// submission order.
+///
+/// Dropping the queue cancels the in flight transfers (without waiting, libusb's callbacks free
+/// them) and discards their data. Use `stop` to keep the data that already arrived.
+pub struct BulkInQueue<'d> {
+    device: &'d AsyncDevice,
+    endpoint: u8,
+    timeout: core::time::Duration,
+    in_flight: VecDeque<SafeTransfer<Vec<u8>>>,
+    /// Set once the device is gone (or a resubmission failed). Returned by every `next` after.
+    error: Option<Error>,
+}
+impl<'d> BulkInQueue<'d> {
+    /// Submits `depth` reads of `transfer_size` bytes each. Transfers never time out.
+    pub fn new(
+        device: &'d AsyncDevice,
+        endpoint: u8,
+        transfer_size: usize,
+        depth: usize,
+    ) -> Result<BulkInQueue<'d>, Error> {
+        Self::with_timeout(
+            device,
+            endpoint,
+            transfer_size,
+            depth,
+            core::time::Duration::from_secs(0),
+        )
+    }
+    /// `new` but with a per transfer timeout. Timed out transfers are returned as
+    /// `Error::Timeout` by `next` and then resubmitted.
+    pub fn with_timeout(
+        device: &'d AsyncDevice,
+        endpoint: u8,
+        transfer_size: usize,
+        depth: usize,
+        timeout: core::time::Duration,
+    ) -> Result<BulkInQueue<'d>, Error> {
+        if depth == 0 || transfer_size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let mut queue = BulkInQueue {
+            device,
+            endpoint,
+            timeout,
+            in_flight: VecDeque::with_capacity(depth),
+            error: None,
+        };
+        for _ in 0..depth {
+            let mut transfer = SafeTransfer::from_buf(vec![0_u8; transfer_size]);
+            transfer.set_type(TransferType::Bulk);
+            transfer.set_endpoint(endpoint);
+            transfer.set_timeout(timeout);
+            queue.submit(transfer)?;
+        }
+        Ok(queue)
+    }
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+    pub fn timeout(&self) -> core::time::Duration {
+        self.timeout
+    }
+    /// Amount of transfers currently submitted.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+    fn submit(&mut self, mut transfer: SafeTransfer<Vec<u8>>) -> Result<(), Error> {
+        // The `Vec`'s heap buffer doesn't move with the `SafeTransfer` so it can be queued while
+        // active.
+        transfer.start_read(self.device)?;
+        self.in_flight.push_back(transfer);
+        Ok(())
+    }
+    /// Resubmits a transfer returned by `next`. A failed resubmission is returned from the
+    /// following `next` calls.
+    fn resubmit(&mut self, transfer: SafeTransfer<Vec<u8>>) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.submit(transfer) {
+            self.error = Some(e);
+        }
+    }
+    /// Waits for the oldest transfer. The buffer gets resubmitted once the returned
+    /// `QueueBuffer` is dropped. After `Error::NoDevice` the queue cancels everything and keeps
+    /// returning that error. A failed resubmission is also returned by every `next` after it.
+    pub async fn next(&mut self) -> Result<QueueBuffer<'_, 'd>, Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let front = self.in_flight.front_mut().ok_or(Error::NotFound)?;
+        // Only pop once complete so dropping this future doesn't lose the transfer
+        let result = front.wait_for_completion().await;
+        let transfer = self.in_flight.pop_front().expect("front transfer exists");
+        match result {
+            Ok(len) => Ok(QueueBuffer {
+                queue: self,
+                transfer: Some(transfer),
+                len,
+            }),
+            Err(Error::NoDevice) => {
+                self.error = Some(Error::NoDevice);
+                // Transfers complete in order so nothing after this one has any data
+                self.cancel_all().await;
+                Err(Error::NoDevice)
+            }
+            Err(e) => {
+                self.resubmit(transfer);
+                Err(e)
+            }
+        }
+    }
+    async fn cancel_all(&mut self) -> Vec<SafeTransfer<Vec<u8>>> {
+        for transfer in &self.in_flight {
+            // `NotFound` (already completing) is handled by `try_cancel`. Any other error means
+            // the transfer isn't active anymore.
+            transfer.try_cancel().ok();
+        }
+        let mut transfers = Vec::with_capacity(self.in_flight.len());
+        while let Some(mut transfer) = self.in_flight.pop_front() {
+            transfer.wait_for_completion().await.ok();
+            transfers.push(transfer);
+        }
+        transfers
+    }
+    /// Cancels all the in flight transfers and returns the data that already arrived (in
+    /// order, including the partial data of cancelled transfers).
+    pub async fn stop(mut self) -> Vec<Vec<u8>> {
+        self.cancel_all()
+            .await
+            .into_iter()
+            .filter_map(|transfer| {
+                let len = transfer.transfer_ref().actual_length() as usize;
+                if len == 0 {
+                    None
+                } else {
+                    Some(transfer.buf_ref()[..len].to_vec())
+                }
+            })
+            .collect()
+    }
+}
+/// Completed transfer buffer from a `BulkInQueue`. Resubmitted when dropped.
+pub struct QueueBuffer<'q, 'd> {
+    queue: &'q mut BulkInQueue<'d>,
+    transfer: Option<SafeTransfer<Vec<u8>>>,
+    len: usize,
+}
+impl<'q, 'd> QueueBuffer<'q, 'd> {
+    pub fn data(&self) -> &[u8] {
+        let transfer = self
+            .transfer
+            .as_ref()
+            .expect("transfer is only taken on drop");
+        &transfer.buf_ref()[..self.len]
+    }
+}
+impl<'q, 'd> core::ops::Deref for QueueBuffer<'q, 'd> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.data()
+    }
+}
+impl<'q, 'd> AsRef<[u8]> for QueueBuffer<'q, 'd> {
+    fn as_ref(&self) -> &[u8] {
+        self.data()
+    }
+}
+impl<'q, 'd> Drop for QueueBuffer<'q, 'd> {
+    fn drop(&mut self) {
+        if let Some(transfer) = self.transfer.take() {
+            self.queue.resubmit(transfer)
+        }
+    }
+}
+
+/// Writes arbitrarily large data to a bulk OUT endpoint in `chunk_size` transfers, keeping up to
+/// `depth` of them in flight.
+///
+/// `write_all` returns once the data is submitted, `flush` waits until it's all sent. Dropping
+/// the sink cancels whatever is still in flight.
+pub struct BulkOutSink<'d> {
+    device: &'d AsyncDevice,
+    endpoint: u8,
+    chunk_size: usize,
+    max_packet_size: usize,
+    depth: usize,
+    timeout: core::time::Duration,
+    zero_length_packet: bool,
+    in_flight: VecDeque<SafeTransfer<Vec<u8>>>,
+    /// Inactive transfers to reuse.
+    free: Vec<SafeTransfer<Vec<u8>>>,
+}
+impl<'d> BulkOutSink<'d> {
+    /// Default `chunk_size` in max sized packets.
+    pub const DEFAULT_PACKETS_PER_CHUNK: usize = 32;
+    /// Uses chunks of `DEFAULT_PACKETS_PER_CHUNK` max sized packets.
+    pub fn new(
+        device: &'d AsyncDevice,
+        endpoint: u8,
+        depth: usize,
+    ) -> Result<BulkOutSink<'d>, Error> {
+        let max_packet_size = device.device().max_packet_size(endpoint)?;
+        Self::with_chunk_size(
+            device,
+            endpoint,
+            max_packet_size * Self::DEFAULT_PACKETS_PER_CHUNK,
+            depth,
+        )
+    }
+    pub fn with_chunk_size(
+        device: &'d AsyncDevice,
+        endpoint: u8,
+        chunk_size: usize,
+        depth: usize,
+    ) -> Result<BulkOutSink<'d>, Error> {
+        if depth == 0 || chunk_size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(BulkOutSink {
+            device,
+            endpoint,
+            chunk_size,
+            max_packet_size: device.device().max_packet_size(endpoint)?,
+            depth,
+            timeout: core::time::Duration::from_secs(0),
+            zero_length_packet: false,
+            in_flight: VecDeque::with_capacity(depth),
+            free: Vec::with_capacity(depth),
+        })
+    }
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+    /// Per transfer timeout. Zero (the default) never times out.
+    pub fn set_timeout(&mut self, timeout: core::time::Duration) {
+        self.timeout = timeout
+    }
+    /// Ends every `write_all` whose length is a multiple of the max packet size with a zero
+    /// length packet, so the device can tell where the write ends.
+    pub fn set_zero_length_packet(&mut self, zero_length_packet: bool) {
+        self.zero_length_packet = zero_length_packet
+    }
+    /// Queues all of `data`, waiting for transfers to complete when `depth` are in flight.
+    /// On error, everything in flight gets cancelled.
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        for chunk in data.chunks(self.chunk_size) {
+            self.push_chunk(chunk).await?;
+        }
+        // `is_multiple_of` is false for a max packet size of 0 (the data isn't empty)
+        let whole_packets = data.len().is_multiple_of(self.max_packet_size);
+        if self.zero_length_packet && !data.is_empty() && whole_packets {
+            self.push_chunk(&[]).await?;
+        }
+        Ok(())
+    }
+    /// Waits until everything written has been sent.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        while !self.in_flight.is_empty() {
+            self.reap_front().await?;
+        }
+        Ok(())
+    }
+    async fn push_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        while self.in_flight.len() >= self.depth {
+            self.reap_front().await?;
+        }
+        let mut transfer = match self.free.pop() {
+            Some(transfer) => transfer,
+            None => {
+                let mut transfer = SafeTransfer::from_buf(Vec::with_capacity(self.chunk_size));
+                transfer.set_type(TransferType::Bulk);
+                transfer.set_endpoint(self.endpoint);
+                transfer
+            }
+        };
+        transfer.set_timeout(self.timeout);
+        let buf = transfer.buf_mut();
+        buf.clear();
+        buf.extend_from_slice(chunk);
+        self.submit(transfer).await
+    }
+    async fn submit(&mut self, mut transfer: SafeTransfer<Vec<u8>>) -> Result<(), Error> {
+        match transfer.start_write(self.device) {
+            Ok(()) => {
+                self.in_flight.push_back(transfer);
+                Ok(())
+            }
+            Err(e) => {
+                self.free.push(transfer);
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+    /// Waits for the oldest transfer.
+    async fn reap_front(&mut self) -> Result<(), Error> {
+        let front = self
+            .in_flight
+            .front_mut()
+            .expect("reaping with nothing in flight");
+        // Only pop once complete so dropping the future doesn't lose the transfer
+        let result = front.wait_for_completion().await;
+        let transfer = self.in_flight.pop_front().expect("front transfer exists");
+        match result {
+            Ok(len) if len == transfer.buf_ref().len() => {
+                self.free.push(transfer);
+                Ok(())
+            }
+            Ok(_) => self.retry_unsent(transfer).await,
+            Err(e) => {
+                self.free.push(transfer);
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+    /// `short` was only partially sent. Everything queued after it has to wait for the rest of
+    /// it, so the later transfers get cancelled and all of them are resubmitted from where
+    /// they stopped.
+    async fn retry_unsent(&mut self, short: SafeTransfer<Vec<u8>>) -> Result<(), Error> {
+        for transfer in &self.in_flight {
+            transfer.try_cancel().ok();
+        }
+        let mut transfers = vec![short];
+        while let Some(mut transfer) = self.in_flight.pop_front() {
+            transfer.wait_for_completion().await.ok();
+            transfers.push(transfer);
+        }
+        for mut transfer in transfers {
+            let sent = transfer.transfer_ref().actual_length() as usize;
+            let completed = transfer.transfer_ref().status() == Some(Status::Completed);
+            if completed && sent == transfer.buf_ref().len() {
+                self.free.push(transfer);
+                continue;
+            }
+            // Includes cancelled zero length packets (nothing sent but not done either)
+            transfer.buf_mut().drain(..sent);
+            self.submit(transfer).await?;
+        }
+        Ok(())
+    }
+    /// Cancels everything in flight.
+    async fn abort(&mut self) {
+        for transfer in &self.in_flight {
+            transfer.try_cancel().ok();
+        }
+        while let Some(mut transfer) = self.in_flight.pop_front() {
+            transfer.wait_for_completion().await.ok();
+            self.free.push(transfer);
+        }
+    }
+}
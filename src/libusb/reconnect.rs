@@ -0,0 +1,374 @@
+//! `ReconnectingDevice`, an `AsyncDevice` that's reopened and set up again when it's unplugged and
+//! plugged back in.
+use crate::device::DeviceFilter;
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::context::Context;
+use crate::libusb::device::Device;
+use crate::libusb::error::Error;
+use crate::libusb::hotplug::{Event, Flags, HotplugStream};
+use crate::libusb::retry::RetryPolicy;
+use crate::libusb::runtime;
+use crate::traits::UsbFuture;
+use core::time::Duration;
+use futures_util::future::Either;
+use futures_util::{FutureExt, StreamExt};
+use std::sync::Arc;
+
+/// How often `device_list` is polled on platforms without hotplug support.
+const HOTPLUG_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What IO does while the device is disconnected.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum ReconnectPolicy {
+    /// Fail with `Error::NoDevice` unless the device already came back.
+    FailFast,
+    /// Wait up to the grace period for the device to come back.
+    Wait(Duration),
+}
+
+/// The arrived devices waiting to be tried and the open one. Generic over the devices so the
+/// bookkeeping doesn't need libusb.
+struct Arrivals<D, O> {
+    /// Devices that arrived and weren't tried yet.
+    arrived: Vec<D>,
+    current: Option<(D, O)>,
+}
+impl<D: PartialEq, O> Arrivals<D, O> {
+    fn new() -> Arrivals<D, O> {
+        Arrivals {
+            arrived: Vec::new(),
+            current: None,
+        }
+    }
+    /// Arrivals are kept if they `matches`, departures drop the device if it's the open one.
+    fn handle_event(&mut self, device: D, event: Event, matches: impl FnOnce(&D) -> bool) {
+        match event {
+            Event::DeviceLeft => {
+                self.arrived.retain(|d| d != &device);
+                if matches!(&self.current, Some((current, _)) if current == &device) {
+                    self.current = None;
+                }
+            }
+            _ => {
+                if matches(&device) {
+                    self.arrived.push(device)
+                }
+            }
+        }
+    }
+    /// The next device to try opening (in arrival order), `None` while one is open.
+    fn next_arrived(&mut self) -> Option<D> {
+        match self.current {
+            Some(_) => None,
+            None if self.arrived.is_empty() => None,
+            None => Some(self.arrived.remove(0)),
+        }
+    }
+}
+
+type InitFn =
+    Box<dyn for<'a> FnMut(&'a mut AsyncDevice) -> UsbFuture<'a, Result<(), Error>> + Send>;
+
+/// The first device matching a `DeviceFilter`, reopened whenever it comes back after being
+/// unplugged. Reopening claims the interfaces given to `claim` (detaching their kernel drivers),
+/// sets the alt settings given to `alt_setting` and then runs the `init` closure.
+///
+/// Arrivals and departures come from a `HotplugStream` (so the context's events have to be
+/// handled, like by an `AsyncContext`). They're looked at by the IO methods, there's no background
+/// task. A transfer failing with `Error::NoDevice` marks the device as disconnected and returns the
+/// error, it's not retried. The next IO waits for the device according to the `ReconnectPolicy`.
+pub struct ReconnectingDevice {
+    filter: DeviceFilter,
+    hotplug: HotplugStream,
+    policy: ReconnectPolicy,
+    open_retry: RetryPolicy,
+    interfaces: Vec<u8>,
+    alt_settings: Vec<(u8, u8)>,
+    init: Option<InitFn>,
+    devices: Arrivals<Device, AsyncDevice>,
+}
+impl ReconnectingDevice {
+    /// Subscribes to `context`'s hotplug events. Devices already connected count as arrived.
+    pub fn new(context: Arc<Context>, filter: DeviceFilter) -> Result<ReconnectingDevice, Error> {
+        Ok(ReconnectingDevice {
+            filter,
+            hotplug: HotplugStream::new(context, Flags::Enumerate, HOTPLUG_POLL_INTERVAL)?,
+            policy: ReconnectPolicy::FailFast,
+            open_retry: RetryPolicy::default(),
+            interfaces: Vec::new(),
+            alt_settings: Vec::new(),
+            init: None,
+            devices: Arrivals::new(),
+        })
+    }
+    /// Claims `interface` every time the device is opened.
+    pub fn claim(mut self, interface: u8) -> ReconnectingDevice {
+        if !self.interfaces.contains(&interface) {
+            self.interfaces.push(interface);
+        }
+        self
+    }
+    /// Selects `setting` of `interface` (which is claimed too) every time the device is opened.
+    pub fn alt_setting(mut self, interface: u8, setting: u8) -> ReconnectingDevice {
+        self.alt_settings.retain(|&(i, _)| i != interface);
+        self.alt_settings.push((interface, setting));
+        self.claim(interface)
+    }
+    /// Runs `init` (like `Box::pin(async move { ... })`) every time the device is opened, after
+    /// the interfaces are claimed. An error drops the device and waits for the next arrival.
+    pub fn init<F>(mut self, init: F) -> ReconnectingDevice
+    where
+        F: for<'a> FnMut(&'a mut AsyncDevice) -> UsbFuture<'a, Result<(), Error>> + Send + 'static,
+    {
+        self.init = Some(Box::new(init));
+        self
+    }
+    /// Retries of opening a device that just arrived. Defaults to `RetryPolicy::default()`.
+    pub fn open_retry(mut self, policy: RetryPolicy) -> ReconnectingDevice {
+        self.open_retry = policy;
+        self
+    }
+    /// Starts as `ReconnectPolicy::FailFast`.
+    pub fn policy(&self) -> ReconnectPolicy {
+        self.policy
+    }
+    pub fn set_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy
+    }
+    /// If the device is open. Doesn't look at hotplug events, see `connected`.
+    pub fn is_connected(&self) -> bool {
+        self.devices.current.is_some()
+    }
+    /// The open device, if there's one.
+    pub fn device(&self) -> Option<&AsyncDevice> {
+        self.devices.current.as_ref().map(|(_, device)| device)
+    }
+    /// Forgets the current device (like after an error that needs it reopened).
+    pub fn disconnect(&mut self) {
+        self.devices.current = None;
+    }
+    /// Takes the hotplug events that are ready.
+    fn handle_events(&mut self) {
+        while let Some(Some((device, event))) = self.hotplug.next().now_or_never() {
+            self.handle_event(device, event)
+        }
+    }
+    fn handle_event(&mut self, device: Device, event: Event) {
+        let filter = &self.filter;
+        self.devices.handle_event(device, event, |device| {
+            filter.matches_device_descriptors(device) == Ok(true)
+        })
+    }
+    /// Opens and sets up the arrived devices until one works.
+    async fn open_arrived(&mut self) {
+        while let Some(device) = self.devices.next_arrived() {
+            if let Ok(opened) = self.open(&device).await {
+                self.devices.current = Some((device, opened));
+            }
+        }
+    }
+    async fn open(&mut self, device: &Device) -> Result<AsyncDevice, Error> {
+        let handle = self
+            .open_retry
+            .retry_async(|| {
+                let device = device.clone();
+                blocking::unblock(move || device.open())
+            })
+            .await?;
+        let mut opened = AsyncDevice::new(handle);
+        if self.filter.serial_number().is_some() {
+            let serial = opened.strings().serial_number().await?;
+            if !self.filter.matches_serial(serial.as_deref()) {
+                return Err(Error::NotFound);
+            }
+        }
+        for &interface in &self.interfaces {
+            if opened.handle_ref().kernel_driver_active(interface)? {
+                opened
+                    .handle_mut_async()
+                    .await
+                    .detach_kernel_driver(interface)?;
+            }
+            opened.claim_interface(interface).await?;
+        }
+        for &(interface, setting) in &self.alt_settings {
+            opened.set_alternate_setting(interface, setting).await?;
+        }
+        if let Some(init) = &mut self.init {
+            init(&mut opened).await?;
+        }
+        Ok(opened)
+    }
+    /// The open device, (re)opening it according to the `ReconnectPolicy`. `Error::NoDevice`
+    /// if it isn't back in time.
+    pub async fn connected(&mut self) -> Result<&mut AsyncDevice, Error> {
+        self.handle_events();
+        self.open_arrived().await;
+        if self.devices.current.is_none() {
+            if let ReconnectPolicy::Wait(grace) = self.policy {
+                // One timer for the whole grace period, not one per event
+                let timeout = runtime::sleep(grace);
+                futures_util::pin_mut!(timeout);
+                while self.devices.current.is_none() {
+                    match futures_util::future::select(self.hotplug.next(), timeout.as_mut()).await
+                    {
+                        Either::Left((Some((device, event)), _)) => {
+                            self.handle_event(device, event)
+                        }
+                        Either::Left((None, _)) | Either::Right(_) => break,
+                    }
+                    self.open_arrived().await;
+                }
+            }
+        }
+        match &mut self.devices.current {
+            Some((_, device)) => Ok(device),
+            None => Err(Error::NoDevice),
+        }
+    }
+    /// Drops the device if `result` says it's gone.
+    fn check<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(Error::NoDevice) = result {
+            self.devices.current = None;
+        }
+        result
+    }
+    pub async fn control_read(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let result = self
+            .connected()
+            .await?
+            .control_read(request_type, request, value, index, data, timeout)
+            .await;
+        self.check(result)
+    }
+    pub async fn control_write(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let result = self
+            .connected()
+            .await?
+            .control_write(request_type, request, value, index, data, timeout)
+            .await;
+        self.check(result)
+    }
+    pub async fn bulk_read(
+        &mut self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let result = self
+            .connected()
+            .await?
+            .bulk_read(endpoint, data, timeout)
+            .await;
+        self.check(result)
+    }
+    pub async fn bulk_write(
+        &mut self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let result = self
+            .connected()
+            .await?
+            .bulk_write(endpoint, data, timeout)
+            .await;
+        self.check(result)
+    }
+    pub async fn interrupt_read(
+        &mut self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let result = self
+            .connected()
+            .await?
+            .interrupt_read(endpoint, data, timeout)
+            .await;
+        self.check(result)
+    }
+    pub async fn interrupt_write(
+        &mut self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let result = self
+            .connected()
+            .await?
+            .interrupt_write(endpoint, data, timeout)
+            .await;
+        self.check(result)
+    }
+}
+impl core::fmt::Debug for ReconnectingDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ReconnectingDevice")
+            .field("filter", &self.filter)
+            .field("policy", &self.policy)
+            .field("connected", &self.is_connected())
+            .finish()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::hotplug::Event;
+    use crate::libusb::reconnect::Arrivals;
+
+    #[test]
+    pub fn test_handle_event() {
+        let mut devices = Arrivals::<u8, &str>::new();
+        let odd = |device: &u8| device % 2 == 1;
+        devices.handle_event(1, Event::DeviceArrived, odd);
+        // Doesn't match the filter
+        devices.handle_event(2, Event::DeviceArrived, odd);
+        devices.handle_event(3, Event::DeviceArrived, odd);
+        devices.handle_event(5, Event::DeviceArrived, odd);
+        assert_eq!(devices.arrived, vec![1, 3, 5]);
+        // Left before it was tried
+        devices.handle_event(3, Event::DeviceLeft, odd);
+        assert_eq!(devices.arrived, vec![1, 5]);
+
+        devices.current = Some((7, "open"));
+        devices.handle_event(1, Event::DeviceLeft, odd);
+        assert_eq!(devices.current, Some((7, "open")));
+        devices.handle_event(7, Event::DeviceLeft, odd);
+        assert_eq!(devices.current, None);
+        assert_eq!(devices.arrived, vec![5]);
+    }
+    #[test]
+    pub fn test_next_arrived() {
+        let mut devices = Arrivals::<u8, &str>::new();
+        assert_eq!(devices.next_arrived(), None);
+        for device in [1, 2, 3] {
+            devices.handle_event(device, Event::DeviceArrived, |_| true);
+        }
+        // Tried in arrival order until one opens, like `open_arrived`
+        assert_eq!(devices.next_arrived(), Some(1));
+        assert_eq!(devices.next_arrived(), Some(2));
+        devices.current = Some((2, "open"));
+        assert_eq!(devices.next_arrived(), None);
+        assert_eq!(devices.arrived, vec![3]);
+        // Once it leaves the rest are tried again
+        devices.handle_event(2, Event::DeviceLeft, |_| true);
+        assert_eq!(devices.next_arrived(), Some(3));
+        assert_eq!(devices.next_arrived(), None);
+    }
+}
@@ -0,0 +1,318 @@
+//! Everything knowable about a device in one owned struct (`Device::probe`), for logs and bug
+//! reports.
+use crate::descriptors::{ParsedConfig, ParsedEndpoint, RawDescriptor};
+use crate::device::Descriptor;
+use crate::libusb::device::DeviceLocation;
+use crate::libusb::device_handle::DeviceHandle;
+use crate::libusb::error::Error;
+use crate::libusb::speed::Speed;
+use crate::version::Version;
+use core::fmt;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// Snapshot of a device. Displays like `lsusb -v`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DeviceReport {
+    pub descriptor: Descriptor,
+    pub bus_number: u8,
+    pub address: u8,
+    /// Empty if libusb couldn't get the port path.
+    pub port_numbers: Vec<u8>,
+    pub speed: Speed,
+    /// Configurations libusb couldn't read are left out.
+    pub configs: Vec<ParsedConfig>,
+    /// Every string descriptor (device, configuration and interface) that could be read, by
+    /// index. Empty if the device couldn't be opened.
+    pub strings: BTreeMap<u8, String>,
+    /// Why the device couldn't be opened (usually `Error::Access`).
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_error"))]
+    pub open_error: Option<Error>,
+}
+#[cfg(feature = "serde")]
+fn serialize_error<S: Serializer>(error: &Option<Error>, serializer: S) -> Result<S::Ok, S::Error> {
+    error.map(Error::as_str).serialize(serializer)
+}
+impl DeviceReport {
+    pub fn string(&self, index: Option<u8>) -> Option<&str> {
+        self.strings.get(&index?).map(String::as_str)
+    }
+    pub fn manufacturer(&self) -> Option<&str> {
+        self.string(self.descriptor.string_indices.manufacturer)
+    }
+    pub fn product(&self) -> Option<&str> {
+        self.string(self.descriptor.string_indices.product)
+    }
+    pub fn serial_number(&self) -> Option<&str> {
+        self.string(self.descriptor.string_indices.serial_number)
+    }
+    pub fn location(&self) -> DeviceLocation {
+        DeviceLocation {
+            bus: self.bus_number,
+            ports: self.port_numbers.clone(),
+        }
+    }
+    /// String indices used by the device descriptor and `configs`.
+    pub fn string_indices(&self) -> Vec<u8> {
+        let indices = &self.descriptor.string_indices;
+        let mut out: Vec<u8> = [indices.manufacturer, indices.product, indices.serial_number]
+            .iter()
+            .flatten()
+            .copied()
+            .chain(self.configs.iter().flat_map(|c| {
+                c.string_index.into_iter().chain(
+                    c.interfaces
+                        .iter()
+                        .flat_map(|i| &i.alt_settings)
+                        .filter_map(|a| a.string_index),
+                )
+            }))
+            .collect();
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+    /// Reads `string_indices` with `handle`. Strings that fail to read are skipped.
+    pub(crate) fn read_strings(&mut self, handle: &DeviceHandle) {
+        for index in self.string_indices() {
+            if let Ok(s) = handle.read_string_descriptor_ascii(index) {
+                self.strings.insert(index, s);
+            }
+        }
+    }
+    fn field(
+        f: &mut fmt::Formatter<'_>,
+        indent: usize,
+        name: &str,
+        value: impl fmt::Display,
+    ) -> fmt::Result {
+        writeln!(f, "{:indent$}{:<20}{:>6}", "", name, value, indent = indent)
+    }
+    fn string_field(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        indent: usize,
+        name: &str,
+        index: Option<u8>,
+    ) -> fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}{:<20}{:>6} {}",
+            "",
+            name,
+            index.unwrap_or(0),
+            self.string(index).unwrap_or(""),
+            indent = indent
+        )
+    }
+    fn fmt_extra(
+        f: &mut fmt::Formatter<'_>,
+        indent: usize,
+        extra: &[RawDescriptor],
+    ) -> fmt::Result {
+        for raw in extra {
+            write!(
+                f,
+                "{:indent$}** UNRECOGNIZED: {:02x} {:02x}",
+                "",
+                raw.data.len() + 2,
+                raw.descriptor_type,
+                indent = indent
+            )?;
+            for b in &raw.data {
+                write!(f, " {:02x}", b)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+    fn fmt_endpoint(f: &mut fmt::Formatter<'_>, endpoint: &ParsedEndpoint) -> fmt::Result {
+        writeln!(f, "      Endpoint Descriptor:")?;
+        let direction = if endpoint.address & 0x80 != 0 {
+            "IN"
+        } else {
+            "OUT"
+        };
+        writeln!(
+            f,
+            "        {:<20}  0x{:02x}  EP {} {}",
+            "bEndpointAddress",
+            endpoint.address,
+            endpoint.address & 0x0F,
+            direction
+        )?;
+        Self::field(f, 8, "bmAttributes", endpoint.attributes)?;
+        let transfer_type = ["Control", "Isochronous", "Bulk", "Interrupt"];
+        writeln!(
+            f,
+            "          Transfer Type          {}",
+            transfer_type[usize::from(endpoint.attributes & 0x03)]
+        )?;
+        let size = endpoint.max_packet_size;
+        writeln!(
+            f,
+            "        {:<20}0x{:04x}  {}x {} bytes",
+            "wMaxPacketSize",
+            size,
+            ((size >> 11) & 0x03) + 1,
+            size & 0x07FF
+        )?;
+        Self::field(f, 8, "bInterval", endpoint.interval)?;
+        Self::fmt_extra(f, 8, &endpoint.extra)
+    }
+    fn fmt_config(&self, f: &mut fmt::Formatter<'_>, config: &ParsedConfig) -> fmt::Result {
+        writeln!(f, "  Configuration Descriptor:")?;
+        Self::field(f, 4, "bNumInterfaces", config.interfaces.len())?;
+        Self::field(f, 4, "bConfigurationValue", config.configuration_value)?;
+        self.string_field(f, 4, "iConfiguration", config.string_index)?;
+        writeln!(f, "    {:<20}  0x{:02x}", "bmAttributes", config.attributes)?;
+        if config.attributes & 0x40 != 0 {
+            writeln!(f, "      Self Powered")?;
+        }
+        if config.attributes & 0x20 != 0 {
+            writeln!(f, "      Remote Wakeup")?;
+        }
+        let unit = if self.speed >= Speed::Super { 8 } else { 2 };
+        writeln!(
+            f,
+            "    {:<20}{:>4}mA",
+            "MaxPower",
+            u16::from(config.max_power) * unit
+        )?;
+        Self::fmt_extra(f, 4, &config.extra)?;
+        for alt in config.interfaces.iter().flat_map(|i| &i.alt_settings) {
+            writeln!(f, "    Interface Descriptor:")?;
+            Self::field(f, 6, "bInterfaceNumber", alt.interface_number)?;
+            Self::field(f, 6, "bAlternateSetting", alt.alternate_setting)?;
+            Self::field(f, 6, "bNumEndpoints", alt.endpoints.len())?;
+            Self::field(f, 6, "bInterfaceClass", alt.codes.class)?;
+            Self::field(f, 6, "bInterfaceSubClass", alt.codes.sub_class)?;
+            Self::field(f, 6, "bInterfaceProtocol", alt.codes.protocol)?;
+            self.string_field(f, 6, "iInterface", alt.string_index)?;
+            Self::fmt_extra(f, 6, &alt.extra)?;
+            for endpoint in &alt.endpoints {
+                Self::fmt_endpoint(f, endpoint)?;
+            }
+        }
+        Ok(())
+    }
+}
+/// BCD like `lsusb` shows it (`2.10`).
+fn bcd(version: Version) -> String {
+    format!("{:x}.{:02x}", version.0 >> 8, version.0 & 0xFF)
+}
+fn speed_name(speed: Speed) -> &'static str {
+    match speed {
+        Speed::Unknown => "unknown",
+        Speed::Low => "1.5M",
+        Speed::Full => "12M",
+        Speed::High => "480M",
+        Speed::Super => "5000M",
+        Speed::SuperPlus => "10000M",
+    }
+}
+impl fmt::Display for DeviceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.descriptor;
+        let id = d.device_identifier;
+        write!(
+            f,
+            "Bus {:03} Device {:03}: ID {:04x}:{:04x}",
+            self.bus_number, self.address, id.vendor_id.0, id.product_id.0
+        )?;
+        for s in [self.manufacturer(), self.product()].iter().flatten() {
+            write!(f, " {}", s)?;
+        }
+        writeln!(f)?;
+        if !self.port_numbers.is_empty() {
+            writeln!(f, "Port path: {}", self.location())?;
+        }
+        writeln!(f, "Speed: {}", speed_name(self.speed))?;
+        if let Some(e) = self.open_error {
+            writeln!(f, "Couldn't open device: {}", e)?;
+        }
+        writeln!(f, "Device Descriptor:")?;
+        Self::field(f, 2, "bcdUSB", bcd(d.usb_version))?;
+        Self::field(f, 2, "bDeviceClass", d.codes.class)?;
+        Self::field(f, 2, "bDeviceSubClass", d.codes.sub_class)?;
+        Self::field(f, 2, "bDeviceProtocol", d.codes.protocol)?;
+        Self::field(f, 2, "bMaxPacketSize0", d.max_packet_size)?;
+        Self::field(f, 2, "idVendor", format_args!("0x{:04x}", id.vendor_id.0))?;
+        Self::field(f, 2, "idProduct", format_args!("0x{:04x}", id.product_id.0))?;
+        Self::field(f, 2, "bcdDevice", bcd(d.device_version))?;
+        self.string_field(f, 2, "iManufacturer", d.string_indices.manufacturer)?;
+        self.string_field(f, 2, "iProduct", d.string_indices.product)?;
+        self.string_field(f, 2, "iSerial", d.string_indices.serial_number)?;
+        Self::field(f, 2, "bNumConfigurations", d.num_configurations)?;
+        for config in &self.configs {
+            self.fmt_config(f, config)?;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::descriptors::{parse_config_descriptor, parse_device_descriptor};
+    use crate::libusb::error::Error;
+    use crate::libusb::report::DeviceReport;
+    use crate::libusb::speed::Speed;
+    use std::collections::BTreeMap;
+
+    fn report() -> DeviceReport {
+        let descriptor = parse_device_descriptor(&[
+            18, 1, 0x10, 0x02, 0, 0, 0, 64, 0x6D, 0x04, 0x2B, 0xC5, 0x03, 0x12, 1, 2, 0, 1,
+        ])
+        .expect("valid device descriptor");
+        let config = parse_config_descriptor(&[
+            9, 2, 34, 0, 1, 1, 0, 0xA0, 50, // config
+            9, 4, 0, 0, 1, 3, 1, 1, 4, // interface
+            9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0, // HID
+            7, 5, 0x81, 3, 8, 0, 10, // endpoint
+        ])
+        .expect("valid config");
+        let mut strings = BTreeMap::new();
+        strings.insert(2, "USB Receiver".to_owned());
+        DeviceReport {
+            descriptor,
+            bus_number: 1,
+            address: 5,
+            port_numbers: vec![4, 2],
+            speed: Speed::Full,
+            configs: vec![config],
+            strings,
+            open_error: None,
+        }
+    }
+    #[test]
+    pub fn test_report_display() {
+        let mut report = report();
+        assert_eq!(report.string_indices(), vec![1, 2, 4]);
+        assert_eq!(report.product(), Some("USB Receiver"));
+        assert_eq!(report.manufacturer(), None);
+        let text = report.to_string();
+        assert!(text.starts_with("Bus 001 Device 005: ID 046d:c52b USB Receiver\n"));
+        assert!(text.contains("Port path: 1-4.2\n"));
+        assert!(text.contains("  bcdUSB                2.10\n"));
+        assert!(text.contains("  iProduct                 2 USB Receiver\n"));
+        assert!(text.contains("      Remote Wakeup\n"));
+        assert!(text.contains("    MaxPower             100mA\n"));
+        assert!(text.contains("      ** UNRECOGNIZED: 09 21 11 01 00 01 22 3f 00\n"));
+        assert!(text.contains("        bEndpointAddress      0x81  EP 1 IN\n"));
+        assert!(text.contains("          Transfer Type          Interrupt\n"));
+        assert!(text.contains("        wMaxPacketSize      0x0008  1x 8 bytes\n"));
+        report.open_error = Some(Error::Access);
+        assert!(report
+            .to_string()
+            .contains("Couldn't open device: Access denied"));
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn test_report_serialize() {
+        let json = serde_json::to_value(report()).expect("serializes");
+        assert_eq!(json["descriptor"]["device_identifier"], "046d:c52b");
+        assert_eq!(json["strings"]["2"], "USB Receiver");
+        assert_eq!(json["open_error"], serde_json::Value::Null);
+    }
+}
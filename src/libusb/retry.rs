@@ -0,0 +1,369 @@
+//! Retrying operations that fail while a device is still settling (like opening a device right
+//! after it's plugged in, before udev fixed its permissions).
+use crate::libusb::error::Error;
+use crate::libusb::runtime;
+use crate::libusb::transfer::{TransferError, NO_TIMEOUT};
+use core::time::Duration;
+use std::time::Instant;
+
+/// How often and how long to retry. The delay starts at `initial_backoff` and is multiplied by
+/// `multiplier` after every attempt (up to `max_backoff`). No more attempts are started once
+/// `deadline` has passed since the first one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct RetryPolicy {
+    /// Attempts in total (the first one included). `0` acts like `1`.
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: u32,
+    pub deadline: Option<Duration>,
+}
+impl Default for RetryPolicy {
+    /// 8 attempts starting at 50 ms, for up to 3 seconds. Enough for udev rules to apply.
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 8,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2,
+            deadline: Some(Duration::from_secs(3)),
+        }
+    }
+}
+impl RetryPolicy {
+    /// Exponential backoff (doubling) without a deadline.
+    pub const fn new(attempts: u32, initial_backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_backoff,
+            max_backoff: Duration::from_secs(u64::MAX),
+            multiplier: 2,
+            deadline: None,
+        }
+    }
+    /// Waits `backoff` between every attempt.
+    pub const fn constant(attempts: u32, backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_backoff: backoff,
+            max_backoff: backoff,
+            multiplier: 1,
+            deadline: None,
+        }
+    }
+    pub const fn with_max_backoff(mut self, max_backoff: Duration) -> RetryPolicy {
+        self.max_backoff = max_backoff;
+        self
+    }
+    pub const fn with_multiplier(mut self, multiplier: u32) -> RetryPolicy {
+        self.multiplier = multiplier;
+        self
+    }
+    pub const fn with_deadline(mut self, deadline: Duration) -> RetryPolicy {
+        self.deadline = Some(deadline);
+        self
+    }
+    /// The delays between the attempts. The delays never add up to more than `deadline`.
+    pub fn backoffs(&self) -> Backoffs {
+        Backoffs {
+            policy: *self,
+            next: self.initial_backoff.min(self.max_backoff),
+            remaining: self.attempts.saturating_sub(1),
+            total: Duration::from_secs(0),
+        }
+    }
+    /// Errors that might go away by waiting: `Error::is_transient` (like `Busy`, another process
+    /// or the kernel still has it) and `Error::is_permission` (permissions not applied yet).
+    /// `NoDevice` and everything else fail straight away.
+    pub fn is_retryable(error: Error) -> bool {
+        error.is_transient() || error.is_permission()
+    }
+    fn deadline_passed(&self, start: Instant) -> bool {
+        match self.deadline {
+            Some(deadline) => start.elapsed() >= deadline,
+            None => false,
+        }
+    }
+    /// Runs `f` until it succeeds, fails with an error that isn't `is_retryable` or the policy
+    /// runs out. Sleeps the thread between attempts. Returns the last error.
+    pub fn retry<T, E: Copy + Into<Error>>(
+        &self,
+        mut f: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let mut backoffs = self.backoffs();
+        loop {
+            match f() {
+                Err(e) if Self::is_retryable(e.into()) => match backoffs.next() {
+                    Some(delay) if !self.deadline_passed(start) => std::thread::sleep(delay),
+                    _ => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+    /// `retry` for async operations. The waiting happens on a blocking task (there's no runtime
+    /// independent timer).
+    pub async fn retry_async<T, E, F, Fut>(&self, mut f: F) -> Result<T, E>
+    where
+        E: Copy + Into<Error>,
+        F: FnMut() -> Fut,
+        Fut: core::future::Future<Output = Result<T, E>>,
+    {
+        let start = Instant::now();
+        let mut backoffs = self.backoffs();
+        loop {
+            match f().await {
+                Err(e) if Self::is_retryable(e.into()) => match backoffs.next() {
+                    Some(delay) if !self.deadline_passed(start) => runtime::sleep(delay).await,
+                    _ => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+}
+/// Delays of a `RetryPolicy` (`RetryPolicy::backoffs`).
+#[derive(Clone, Debug)]
+pub struct Backoffs {
+    policy: RetryPolicy,
+    next: Duration,
+    remaining: u32,
+    /// Sum of the delays so far. Only kept with a deadline.
+    total: Duration,
+}
+impl Iterator for Backoffs {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let mut delay = self.next;
+        if let Some(deadline) = self.policy.deadline {
+            let left = deadline.checked_sub(self.total).unwrap_or_default();
+            if left == Duration::from_secs(0) {
+                self.remaining = 0;
+                return None;
+            }
+            if delay >= left {
+                delay = left;
+                // The deadline is reached after this delay, one last attempt
+                self.remaining = 0;
+            }
+            self.total += delay;
+        }
+        self.next = self
+            .next
+            .checked_mul(self.policy.multiplier)
+            .unwrap_or(self.policy.max_backoff)
+            .min(self.policy.max_backoff);
+        Some(delay)
+    }
+}
+/// What's left of `timeout` after `elapsed`. `None` once it's used up, `NO_TIMEOUT` stays
+/// `NO_TIMEOUT`.
+pub(crate) fn remaining_timeout(timeout: Duration, elapsed: Duration) -> Option<Duration> {
+    if timeout == NO_TIMEOUT {
+        return Some(NO_TIMEOUT);
+    }
+    timeout
+        .checked_sub(elapsed)
+        .filter(|left| *left != Duration::from_secs(0))
+}
+/// Stall recovery of one transfer (`AsyncDevice::set_auto_clear_halt`). After the first stall the
+/// halt is cleared and the transfer is retried once, both within the original timeout.
+#[derive(Debug)]
+pub(crate) struct StallRecovery {
+    enabled: bool,
+    timeout: Duration,
+    start: Instant,
+    /// The stall being recovered from. Given back if the recovery fails.
+    stall: Option<TransferError>,
+}
+impl StallRecovery {
+    pub(crate) fn new(enabled: bool, timeout: Duration) -> StallRecovery {
+        StallRecovery {
+            enabled,
+            timeout,
+            start: Instant::now(),
+            stall: None,
+        }
+    }
+    /// If the halt should be cleared and the transfer retried after `result`. Only the first
+    /// stall is recovered from.
+    pub(crate) fn should_recover(&mut self, result: &Result<usize, TransferError>) -> bool {
+        match result {
+            Err(e)
+                if self.enabled
+                    && Error::from(*e).should_retry_after_clear_halt()
+                    && self.stall.is_none() =>
+            {
+                self.stall = Some(*e);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Timeout for the next step of the recovery or the stall if there's no time left.
+    pub(crate) fn remaining(&self) -> Result<Duration, TransferError> {
+        remaining_timeout(self.timeout, self.start.elapsed()).ok_or_else(|| self.stall())
+    }
+    /// The stall being recovered from.
+    pub(crate) fn stall(&self) -> TransferError {
+        self.stall.unwrap_or(TransferError::Error(Error::Other))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::context::Context;
+    use crate::libusb::error::Error;
+    use crate::libusb::retry::{remaining_timeout, RetryPolicy, StallRecovery};
+    use crate::libusb::transfer::{Status, TransferError, NO_TIMEOUT};
+    use core::time::Duration;
+
+    fn millis(backoffs: impl Iterator<Item = Duration>) -> Vec<u128> {
+        backoffs.map(|d| d.as_millis()).collect()
+    }
+    #[test]
+    pub fn test_backoffs() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(10));
+        assert_eq!(millis(policy.backoffs()), vec![10, 20, 40, 80]);
+        let capped = policy.with_max_backoff(Duration::from_millis(25));
+        assert_eq!(millis(capped.backoffs()), vec![10, 20, 25, 25]);
+        let constant = RetryPolicy::constant(3, Duration::from_millis(100));
+        assert_eq!(millis(constant.backoffs()), vec![100, 100]);
+        assert_eq!(
+            millis(RetryPolicy::new(1, Duration::from_millis(10)).backoffs()),
+            vec![]
+        );
+        assert_eq!(
+            millis(RetryPolicy::new(0, Duration::from_millis(10)).backoffs()),
+            vec![]
+        );
+    }
+    #[test]
+    pub fn test_backoffs_deadline() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(10))
+            .with_deadline(Duration::from_millis(100));
+        // 10 + 20 + 40 = 70, only 30 left for the 80
+        assert_eq!(millis(policy.backoffs()), vec![10, 20, 40, 30]);
+        let policy = RetryPolicy::new(u32::MAX, Duration::from_secs(u64::MAX))
+            .with_deadline(Duration::from_millis(5));
+        assert_eq!(millis(policy.backoffs()), vec![5]);
+        assert_eq!(
+            millis(RetryPolicy::default().backoffs())
+                .iter()
+                .sum::<u128>(),
+            3000
+        );
+    }
+    #[test]
+    pub fn test_retry() {
+        let policy = RetryPolicy::constant(3, Duration::from_millis(1));
+        let mut calls = 0;
+        let result = policy.retry(|| {
+            calls += 1;
+            match calls {
+                1 => Err(Error::Access),
+                2 => Err(Error::Busy),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result, Ok(3));
+        calls = 0;
+        let result: Result<(), Error> = policy.retry(|| {
+            calls += 1;
+            Err(Error::NoDevice)
+        });
+        assert_eq!((result, calls), (Err(Error::NoDevice), 1));
+        calls = 0;
+        let result: Result<(), Error> = policy.retry(|| {
+            calls += 1;
+            Err(Error::Access)
+        });
+        assert_eq!((result, calls), (Err(Error::Access), 3));
+        let retryable = [
+            Error::Access,
+            Error::Busy,
+            Error::Timeout,
+            Error::Interrupted,
+        ];
+        for &error in retryable.iter() {
+            assert!(RetryPolicy::is_retryable(error), "{:?}", error);
+        }
+        for &error in [Error::NoDevice, Error::Pipe, Error::InvalidParam].iter() {
+            assert!(!RetryPolicy::is_retryable(error), "{:?}", error);
+        }
+    }
+    #[test]
+    pub fn test_remaining_timeout() {
+        let second = Duration::from_secs(1);
+        assert_eq!(remaining_timeout(NO_TIMEOUT, second), Some(NO_TIMEOUT));
+        assert_eq!(
+            remaining_timeout(second, Duration::from_millis(400)),
+            Some(Duration::from_millis(600))
+        );
+        assert_eq!(remaining_timeout(second, second), None);
+        assert_eq!(remaining_timeout(second, second * 2), None);
+    }
+    /// Fake transfers: how many the recovery lets run for a sequence of results.
+    fn attempts(enabled: bool, results: &[Result<usize, TransferError>]) -> usize {
+        let mut recovery = StallRecovery::new(enabled, Duration::from_secs(10));
+        let mut submitted = 0;
+        for result in results {
+            submitted += 1;
+            if !recovery.should_recover(result) {
+                break;
+            }
+            assert!(recovery.remaining().is_ok());
+        }
+        submitted
+    }
+    #[test]
+    pub fn test_stall_recovery() {
+        let stall = TransferError::Status {
+            status: Status::Stall,
+            actual_length: 0,
+        };
+        let timeout = TransferError::Status {
+            status: Status::TimedOut,
+            actual_length: 0,
+        };
+        // Retried once after a stall
+        assert_eq!(attempts(true, &[Err(stall), Ok(4)]), 2);
+        // Never loops on an endpoint that keeps stalling
+        assert_eq!(attempts(true, &[Err(stall), Err(stall), Err(stall)]), 2);
+        // Other errors and successes aren't retried
+        assert_eq!(attempts(true, &[Err(timeout), Ok(4)]), 1);
+        assert_eq!(attempts(true, &[Ok(4), Ok(4)]), 1);
+        assert_eq!(attempts(false, &[Err(stall), Ok(4)]), 1);
+
+        let mut recovery = StallRecovery::new(true, Duration::from_nanos(1));
+        assert!(recovery.should_recover(&Err(stall)));
+        std::thread::sleep(Duration::from_millis(1));
+        // The time is used up, the stall is given back
+        assert_eq!(recovery.remaining(), Err(stall));
+        assert_eq!(Error::from(recovery.stall()), Error::Pipe);
+    }
+    /// Needs an attached device that can be opened.
+    #[test]
+    #[ignore]
+    pub fn test_open_async_with_retry() {
+        let context = Context::new().expect("libusb context");
+        let device = context
+            .device_list()
+            .iter()
+            .find(|d| d.open().is_ok())
+            .expect("an openable device");
+        assert!(device.open_with_retry(3, Duration::from_millis(10)).is_ok());
+        let context = context.start_async();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can't make async runtime");
+        let opened =
+            runtime.block_on(context.open_async_with_retry(&device, RetryPolicy::default()));
+        assert!(opened.is_ok());
+    }
+}
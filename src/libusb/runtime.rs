@@ -0,0 +1,58 @@
+//! Timers used by the async API (retry backoffs, reopen polling, transfer timeouts).
+//!
+//! Without a `runtime-*` feature a timer sleeps on a thread of the `blocking` crate's pool, which
+//! works under any executor (or none) but ties up a thread while it runs. With `runtime-tokio` or
+//! `runtime-async-std` the executor's own timer is used instead, so the futures have to be polled
+//! inside that runtime. `runtime-tokio` wins if both are enabled. `runtime-driver-async` keeps the
+//! `blocking` thread, `driver_async` has no timer of its own, so the futures can also be driven by
+//! its `block_on_future`.
+use core::time::Duration;
+
+#[cfg(feature = "runtime-tokio")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+pub(crate) async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+pub(crate) async fn sleep(duration: Duration) {
+    blocking::unblock(move || std::thread::sleep(duration)).await
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::runtime::sleep;
+    use core::time::Duration;
+    use std::time::Instant;
+
+    const DURATION: Duration = Duration::from_millis(20);
+
+    #[test]
+    pub fn test_sleep_tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("can't make async runtime");
+        let start = Instant::now();
+        runtime.block_on(sleep(DURATION));
+        assert!(start.elapsed() >= DURATION);
+    }
+    #[test]
+    #[cfg(not(feature = "runtime-tokio"))]
+    pub fn test_sleep_async_std() {
+        let start = Instant::now();
+        async_std::task::block_on(sleep(DURATION));
+        assert!(start.elapsed() >= DURATION);
+    }
+    #[test]
+    #[cfg(all(
+        feature = "runtime-driver-async",
+        not(any(feature = "runtime-tokio", feature = "runtime-async-std"))
+    ))]
+    pub fn test_sleep_driver_async() {
+        let start = Instant::now();
+        driver_async::asyncs::task::block_on_future(sleep(DURATION));
+        assert!(start.elapsed() >= DURATION);
+    }
+}
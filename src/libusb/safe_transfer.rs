@@ -1,71 +1,242 @@
 use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::buffer::gathered;
+use crate::libusb::context::ContextInner;
 use crate::libusb::error::Error;
-use crate::libusb::transfer::{ControlSetup, Flags, Transfer, TransferType};
+use crate::libusb::in_flight::InFlight;
+#[cfg(feature = "metrics")]
+use crate::libusb::metrics::{completion_endpoint, DeviceMetrics};
+use crate::libusb::runtime;
+use crate::libusb::transfer::{
+    transfer_len, ControlSetup, Flag, Flags, IsoPacket, Transfer, TransferError, TransferResult,
+    TransferType,
+};
 use core::borrow::BorrowMut;
+use core::future::Future;
 use core::mem;
-use core::sync::atomic::{AtomicBool, Ordering};
-use driver_async::asyncs::sync::mpsc;
-use driver_async::asyncs::task::block_on_future;
+use core::mem::ManuallyDrop;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::future::Either;
+use std::io::IoSlice;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 struct UserData {
-    sender: mpsc::Sender<()>,
+    /// Counts the submissions, set before each one so its completion is told apart from a late
+    /// one of the previous submission (links are reused).
+    generation: AtomicU64,
+    /// Generation of the last completion and the waker of whoever waits for the next one.
+    completion: Mutex<(u64, Option<Waker>)>,
     is_active: AtomicBool,
+    hooks: Mutex<Hooks>,
+    /// The buffer and `Transfer` of a `SafeTransfer` dropped while in flight, freed by the
+    /// callback once libusb is done with them.
+    detached: Mutex<Option<Box<dyn Send>>>,
+}
+/// What the callback reports the completion to, set on submission.
+#[derive(Default)]
+struct Hooks {
+    /// In flight registry of the device the transfer was last submitted to.
+    in_flight: Option<InFlight>,
+    /// Context of the device the transfer was last submitted to, whose events a dropped
+    /// transfer with borrowed parts handles until it's done.
+    context: Option<Arc<ContextInner>>,
+    /// Counters of the device the transfer was last submitted to and when.
+    #[cfg(feature = "metrics")]
+    metrics: Option<(DeviceMetrics, std::time::Instant)>,
 }
 
 impl UserData {
     pub fn send_completion(&self) {
-        debug_assert_eq!(self.is_active.load(Ordering::SeqCst), true);
-        self.is_active.store(false, Ordering::SeqCst);
-        // Ignore if receiver is dropped
-        self.sender.try_send(()).ok();
+        debug_assert!(self.is_active.load(Ordering::SeqCst));
+        let waker = {
+            let mut completion = self.completion.lock().unwrap_or_else(|e| e.into_inner());
+            completion.0 = self.generation.load(Ordering::SeqCst);
+            // Inactive before the lock is released so a `WaitCompletion` that's ready always
+            // sees it. From here the link can be reused or dropped, the callback's own reference
+            // keeps `self` alive until it returns.
+            self.is_active.store(false, Ordering::SeqCst);
+            completion.1.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake()
+        }
+    }
+    fn take_detached(&self) -> Option<Box<dyn Send>> {
+        self.detached
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+}
+
+/// The owned parts of a `SafeTransfer`, moved into its `UserData` when it's dropped in flight.
+struct DetachedParts<Buf> {
+    _buf: Buf,
+    _transfer: Transfer,
+}
+// # Safety
+// libusb is done with the `Transfer` once it's dropped, which only frees it and
+// `libusb_free_transfer` can be called from any thread.
+unsafe impl<Buf: Send> Send for DetachedParts<Buf> {}
+
+/// How long a dropped transfer with borrowed parts handles events at a time while it waits.
+const DROP_EVENTS_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(100);
+
+/// Cancels an in flight transfer when a submit future is dropped before completion. It doesn't
+/// wait for the cancellation to finish, the `SafeTransfer` still does that.
+struct CancelOnDrop {
+    transfer: core::ptr::NonNull<libusb1_sys::libusb_transfer>,
+    user_data: *const UserData,
+}
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        // # Safety
+        // The guard only lives inside `SafeTransfer::submit` which borrows the `SafeTransfer` so
+        // both pointers are valid.
+        unsafe {
+            if (*self.user_data).is_active.load(Ordering::SeqCst) {
+                libusb1_sys::libusb_cancel_transfer(self.transfer.as_ptr());
+            }
+        }
+    }
+}
+
+/// Resolves once the submission `generation` completed or the transfer is otherwise inactive
+/// (like a submission that failed, which never completes).
+struct WaitCompletion<'a> {
+    user_data: &'a UserData,
+    generation: u64,
+}
+impl<'a> Future for WaitCompletion<'a> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut completion = self
+            .user_data
+            .completion
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if completion.0 >= self.generation {
+            return Poll::Ready(());
+        }
+        completion.1 = Some(cx.waker().clone());
+        // Checked again with the waker stored, still locked. Going inactive without a completion
+        // doesn't take the lock, so it could've happened while the waker was cloned and nobody
+        // would wake it.
+        if self.user_data.is_active.load(Ordering::SeqCst) {
+            Poll::Pending
+        } else {
+            completion.1 = None;
+            Poll::Ready(())
+        }
     }
 }
 
+/// Wakes a thread parked in `SafeTransfer::park_until_inactive`.
+struct Unpark(std::thread::Thread);
+impl futures_util::task::ArcWake for Unpark {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark()
+    }
+}
+
+/// The completion state shared with the libusb callback. Every submission leaks a reference to
+/// the `UserData` as the transfer's `user_data` which the callback takes back, so the callback
+/// never touches freed memory even if the `SafeTransfer` or the link is gone by then.
 pub struct SafeTransferAsyncLink {
-    receiver: mpsc::Receiver<()>,
-    user_data: Box<UserData>,
+    user_data: Arc<UserData>,
 }
 
 impl SafeTransferAsyncLink {
     pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(1);
         SafeTransferAsyncLink {
-            receiver,
-            user_data: Box::new(UserData {
-                sender,
+            user_data: Arc::new(UserData {
+                generation: AtomicU64::new(0),
+                completion: Mutex::new((0, None)),
                 is_active: AtomicBool::new(false),
+                hooks: Mutex::new(Hooks::default()),
+                detached: Mutex::new(None),
             }),
         }
     }
 }
+impl Default for SafeTransferAsyncLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub struct SafeTransfer<
     Buf,
     Trans: BorrowMut<Transfer> = Transfer,
     Link: BorrowMut<SafeTransferAsyncLink> = SafeTransferAsyncLink,
 > {
-    buf: Buf,
-    transfer: Trans,
-    link: Link,
+    /// Dropped by hand (or given to the callback) in `Drop`.
+    buf: ManuallyDrop<Buf>,
+    transfer: ManuallyDrop<Trans>,
+    link: ManuallyDrop<Link>,
+    flags: Flags,
+    /// Moves `buf` and `transfer` out for the callback to free, set by the constructors that
+    /// own them.
+    detach: Option<Detach<Self>>,
+}
+type Detach<T> = unsafe fn(&mut T) -> Box<dyn Send>;
+/// Per transfer options that are applied to the `Transfer` flags on submission.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Default)]
+pub struct TransferOptions {
+    /// Send a trailing zero length packet if the OUT data is a multiple of the max packet size.
+    pub add_zero_packet: bool,
+    /// Treat short IN transfers as errors.
+    pub short_not_ok: bool,
 }
 
 impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
     SafeTransfer<Buf, Trans, Link>
 {
+    /// Dropping the transfer while it's in flight waits for libusb to be done with the parts
+    /// (see `Drop`), prefer `from_buf` when they can be owned. Submitting it on a handle without
+    /// a context (`DeviceHandle::from_libusb`) fails with `Error::NotSupported`.
     pub fn from_parts(buf: Buf, transfer: Trans, link: Link) -> Self {
         Self {
-            buf,
-            transfer,
-            link,
+            buf: ManuallyDrop::new(buf),
+            transfer: ManuallyDrop::new(transfer),
+            link: ManuallyDrop::new(link),
+            flags: Flags::ZEROED,
+            detach: None,
         }
     }
 }
-impl<Buf> SafeTransfer<Buf, Transfer, SafeTransferAsyncLink> {
+impl<Buf: Send + 'static> SafeTransfer<Buf, Transfer, SafeTransferAsyncLink> {
     pub fn from_buf(buf: Buf) -> Self {
         Self::from_transfer_buf(Transfer::new(0), buf)
     }
+    /// Allocates the `Transfer` with room for `iso_packets` isochronous packets, see
+    /// `Transfer::try_new` for the errors.
+    pub fn from_buf_iso(buf: Buf, iso_packets: usize) -> Result<Self, Error> {
+        Ok(Self::from_transfer_buf(
+            Transfer::try_new(iso_packets)?,
+            buf,
+        ))
+    }
     pub fn from_transfer_buf(transfer: Transfer, buf: Buf) -> Self {
-        Self::from_parts(buf, transfer, SafeTransferAsyncLink::new())
+        let mut safe = Self::from_parts(buf, transfer, SafeTransferAsyncLink::new());
+        safe.detach = Some(Self::detach_parts);
+        safe
+    }
+    /// # Safety
+    /// `buf` and `transfer` mustn't be used afterwards.
+    unsafe fn detach_parts(&mut self) -> Box<dyn Send> {
+        Box::new(DetachedParts {
+            _buf: ManuallyDrop::take(&mut self.buf),
+            _transfer: ManuallyDrop::take(&mut self.transfer),
+        })
+    }
+}
+impl SafeTransfer<Vec<u8>, Transfer, SafeTransferAsyncLink> {
+    /// Transfer of `bufs` one after the other, copied into one buffer (libusb needs contiguous
+    /// memory). `PooledTransfer::staged_vectored` does the same without allocating.
+    pub fn from_io_slices(bufs: &[IoSlice<'_>]) -> Self {
+        Self::from_buf(gathered(bufs))
     }
 }
 impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
@@ -77,29 +248,87 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
                 core::ptr::NonNull::new(transfer).expect("null transfer ptr in callback"),
             )
         };
-        Self::callback(&mut transfer);
+        let user_data = Self::callback(&mut transfer);
         // Forget because dropping call's `libusb_transfer_free` and that is handled elsewhere
-        core::mem::forget(transfer)
+        core::mem::forget(transfer);
+        // libusb allows freeing the transfer in its callback
+        if let Some(user_data) = user_data {
+            drop(user_data.take_detached())
+        }
     }
-    fn callback(transfer: &mut Transfer) {
-        if transfer.libusb_ref().user_data.is_null() {
-            return;
+    fn callback(transfer: &mut Transfer) -> Option<Arc<UserData>> {
+        let user_data = transfer.libusb_ref().user_data as *const UserData;
+        if user_data.is_null() {
+            return None;
+        }
+        transfer.set_user_data(core::ptr::null_mut::<UserData>());
+        // # Safety
+        // The reference leaked by `submit_asynchronously` for this submission.
+        let user_data = unsafe { Arc::from_raw(user_data) };
+        usb_trace!(
+            endpoint = transfer.get_endpoint(),
+            transfer_type = ?transfer.get_type(),
+            status = ?transfer.status(),
+            actual_length = transfer.actual_length(),
+            "transfer completed"
+        );
+        {
+            let hooks = user_data.hooks.lock().unwrap_or_else(|e| e.into_inner());
+            #[cfg(feature = "metrics")]
+            Self::record_completion(transfer, &hooks);
+            if let Some(in_flight) = &hooks.in_flight {
+                in_flight.remove(transfer.libusb_inner());
+            }
         }
-        let user_data = unsafe { transfer.cast_userdata_ref::<UserData>() };
         // Signal completion
         user_data.send_completion();
+        Some(user_data)
+    }
+    #[cfg(feature = "metrics")]
+    fn record_completion(transfer: &Transfer, hooks: &Hooks) {
+        if let (Some((metrics, submitted)), Some(status)) = (&hooks.metrics, transfer.status()) {
+            let libusb = transfer.libusb_ref();
+            let setup = match libusb.buffer.is_null() {
+                true => 0,
+                // The first byte of a control transfer's buffer is `bmRequestType`
+                false => unsafe { *libusb.buffer },
+            };
+            metrics.record_completion(
+                completion_endpoint(transfer.get_type(), transfer.get_endpoint(), setup),
+                status,
+                transfer.actual_length().max(0) as usize,
+                submitted.elapsed(),
+            );
+        }
     }
     pub fn is_active(&self) -> bool {
-        self.link
-            .borrow()
-            .user_data
-            .is_active
-            .load(Ordering::SeqCst)
+        self.user_data().is_active.load(Ordering::SeqCst)
     }
     pub async fn into_parts(mut self) -> (Buf, Trans, Link) {
         self.wait_for_inactive().await;
         self.into_all_parts()
     }
+    /// `into_parts` giving up after `timeout`, the transfer is returned so it can be cancelled
+    /// (or waited for again).
+    pub async fn into_parts_timeout(
+        mut self,
+        timeout: core::time::Duration,
+    ) -> Result<(Buf, Trans, Link), Self> {
+        let inactive = {
+            let wait = self.wait_for_inactive();
+            futures_util::pin_mut!(wait);
+            let timer = runtime::sleep(timeout);
+            futures_util::pin_mut!(timer);
+            matches!(
+                futures_util::future::select(wait, timer).await,
+                Either::Left(_)
+            )
+        };
+        match inactive {
+            true => Ok(self.into_all_parts()),
+            false => Err(self),
+        }
+    }
     /// # Safety
     /// Must be called when inactive
     fn into_all_parts(mut self) -> (Buf, Trans, Link) {
@@ -108,11 +337,11 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
             "deconstructing SafeTransfer while active"
         );
         // # Safety
-        // Manual dropping of the fields in order to move `buf` and `transfer` out of the struct
+        // The fields are moved out and `self` is forgotten so they aren't dropped again
         unsafe {
-            let buf = (&mut self.buf as *mut Buf).read();
-            let transfer = (&mut self.transfer as *mut Trans).read();
-            let link = (&mut self.link as *mut Link).read();
+            let buf = ManuallyDrop::take(&mut self.buf);
+            let transfer = ManuallyDrop::take(&mut self.transfer);
+            let link = ManuallyDrop::take(&mut self.link);
             mem::forget(self);
             (buf, transfer, link)
         }
@@ -123,19 +352,59 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
     pub async fn into_transfer(self) -> Trans {
         self.into_parts().await.1
     }
-    async fn wait_for_receiver(&mut self) {
-        self.link.borrow_mut().receiver.recv().await;
-    }
+    /// Waits for the completion of the current submission. A completion that never comes (like
+    /// with the events not being handled) waits forever, see `into_parts_timeout`.
     async fn wait_for_inactive(&mut self) {
         if self.is_active() {
-            self.wait_for_receiver().await
+            let user_data = &**self.user_data();
+            WaitCompletion {
+                user_data,
+                generation: user_data.generation.load(Ordering::SeqCst),
+            }
+            .await;
+        }
+        debug_assert!(!self.is_active(), "completed but still active");
+    }
+    fn hooks(&self) -> MutexGuard<'_, Hooks> {
+        self.user_data()
+            .hooks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+    /// Handles the events of the context the transfer was submitted in until it's inactive, so
+    /// it doesn't matter which thread (if any) usually handles them. Transfers with borrowed
+    /// parts aren't submitted without a context, one that's active anyway (never submitted by
+    /// `start_submit`) parks the thread until another one completes it.
+    fn handle_events_until_inactive(&self) {
+        // Not matched on directly, the hooks can't stay locked while parked
+        let context = self.hooks().context.clone();
+        let context = match context {
+            Some(context) => context,
+            None => return self.park_until_inactive(),
+        };
+        let timeout =
+            crate::libusb::context::timeval_from_duration(DROP_EVENTS_TIMEOUT).expect("fits");
+        while self.is_active() {
+            unsafe {
+                libusb1_sys::libusb_handle_events_timeout_completed(
+                    context.ptr(),
+                    &timeout,
+                    core::ptr::null_mut(),
+                );
+            }
         }
     }
-    fn sync_wait_for_cancel(&mut self) -> Result<(), Error> {
-        if self.cancel_asynchronously()? {
-            block_on_future(self.wait_for_inactive())
+    fn park_until_inactive(&self) {
+        let user_data = &**self.user_data();
+        let mut wait = WaitCompletion {
+            user_data,
+            generation: user_data.generation.load(Ordering::SeqCst),
+        };
+        let waker = futures_util::task::waker(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        while Pin::new(&mut wait).poll(&mut cx).is_pending() {
+            std::thread::park();
         }
-        Ok(())
     }
     fn check_endpoint(&self, is_read: bool) -> Result<(), Error> {
         if self.transfer_ref().is_endpoint_read() != is_read {
@@ -145,7 +414,7 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
         }
     }
     pub fn set_timeout(&mut self, timeout: core::time::Duration) {
-        self.transfer.borrow_mut().set_timeout(timeout)
+        self.transfer_mut().set_timeout(timeout)
     }
     pub fn get_timeout(&self) -> core::time::Duration {
         self.transfer_ref().get_timeout()
@@ -154,21 +423,79 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
         self.transfer_ref().get_endpoint()
     }
     pub fn set_endpoint(&mut self, endpoint: u8) {
-        self.transfer.borrow_mut().set_endpoint(endpoint)
+        self.transfer_mut().set_endpoint(endpoint)
     }
 
+    /// Starts a new submission generation and marks the transfer active.
+    fn begin_submission(&self) {
+        let user_data = &self.user_data();
+        user_data.generation.fetch_add(1, Ordering::SeqCst);
+        user_data.is_active.store(true, Ordering::SeqCst)
+    }
     fn set_active(&self, is_active: bool) {
-        self.link
-            .borrow()
-            .user_data
+        self.user_data()
             .is_active
             .store(is_active, Ordering::SeqCst)
     }
     pub fn get_type(&self) -> TransferType {
         self.transfer_ref().get_type()
     }
+    fn set_flag(&mut self, flag: Flag, value: bool) {
+        if value {
+            self.flags.set(flag)
+        } else {
+            self.flags.clear(flag)
+        }
+    }
+    /// Sends a trailing zero length packet if the OUT data is a multiple of the max packet size.
+    pub fn set_add_zero_packet(&mut self, add_zero_packet: bool) {
+        self.set_flag(Flag::AddZeroPacket, add_zero_packet)
+    }
+    /// Makes short IN transfers complete with an error.
+    pub fn set_short_not_ok(&mut self, short_not_ok: bool) {
+        self.set_flag(Flag::ShortNotOk, short_not_ok)
+    }
+    pub fn set_options(&mut self, options: TransferOptions) {
+        self.set_add_zero_packet(options.add_zero_packet);
+        self.set_short_not_ok(options.short_not_ok);
+    }
+    pub fn options(&self) -> TransferOptions {
+        TransferOptions {
+            add_zero_packet: self.flags.get(Flag::AddZeroPacket),
+            short_not_ok: self.flags.get(Flag::ShortNotOk),
+        }
+    }
+    /// Sets the number of isochronous packets and gives each of them a length of `packet_len`.
+    /// Returns `Error::Overflow` if the `Transfer` wasn't allocated with enough iso packets.
+    pub fn set_iso_packets(&mut self, num_packets: usize, packet_len: u32) -> Result<(), Error> {
+        self.ensure_inactive()?;
+        let transfer = self.transfer_mut();
+        if num_packets > transfer.max_iso_packets() {
+            return Err(Error::Overflow);
+        }
+        transfer.set_num_iso_packets(num_packets);
+        transfer.set_iso_packet_lengths(packet_len);
+        Ok(())
+    }
+    /// Sets the length of a single isochronous packet. `set_iso_packets` must be called first.
+    pub fn set_iso_packet_length(&mut self, packet: usize, len: u32) -> Result<(), Error> {
+        self.ensure_inactive()?;
+        self.transfer_mut()
+            .iso_packet_descriptors_mut()
+            .get_mut(packet)
+            .ok_or(Error::InvalidParam)?
+            .length = len;
+        Ok(())
+    }
     pub fn set_type(&mut self, transfer_type: TransferType) {
-        self.transfer.borrow_mut().set_type(transfer_type)
+        self.transfer_mut().set_type(transfer_type)
+    }
+    /// Sets the bulk stream id. Also sets the transfer type to `TransferType::Stream`.
+    pub fn set_stream_id(&mut self, stream_id: u32) {
+        self.transfer_mut().set_stream_id(stream_id)
+    }
+    pub fn get_stream_id(&self) -> u32 {
+        self.transfer_ref().get_stream_id()
     }
     fn ensure_inactive(&self) -> Result<(), Error> {
         if self.is_active() {
@@ -178,7 +505,13 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
         }
     }
     pub fn transfer_ref(&self) -> &Transfer {
-        self.transfer.borrow()
+        (*self.transfer).borrow()
+    }
+    fn transfer_mut(&mut self) -> &mut Transfer {
+        (*self.transfer).borrow_mut()
+    }
+    fn user_data(&self) -> &Arc<UserData> {
+        &(*self.link).borrow().user_data
     }
     /// Returns if it did try to cancel
     fn cancel_asynchronously(&self) -> Result<bool, Error> {
@@ -189,6 +522,24 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
             Ok(false)
         }
     }
+    /// Requests the transfer to be cancelled without waiting for it. Returns if a cancel was
+    /// issued.
+    pub fn try_cancel(&self) -> Result<bool, Error> {
+        match self.cancel_asynchronously() {
+            // The transfer is already completing
+            Err(Error::NotFound) => {
+                usb_trace!("transfer already completing, nothing to cancel");
+                Ok(false)
+            }
+            r => r,
+        }
+    }
+    /// Cancels the transfer (if active) and waits until libusb is done with it.
+    pub async fn cancel(&mut self) -> Result<(), Error> {
+        self.try_cancel()?;
+        self.wait_for_inactive().await;
+        Ok(())
+    }
     pub fn buf_ref(&self) -> &Buf {
         &self.buf
     }
@@ -200,34 +551,78 @@ impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
 impl<Buf, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>> Drop
     for SafeTransfer<Buf, Trans, Link>
 {
+    /// Dropping an active transfer cancels it without waiting. Owned parts (`from_buf`) are
+    /// handed to the callback which frees them once libusb is done. Borrowed parts
+    /// (`from_parts`) can't outlive the borrow so the libusb events are handled right here until
+    /// the transfer completes.
     fn drop(&mut self) {
-        self.sync_wait_for_cancel()
-            .expect("SafeTransfer drop cancel failed. This should never happen")
+        if self.is_active() {
+            if let Err(_e) = self.try_cancel() {
+                usb_debug!(error = ?_e, "cancelling a dropped transfer failed");
+            }
+            if let Some(detach) = self.detach {
+                // # Safety
+                // `buf` and `transfer` aren't touched again, only `link` is left to drop
+                let parts = unsafe { detach(self) };
+                let user_data = self.user_data().clone();
+                *user_data.detached.lock().unwrap_or_else(|e| e.into_inner()) = Some(parts);
+                // The callback might have taken the (empty) slot before it was filled
+                if !user_data.is_active.load(Ordering::SeqCst) {
+                    drop(user_data.take_detached());
+                }
+                unsafe { ManuallyDrop::drop(&mut self.link) };
+                return;
+            }
+            self.handle_events_until_inactive();
+        }
+        // # Safety
+        // Inactive, libusb is done with the parts
+        unsafe {
+            ManuallyDrop::drop(&mut self.buf);
+            ManuallyDrop::drop(&mut self.transfer);
+            ManuallyDrop::drop(&mut self.link);
+        }
     }
 }
 
 impl<Buf: AsRef<[u8]>, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferAsyncLink>>
     SafeTransfer<Buf, Trans, Link>
 {
-    /// # Safety
-    /// This fills the transfer with information including pointers. This function is safe to call
-    /// as long as you make sure the `Buf` is mutable vs immutable ('BorrowMut' vs 'Borrow') because
-    /// `libusb` might write data
-    fn set_fields(&mut self) {
-        let buf = self.buf.as_ref();
-        let trans = self.transfer.borrow_mut();
-        trans.set_buffer(buf.as_ptr() as *mut u8, buf.len());
-        trans.set_flags(Flags::ZEROED);
+    /// Fills the transfer with everything but the device, using `buf` as the transfer buffer.
+    fn set_fields(&mut self, buf: *mut u8, len: usize) {
+        let flags = self.flags;
+        let trans = self.transfer_mut();
+        trans.set_buffer(buf, len);
+        trans.set_flags(flags);
         trans.set_callback(Self::system_callback);
-        trans.set_user_data(&mut *self.link.borrow_mut().user_data as *mut UserData);
     }
-    fn get_control_setup(&self) -> Option<ControlSetup> {
+    /// Sets the transfer fields with a buffer pointer from `as_ref`. libusb takes a `*mut` but
+    /// only reads the buffer of an OUT transfer (`check_transfer` makes sure it is one), so
+    /// shared data like a `&'static [u8]` can be written without a copy. `Error::Busy` while the
+    /// transfer is in flight.
+    fn set_write_fields(&mut self) -> Result<(), Error> {
+        self.ensure_inactive()?;
         let buf = self.buf.as_ref();
-        if buf.len() > ControlSetup::SIZE {
-            Some(ControlSetup::deserialize(buf))
-        } else {
-            None
-        }
+        let (ptr, len) = (buf.as_ptr() as *mut u8, buf.len());
+        self.set_fields(ptr, len);
+        Ok(())
+    }
+    /// A transfer that ran but didn't complete is a `TransferError::Status` with the bytes
+    /// written before it stopped.
+    pub async fn submit_write(
+        &mut self,
+        device_handle: &AsyncDevice,
+    ) -> Result<usize, TransferError> {
+        self.set_write_fields()?;
+        self.submit(device_handle, false).await
+    }
+    /// Submits a write without waiting for it (see `wait_for_completion`).
+    pub(crate) fn start_write(&mut self, device_handle: &AsyncDevice) -> Result<(), Error> {
+        self.set_write_fields()?;
+        self.start_submit(device_handle, false)
+    }
+    fn get_control_setup(&self) -> Option<ControlSetup> {
+        ControlSetup::try_deserialize(self.buf.as_ref()).ok()
     }
     fn check_control_setup(&self, is_read: bool) -> Result<(), Error> {
         // TODO: Check endpoint?
@@ -242,9 +637,6 @@ impl<Buf: AsRef<[u8]>, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferA
             Err(Error::Overflow)
         }
     }
-    pub async fn submit_write(&mut self, device_handle: &AsyncDevice) -> Result<usize, Error> {
-        self.submit(device_handle, false).await
-    }
     pub fn control_data_ref(&self) -> &[u8] {
         &self.buf.as_ref()[ControlSetup::SIZE..]
     }
@@ -257,46 +649,153 @@ impl<Buf: AsRef<[u8]>, Trans: BorrowMut<Transfer>, Link: BorrowMut<SafeTransferA
     pub fn control_setup_len_field(&self) -> Result<u16, Error> {
         self.try_control_setup().map(|c| c.len)
     }
+    fn check_iso_packets(&self, is_read: bool) -> Result<(), Error> {
+        self.check_endpoint(is_read)?;
+        if self.transfer_ref().iso_packets_len() <= self.buf.as_ref().len() {
+            Ok(())
+        } else {
+            Err(Error::Overflow)
+        }
+    }
+    fn check_stream(&self, is_read: bool) -> Result<(), Error> {
+        // Stream id 0 is reserved and means no stream has been set.
+        if self.get_stream_id() == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.check_endpoint(is_read)
+    }
     fn check_transfer(&self, is_read: bool) -> Result<(), Error> {
-        match self.transfer.borrow().get_type() {
+        match self.transfer_ref().get_type() {
             TransferType::Control => self.check_control_setup(is_read),
             TransferType::Bulk | TransferType::Interrupt => self.check_endpoint(is_read),
-            TransferType::Stream => unimplemented!("libusb stream are not yet implemented"),
-            TransferType::Isochronous => {
-                unimplemented!("libusb isochronous are not yet implemented")
-            }
+            TransferType::Stream => self.check_stream(is_read),
+            TransferType::Isochronous => self.check_iso_packets(is_read),
         }
     }
-    fn submit_asynchronously(&self, is_read: bool) -> Result<(), Error> {
+    /// Returns the isochronous packets of the last completed transfer.
+    pub fn iso_packets(&self) -> Vec<IsoPacket<'_>> {
+        self.transfer_ref().iso_packets(self.buf.as_ref())
+    }
+    fn submit_asynchronously(&mut self, is_read: bool) -> Result<(), Error> {
+        self.ensure_inactive()?;
         self.check_transfer(is_read)?;
-        self.set_active(true);
+        self.begin_submission();
+        let user_data = self.lend_user_data();
         // Send the transfer off
-        match unsafe { self.transfer.borrow().submit() } {
+        match unsafe { self.transfer_ref().submit() } {
             Ok(_) => Ok(()),
             Err(e) => {
+                usb_debug!(error = ?e, "transfer submit failed");
+                // The callback won't run
+                self.transfer_mut()
+                    .set_user_data(core::ptr::null_mut::<UserData>());
+                drop(unsafe { Arc::from_raw(user_data) });
                 // ensure its set to inactive
                 self.set_active(false);
                 Err(e)
             }
         }
     }
-    async fn submit(&mut self, device_handle: &AsyncDevice, is_read: bool) -> Result<usize, Error> {
-        self.set_fields();
-        self.transfer
-            .borrow_mut()
-            .set_device(device_handle.handle_ref());
-
-        // Submit
-        self.submit_asynchronously(is_read)?;
-        // Wait for completion
+    /// Leaks a reference to the `UserData` as the transfer's `user_data`, the callback takes it
+    /// back.
+    fn lend_user_data(&mut self) -> *const UserData {
+        let user_data = Arc::into_raw(self.user_data().clone());
+        self.transfer_mut()
+            .set_user_data(user_data as *mut UserData);
+        user_data
+    }
+    /// Submits the transfer without waiting for it to complete. The fields have to be set first
+    /// (`set_buf_fields`). The buffer's memory must not move or be touched
+    /// until `wait_for_completion` returns (true for heap buffers like `Vec<u8>` even if the
+    /// `SafeTransfer` moves).
+    fn start_submit(&mut self, device_handle: &AsyncDevice, is_read: bool) -> Result<(), Error> {
+        // Before anything is touched, libusb might be using the fields (and the hooks)
+        self.ensure_inactive()?;
+        // `Transfer::set_buffer` would have cut a longer buffer short
+        transfer_len(self.buf.as_ref().len())?;
+        let context = device_handle.handle_ref().context_inner().cloned();
+        // Dropping borrowed parts in flight handles the context's events until they're free
+        if self.detach.is_none() && context.is_none() {
+            return Err(Error::NotSupported);
+        }
+        self.transfer_mut().set_device(device_handle.handle_ref());
+        // Registered before submitting so the callback can't run first
+        let in_flight = device_handle.in_flight_ref();
+        let libusb_transfer = self.transfer_ref().libusb_inner();
+        {
+            let mut hooks = self.hooks();
+            match &mut hooks.in_flight {
+                Some(last) if last.same_registry(in_flight) => (),
+                slot => *slot = Some(in_flight.clone()),
+            }
+            hooks.context = context;
+        }
+        in_flight.insert(libusb_transfer);
+        let result = self.submit_counted(device_handle, is_read);
+        if result.is_err() {
+            in_flight.remove(libusb_transfer);
+        }
+        result
+    }
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn submit_counted(&mut self, device_handle: &AsyncDevice, is_read: bool) -> Result<(), Error> {
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = device_handle.metrics_ref();
+            match &mut self.hooks().metrics {
+                Some((last, submitted)) if last.same_counters(metrics) => {
+                    *submitted = std::time::Instant::now()
+                }
+                slot => *slot = Some((metrics.clone(), std::time::Instant::now())),
+            }
+            let result = self.submit_asynchronously(is_read);
+            metrics.record_submit(result.is_ok());
+            result
+        }
+        #[cfg(not(feature = "metrics"))]
+        self.submit_asynchronously(is_read)
+    }
+    /// Waits for a transfer started with `start_submit` and returns the actual data transferred
+    /// length. Dropping the future before completion leaves the transfer in flight.
+    pub(crate) async fn wait_for_completion(&mut self) -> Result<usize, Error> {
         self.wait_for_inactive().await;
-        // Set to inactive
-        debug_assert_eq!(self.is_active(), false, "transfer still active");
-        // Return actual data transferred length
-        self.transfer
-            .borrow()
-            .try_actual_length()
-            .map(|l| l as usize)
+        debug_assert!(!self.is_active(), "transfer still active");
+        self.transfer_ref().try_actual_length().map(|l| l as usize)
+    }
+    /// Poll version of `wait_for_completion` for `Future`/`AsyncRead` implementations.
+    pub(crate) fn poll_completion(
+        &mut self,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<usize, Error>> {
+        let future = self.wait_for_completion();
+        futures_util::pin_mut!(future);
+        future.poll(cx)
+    }
+    async fn submit(
+        &mut self,
+        device_handle: &AsyncDevice,
+        is_read: bool,
+    ) -> Result<usize, TransferError> {
+        self.submit_for_result(device_handle, is_read)
+            .await?
+            .checked()
+    }
+    /// Submits and waits for the transfer, returning its status and length instead of mapping
+    /// failures to errors.
+    async fn submit_for_result(
+        &mut self,
+        device_handle: &AsyncDevice,
+        is_read: bool,
+    ) -> Result<TransferResult, Error> {
+        self.start_submit(device_handle, is_read)?;
+        // Cancel the transfer if this future gets dropped before completion
+        let guard = CancelOnDrop {
+            transfer: self.transfer_ref().libusb_inner(),
+            user_data: &**self.user_data() as *const UserData,
+        };
+        self.wait_for_inactive().await;
+        core::mem::forget(guard);
+        self.transfer_ref().try_result()
     }
 }
 impl<
@@ -306,19 +805,417 @@ impl<
     > SafeTransfer<Buf, Trans, Link>
 {
     pub fn set_control_setup(&mut self, control_setup: ControlSetup) -> Result<(), Error> {
-        let buf = self.buf.as_mut();
-        if buf.len() > ControlSetup::SIZE {
-            control_setup.serialize(buf);
-            Ok(())
-        } else {
-            Err(Error::Overflow)
-        }
+        control_setup.try_serialize(self.buf.as_mut())
     }
     pub fn control_data_mut(&mut self) -> &mut [u8] {
         &mut self.buf.as_mut()[ControlSetup::SIZE..]
     }
 
-    pub async fn submit_read(&mut self, device_handle: &AsyncDevice) -> Result<usize, Error> {
+    /// Sets the transfer fields with a buffer pointer from `as_mut`, for transfers libusb might
+    /// write into. `Error::Busy` while the transfer is in flight.
+    fn set_buf_fields(&mut self) -> Result<(), Error> {
+        self.ensure_inactive()?;
+        let buf = self.buf.as_mut();
+        let (ptr, len) = (buf.as_mut_ptr(), buf.len());
+        self.set_fields(ptr, len);
+        Ok(())
+    }
+    /// See `submit_write` for the errors.
+    pub async fn submit_read(
+        &mut self,
+        device_handle: &AsyncDevice,
+    ) -> Result<usize, TransferError> {
+        self.set_buf_fields()?;
         self.submit(device_handle, true).await
     }
+    /// `submit_read` but a timed out, cancelled or overflowed transfer isn't an error so the
+    /// bytes read before it can still be used (see `TransferResult::partial`).
+    pub async fn submit_read_partial(
+        &mut self,
+        device_handle: &AsyncDevice,
+    ) -> Result<TransferResult, Error> {
+        self.set_buf_fields()?;
+        self.submit_for_result(device_handle, true).await
+    }
+    /// `submit_read_partial` for either direction.
+    pub(crate) async fn submit_with_result(
+        &mut self,
+        device_handle: &AsyncDevice,
+        is_read: bool,
+    ) -> Result<TransferResult, Error> {
+        self.set_buf_fields()?;
+        self.submit_for_result(device_handle, is_read).await
+    }
+    /// Submits a read without waiting for it (see `wait_for_completion`).
+    pub(crate) fn start_read(&mut self, device_handle: &AsyncDevice) -> Result<(), Error> {
+        self.set_buf_fields()?;
+        self.start_submit(device_handle, true)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::async_device::AsyncDevice;
+    use crate::libusb::device_handle::DeviceHandle;
+    use crate::libusb::error::Error;
+    use crate::libusb::safe_transfer::{
+        SafeTransfer, SafeTransferAsyncLink, TransferOptions, UserData, WaitCompletion,
+    };
+    use crate::libusb::transfer::{Flag, Transfer, TransferType};
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::task::{Context, Waker};
+    use futures_util::FutureExt;
+    use std::sync::{Arc, Mutex};
+
+    /// Buffer that records when it's dropped.
+    struct FlagOnDrop(Vec<u8>, Arc<AtomicBool>);
+    impl AsRef<[u8]> for FlagOnDrop {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+    impl AsMut<[u8]> for FlagOnDrop {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+    impl Drop for FlagOnDrop {
+        fn drop(&mut self) {
+            self.1.store(true, Ordering::SeqCst)
+        }
+    }
+    #[test]
+    pub fn test_drop_in_flight_same_thread() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut transfer = SafeTransfer::from_buf(FlagOnDrop(vec![0_u8; 64], dropped.clone()));
+        transfer.set_buf_fields().expect("inactive");
+        // What submitting does, minus libusb. libusb can't cancel it (it was never submitted).
+        transfer.begin_submission();
+        transfer.lend_user_data();
+        let libusb_transfer = transfer.transfer_ref().libusb_inner();
+        // Returns right away even though nothing else handles the events
+        drop(transfer);
+        assert!(!dropped.load(Ordering::SeqCst), "freed while in flight");
+        // The completion comes later on the same thread, like from an `EventPump`
+        SafeTransfer::<FlagOnDrop>::system_callback(libusb_transfer.as_ptr());
+        assert!(dropped.load(Ordering::SeqCst), "parts never freed");
+    }
+    #[test]
+    pub fn test_drop_in_flight_borrowed() {
+        let mut buf = [0_u8; 8];
+        let mut transfer = Transfer::new(0);
+        let mut link = SafeTransferAsyncLink::new();
+        let libusb_transfer = transfer.libusb_inner().as_ptr() as usize;
+        {
+            let mut safe = SafeTransfer::from_parts(&mut buf[..], &mut transfer, &mut link);
+            safe.set_buf_fields().expect("inactive");
+            safe.begin_submission();
+            safe.lend_user_data();
+            // Completed from another thread like the event thread would
+            let completer = std::thread::spawn(move || {
+                std::thread::sleep(core::time::Duration::from_millis(50));
+                SafeTransfer::<&mut [u8]>::system_callback(
+                    libusb_transfer as *mut libusb1_sys::libusb_transfer,
+                )
+            });
+            // Waits for the completion, the borrows end right after
+            drop(safe);
+            assert!(!link.user_data.is_active.load(Ordering::SeqCst));
+            completer.join().expect("completer panicked");
+        }
+        assert_eq!(Arc::strong_count(&link.user_data), 1);
+    }
+
+    #[test]
+    pub fn test_drop_in_flight_owned_buf() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut transfer = SafeTransfer::from_buf(FlagOnDrop(vec![0_u8; 64], dropped.clone()));
+        transfer.set_buf_fields().expect("inactive");
+        transfer.begin_submission();
+        transfer.lend_user_data();
+        let libusb_transfer = transfer.transfer_ref().libusb_inner().as_ptr() as usize;
+        let buf = transfer.transfer_ref().libusb_ref().buffer as usize;
+        // Like a dropped `bulk_read_buf` future, the buffer is detached instead of being freed
+        drop(transfer);
+        assert!(!dropped.load(Ordering::SeqCst), "freed while in flight");
+        // libusb still writes into the buffer and completes the transfer on the event thread
+        let completer = std::thread::spawn(move || unsafe {
+            core::ptr::write_bytes(buf as *mut u8, 0xAA, 64);
+            SafeTransfer::<FlagOnDrop>::system_callback(
+                libusb_transfer as *mut libusb1_sys::libusb_transfer,
+            )
+        });
+        completer.join().expect("completer panicked");
+        assert!(dropped.load(Ordering::SeqCst), "parts never freed");
+    }
+
+    #[test]
+    pub fn test_into_parts_timeout() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("can't make async runtime");
+        runtime.block_on(async {
+            let transfer = SafeTransfer::from_buf(vec![0_u8; 64]);
+            transfer.begin_submission();
+            // Still active, the transfer comes back
+            let transfer = transfer
+                .into_parts_timeout(core::time::Duration::from_millis(20))
+                .await
+                .err()
+                .expect("inactive without a completion");
+            assert!(transfer.is_active());
+            let user_data = &**transfer.user_data() as *const UserData as usize;
+            let completer = std::thread::spawn(move || {
+                std::thread::sleep(core::time::Duration::from_millis(50));
+                unsafe { (*(user_data as *const UserData)).send_completion() }
+            });
+            // The link is kept until the completer is done with it
+            let (buf, _transfer, _link) = transfer
+                .into_parts_timeout(core::time::Duration::from_secs(5))
+                .await
+                .ok()
+                .expect("completion never noticed");
+            assert_eq!(buf.len(), 64);
+            completer.join().expect("completer panicked");
+        });
+    }
+    #[test]
+    pub fn test_stale_completion() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can't make async runtime");
+        runtime.block_on(async {
+            let mut transfer = SafeTransfer::from_buf(vec![0_u8; 64]);
+            let user_data = &*transfer.link.user_data as *const UserData as usize;
+            let complete = move || unsafe { (*(user_data as *const UserData)).send_completion() };
+            // A submission whose waiter was dropped (cancelled) completes with nobody waiting
+            transfer.begin_submission();
+            complete();
+            // Its completion mustn't count for the next submission
+            transfer.begin_submission();
+            assert!(transfer.wait_for_inactive().now_or_never().is_none());
+            assert!(transfer.is_active());
+            let completer = std::thread::spawn(move || {
+                std::thread::sleep(core::time::Duration::from_millis(50));
+                complete()
+            });
+            transfer.wait_for_inactive().await;
+            assert!(!transfer.is_active());
+            completer.join().expect("completer panicked");
+        });
+    }
+    /// Waker that runs `on_clone` when it's cloned (stored) and counts its wakes.
+    struct CloneHook {
+        on_clone: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+        woken: core::sync::atomic::AtomicUsize,
+    }
+    impl CloneHook {
+        fn waker(on_clone: impl FnOnce() + Send + 'static) -> (Arc<Self>, Waker) {
+            let hook = Arc::new(CloneHook {
+                on_clone: Mutex::new(Some(Box::new(on_clone))),
+                woken: core::sync::atomic::AtomicUsize::new(0),
+            });
+            let waker = unsafe { Waker::from_raw(Self::raw(Arc::into_raw(hook.clone()))) };
+            (hook, waker)
+        }
+        fn raw(hook: *const Self) -> core::task::RawWaker {
+            const VTABLE: core::task::RawWakerVTable =
+                core::task::RawWakerVTable::new(clone, wake, wake_by_ref, drop_hook);
+            unsafe fn clone(hook: *const ()) -> core::task::RawWaker {
+                let hook = hook as *const CloneHook;
+                Arc::increment_strong_count(hook);
+                let on_clone = (*hook).on_clone.lock().unwrap().take();
+                if let Some(on_clone) = on_clone {
+                    on_clone()
+                }
+                CloneHook::raw(hook)
+            }
+            unsafe fn wake(hook: *const ()) {
+                wake_by_ref(hook);
+                drop_hook(hook)
+            }
+            unsafe fn wake_by_ref(hook: *const ()) {
+                (*(hook as *const CloneHook))
+                    .woken
+                    .fetch_add(1, Ordering::SeqCst);
+            }
+            unsafe fn drop_hook(hook: *const ()) {
+                drop(Arc::from_raw(hook as *const CloneHook))
+            }
+            core::task::RawWaker::new(hook as *const (), &VTABLE)
+        }
+    }
+    #[test]
+    pub fn test_wait_completion_lost_wakeup() {
+        let link = SafeTransferAsyncLink::new();
+        let user_data = Arc::clone(&link.user_data);
+        fn wait(user_data: &UserData) -> WaitCompletion<'_> {
+            user_data.generation.fetch_add(1, Ordering::SeqCst);
+            user_data.is_active.store(true, Ordering::SeqCst);
+            WaitCompletion {
+                user_data,
+                generation: user_data.generation.load(Ordering::SeqCst),
+            }
+        }
+
+        // Completed from another thread between storing the waker and the check
+        let mut wait_completed = wait(&user_data);
+        let completer_data = Arc::clone(&user_data);
+        let completer = Arc::new(Mutex::new(None));
+        let completer_slot = Arc::clone(&completer);
+        let (hook, waker) = CloneHook::waker(move || {
+            let completer = std::thread::spawn(move || completer_data.send_completion());
+            *completer_slot.lock().unwrap() = Some(completer);
+        });
+        let mut cx = Context::from_waker(&waker);
+        // The completer waits for the lock, so it only completes once the waker is stored
+        assert!(Pin::new(&mut wait_completed).poll(&mut cx).is_pending());
+        let completer = completer
+            .lock()
+            .unwrap()
+            .take()
+            .expect("waker never stored");
+        completer.join().expect("completer panicked");
+        assert_eq!(hook.woken.load(Ordering::SeqCst), 1, "wakeup lost");
+        assert!(Pin::new(&mut wait_completed).poll(&mut cx).is_ready());
+
+        // Going inactive without a completion (a failed submission) in the same window
+        let mut wait_failed = wait(&user_data);
+        let failed_data = Arc::clone(&user_data);
+        let (hook, waker) = CloneHook::waker(move || {
+            std::thread::spawn(move || failed_data.is_active.store(false, Ordering::SeqCst))
+                .join()
+                .expect("submitter panicked")
+        });
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut wait_failed).poll(&mut cx).is_ready());
+        assert_eq!(hook.woken.load(Ordering::SeqCst), 0);
+        assert!(
+            user_data.completion.lock().unwrap().1.is_none(),
+            "waker kept"
+        );
+    }
+    #[test]
+    pub fn test_callback_after_wrapper_dropped() {
+        let mut buf = [0_u8; 8];
+        let mut transfer = Transfer::new(0);
+        let mut link = SafeTransferAsyncLink::new();
+        let user_data = Arc::clone(&link.user_data);
+        let libusb_transfer = transfer.libusb_inner();
+        {
+            let mut safe = SafeTransfer::from_parts(&mut buf[..], &mut transfer, &mut link);
+            safe.set_buf_fields().expect("inactive");
+            // What submitting does, minus libusb
+            safe.begin_submission();
+            safe.lend_user_data();
+            // The wrapper goes away while the transfer is in flight
+            core::mem::forget(safe);
+        }
+        drop(link);
+        SafeTransfer::<&mut [u8]>::system_callback(libusb_transfer.as_ptr());
+        assert!(!user_data.is_active.load(Ordering::SeqCst));
+        assert_eq!(
+            Arc::strong_count(&user_data),
+            1,
+            "callback kept its reference"
+        );
+        assert!(transfer.libusb_ref().user_data.is_null());
+        // A second callback for the same submission does nothing
+        SafeTransfer::<&mut [u8]>::system_callback(libusb_transfer.as_ptr());
+    }
+    #[test]
+    pub fn test_resubmit_while_active() {
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let device = AsyncDevice::new(handle);
+        let mut transfer = SafeTransfer::from_buf(vec![0_u8; 64]);
+        transfer.set_type(TransferType::Bulk);
+        transfer.set_endpoint(0x81);
+        transfer.set_buf_fields().expect("inactive");
+        transfer.begin_submission();
+        let user_data = transfer.lend_user_data();
+        let generation = transfer.user_data().generation.load(Ordering::SeqCst);
+        let libusb_transfer = transfer.transfer_ref().libusb_inner();
+        assert_eq!(transfer.start_read(&device), Err(Error::Busy));
+        assert_eq!(transfer.set_buf_fields(), Err(Error::Busy));
+        assert_eq!(transfer.submit_asynchronously(true), Err(Error::Busy));
+        // Nothing libusb uses was touched
+        let libusb = transfer.transfer_ref().libusb_ref();
+        assert!(libusb.dev_handle.is_null());
+        assert_eq!(libusb.user_data as *const UserData, user_data);
+        assert_eq!(libusb.buffer as *const u8, transfer.buf_ref().as_ptr());
+        assert_eq!(
+            transfer.user_data().generation.load(Ordering::SeqCst),
+            generation
+        );
+        assert!(transfer.hooks().in_flight.is_none());
+        assert_eq!(device.in_flight(), 0);
+        SafeTransfer::<Vec<u8>>::system_callback(libusb_transfer.as_ptr());
+        assert!(!transfer.is_active());
+        core::mem::forget(device);
+    }
+    #[test]
+    pub fn test_borrowed_parts_need_context() {
+        // The handle has no context whose events a dropped transfer could handle
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let device = AsyncDevice::new(handle);
+        let (mut buf, mut transfer) = ([0_u8; 8], Transfer::new(0));
+        let mut link = SafeTransferAsyncLink::new();
+        let mut safe = SafeTransfer::from_parts(&mut buf[..], &mut transfer, &mut link);
+        safe.set_type(TransferType::Bulk);
+        safe.set_endpoint(0x81);
+        safe.set_buf_fields().expect("inactive");
+        assert_eq!(safe.start_read(&device), Err(Error::NotSupported));
+        assert!(!safe.is_active());
+        assert!(safe.transfer_ref().libusb_ref().dev_handle.is_null());
+        drop(safe);
+        core::mem::forget(device);
+    }
+    #[test]
+    pub fn test_write_static() {
+        static DATA: [u8; 4] = [1, 2, 3, 4];
+        let handle = unsafe { DeviceHandle::from_libusb(core::ptr::NonNull::dangling()) };
+        let device = AsyncDevice::new(handle);
+        // Only `AsRef<[u8]>`, it can't be read into
+        let mut transfer = SafeTransfer::from_buf(&DATA[..]);
+        transfer.set_type(TransferType::Bulk);
+        // The direction is checked before the transfer gets to libusb
+        transfer.set_endpoint(0x81);
+        assert_eq!(transfer.start_write(&device), Err(Error::InvalidParam));
+        assert!(!transfer.is_active());
+        let libusb = transfer.transfer_ref().libusb_ref();
+        assert_eq!(libusb.buffer as *const u8, DATA.as_ptr());
+        assert_eq!(libusb.length, 4);
+        assert_eq!(device.in_flight(), 0);
+        core::mem::forget(device);
+    }
+    #[test]
+    pub fn test_set_fields_keeps_flags() {
+        let mut transfer = SafeTransfer::from_buf(vec![0_u8; 64]);
+        transfer.set_add_zero_packet(true);
+        transfer.set_buf_fields().expect("inactive");
+        let flags = transfer.transfer_ref().get_flags();
+        assert!(flags.get(Flag::AddZeroPacket));
+        assert!(!flags.get(Flag::ShortNotOk));
+        assert!(!flags.get(Flag::FreeBuffer));
+        assert!(!flags.get(Flag::FreeTransfer));
+
+        transfer.set_options(TransferOptions {
+            add_zero_packet: false,
+            short_not_ok: true,
+        });
+        transfer.set_buf_fields().expect("inactive");
+        let flags = transfer.transfer_ref().get_flags();
+        assert!(!flags.get(Flag::AddZeroPacket));
+        assert!(flags.get(Flag::ShortNotOk));
+    }
+    #[test]
+    pub fn test_buf_fields_mut_buf() {
+        let mut transfer = SafeTransfer::from_buf(vec![0_u8; 16]);
+        transfer.set_buf_fields().expect("inactive");
+        let ptr = transfer.transfer_ref().libusb_ref().buffer;
+        assert_eq!(ptr as *const u8, transfer.buf_ref().as_ptr());
+        assert_eq!(transfer.transfer_ref().libusb_ref().length, 16);
+    }
 }
@@ -0,0 +1,225 @@
+//! `AsyncDevice` shared between tasks.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::error;
+use crate::libusb::error::Error;
+use crate::libusb::interfaces::ClaimedInterfaces;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct Shared {
+    /// Only `None` after `try_unwrap` took it.
+    device: Option<AsyncDevice>,
+    /// Claimed interfaces while shared. Moved out of (and back into) the `DeviceHandle` so the
+    /// handle still releases them when it's dropped.
+    claimed: Mutex<ClaimedInterfaces>,
+    /// Held for the whole of a configuration change so claims and alt settings don't race.
+    config: futures_util::lock::Mutex<()>,
+}
+impl Shared {
+    fn device(&self) -> &AsyncDevice {
+        self.device.as_ref().expect("device taken")
+    }
+    /// Moves the claimed interfaces back into the `DeviceHandle` and takes the device.
+    fn take_device(&mut self) -> Option<AsyncDevice> {
+        let mut device = self.device.take()?;
+        let claimed = core::mem::take(self.claimed.get_mut().unwrap_or_else(|e| e.into_inner()));
+        *device.handle_mut().interfaces_mut() = claimed;
+        Some(device)
+    }
+}
+impl Drop for Shared {
+    fn drop(&mut self) {
+        drop(self.take_device())
+    }
+}
+/// A cloneable `AsyncDevice`. Derefs to the `AsyncDevice` so IO (which only needs `&self`) can
+/// run from every clone at once, like an interrupt IN reader task next to a bulk OUT writer
+/// task. The IO futures aren't `Send` so the tasks have to share a thread (a `LocalSet` or
+/// `join`), the `SharedAsyncDevice` itself can be sent anywhere. Configuration changes (which
+/// need `&mut AsyncDevice`) go through the methods here, which take turns on an async mutex and
+/// track the claimed interfaces for all the clones.
+#[derive(Clone)]
+pub struct SharedAsyncDevice {
+    inner: Arc<Shared>,
+}
+impl SharedAsyncDevice {
+    pub fn new(mut device: AsyncDevice) -> SharedAsyncDevice {
+        let claimed = core::mem::take(device.handle_mut().interfaces_mut());
+        SharedAsyncDevice {
+            inner: Arc::new(Shared {
+                device: Some(device),
+                claimed: Mutex::new(claimed),
+                config: futures_util::lock::Mutex::new(()),
+            }),
+        }
+    }
+    /// Gives back the `AsyncDevice` if this is the last clone.
+    pub fn try_unwrap(self) -> Result<AsyncDevice, SharedAsyncDevice> {
+        let mut shared =
+            Arc::try_unwrap(self.inner).map_err(|inner| SharedAsyncDevice { inner })?;
+        Ok(shared.take_device().expect("device taken"))
+    }
+    fn claimed(&self) -> MutexGuard<'_, ClaimedInterfaces> {
+        self.inner.claimed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    pub fn is_claimed(&self, interface: u8) -> bool {
+        self.claimed().is_claimed(interface)
+    }
+    /// The claimed interfaces in ascending order.
+    pub fn claimed_interfaces(&self) -> Vec<u8> {
+        self.claimed().iter().collect()
+    }
+    /// Runs `f` with the raw libusb handle on a blocking thread. The blocking task keeps its own
+    /// reference to the device so dropping the future early is fine.
+    async fn unblock_handle<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(*mut libusb1_sys::libusb_device_handle) -> i32 + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        match blocking::unblock(move || f(inner.device().handle_ref().inner().as_ptr())).await {
+            0 => Ok(()),
+            err => Err(error::from_libusb(err)),
+        }
+    }
+    /// `unblock_handle` with the claimed interfaces locked for the whole call, so they're
+    /// updated even if the future is dropped before the blocking task is done.
+    async fn unblock_claims<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut ClaimedInterfaces, *mut libusb1_sys::libusb_device_handle) -> i32
+            + Send
+            + 'static,
+    {
+        let inner = self.inner.clone();
+        let job = move || {
+            let mut claimed = inner.claimed.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut claimed, inner.device().handle_ref().inner().as_ptr())
+        };
+        match blocking::unblock(job).await {
+            0 => Ok(()),
+            err => Err(error::from_libusb(err)),
+        }
+    }
+    /// `AsyncDevice::claim_interface` for every clone.
+    pub async fn claim_interface(&self, interface: u8) -> Result<(), Error> {
+        let _config = self.inner.config.lock().await;
+        self.unblock_claims(move |claimed, handle| {
+            if claimed.is_claimed(interface) {
+                return 0;
+            }
+            let res = unsafe { libusb1_sys::libusb_claim_interface(handle, interface.into()) };
+            if res == 0 {
+                claimed.claim(interface);
+            }
+            res
+        })
+        .await
+    }
+    /// `AsyncDevice::release_interface` for every clone.
+    pub async fn release_interface(&self, interface: u8) -> Result<(), Error> {
+        let _config = self.inner.config.lock().await;
+        self.unblock_claims(move |claimed, handle| {
+            if !claimed.is_claimed(interface) {
+                return 0;
+            }
+            let res = unsafe { libusb1_sys::libusb_release_interface(handle, interface.into()) };
+            if res == 0 {
+                claimed.release(interface);
+            }
+            res
+        })
+        .await
+    }
+    pub async fn set_alternate_setting(&self, interface: u8, setting: u8) -> Result<(), Error> {
+        let _config = self.inner.config.lock().await;
+        if !self.is_claimed(interface) {
+            return Err(Error::NotFound);
+        }
+        self.unblock_handle(move |handle| unsafe {
+            libusb1_sys::libusb_set_interface_alt_setting(handle, interface.into(), setting.into())
+        })
+        .await
+    }
+    pub async fn set_active_configuration(&self, config: u8) -> Result<(), Error> {
+        let _config = self.inner.config.lock().await;
+        self.unblock_handle(move |handle| unsafe {
+            libusb1_sys::libusb_set_configuration(handle, config.into())
+        })
+        .await
+    }
+}
+impl core::ops::Deref for SharedAsyncDevice {
+    type Target = AsyncDevice;
+
+    fn deref(&self) -> &Self::Target {
+        self.inner.device()
+    }
+}
+impl From<AsyncDevice> for SharedAsyncDevice {
+    fn from(device: AsyncDevice) -> Self {
+        SharedAsyncDevice::new(device)
+    }
+}
+impl core::fmt::Debug for SharedAsyncDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedAsyncDevice")
+            .field("claimed", &self.claimed_interfaces())
+            .finish()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::context::Context;
+    use crate::libusb::shared_device::SharedAsyncDevice;
+    use core::time::Duration;
+
+    fn assert_send_sync_clone<T: Send + Sync + Clone>() {}
+    #[test]
+    pub fn test_shared_is_send_sync_clone() {
+        assert_send_sync_clone::<SharedAsyncDevice>();
+    }
+
+    /// Compile test: an interrupt IN reader and a bulk OUT writer on two clones at once.
+    #[allow(dead_code)]
+    async fn read_while_writing(
+        device: SharedAsyncDevice,
+    ) -> Result<(), crate::libusb::error::Error> {
+        device.claim_interface(0).await?;
+        let reader = device.clone();
+        let read = async move {
+            let mut buf = [0_u8; 64];
+            reader
+                .interrupt_read(0x81, &mut buf, Duration::from_secs(1))
+                .await
+        };
+        let write = device.bulk_write(0x02, &[0x01, 0x03, 0x0C, 0x00], Duration::from_secs(1));
+        let (read, write) = futures_util::future::join(read, write).await;
+        read?;
+        write?;
+        device.release_interface(0).await
+    }
+    /// Needs an attached device with interface 0 that can be claimed.
+    #[test]
+    #[ignore]
+    pub fn test_shared_claims() {
+        let context = Context::new().expect("libusb context");
+        let handle = context
+            .device_list()
+            .iter()
+            .find_map(|d| d.open().ok())
+            .expect("an openable device");
+        let context = context.start_async();
+        let device =
+            SharedAsyncDevice::new(context.make_async_device(handle).expect("same context"));
+        let clone = device.clone();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can't make async runtime");
+        let claim = runtime.block_on(clone.claim_interface(0));
+        assert_eq!(claim.is_ok(), device.is_claimed(0));
+        drop(clone);
+        let device = device.try_unwrap().expect("last clone");
+        assert_eq!(
+            claim.is_ok(),
+            device.handle_ref().claimed_interfaces().any(|i| i == 0)
+        );
+    }
+}
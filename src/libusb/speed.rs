@@ -1,8 +1,48 @@
+use core::convert::TryFrom;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Speed {
-    Unknown,
-    Low,
-    Full,
-    High,
-    Super,
-    SuperPlus,
+    Unknown = 0,
+    Low = 1,
+    Full = 2,
+    High = 3,
+    Super = 4,
+    SuperPlus = 5,
+}
+impl Speed {
+    /// Returns the theoretical bus speed from the USB spec in bits/sec. `Speed::Unknown` returns
+    /// `0`.
+    pub fn max_packet_theoretical(self) -> u64 {
+        match self {
+            Speed::Unknown => 0,
+            Speed::Low => 1_500_000,
+            Speed::Full => 12_000_000,
+            Speed::High => 480_000_000,
+            Speed::Super => 5_000_000_000,
+            Speed::SuperPlus => 10_000_000_000,
+        }
+    }
+}
+impl From<Speed> for i32 {
+    fn from(s: Speed) -> Self {
+        s as i32
+    }
+}
+impl TryFrom<i32> for Speed {
+    type Error = ();
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Speed::Unknown),
+            1 => Ok(Speed::Low),
+            2 => Ok(Speed::Full),
+            3 => Ok(Speed::High),
+            4 => Ok(Speed::Super),
+            5 => Ok(Speed::SuperPlus),
+            _ => Err(()),
+        }
+    }
 }
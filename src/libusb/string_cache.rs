@@ -0,0 +1,332 @@
+//! Lazily filled cache of a device's string descriptors.
+//!
+//! Concurrent reads of the same descriptor share one transfer: the first caller fetches it
+//! while the others wait for the result. Errors aren't cached, a waiter whose fetch failed tries
+//! again itself.
+use crate::libusb::async_device::AsyncDevice;
+use crate::libusb::control::DescriptorType;
+use crate::libusb::error::Error;
+use crate::libusb::string_descriptor::{self, Language};
+use crate::libusb::transfer::ControlSetup;
+use crate::traits::AsyncUsbHandle;
+use core::future::Future;
+use core::hash::Hash;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+enum Slot<T> {
+    /// Someone is fetching it, wake these once they're done.
+    Fetching(Vec<Waker>),
+    Ready(T),
+}
+struct Slots<K, T> {
+    map: HashMap<K, Slot<T>>,
+    /// Bumped by `invalidate` so fetches started before it don't fill the cache.
+    generation: u64,
+}
+/// Map where a missing value is fetched by one caller at a time.
+struct Coalescing<K, T>(Mutex<Slots<K, T>>);
+impl<K, T> Default for Coalescing<K, T> {
+    fn default() -> Self {
+        Coalescing(Mutex::new(Slots {
+            map: HashMap::new(),
+            generation: 0,
+        }))
+    }
+}
+fn wake_all(slot: Option<Slot<impl Sized>>) {
+    if let Some(Slot::Fetching(wakers)) = slot {
+        wakers.into_iter().for_each(Waker::wake)
+    }
+}
+impl<K: Copy + Eq + Hash, T: Clone> Coalescing<K, T> {
+    fn lock(&self) -> MutexGuard<'_, Slots<K, T>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn invalidate(&self) {
+        let mut slots = self.lock();
+        slots.generation += 1;
+        for (_, slot) in slots.map.drain() {
+            wake_all(Some(slot))
+        }
+    }
+    /// The cached value or the result of `fetch`, unless another caller is already fetching it.
+    async fn get_or_fetch<E, F: Future<Output = Result<T, E>>>(
+        &self,
+        key: K,
+        fetch: impl FnOnce() -> F,
+    ) -> Result<T, E> {
+        let generation = match (Claim { slots: self, key }).await {
+            Ok(value) => return Ok(value),
+            Err(generation) => generation,
+        };
+        let mut guard = FetchGuard {
+            slots: self,
+            key,
+            generation,
+            value: None,
+        };
+        let result = fetch().await;
+        guard.value = result.as_ref().ok().cloned();
+        result
+    }
+}
+/// Resolves to the cached value or to `Err(generation)` once the caller should fetch it.
+struct Claim<'a, K, T> {
+    slots: &'a Coalescing<K, T>,
+    key: K,
+}
+impl<'a, K: Copy + Eq + Hash, T: Clone> Future for Claim<'a, K, T> {
+    type Output = Result<T, u64>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slots = self.slots.lock();
+        let generation = slots.generation;
+        match slots.map.get_mut(&self.key) {
+            Some(Slot::Ready(value)) => Poll::Ready(Ok(value.clone())),
+            Some(Slot::Fetching(wakers)) => {
+                if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            None => {
+                slots.map.insert(self.key, Slot::Fetching(Vec::new()));
+                Poll::Ready(Err(generation))
+            }
+        }
+    }
+}
+/// Stores the fetched value (or frees the slot after an error or cancellation) and wakes the
+/// waiters.
+struct FetchGuard<'a, K: Copy + Eq + Hash, T: Clone> {
+    slots: &'a Coalescing<K, T>,
+    key: K,
+    generation: u64,
+    value: Option<T>,
+}
+impl<'a, K: Copy + Eq + Hash, T: Clone> Drop for FetchGuard<'a, K, T> {
+    fn drop(&mut self) {
+        let mut slots = self.slots.lock();
+        // After an `invalidate` the slot belongs to someone else
+        if slots.generation != self.generation {
+            return;
+        }
+        let old = match self.value.take() {
+            Some(value) => slots.map.insert(self.key, Slot::Ready(value)),
+            None => slots.map.remove(&self.key),
+        };
+        drop(slots);
+        wake_all(old)
+    }
+}
+
+/// Cached strings of one device. `AsyncDevice` keeps one, see `AsyncDevice::strings`.
+#[derive(Default)]
+pub struct CachedStrings {
+    languages: Coalescing<(), Vec<Language>>,
+    strings: Coalescing<(u8, u16), String>,
+}
+impl CachedStrings {
+    pub fn new() -> CachedStrings {
+        CachedStrings::default()
+    }
+    /// Forgets everything (after a reset or anything else that can change the strings).
+    pub fn invalidate(&self) {
+        self.languages.invalidate();
+        self.strings.invalidate();
+    }
+}
+impl core::fmt::Debug for CachedStrings {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CachedStrings").finish()
+    }
+}
+
+/// String descriptors of `handle` read through a `CachedStrings`. Requests use
+/// `AsyncDevice::DEFAULT_CONTROL_TIMEOUT`.
+pub struct StringCache<'a, H = AsyncDevice> {
+    handle: &'a H,
+    cache: &'a CachedStrings,
+}
+impl<'a, H> Clone for StringCache<'a, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, H> Copy for StringCache<'a, H> {}
+impl<'a, H: AsyncUsbHandle> StringCache<'a, H>
+where
+    Error: From<H::Error>,
+{
+    pub fn new(handle: &'a H, cache: &'a CachedStrings) -> StringCache<'a, H> {
+        StringCache { handle, cache }
+    }
+    pub fn invalidate(&self) {
+        self.cache.invalidate()
+    }
+    async fn read_raw(&self, desc_index: u8, langid: u16) -> Result<Vec<u8>, Error> {
+        let setup = ControlSetup::get_descriptor(DescriptorType::String, desc_index, langid, 255);
+        let mut buf = vec![0_u8; usize::from(setup.len)];
+        let len = self
+            .handle
+            .control_read(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                &mut buf,
+                AsyncDevice::DEFAULT_CONTROL_TIMEOUT,
+            )
+            .await?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+    /// Languages the strings are available in (string descriptor 0).
+    pub async fn languages(&self) -> Result<Vec<Language>, Error> {
+        self.cache
+            .languages
+            .get_or_fetch((), || async {
+                string_descriptor::parse_languages(&self.read_raw(0, 0).await?)
+            })
+            .await
+    }
+    /// String `desc_index` in `langid`.
+    pub async fn get(&self, desc_index: u8, langid: u16) -> Result<String, Error> {
+        if desc_index == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.cache
+            .strings
+            .get_or_fetch((desc_index, langid), || async {
+                string_descriptor::parse_string(&self.read_raw(desc_index, langid).await?)
+            })
+            .await
+    }
+    /// String `desc_index` in the first language the device supports.
+    pub async fn get_ascii(&self, desc_index: u8) -> Result<String, Error> {
+        let language = *self
+            .languages()
+            .await?
+            .first()
+            .ok_or(Error::BadDescriptor)?;
+        self.get(desc_index, language.into()).await
+    }
+    async fn get_optional(&self, desc_index: Option<u8>) -> Result<Option<String>, Error> {
+        match desc_index {
+            Some(index) => Ok(Some(self.get_ascii(index).await?)),
+            None => Ok(None),
+        }
+    }
+}
+impl<'a> StringCache<'a, AsyncDevice> {
+    /// `None` if the device descriptor doesn't have a manufacturer string.
+    pub async fn manufacturer(&self) -> Result<Option<String>, Error> {
+        let descriptor = self.handle.device().device_descriptor()?;
+        self.get_optional(descriptor.manufacturer_string_index())
+            .await
+    }
+    pub async fn product(&self) -> Result<Option<String>, Error> {
+        let descriptor = self.handle.device().device_descriptor()?;
+        self.get_optional(descriptor.product_string_index()).await
+    }
+    pub async fn serial_number(&self) -> Result<Option<String>, Error> {
+        let descriptor = self.handle.device().device_descriptor()?;
+        self.get_optional(descriptor.serial_number_string_index())
+            .await
+    }
+}
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use crate::libusb::error::Error;
+    use crate::libusb::string_cache::{CachedStrings, Coalescing, StringCache};
+    use crate::libusb::string_descriptor::Language;
+    use crate::mock::{self, MockAsyncDevice};
+    use core::cell::Cell;
+    use core::task::{Context, Poll};
+    use futures_util::FutureExt;
+
+    const LANGUAGES: [u8; 4] = [0x04, 0x03, 0x09, 0x04];
+    const PRODUCT: [u8; 8] = [0x08, 0x03, b'u', 0, b's', 0, b'b', 0];
+
+    #[test]
+    pub fn test_cached_strings() {
+        let mock = MockAsyncDevice::new();
+        let cached = CachedStrings::new();
+        let strings = StringCache::new(&mock, &cached);
+        mock.expect_control_read(0x80, 0x06, 0x0300, 0)
+            .respond(&LANGUAGES);
+        mock.expect_control_read(0x80, 0x06, 0x0302, 0x0409)
+            .respond(&PRODUCT);
+        for _ in 0..3 {
+            let product = strings.get_ascii(2).now_or_never().expect("ready");
+            assert_eq!(product, Ok("usb".to_string()));
+        }
+        let languages = strings.languages().now_or_never().expect("ready");
+        assert_eq!(languages, Ok(vec![Language::ENGLISH_US]));
+        assert_eq!(mock.transfers(), 2);
+        mock.verify();
+
+        // Errors aren't cached
+        mock.expect_control_read(0x80, 0x06, 0x0303, 0x0409)
+            .fail(mock::Error::Stall);
+        mock.expect_control_read(0x80, 0x06, 0x0303, 0x0409)
+            .respond(&PRODUCT);
+        let serial = strings.get(3, 0x0409).now_or_never().expect("ready");
+        assert_eq!(serial, Err(Error::Pipe));
+        let serial = strings.get(3, 0x0409).now_or_never().expect("ready");
+        assert_eq!(serial, Ok("usb".to_string()));
+        assert_eq!(
+            strings.get(0, 0x0409).now_or_never(),
+            Some(Err(Error::InvalidParam))
+        );
+
+        // Everything is read again after `invalidate`
+        strings.invalidate();
+        mock.expect_control_read(0x80, 0x06, 0x0300, 0)
+            .respond(&LANGUAGES);
+        mock.expect_control_read(0x80, 0x06, 0x0302, 0x0409)
+            .respond(&PRODUCT);
+        let product = strings.get_ascii(2).now_or_never().expect("ready");
+        assert_eq!(product, Ok("usb".to_string()));
+        mock.verify();
+    }
+    #[test]
+    pub fn test_coalescing() {
+        let slots = Coalescing::<u8, u32>::default();
+        let fetches = Cell::new(0);
+        let done = Cell::new(false);
+        // A fetch that stays pending until `done` is set
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            futures_util::future::poll_fn(|_| match done.get() {
+                true => Poll::Ready(Ok::<_, ()>(7)),
+                false => Poll::Pending,
+            })
+        };
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut first = Box::pin(slots.get_or_fetch(1, fetch));
+        let mut second = Box::pin(slots.get_or_fetch(1, fetch));
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        done.set(true);
+        assert_eq!(first.poll_unpin(&mut cx), Poll::Ready(Ok(7)));
+        assert_eq!(second.poll_unpin(&mut cx), Poll::Ready(Ok(7)));
+        assert_eq!(fetches.get(), 1);
+
+        // A cancelled fetch frees the slot for the waiters
+        done.set(false);
+        slots.invalidate();
+        let mut first = Box::pin(slots.get_or_fetch(1, fetch));
+        let mut second = Box::pin(slots.get_or_fetch(1, fetch));
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        drop(first);
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        done.set(true);
+        assert_eq!(second.poll_unpin(&mut cx), Poll::Ready(Ok(7)));
+        assert_eq!(fetches.get(), 3);
+    }
+}
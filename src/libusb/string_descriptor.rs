@@ -0,0 +1,95 @@
+//! String descriptor parsing. String descriptors are `bLength`, `bDescriptorType` and then
+//! UTF-16LE code units (or LANGIDs for string descriptor 0).
+use crate::libusb::control::DescriptorType;
+use crate::libusb::error::Error;
+
+const HEADER_SIZE: usize = 2;
+
+/// USB LANGID. The low 10 bits are the primary language and the high 6 bits the sub language.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub struct Language(pub u16);
+impl Language {
+    pub const ENGLISH_US: Language = Language(0x0409);
+    pub fn primary_language(self) -> u16 {
+        self.0 & 0x03FF
+    }
+    pub fn sub_language(self) -> u8 {
+        (self.0 >> 10) as u8
+    }
+}
+impl From<Language> for u16 {
+    fn from(l: Language) -> Self {
+        l.0
+    }
+}
+/// Returns the payload of a string descriptor (everything after the header up to `bLength`).
+fn descriptor_payload(bytes: &[u8]) -> Result<&[u8], Error> {
+    if bytes.len() < HEADER_SIZE || bytes[1] != u8::from(DescriptorType::String) {
+        return Err(Error::BadDescriptor);
+    }
+    let len = usize::from(bytes[0]);
+    if len < HEADER_SIZE || len > bytes.len() {
+        return Err(Error::BadDescriptor);
+    }
+    Ok(&bytes[HEADER_SIZE..len])
+}
+/// Parses the LANGIDs out of string descriptor 0.
+pub fn parse_languages(bytes: &[u8]) -> Result<Vec<Language>, Error> {
+    Ok(descriptor_payload(bytes)?
+        .chunks_exact(2)
+        .map(|c| Language(u16::from_le_bytes([c[0], c[1]])))
+        .collect())
+}
+/// Decodes the UTF-16LE string of a string descriptor. Unpaired surrogates are
+/// `Error::BadDescriptor`.
+pub fn parse_string(bytes: &[u8]) -> Result<String, Error> {
+    let units = descriptor_payload(bytes)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]));
+    core::char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .map_err(|_| Error::BadDescriptor)
+}
+/// The standard strings of a device. `None` if the device doesn't have that string.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::error::Error;
+    use crate::libusb::string_descriptor::{parse_languages, parse_string, Language};
+
+    #[test]
+    pub fn test_parse_languages() {
+        let languages = parse_languages(&[0x06, 0x03, 0x09, 0x04, 0x07, 0x04]).expect("valid");
+        assert_eq!(languages, vec![Language::ENGLISH_US, Language(0x0407)]);
+        assert_eq!(languages[0].primary_language(), 0x09);
+        assert_eq!(languages[0].sub_language(), 0x01);
+    }
+    #[test]
+    pub fn test_parse_string() {
+        // "Hé€" followed by junk past `bLength`
+        let bytes = [0x08, 0x03, b'H', 0x00, 0xE9, 0x00, 0xAC, 0x20, 0xFF, 0xFF];
+        assert_eq!(parse_string(&bytes), Ok("Hé€".to_string()));
+    }
+    #[test]
+    pub fn test_parse_bad_descriptor() {
+        assert_eq!(parse_string(&[0x02]), Err(Error::BadDescriptor));
+        // Unpaired high surrogate
+        assert_eq!(
+            parse_string(&[0x04, 0x03, 0x00, 0xD8]),
+            Err(Error::BadDescriptor)
+        );
+        assert_eq!(
+            parse_string(&[0x04, 0x02, 0x41, 0x00]),
+            Err(Error::BadDescriptor)
+        );
+        assert_eq!(
+            parse_string(&[0x08, 0x03, 0x41, 0x00]),
+            Err(Error::BadDescriptor)
+        );
+    }
+}
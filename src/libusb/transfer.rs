@@ -1,37 +1,65 @@
 #![allow(unused_unsafe)]
+pub use crate::libusb::control::ControlSetup;
 use crate::libusb::device_handle::DeviceHandle;
 use crate::libusb::error::Error;
 use core::convert::TryFrom;
 use core::convert::TryInto;
+use libusb1_sys::constants::{
+    LIBUSB_TRANSFER_ADD_ZERO_PACKET, LIBUSB_TRANSFER_CANCELLED, LIBUSB_TRANSFER_COMPLETED,
+    LIBUSB_TRANSFER_ERROR, LIBUSB_TRANSFER_FREE_BUFFER, LIBUSB_TRANSFER_FREE_TRANSFER,
+    LIBUSB_TRANSFER_NO_DEVICE, LIBUSB_TRANSFER_OVERFLOW, LIBUSB_TRANSFER_SHORT_NOT_OK,
+    LIBUSB_TRANSFER_STALL, LIBUSB_TRANSFER_TIMED_OUT, LIBUSB_TRANSFER_TYPE_BULK,
+    LIBUSB_TRANSFER_TYPE_BULK_STREAM, LIBUSB_TRANSFER_TYPE_CONTROL, LIBUSB_TRANSFER_TYPE_INTERRUPT,
+    LIBUSB_TRANSFER_TYPE_ISOCHRONOUS,
+};
+/// `libusb_transfer_status`.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 pub enum Status {
-    Completed = 0,
-    Error = 1,
-    TimedOut = 2,
-    Cancelled = 3,
-    Stall = 4,
-    NoDevice = 5,
-    Overflow = 6,
+    Completed = LIBUSB_TRANSFER_COMPLETED as isize,
+    Error = LIBUSB_TRANSFER_ERROR as isize,
+    TimedOut = LIBUSB_TRANSFER_TIMED_OUT as isize,
+    Cancelled = LIBUSB_TRANSFER_CANCELLED as isize,
+    Stall = LIBUSB_TRANSFER_STALL as isize,
+    NoDevice = LIBUSB_TRANSFER_NO_DEVICE as isize,
+    Overflow = LIBUSB_TRANSFER_OVERFLOW as isize,
 }
 impl Status {
     pub fn from_i32(i: i32) -> Option<Status> {
         match i {
-            0 => Some(Status::Completed),
-            1 => Some(Status::Error),
-            2 => Some(Status::TimedOut),
-            3 => Some(Status::Cancelled),
-            4 => Some(Status::Stall),
-            5 => Some(Status::NoDevice),
-            6 => Some(Status::Overflow),
+            LIBUSB_TRANSFER_COMPLETED => Some(Status::Completed),
+            LIBUSB_TRANSFER_ERROR => Some(Status::Error),
+            LIBUSB_TRANSFER_TIMED_OUT => Some(Status::TimedOut),
+            LIBUSB_TRANSFER_CANCELLED => Some(Status::Cancelled),
+            LIBUSB_TRANSFER_STALL => Some(Status::Stall),
+            LIBUSB_TRANSFER_NO_DEVICE => Some(Status::NoDevice),
+            LIBUSB_TRANSFER_OVERFLOW => Some(Status::Overflow),
             _ => None,
         }
     }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Completed => "transfer completed",
+            Status::Error => "transfer failed",
+            Status::TimedOut => "transfer timed out",
+            Status::Cancelled => "transfer was cancelled",
+            Status::Stall => "endpoint stalled",
+            Status::NoDevice => "device was disconnected",
+            Status::Overflow => "device sent more data than requested",
+        }
+    }
+}
+impl core::fmt::Display for Status {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 impl Status {
+    /// The one mapping from transfer statuses to errors. A stall is `Error::Pipe`.
     pub fn as_error(self) -> Result<(), Error> {
         match self {
             Status::Completed => Ok(()),
-            Status::Error | Status::Cancelled => Err(Error::Io),
+            Status::Error => Err(Error::Io),
+            Status::Cancelled => Err(Error::Cancelled),
             Status::TimedOut => Err(Error::Timeout),
             Status::Stall => Err(Error::Pipe),
             Status::NoDevice => Err(Error::NoDevice),
@@ -39,9 +67,116 @@ impl Status {
         }
     }
 }
+/// A transfer that didn't complete.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TransferError {
+    /// The transfer couldn't be submitted (or its status is unknown).
+    Error(Error),
+    /// The transfer ran but finished with `status`. `actual_length` bytes were still moved.
+    Status {
+        status: Status,
+        actual_length: usize,
+    },
+}
+impl TransferError {
+    /// `None` if the transfer never ran.
+    pub fn status(self) -> Option<Status> {
+        match self {
+            TransferError::Error(_) => None,
+            TransferError::Status { status, .. } => Some(status),
+        }
+    }
+    pub fn actual_length(self) -> usize {
+        match self {
+            TransferError::Error(_) => 0,
+            TransferError::Status { actual_length, .. } => actual_length,
+        }
+    }
+    pub fn error(self) -> Error {
+        match self {
+            TransferError::Error(e) => e,
+            TransferError::Status { status, .. } => status.as_error().err().unwrap_or(Error::Other),
+        }
+    }
+    pub fn is_cancelled(self) -> bool {
+        self.status() == Some(Status::Cancelled)
+    }
+    pub fn is_stall(self) -> bool {
+        self.status() == Some(Status::Stall)
+    }
+}
+impl From<Error> for TransferError {
+    fn from(e: Error) -> Self {
+        TransferError::Error(e)
+    }
+}
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Self {
+        e.error()
+    }
+}
+impl core::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransferError::Error(e) => core::fmt::Display::fmt(e, f),
+            TransferError::Status {
+                status,
+                actual_length,
+            } => write!(f, "{} after {} bytes", status, actual_length),
+        }
+    }
+}
+impl std::error::Error for TransferError {}
+/// Status and actual length of a finished transfer. Unlike `Transfer::try_actual_length`, the
+/// bytes moved before a timeout, cancellation or overflow aren't lost.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct TransferResult {
+    pub status: Status,
+    pub transferred: usize,
+}
+impl TransferResult {
+    pub fn new(status: Status, actual_length: i32) -> TransferResult {
+        TransferResult {
+            status,
+            transferred: actual_length.try_into().unwrap_or(0),
+        }
+    }
+    /// `transferred` only if the transfer completed, otherwise the status as an error.
+    pub fn completed(self) -> Result<usize, Error> {
+        self.checked().map_err(Error::from)
+    }
+    /// `completed` but the error keeps the status and the bytes transferred.
+    pub fn checked(self) -> Result<usize, TransferError> {
+        match self.status {
+            Status::Completed => Ok(self.transferred),
+            status => Err(TransferError::Status {
+                status,
+                actual_length: self.transferred,
+            }),
+        }
+    }
+    /// Like `DeviceHandle::bulk_read`, a timeout, cancellation or overflow that still moved some
+    /// bytes is `Ok`.
+    pub fn partial(self) -> Result<usize, Error> {
+        match self.status {
+            Status::TimedOut | Status::Cancelled | Status::Overflow if self.transferred > 0 => {
+                Ok(self.transferred)
+            }
+            _ => self.completed(),
+        }
+    }
+}
 impl From<Status> for i32 {
     fn from(s: Status) -> Self {
-        s as i32
+        match s {
+            Status::Completed => LIBUSB_TRANSFER_COMPLETED,
+            Status::Error => LIBUSB_TRANSFER_ERROR,
+            Status::TimedOut => LIBUSB_TRANSFER_TIMED_OUT,
+            Status::Cancelled => LIBUSB_TRANSFER_CANCELLED,
+            Status::Stall => LIBUSB_TRANSFER_STALL,
+            Status::NoDevice => LIBUSB_TRANSFER_NO_DEVICE,
+            Status::Overflow => LIBUSB_TRANSFER_OVERFLOW,
+        }
     }
 }
 impl TryFrom<i32> for Status {
@@ -51,17 +186,24 @@ impl TryFrom<i32> for Status {
         Self::from_i32(value).ok_or(())
     }
 }
+/// `libusb_transfer_type`.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 pub enum TransferType {
-    Control = 0,
-    Isochronous = 1,
-    Bulk = 2,
-    Interrupt = 3,
-    Stream = 4,
+    Control = LIBUSB_TRANSFER_TYPE_CONTROL as isize,
+    Isochronous = LIBUSB_TRANSFER_TYPE_ISOCHRONOUS as isize,
+    Bulk = LIBUSB_TRANSFER_TYPE_BULK as isize,
+    Interrupt = LIBUSB_TRANSFER_TYPE_INTERRUPT as isize,
+    Stream = LIBUSB_TRANSFER_TYPE_BULK_STREAM as isize,
 }
 impl From<TransferType> for u8 {
     fn from(t: TransferType) -> Self {
-        t as u8
+        match t {
+            TransferType::Control => LIBUSB_TRANSFER_TYPE_CONTROL,
+            TransferType::Isochronous => LIBUSB_TRANSFER_TYPE_ISOCHRONOUS,
+            TransferType::Bulk => LIBUSB_TRANSFER_TYPE_BULK,
+            TransferType::Interrupt => LIBUSB_TRANSFER_TYPE_INTERRUPT,
+            TransferType::Stream => LIBUSB_TRANSFER_TYPE_BULK_STREAM,
+        }
     }
 }
 impl TryFrom<u8> for TransferType {
@@ -69,41 +211,125 @@ impl TryFrom<u8> for TransferType {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(TransferType::Control),
-            1 => Ok(TransferType::Isochronous),
-            2 => Ok(TransferType::Bulk),
-            3 => Ok(TransferType::Interrupt),
-            4 => Ok(TransferType::Stream),
+            LIBUSB_TRANSFER_TYPE_CONTROL => Ok(TransferType::Control),
+            LIBUSB_TRANSFER_TYPE_ISOCHRONOUS => Ok(TransferType::Isochronous),
+            LIBUSB_TRANSFER_TYPE_BULK => Ok(TransferType::Bulk),
+            LIBUSB_TRANSFER_TYPE_INTERRUPT => Ok(TransferType::Interrupt),
+            LIBUSB_TRANSFER_TYPE_BULK_STREAM => Ok(TransferType::Stream),
             _ => Err(()),
         }
     }
 }
+/// `libusb_transfer_flags`. The values are the masks (not bit positions).
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 pub enum Flag {
-    ShortNotOk = 0,
-    FreeBuffer = 1,
-    FreeTransfer = 2,
-    AddZeroPacket = 3,
+    ShortNotOk = LIBUSB_TRANSFER_SHORT_NOT_OK as isize,
+    FreeBuffer = LIBUSB_TRANSFER_FREE_BUFFER as isize,
+    FreeTransfer = LIBUSB_TRANSFER_FREE_TRANSFER as isize,
+    AddZeroPacket = LIBUSB_TRANSFER_ADD_ZERO_PACKET as isize,
+}
+impl Flag {
+    pub const ALL: [Flag; 4] = [
+        Flag::ShortNotOk,
+        Flag::FreeBuffer,
+        Flag::FreeTransfer,
+        Flag::AddZeroPacket,
+    ];
+    pub const fn mask(self) -> u8 {
+        match self {
+            Flag::ShortNotOk => LIBUSB_TRANSFER_SHORT_NOT_OK,
+            Flag::FreeBuffer => LIBUSB_TRANSFER_FREE_BUFFER,
+            Flag::FreeTransfer => LIBUSB_TRANSFER_FREE_TRANSFER,
+            Flag::AddZeroPacket => LIBUSB_TRANSFER_ADD_ZERO_PACKET,
+        }
+    }
 }
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Default)]
 pub struct Flags(u8);
 impl Flags {
     pub const ZEROED: Flags = Flags::new(0);
+    /// Every bit libusb knows about.
+    pub const MASK: u8 = LIBUSB_TRANSFER_SHORT_NOT_OK
+        | LIBUSB_TRANSFER_FREE_BUFFER
+        | LIBUSB_TRANSFER_FREE_TRANSFER
+        | LIBUSB_TRANSFER_ADD_ZERO_PACKET;
+    /// Bits outside of `Flags::MASK` are cleared.
     pub const fn new(flags: u8) -> Flags {
-        // TODO: Maybe clear higher bits?
-        Flags(flags)
+        Flags(flags & Self::MASK)
     }
     pub const fn inner(self) -> u8 {
         self.0
     }
     pub fn get(self, flag: Flag) -> bool {
-        self.0 & (1_u8 << (flag as u8)) != 0
+        self.0 & flag.mask() != 0
     }
     pub fn set(&mut self, flag: Flag) {
-        self.0 |= 1_u8 << (flag as u8)
+        self.0 |= flag.mask()
     }
     pub fn clear(&mut self, flag: Flag) {
-        self.0 &= !(1_u8 << (flag as u8))
+        self.0 &= !flag.mask()
+    }
+    pub fn from_flags(flags: &[Flag]) -> Flags {
+        flags
+            .iter()
+            .copied()
+            .fold(Flags::ZEROED, |f, flag| f | flag)
+    }
+    /// The set flags, in `Flag::ALL` order.
+    pub fn iter(self) -> impl Iterator<Item = Flag> {
+        Flag::ALL
+            .iter()
+            .copied()
+            .filter(move |&flag| self.get(flag))
+    }
+}
+impl From<Flag> for Flags {
+    fn from(flag: Flag) -> Self {
+        Flags(flag.mask())
+    }
+}
+impl core::ops::BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+impl core::ops::BitOr<Flag> for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flag) -> Flags {
+        self | Flags::from(rhs)
+    }
+}
+impl core::ops::BitAnd for Flags {
+    type Output = Flags;
+
+    fn bitand(self, rhs: Flags) -> Flags {
+        Flags(self.0 & rhs.0)
+    }
+}
+/// Only flips the bits in `Flags::MASK`.
+impl core::ops::Not for Flags {
+    type Output = Flags;
+
+    fn not(self) -> Flags {
+        Flags::new(!self.0)
+    }
+}
+/// The set flags separated by `|` (`ShortNotOk|AddZeroPacket`), `empty` if none are.
+impl core::fmt::Display for Flags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("empty");
+        }
+        for (i, flag) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str("|")?;
+            }
+            write!(f, "{:?}", flag)?;
+        }
+        Ok(())
     }
 }
 impl From<Flags> for u8 {
@@ -116,66 +342,37 @@ impl From<u8> for Flags {
         Flags::new(u)
     }
 }
-/// Any Serialization or deserialization of this struct should be careful to make sure the `u16`s
-/// are in Little Endian for the wire and Host Endian at all other times.
+/// A single completed isochronous packet.
 #[derive(Copy, Clone, Debug)]
-#[repr(C)]
-pub struct ControlSetup {
-    pub request_type: u8,
-    pub request: u8,
-    pub value: u16,
-    pub index: u16,
-    pub len: u16,
-}
-impl ControlSetup {
-    pub const SIZE: usize = core::mem::size_of::<Self>();
-    /// Taste Host-Endian `ControlSetup` and serializes it in Little-Endian
-    pub fn serialize(self, buf: &mut [u8]) {
-        assert!(buf.len() >= Self::SIZE, "ControlSetup buf too small");
-        let le = ControlSetup {
-            request_type: self.request_type,
-            request: self.request,
-            value: self.value.to_le(),
-            index: self.index.to_le(),
-            len: self.len.to_le(),
-        };
-        // Unaligned write because `buf` is only 1-byte aligned and `ControlSetup`
-        // might need aligned
-        unsafe { core::ptr::write_unaligned(buf.as_mut_ptr() as *mut Self, le) }
-    }
-    pub fn deserialize(buf: &[u8]) -> ControlSetup {
-        assert!(buf.len() >= Self::SIZE, "ControlSetup buf too small");
-        let le = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Self) };
-        ControlSetup {
-            request_type: le.request_type,
-            request: le.request,
-            value: u16::from_le(le.value),
-            index: u16::from_le(le.index),
-            len: u16::from_le(le.len),
-        }
-    }
-    pub fn is_write(&self) -> bool {
-        self.request_type & libusb1_sys::constants::LIBUSB_ENDPOINT_DIR_MASK
-            == libusb1_sys::constants::LIBUSB_ENDPOINT_OUT
-    }
-    pub fn is_read(&self) -> bool {
-        self.request_type & libusb1_sys::constants::LIBUSB_ENDPOINT_DIR_MASK
-            == libusb1_sys::constants::LIBUSB_ENDPOINT_IN
+pub struct IsoPacket<'a> {
+    pub status: Option<Status>,
+    pub data: &'a [u8],
+}
+impl<'a> IsoPacket<'a> {
+    pub fn is_completed(&self) -> bool {
+        self.status == Some(Status::Completed)
     }
 }
 /// [`Transfer`] tries to be a lightweight safe abstraction over [`libusb1_sys::libusb_transfer`].
 /// Only a limited subset of actions are safe on the libusb_transfer. Stuff like setting the data
 /// pointer are unsafe or should be abstracted over (like `SafeTransfer`).
+/// The second field is the amount of isochronous packet descriptors allocated with the transfer.
 #[derive(Debug)]
-pub struct Transfer(core::ptr::NonNull<libusb1_sys::libusb_transfer>);
+pub struct Transfer(core::ptr::NonNull<libusb1_sys::libusb_transfer>, usize);
 impl Transfer {
+    /// Panics if libusb can't allocate it or `iso_packets` doesn't fit an `i32`, see `try_new`.
     pub fn new(iso_packets: usize) -> Transfer {
-        Transfer(
-            core::ptr::NonNull::new(unsafe {
-                libusb1_sys::libusb_alloc_transfer(iso_packets as i32)
-            })
-            .expect("null libusb transfer ptr"),
-        )
+        Self::try_new(iso_packets).expect("can't allocate libusb transfer")
+    }
+    /// `Error::InvalidParam` if `iso_packets` doesn't fit an `i32`, `Error::NoMem` if libusb
+    /// can't allocate the transfer.
+    pub fn try_new(iso_packets: usize) -> Result<Transfer, Error> {
+        let packets = i32::try_from(iso_packets).map_err(|_| Error::InvalidParam)?;
+        let transfer = unsafe { libusb1_sys::libusb_alloc_transfer(packets) };
+        Ok(Transfer(
+            core::ptr::NonNull::new(transfer).ok_or(Error::NoMem)?,
+            iso_packets,
+        ))
     }
     /// Allows access to the inner  [`libusb1_sys::libusb_transfer`] internals.
     pub fn libusb_inner(&self) -> core::ptr::NonNull<libusb1_sys::libusb_transfer> {
@@ -197,9 +394,60 @@ impl Transfer {
         inner.num_iso_packets = 0;
         inner.dev_handle = device.inner().as_ptr();
     }
+    /// # Panics
+    /// Panics if `num` is greater than the amount of iso packets the transfer was allocated with.
     pub fn set_num_iso_packets(&mut self, num: usize) {
+        assert!(num <= self.1, "more iso packets than allocated");
         self.libusb_mut().num_iso_packets = num as i32;
     }
+    /// Returns the amount of isochronous packet descriptors allocated with the transfer.
+    pub fn max_iso_packets(&self) -> usize {
+        self.1
+    }
+    pub fn iso_packet_descriptors(&self) -> &[libusb1_sys::libusb_iso_packet_descriptor] {
+        let inner = self.libusb_ref();
+        unsafe {
+            core::slice::from_raw_parts(inner.iso_packet_desc.as_ptr(), self.get_num_iso_packets())
+        }
+    }
+    pub fn iso_packet_descriptors_mut(
+        &mut self,
+    ) -> &mut [libusb1_sys::libusb_iso_packet_descriptor] {
+        let len = self.get_num_iso_packets();
+        unsafe {
+            core::slice::from_raw_parts_mut(self.libusb_mut().iso_packet_desc.as_mut_ptr(), len)
+        }
+    }
+    /// Sets the length of every isochronous packet (`libusb_set_iso_packet_lengths`).
+    pub fn set_iso_packet_lengths(&mut self, length: u32) {
+        for desc in self.iso_packet_descriptors_mut() {
+            desc.length = length;
+        }
+    }
+    /// Returns each isochronous packet's status and the data it transferred. `buf` must be the
+    /// buffer the transfer was submitted with.
+    pub fn iso_packets<'a>(&self, buf: &'a [u8]) -> Vec<IsoPacket<'a>> {
+        let mut offset = 0_usize;
+        self.iso_packet_descriptors()
+            .iter()
+            .map(move |desc| {
+                let start = offset.min(buf.len());
+                let end = (offset + desc.actual_length as usize).min(buf.len());
+                offset += desc.length as usize;
+                IsoPacket {
+                    status: Status::from_i32(desc.status),
+                    data: &buf[start..end],
+                }
+            })
+            .collect()
+    }
+    /// Returns the total length of all the isochronous packets.
+    pub fn iso_packets_len(&self) -> usize {
+        self.iso_packet_descriptors()
+            .iter()
+            .map(|desc| desc.length as usize)
+            .sum()
+    }
     pub fn is_endpoint_read(&self) -> bool {
         self.libusb_ref().endpoint & libusb1_sys::constants::LIBUSB_ENDPOINT_DIR_MASK
             != libusb1_sys::constants::LIBUSB_ENDPOINT_OUT
@@ -231,17 +479,20 @@ impl Transfer {
     /// Checks `.status()` to make sure its `Status::Completed` before returning `Ok(actual_length)`.
     /// If `.status()` is not `Status::Completed`, it will return a `Err(status_error)`
     pub fn try_actual_length(&self) -> Result<i32, Error> {
-        match self.status() {
-            Some(status) => match status {
-                Status::Completed => Ok(self.actual_length()),
-                Status::Error | Status::Cancelled => Err(Error::Io),
-                Status::TimedOut => Err(Error::Timeout),
-                Status::Stall => Err(Error::Pipe),
-                Status::NoDevice => Err(Error::NoDevice),
-                Status::Overflow => Err(Error::Overflow),
-            },
-            None => Err(Error::Other),
-        }
+        self.result()
+            .map(|_| self.actual_length())
+            .map_err(Error::from)
+    }
+    /// Like `try_actual_length` but the error keeps the status and how much was transferred.
+    /// An unknown status is `TransferError::Error(Error::Other)`.
+    pub fn result(&self) -> Result<usize, TransferError> {
+        self.try_result()?.checked()
+    }
+    /// Status and actual length of the finished transfer. `Error::Other` if the status is
+    /// unknown.
+    pub fn try_result(&self) -> Result<TransferResult, Error> {
+        let status = self.status().ok_or(Error::Other)?;
+        Ok(TransferResult::new(status, self.actual_length()))
     }
     pub fn actual_length(&self) -> i32 {
         self.libusb_ref().actual_length
@@ -249,14 +500,22 @@ impl Transfer {
     pub fn libusb_mut(&mut self) -> &mut libusb1_sys::libusb_transfer {
         unsafe { self.0.as_mut() }
     }
+    /// Only the first `i32::MAX` bytes of a longer buffer are used (`SafeTransfer` and
+    /// `BlockingTransfer` refuse longer buffers with `Error::InvalidParam` instead).
     pub fn set_buffer(&mut self, buffer: *mut u8, len: usize) {
         self.libusb_mut().buffer = buffer;
-        self.libusb_mut().length = len as i32;
+        self.libusb_mut().length = transfer_len(len).unwrap_or(i32::MAX);
     }
     /// # Safety
     /// The transfer status and pointers could cause memory to be read and write. Memory Safety
     /// isn't guaranteed for this struct
     pub unsafe fn submit(&self) -> Result<(), Error> {
+        usb_trace!(
+            endpoint = self.get_endpoint(),
+            transfer_type = ?self.get_type(),
+            length = self.libusb_ref().length,
+            "submitting transfer"
+        );
         try_unsafe!(libusb1_sys::libusb_submit_transfer(self.0.as_ptr()));
         Ok(())
     }
@@ -264,6 +523,7 @@ impl Transfer {
     /// The transfer status and pointers could cause memory to be read and write. Memory Safety
     /// isn't guaranteed for this struct
     pub unsafe fn cancel(&self) -> Result<(), Error> {
+        usb_trace!(endpoint = self.get_endpoint(), "cancelling transfer");
         try_unsafe!(libusb1_sys::libusb_cancel_transfer(self.0.as_ptr()));
         Ok(())
     }
@@ -282,13 +542,15 @@ impl Transfer {
     /// # Safety
     /// Treats the pointer as a reference and it could dereference dangling memory
     pub unsafe fn from_libusb(ptr: core::ptr::NonNull<libusb1_sys::libusb_transfer>) -> Transfer {
-        Transfer(ptr)
+        let iso_packets = ptr.as_ref().num_iso_packets as usize;
+        Transfer(ptr, iso_packets)
     }
+    /// See `timeout_millis` for how the timeout is rounded. `NO_TIMEOUT` waits forever.
     pub fn set_timeout(&mut self, timeout: core::time::Duration) {
-        self.libusb_mut().timeout = timeout.as_millis().try_into().unwrap_or(u32::MAX)
+        self.libusb_mut().timeout = timeout_millis(timeout)
     }
     pub fn get_timeout(&self) -> core::time::Duration {
-        core::time::Duration::from_millis(self.libusb_ref().timeout.try_into().unwrap_or(0_u64))
+        core::time::Duration::from_millis(u64::from(self.libusb_ref().timeout))
     }
     pub fn status(&self) -> Option<Status> {
         self.libusb_ref().status.try_into().ok()
@@ -339,10 +601,12 @@ impl<'t, 'b> TransferWithBuf<'t, 'b> {
         self.transfer_buf
     }
     pub fn transfer_ref(&self) -> &Transfer {
-        &self.transfer
+        self.transfer
     }
+    /// # Safety
+    /// The buffer pointer and length of the transfer have to keep matching `transfer_buf`.
     pub unsafe fn transfer_mut_unsafe(&mut self) -> &mut Transfer {
-        &mut self.transfer
+        self.transfer
     }
     pub fn control_data_ref(&self) -> &[u8] {
         &self.transfer_buf[ControlSetup::SIZE..]
@@ -350,12 +614,219 @@ impl<'t, 'b> TransferWithBuf<'t, 'b> {
     pub fn control_data_mut(&mut self) -> &mut [u8] {
         &mut self.transfer_buf[ControlSetup::SIZE..]
     }
-    pub fn set_control_setup(&mut self, handle: &DeviceHandle, control_setup: ControlSetup) {
-        assert!(
-            self.transfer_buf.len() >= ControlSetup::SIZE,
-            "buf smaller than a ControlSetup, maybe missing it?"
-        );
-        control_setup.serialize(self.transfer_buf);
+    /// `Error::Overflow` if the buffer is smaller than a `ControlSetup`.
+    pub fn set_control_setup(
+        &mut self,
+        handle: &DeviceHandle,
+        control_setup: ControlSetup,
+    ) -> Result<(), Error> {
+        control_setup.try_serialize(self.transfer_buf)?;
         self.transfer.fill_control(handle);
+        Ok(())
+    }
+}
+/// Waits forever. libusb uses a timeout of 0 for no timeout.
+pub const NO_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(0);
+/// libusb's `int` length for a buffer of `len` bytes. `Error::InvalidParam` if it doesn't fit.
+pub(crate) fn transfer_len(len: usize) -> Result<i32, Error> {
+    i32::try_from(len).map_err(|_| Error::InvalidParam)
+}
+/// Converts a timeout to libusb's milliseconds. `NO_TIMEOUT` (zero) is 0 (no timeout), any
+/// other timeout is rounded up so it can't become 0 (a 500us timeout times out after 1ms
+/// instead of never). Saturates at `u32::MAX` ms.
+pub fn timeout_millis(timeout: core::time::Duration) -> u32 {
+    if timeout == NO_TIMEOUT {
+        return 0;
+    }
+    let millis =
+        timeout.as_millis() + u128::from(!timeout.subsec_nanos().is_multiple_of(1_000_000));
+    millis.try_into().unwrap_or(u32::MAX)
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::error::Error;
+    use crate::libusb::transfer::{
+        timeout_millis, transfer_len, Flag, Flags, Status, Transfer, TransferError, TransferResult,
+        TransferType, NO_TIMEOUT,
+    };
+    use core::convert::TryFrom;
+    use core::time::Duration;
+    use libusb1_sys::constants::*;
+
+    /// The discriminants have to stay equal to the libusb constants.
+    #[test]
+    pub fn test_libusb_constants() {
+        let statuses = [
+            (Status::Completed, LIBUSB_TRANSFER_COMPLETED),
+            (Status::Error, LIBUSB_TRANSFER_ERROR),
+            (Status::TimedOut, LIBUSB_TRANSFER_TIMED_OUT),
+            (Status::Cancelled, LIBUSB_TRANSFER_CANCELLED),
+            (Status::Stall, LIBUSB_TRANSFER_STALL),
+            (Status::NoDevice, LIBUSB_TRANSFER_NO_DEVICE),
+            (Status::Overflow, LIBUSB_TRANSFER_OVERFLOW),
+        ];
+        for &(status, value) in statuses.iter() {
+            assert_eq!(status as i32, value);
+            assert_eq!(i32::from(status), value);
+            assert_eq!(Status::from_i32(value), Some(status));
+        }
+        assert_eq!(Status::from_i32(7), None);
+        let types = [
+            (TransferType::Control, LIBUSB_TRANSFER_TYPE_CONTROL),
+            (TransferType::Isochronous, LIBUSB_TRANSFER_TYPE_ISOCHRONOUS),
+            (TransferType::Bulk, LIBUSB_TRANSFER_TYPE_BULK),
+            (TransferType::Interrupt, LIBUSB_TRANSFER_TYPE_INTERRUPT),
+            (TransferType::Stream, LIBUSB_TRANSFER_TYPE_BULK_STREAM),
+        ];
+        for &(transfer_type, value) in types.iter() {
+            assert_eq!(transfer_type as u8, value);
+            assert_eq!(u8::from(transfer_type), value);
+            assert_eq!(TransferType::try_from(value), Ok(transfer_type));
+        }
+        let flags = [
+            (Flag::ShortNotOk, LIBUSB_TRANSFER_SHORT_NOT_OK),
+            (Flag::FreeBuffer, LIBUSB_TRANSFER_FREE_BUFFER),
+            (Flag::FreeTransfer, LIBUSB_TRANSFER_FREE_TRANSFER),
+            (Flag::AddZeroPacket, LIBUSB_TRANSFER_ADD_ZERO_PACKET),
+        ];
+        for &(flag, mask) in flags.iter() {
+            assert_eq!(flag as u8, mask);
+            assert_eq!(flag.mask(), mask);
+            let mut f = Flags::ZEROED;
+            f.set(flag);
+            assert_eq!(f.inner(), mask);
+            assert!(Flag::ALL
+                .iter()
+                .all(|&other| f.get(other) == (other == flag)));
+            f.clear(flag);
+            assert_eq!(f, Flags::ZEROED);
+        }
+        assert_eq!(Flags::new(0xFF).inner(), Flags::MASK);
+        assert_eq!(
+            Flags::from_flags(&[Flag::ShortNotOk, Flag::AddZeroPacket]).to_string(),
+            "ShortNotOk|AddZeroPacket"
+        );
+        assert_eq!(Flags::ZEROED.to_string(), "empty");
+        assert_eq!(Status::TimedOut.to_string(), "transfer timed out");
+    }
+    #[test]
+    pub fn test_flags_every_value() {
+        for bits in 0..=u8::MAX {
+            let flags = Flags::new(bits);
+            // Reserved bits can never be observed, whatever is done to the flags
+            for observed in [flags, !flags, flags | Flags::new(!bits), !Flags::ZEROED].iter() {
+                assert_eq!(observed.inner() & !Flags::MASK, 0);
+            }
+            assert_eq!(Flags::from_flags(&flags.iter().collect::<Vec<_>>()), flags);
+            assert_eq!(flags & !flags, Flags::ZEROED);
+            assert_eq!(flags | !flags, Flags::new(Flags::MASK));
+            for &flag in Flag::ALL.iter() {
+                let mut set = flags;
+                set.set(flag);
+                assert!(set.get(flag));
+                assert_eq!(set, flags | flag);
+                let mut cleared = set;
+                cleared.clear(flag);
+                assert!(!cleared.get(flag));
+                assert_eq!(cleared, flags & !Flags::from(flag));
+                assert_eq!(cleared | flag, set);
+            }
+        }
+    }
+    #[test]
+    pub fn test_timeout_millis() {
+        assert_eq!(timeout_millis(NO_TIMEOUT), 0);
+        assert_eq!(timeout_millis(Duration::from_nanos(1)), 1);
+        assert_eq!(timeout_millis(Duration::from_micros(500)), 1);
+        assert_eq!(timeout_millis(Duration::from_millis(1)), 1);
+        assert_eq!(timeout_millis(Duration::from_micros(1_001)), 2);
+        assert_eq!(timeout_millis(Duration::from_secs(5)), 5_000);
+        // About 49.7 days, longer timeouts saturate instead of wrapping
+        let max = Duration::from_millis(u64::from(u32::MAX));
+        assert_eq!(timeout_millis(max - Duration::from_nanos(1)), u32::MAX);
+        assert_eq!(timeout_millis(max), u32::MAX);
+        assert_eq!(timeout_millis(max + Duration::from_millis(1)), u32::MAX);
+        assert_eq!(timeout_millis(Duration::from_secs(u64::MAX)), u32::MAX);
+    }
+    #[test]
+    pub fn test_transfer_timeout() {
+        let mut transfer = Transfer::new(0);
+        transfer.set_timeout(NO_TIMEOUT);
+        assert_eq!(transfer.libusb_ref().timeout, 0);
+        assert_eq!(transfer.get_timeout(), NO_TIMEOUT);
+        transfer.set_timeout(Duration::from_micros(500));
+        assert_eq!(transfer.get_timeout(), Duration::from_millis(1));
+        transfer.set_timeout(Duration::from_micros(1_500));
+        assert_eq!(transfer.get_timeout(), Duration::from_millis(2));
+        transfer.set_timeout(Duration::from_secs(u64::MAX));
+        assert_eq!(
+            transfer.get_timeout(),
+            Duration::from_millis(u64::from(u32::MAX))
+        );
+    }
+
+    #[test]
+    pub fn test_transfer_result_timeout_with_data() {
+        let result = TransferResult::new(Status::TimedOut, 12);
+        assert_eq!(result.transferred, 12);
+        assert_eq!(result.completed(), Err(Error::Timeout));
+        assert_eq!(result.partial(), Ok(12));
+        assert_eq!(TransferResult::new(Status::Cancelled, 3).partial(), Ok(3));
+        assert_eq!(TransferResult::new(Status::Overflow, 64).partial(), Ok(64));
+    }
+    #[test]
+    pub fn test_transfer_result_without_data() {
+        assert_eq!(TransferResult::new(Status::Completed, 0).partial(), Ok(0));
+        assert_eq!(TransferResult::new(Status::Completed, 5).completed(), Ok(5));
+        assert_eq!(
+            TransferResult::new(Status::TimedOut, 0).partial(),
+            Err(Error::Timeout)
+        );
+        assert_eq!(
+            TransferResult::new(Status::Cancelled, 0).partial(),
+            Err(Error::Cancelled)
+        );
+        let stall = TransferResult::new(Status::Stall, 8).checked();
+        assert_eq!(
+            stall,
+            Err(TransferError::Status {
+                status: Status::Stall,
+                actual_length: 8
+            })
+        );
+        let stall = stall.unwrap_err();
+        assert!(stall.is_stall() && !stall.is_cancelled());
+        assert_eq!(Error::from(stall), Error::Pipe);
+        // Other failures are errors even with data
+        assert_eq!(
+            TransferResult::new(Status::Stall, 8).partial(),
+            Err(Error::Pipe)
+        );
+        assert_eq!(
+            TransferResult::new(Status::NoDevice, 8).partial(),
+            Err(Error::NoDevice)
+        );
+        assert_eq!(TransferResult::new(Status::Error, -1).transferred, 0);
+    }
+    #[test]
+    pub fn test_transfer_len() {
+        assert_eq!(transfer_len(0), Ok(0));
+        assert_eq!(transfer_len(i32::MAX as usize), Ok(i32::MAX));
+        assert_eq!(
+            transfer_len(i32::MAX as usize + 1),
+            Err(Error::InvalidParam)
+        );
+        let mut transfer = Transfer::new(0);
+        transfer.set_buffer(core::ptr::null_mut(), usize::MAX);
+        assert_eq!(transfer.libusb_ref().length, i32::MAX);
+    }
+    #[test]
+    pub fn test_try_new_iso_packets() {
+        assert_eq!(
+            Transfer::try_new(i32::MAX as usize + 1).err(),
+            Some(Error::InvalidParam)
+        );
+        let transfer = Transfer::try_new(4).expect("allocated");
+        assert_eq!(transfer.max_iso_packets(), 4);
     }
 }
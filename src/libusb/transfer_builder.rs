@@ -0,0 +1,405 @@
+//! `TransferBuilder`, a `SafeTransfer` set up for one kind of transfer in one expression instead
+//! of a handful of setters.
+//!
+//! The direction is part of the builder's type. IN builders are given the buffer to read into
+//! (`buffer`), OUT builders the data to send (`data`), and `build` only exists once they have one.
+//! Control builders are made from their `ControlSetup` so there's no control transfer without a
+//! setup packet. What the types can't check (the direction bit of the endpoint or of the setup
+//! packet, a control buffer matching `wLength`) is checked by `build`.
+//!
+//! ```no_run
+//! # async fn read(device: &usbw::libusb::async_device::AsyncDevice) -> Result<(), usbw::libusb::error::Error> {
+//! use usbw::libusb::transfer_builder::TransferBuilder;
+//! let transfer = TransferBuilder::bulk_in(0x81)
+//!     .timeout(core::time::Duration::from_secs(1))
+//!     .buffer(vec![0_u8; 64])
+//!     .build()?;
+//! let read = device.submit(transfer).await?.transferred();
+//! # Ok(())
+//! # }
+//! ```
+use crate::libusb::buffer::{gather, gathered, io_slices_len};
+use crate::libusb::error::Error;
+use crate::libusb::safe_transfer::{SafeTransfer, TransferOptions};
+use crate::libusb::transfer::{ControlSetup, IsoPacket, TransferResult, TransferType, NO_TIMEOUT};
+use core::convert::TryInto;
+use core::marker::PhantomData;
+use core::time::Duration;
+use libusb1_sys::constants::LIBUSB_ENDPOINT_IN;
+use std::io::IoSlice;
+
+/// Direction of a `TransferBuilder`, `In` or `Out`.
+pub trait TransferDirection {
+    const IS_READ: bool;
+}
+/// Device to host.
+#[derive(Copy, Clone, Debug)]
+pub enum In {}
+/// Host to device.
+#[derive(Copy, Clone, Debug)]
+pub enum Out {}
+impl TransferDirection for In {
+    const IS_READ: bool = true;
+}
+impl TransferDirection for Out {
+    const IS_READ: bool = false;
+}
+/// Buffer of a builder that wasn't given one yet.
+#[derive(Copy, Clone, Debug)]
+pub struct NoBuffer;
+
+/// Builds a ready to submit `SafeTransfer` (see the module docs). The timeout defaults to
+/// `NO_TIMEOUT` and the options to `TransferOptions::default()`.
+#[derive(Clone, Debug)]
+pub struct TransferBuilder<Dir, Buf> {
+    transfer_type: TransferType,
+    endpoint: u8,
+    timeout: Duration,
+    options: TransferOptions,
+    stream_id: u32,
+    setup: Option<ControlSetup>,
+    /// Lengths of the isochronous packets, empty for the other types.
+    iso_packets: Vec<usize>,
+    buf: Buf,
+    direction: PhantomData<Dir>,
+}
+impl<Dir, Buf> TransferBuilder<Dir, Buf> {
+    fn new(transfer_type: TransferType, endpoint: u8, buf: Buf) -> TransferBuilder<Dir, Buf> {
+        TransferBuilder {
+            transfer_type,
+            endpoint,
+            timeout: NO_TIMEOUT,
+            options: TransferOptions::default(),
+            stream_id: 0,
+            setup: None,
+            iso_packets: Vec::new(),
+            buf,
+            direction: PhantomData,
+        }
+    }
+    fn with_buf<NewBuf>(self, buf: NewBuf) -> TransferBuilder<Dir, NewBuf> {
+        TransferBuilder {
+            transfer_type: self.transfer_type,
+            endpoint: self.endpoint,
+            timeout: self.timeout,
+            options: self.options,
+            stream_id: self.stream_id,
+            setup: self.setup,
+            iso_packets: self.iso_packets,
+            buf,
+            direction: PhantomData,
+        }
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn options(mut self, options: TransferOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+impl TransferBuilder<In, NoBuffer> {
+    pub fn bulk_in(endpoint: u8) -> TransferBuilder<In, NoBuffer> {
+        TransferBuilder::new(TransferType::Bulk, endpoint, NoBuffer)
+    }
+    pub fn interrupt_in(endpoint: u8) -> TransferBuilder<In, NoBuffer> {
+        TransferBuilder::new(TransferType::Interrupt, endpoint, NoBuffer)
+    }
+    /// USB 3.0 bulk stream, allocated with `DeviceHandle::alloc_streams`.
+    pub fn stream_in(endpoint: u8, stream_id: u32) -> TransferBuilder<In, NoBuffer> {
+        let mut builder = TransferBuilder::new(TransferType::Stream, endpoint, NoBuffer);
+        builder.stream_id = stream_id;
+        builder
+    }
+    /// Reads into `buf`, the transfer is as long as it is. It's owned so a transfer dropped in
+    /// flight can hand it to libusb's callback instead of waiting (see `SafeTransfer::from_buf`).
+    pub fn buffer<Buf: AsMut<[u8]> + AsRef<[u8]> + Send + 'static>(
+        self,
+        buf: Buf,
+    ) -> TransferBuilder<In, Buf> {
+        self.with_buf(buf)
+    }
+}
+impl TransferBuilder<In, Vec<u8>> {
+    /// Reads `setup.len` bytes, the buffer is allocated.
+    pub fn control_in(setup: ControlSetup) -> TransferBuilder<In, Vec<u8>> {
+        let buf = vec![0_u8; ControlSetup::SIZE + usize::from(setup.len)];
+        let mut builder = TransferBuilder::new(TransferType::Control, 0, buf);
+        builder.setup = Some(setup);
+        builder
+    }
+    /// `num_packets` isochronous packets of up to `packet_len` bytes, the buffer is allocated.
+    /// `Error::InvalidParam` if the buffer's length overflows.
+    pub fn iso_in(
+        endpoint: u8,
+        num_packets: usize,
+        packet_len: usize,
+    ) -> Result<TransferBuilder<In, Vec<u8>>, Error> {
+        let len = num_packets
+            .checked_mul(packet_len)
+            .ok_or(Error::InvalidParam)?;
+        let mut builder =
+            TransferBuilder::new(TransferType::Isochronous, endpoint, vec![0_u8; len]);
+        builder.iso_packets = vec![packet_len; num_packets];
+        Ok(builder)
+    }
+}
+impl TransferBuilder<Out, NoBuffer> {
+    pub fn bulk_out(endpoint: u8) -> TransferBuilder<Out, NoBuffer> {
+        TransferBuilder::new(TransferType::Bulk, endpoint, NoBuffer)
+    }
+    pub fn interrupt_out(endpoint: u8) -> TransferBuilder<Out, NoBuffer> {
+        TransferBuilder::new(TransferType::Interrupt, endpoint, NoBuffer)
+    }
+    /// USB 3.0 bulk stream, allocated with `DeviceHandle::alloc_streams`.
+    pub fn stream_out(endpoint: u8, stream_id: u32) -> TransferBuilder<Out, NoBuffer> {
+        let mut builder = TransferBuilder::new(TransferType::Stream, endpoint, NoBuffer);
+        builder.stream_id = stream_id;
+        builder
+    }
+    /// Writes `setup.len` bytes given by `data` (`data(&[])` without a data stage).
+    pub fn control_out(setup: ControlSetup) -> TransferBuilder<Out, NoBuffer> {
+        let mut builder = TransferBuilder::new(TransferType::Control, 0, NoBuffer);
+        builder.setup = Some(setup);
+        builder
+    }
+    /// Copies `data` into the transfer's buffer (libusb needs a buffer it's allowed to mutate,
+    /// see `SafeTransfer`).
+    pub fn data(self, data: &[u8]) -> TransferBuilder<Out, Vec<u8>> {
+        let buf = match self.setup {
+            Some(_) => {
+                let mut buf = vec![0_u8; ControlSetup::SIZE + data.len()];
+                buf[ControlSetup::SIZE..].copy_from_slice(data);
+                buf
+            }
+            None => data.to_vec(),
+        };
+        self.with_buf(buf)
+    }
+    /// `data` of `bufs` one after the other.
+    pub fn data_vectored(self, bufs: &[IoSlice<'_>]) -> TransferBuilder<Out, Vec<u8>> {
+        let buf = match self.setup {
+            Some(_) => {
+                let mut buf = vec![0_u8; ControlSetup::SIZE + io_slices_len(bufs)];
+                gather(bufs, &mut buf[ControlSetup::SIZE..]);
+                buf
+            }
+            None => gathered(bufs),
+        };
+        self.with_buf(buf)
+    }
+}
+impl TransferBuilder<Out, Vec<u8>> {
+    /// Each slice of `packets` is its own isochronous packet.
+    pub fn iso_out(endpoint: u8, packets: &[&[u8]]) -> TransferBuilder<Out, Vec<u8>> {
+        let mut builder =
+            TransferBuilder::new(TransferType::Isochronous, endpoint, packets.concat());
+        builder.iso_packets = packets.iter().map(|p| p.len()).collect();
+        builder
+    }
+}
+impl<Dir: TransferDirection, Buf: AsMut<[u8]> + AsRef<[u8]> + Send + 'static>
+    TransferBuilder<Dir, Buf>
+{
+    /// `Error::InvalidParam` if the direction bit of the endpoint (or of the setup packet)
+    /// doesn't match the builder's direction or a control buffer doesn't match `setup.len`.
+    pub fn build(self) -> Result<BuiltTransfer<Buf>, Error> {
+        let direction_bit = match self.setup {
+            Some(setup) => {
+                if self.buf.as_ref().len() != ControlSetup::SIZE + usize::from(setup.len) {
+                    return Err(Error::InvalidParam);
+                }
+                setup.request_type
+            }
+            None => self.endpoint,
+        };
+        if (direction_bit & LIBUSB_ENDPOINT_IN != 0) != Dir::IS_READ {
+            return Err(Error::InvalidParam);
+        }
+        let mut transfer = match self.iso_packets.len() {
+            0 => SafeTransfer::from_buf(self.buf),
+            packets => SafeTransfer::from_buf_iso(self.buf, packets)?,
+        };
+        transfer.set_type(self.transfer_type);
+        transfer.set_endpoint(self.endpoint);
+        transfer.set_timeout(self.timeout);
+        transfer.set_options(self.options);
+        if self.transfer_type == TransferType::Stream {
+            transfer.set_stream_id(self.stream_id);
+        }
+        if let Some(setup) = self.setup {
+            transfer.set_control_setup(setup)?;
+        }
+        if !self.iso_packets.is_empty() {
+            transfer.set_iso_packets(self.iso_packets.len(), 0)?;
+            for (i, &len) in self.iso_packets.iter().enumerate() {
+                transfer
+                    .set_iso_packet_length(i, len.try_into().map_err(|_| Error::InvalidParam)?)?;
+            }
+        }
+        Ok(BuiltTransfer {
+            transfer,
+            is_read: Dir::IS_READ,
+        })
+    }
+}
+
+/// A transfer made by `TransferBuilder`, submitted with `AsyncDevice::submit`.
+pub struct BuiltTransfer<Buf> {
+    pub(crate) transfer: SafeTransfer<Buf>,
+    pub(crate) is_read: bool,
+}
+impl<Buf> BuiltTransfer<Buf> {
+    pub fn transfer_ref(&self) -> &SafeTransfer<Buf> {
+        &self.transfer
+    }
+    pub fn is_read(&self) -> bool {
+        self.is_read
+    }
+    /// The `SafeTransfer` to submit it some other way (`submit_read` if `is_read`).
+    pub fn into_transfer(self) -> SafeTransfer<Buf> {
+        self.transfer
+    }
+}
+
+/// A finished `BuiltTransfer`.
+pub struct Completion<Buf> {
+    pub(crate) transfer: SafeTransfer<Buf>,
+    pub(crate) result: TransferResult,
+}
+impl<Buf> Completion<Buf> {
+    pub fn result(&self) -> TransferResult {
+        self.result
+    }
+    pub fn transferred(&self) -> usize {
+        self.result.transferred
+    }
+    pub fn transfer_ref(&self) -> &SafeTransfer<Buf> {
+        &self.transfer
+    }
+    /// The transfer to submit it again (its buffer and fields are still set).
+    pub fn into_transfer(self) -> SafeTransfer<Buf> {
+        self.transfer
+    }
+    /// Gives back the transfer's buffer.
+    pub async fn into_buf(self) -> Buf {
+        self.transfer.into_buf().await
+    }
+}
+impl<Buf: AsRef<[u8]>> Completion<Buf> {
+    /// The bytes transferred, without the setup packet of control transfers. Isochronous packets
+    /// aren't contiguous, see `iso_packets`.
+    pub fn data(&self) -> &[u8] {
+        let data = match self.transfer.get_type() {
+            TransferType::Control => self.transfer.control_data_ref(),
+            _ => self.transfer.buf_ref().as_ref(),
+        };
+        &data[..self.result.transferred.min(data.len())]
+    }
+    pub fn iso_packets(&self) -> Vec<IsoPacket<'_>> {
+        self.transfer.iso_packets()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::libusb::control::ControlSetup;
+    use crate::libusb::error::Error;
+    use crate::libusb::transfer::TransferType;
+    use crate::libusb::transfer_builder::TransferBuilder;
+    use core::time::Duration;
+
+    #[test]
+    pub fn test_build() {
+        let built = TransferBuilder::bulk_in(0x81)
+            .timeout(Duration::from_millis(500))
+            .buffer(vec![0_u8; 16])
+            .build()
+            .unwrap();
+        assert!(built.is_read());
+        let transfer = built.transfer_ref();
+        assert_eq!(transfer.get_type(), TransferType::Bulk);
+        assert_eq!(transfer.get_endpoint(), 0x81);
+        assert_eq!(transfer.get_timeout(), Duration::from_millis(500));
+
+        let built = TransferBuilder::stream_out(0x02, 3)
+            .data(&[1, 2, 3])
+            .build()
+            .unwrap();
+        assert!(!built.is_read());
+        assert_eq!(built.transfer_ref().get_stream_id(), 3);
+        assert_eq!(built.transfer_ref().buf_ref(), &vec![1, 2, 3]);
+
+        let built = TransferBuilder::iso_out(0x03, &[&[1, 2], &[3]])
+            .build()
+            .unwrap();
+        assert_eq!(built.transfer_ref().transfer_ref().get_num_iso_packets(), 2);
+        assert_eq!(built.transfer_ref().buf_ref(), &vec![1, 2, 3]);
+
+        let built = TransferBuilder::iso_in(0x83, 3, 4)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(built.transfer_ref().transfer_ref().get_num_iso_packets(), 3);
+        assert_eq!(built.transfer_ref().buf_ref().len(), 12);
+        assert_eq!(
+            TransferBuilder::iso_in(0x83, usize::MAX, 2).err(),
+            Some(Error::InvalidParam)
+        );
+    }
+    #[test]
+    pub fn test_direction_checks() {
+        assert_eq!(
+            TransferBuilder::bulk_in(0x01)
+                .buffer(vec![0_u8; 4])
+                .build()
+                .err(),
+            Some(Error::InvalidParam)
+        );
+        assert_eq!(
+            TransferBuilder::interrupt_out(0x82)
+                .data(&[0])
+                .build()
+                .err(),
+            Some(Error::InvalidParam)
+        );
+        let get_status = ControlSetup {
+            request_type: 0x80,
+            request: 0,
+            value: 0,
+            index: 0,
+            len: 2,
+        };
+        let built = TransferBuilder::control_in(get_status).build().unwrap();
+        assert_eq!(
+            ControlSetup::deserialize(built.transfer_ref().buf_ref()),
+            get_status
+        );
+        assert_eq!(
+            TransferBuilder::control_out(get_status)
+                .data(&[0, 0])
+                .build()
+                .err(),
+            Some(Error::InvalidParam)
+        );
+        let set_feature = ControlSetup {
+            request_type: 0x00,
+            request: 3,
+            value: 1,
+            index: 0,
+            len: 0,
+        };
+        assert!(TransferBuilder::control_out(set_feature)
+            .data(&[])
+            .build()
+            .is_ok());
+        assert_eq!(
+            TransferBuilder::control_out(set_feature)
+                .data(&[1])
+                .build()
+                .err(),
+            Some(Error::InvalidParam)
+        );
+    }
+}
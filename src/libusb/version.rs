@@ -0,0 +1,104 @@
+//! Version of the libusb library being used.
+use std::ffi::CStr;
+
+/// `libusb_get_version`. Compare versions with `at_least` (the `rc` and `describe` strings
+/// don't take part).
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct LibusbVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub micro: u16,
+    pub nano: u16,
+    /// Release candidate suffix (`-rc4`). `None` for releases.
+    pub rc: Option<String>,
+    /// Usually the libusb URL.
+    pub describe: Option<String>,
+}
+impl LibusbVersion {
+    /// The version of the libusb that's linked in. Doesn't need a `Context`.
+    pub fn get() -> LibusbVersion {
+        let version = unsafe { libusb1_sys::libusb_get_version() };
+        debug_assert!(!version.is_null(), "null libusb version");
+        unsafe { LibusbVersion::from_libusb(&*version) }
+    }
+    /// # Safety
+    /// `rc` and `describe` must be null or point to nul terminated strings.
+    pub unsafe fn from_libusb(version: &libusb1_sys::libusb_version) -> LibusbVersion {
+        let string = |ptr: *const std::os::raw::c_char| {
+            if ptr.is_null() {
+                return None;
+            }
+            let s = CStr::from_ptr(ptr).to_string_lossy();
+            if s.is_empty() {
+                None
+            } else {
+                Some(s.into_owned())
+            }
+        };
+        LibusbVersion {
+            major: version.major,
+            minor: version.minor,
+            micro: version.micro,
+            nano: version.nano,
+            rc: string(version.rc),
+            describe: string(version.describe),
+        }
+    }
+    /// Returns if this is `major.minor.micro` or newer (release candidates of `major.minor.micro`
+    /// count).
+    pub fn at_least(&self, major: u16, minor: u16, micro: u16) -> bool {
+        (self.major, self.minor, self.micro) >= (major, minor, micro)
+    }
+}
+/// Displays as `major.minor.micro.nano` with the `rc` suffix (`1.0.24.11584-rc1`).
+impl core::fmt::Display for LibusbVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}{}",
+            self.major,
+            self.minor,
+            self.micro,
+            self.nano,
+            self.rc.as_deref().unwrap_or("")
+        )
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::libusb::version::LibusbVersion;
+    use std::ffi::CString;
+
+    #[test]
+    pub fn test_from_libusb() {
+        let rc = CString::new("-rc1").expect("no nul");
+        let describe = CString::new("").expect("no nul");
+        let raw = libusb1_sys::libusb_version {
+            major: 1,
+            minor: 0,
+            micro: 24,
+            nano: 11584,
+            rc: rc.as_ptr(),
+            describe: describe.as_ptr(),
+        };
+        let version = unsafe { LibusbVersion::from_libusb(&raw) };
+        assert_eq!(version.rc.as_deref(), Some("-rc1"));
+        assert_eq!(version.describe, None);
+        assert_eq!(version.to_string(), "1.0.24.11584-rc1");
+        assert!(version.at_least(1, 0, 22));
+        assert!(version.at_least(1, 0, 24));
+        assert!(!version.at_least(1, 0, 25));
+        assert!(!version.at_least(1, 1, 0));
+        let raw = libusb1_sys::libusb_version {
+            rc: core::ptr::null(),
+            describe: core::ptr::null(),
+            ..raw
+        };
+        assert_eq!(unsafe { LibusbVersion::from_libusb(&raw) }.rc, None);
+    }
+    #[test]
+    pub fn test_get() {
+        // Static data, works without a context
+        assert!(LibusbVersion::get().at_least(1, 0, 0));
+    }
+}
@@ -0,0 +1,204 @@
+//! Device management over `rusb` (`rusb` feature).
+use crate::device::{Codes, Descriptor, DeviceIdentifier, ProductID, StringIndices, VendorID};
+use crate::error::Error;
+use crate::version::Version;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// `rusb` decodes the BCD versions into decimal digits. This encodes them back.
+pub fn version_from_rusb(version: rusb::Version) -> Version {
+    let major = version.major();
+    Version(
+        (u16::from(((major / 10) << 4) | (major % 10)) << 8)
+            | (u16::from(version.minor()) << 4)
+            | u16::from(version.sub_minor()),
+    )
+}
+impl From<&rusb::DeviceDescriptor> for Descriptor {
+    fn from(d: &rusb::DeviceDescriptor) -> Self {
+        Descriptor {
+            usb_version: version_from_rusb(d.usb_version()),
+            codes: Codes {
+                class: d.class_code(),
+                sub_class: d.sub_class_code(),
+                protocol: d.protocol_code(),
+            },
+            max_packet_size: d.max_packet_size(),
+            device_identifier: DeviceIdentifier {
+                vendor_id: VendorID(d.vendor_id()),
+                product_id: ProductID(d.product_id()),
+            },
+            device_version: version_from_rusb(d.device_version()),
+            string_indices: StringIndices {
+                manufacturer: d.manufacturer_string_index(),
+                product: d.product_string_index(),
+                serial_number: d.serial_number_string_index(),
+            },
+            num_configurations: d.num_configurations(),
+        }
+    }
+}
+/// A USB device (not opened).
+#[derive(Clone, Debug)]
+pub struct Device(rusb::Device<rusb::Context>);
+impl Device {
+    pub fn rusb_device(&self) -> &rusb::Device<rusb::Context> {
+        &self.0
+    }
+    pub fn descriptor(&self) -> Result<Descriptor, Error> {
+        Ok(Descriptor::from(&self.0.device_descriptor()?))
+    }
+    pub fn device_identifier(&self) -> Result<DeviceIdentifier, Error> {
+        Ok(self.descriptor()?.device_identifier)
+    }
+    pub fn open(&self) -> Result<DeviceHandle, Error> {
+        Ok(DeviceHandle(self.0.open()?))
+    }
+}
+/// The USB devices connected when the list was made.
+pub struct DeviceList(rusb::DeviceList<rusb::Context>);
+impl DeviceList {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn iter(&self) -> impl Iterator<Item = Device> + '_ {
+        self.0.iter().map(Device)
+    }
+}
+/// An opened USB device.
+#[derive(Debug)]
+pub struct DeviceHandle(rusb::DeviceHandle<rusb::Context>);
+impl DeviceHandle {
+    pub fn rusb_handle(&self) -> &rusb::DeviceHandle<rusb::Context> {
+        &self.0
+    }
+    pub fn rusb_handle_mut(&mut self) -> &mut rusb::DeviceHandle<rusb::Context> {
+        &mut self.0
+    }
+    pub fn device(&self) -> Device {
+        Device(self.0.device())
+    }
+    pub fn claim_interface(&mut self, interface_number: u8) -> Result<(), Error> {
+        Ok(self.0.claim_interface(interface_number)?)
+    }
+    pub fn release_interface(&mut self, interface_number: u8) -> Result<(), Error> {
+        Ok(self.0.release_interface(interface_number)?)
+    }
+    pub fn control_read(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        Ok(self
+            .0
+            .read_control(request_type, request, value, index, data, timeout)?)
+    }
+    pub fn control_write(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        Ok(self
+            .0
+            .write_control(request_type, request, value, index, data, timeout)?)
+    }
+    pub fn bulk_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        Ok(self.0.read_bulk(endpoint, data, timeout)?)
+    }
+    pub fn bulk_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize, Error> {
+        Ok(self.0.write_bulk(endpoint, data, timeout)?)
+    }
+    pub fn interrupt_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        Ok(self.0.read_interrupt(endpoint, data, timeout)?)
+    }
+    pub fn interrupt_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        Ok(self.0.write_interrupt(endpoint, data, timeout)?)
+    }
+}
+/// Owns a `rusb` context and finds/opens devices in it.
+pub struct Manager {
+    context: rusb::Context,
+}
+impl Manager {
+    pub fn new() -> Result<Manager, Error> {
+        Ok(Manager {
+            context: rusb::Context::new()?,
+        })
+    }
+    pub fn context(&self) -> &rusb::Context {
+        &self.context
+    }
+    pub fn device_list(&self) -> Result<DeviceList, Error> {
+        use rusb::UsbContext;
+        Ok(DeviceList(self.context.devices()?))
+    }
+    /// Devices whose descriptor matches `filter`. Devices whose descriptor can't be read are
+    /// skipped.
+    pub fn devices_with(
+        &self,
+        mut filter: impl FnMut(&Descriptor) -> bool,
+    ) -> Result<Vec<Device>, Error> {
+        Ok(self
+            .device_list()?
+            .iter()
+            .filter(|device| matches!(device.descriptor(), Ok(d) if filter(&d)))
+            .collect())
+    }
+    /// Opens the first device matching `id`. `rusb::Error::NoDevice` if there isn't one.
+    pub fn open_device(&self, id: DeviceIdentifier) -> Result<DeviceHandle, Error> {
+        self.devices_with(|d| d.device_identifier == id)?
+            .first()
+            .ok_or(Error::Rusb(rusb::Error::NoDevice))?
+            .open()
+    }
+    /// Opens every device matching `id`. Fails if any of them can't be opened.
+    pub fn open_all(&self, id: DeviceIdentifier) -> Result<Vec<DeviceHandle>, Error> {
+        self.devices_with(|d| d.device_identifier == id)?
+            .iter()
+            .map(Device::open)
+            .collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::manager::version_from_rusb;
+    use crate::version::Version;
+
+    #[test]
+    pub fn test_version_from_rusb() {
+        assert_eq!(version_from_rusb(rusb::Version(2, 1, 0)), Version(0x0210));
+        assert_eq!(version_from_rusb(rusb::Version(1, 1, 0)), Version(0x0110));
+        assert_eq!(version_from_rusb(rusb::Version(3, 2, 0)), Version(0x0320));
+        assert_eq!(version_from_rusb(rusb::Version(12, 3, 4)), Version(0x1234));
+        assert_eq!(
+            version_from_rusb(rusb::Version::from_bcd(0x0201)),
+            Version(0x0201)
+        );
+    }
+}
@@ -0,0 +1,560 @@
+//! Scripted `AsyncUsbHandle` for unit testing drivers without hardware. Write the driver generic
+//! over `AsyncUsbHandle` and script the transfers it should make:
+//! ```
+//! use usbw::mock::MockAsyncDevice;
+//! use usbw::traits::AsyncUsbHandle;
+//! # use futures_util::FutureExt;
+//! # let timeout = core::time::Duration::from_secs(1);
+//! let mock = MockAsyncDevice::new();
+//! mock.expect_control_write(0x20, 0, 0, 0).data(&[0x03, 0x0C, 0x00]).respond_len(3);
+//! mock.expect_interrupt_read(0x81).respond(&[0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00]);
+//! # let mut buf = [0_u8; 64];
+//! # mock.control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x00], timeout).now_or_never().unwrap().unwrap();
+//! # mock.interrupt_read(0x81, &mut buf, timeout).now_or_never().unwrap().unwrap();
+//! // Run the driver against `&mock`
+//! mock.verify();
+//! ```
+//! Expectations are strictly ordered. A transfer that doesn't match the next expectation fails
+//! with `Error::Unexpected` and makes `verify` panic.
+use crate::traits::{AsyncUsbHandle, UsbFuture};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A transfer the driver made or is expected to make.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum MockRequest {
+    ControlRead {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    },
+    ControlWrite {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    },
+    BulkRead(u8),
+    BulkWrite(u8),
+    InterruptRead(u8),
+    InterruptWrite(u8),
+}
+impl MockRequest {
+    /// If the driver reads data for this request (the scripted response is copied to it).
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            MockRequest::ControlRead { .. }
+                | MockRequest::BulkRead(_)
+                | MockRequest::InterruptRead(_)
+        )
+    }
+}
+impl fmt::Display for MockRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MockRequest::ControlRead {
+                request_type,
+                request,
+                value,
+                index,
+            } => write!(
+                f,
+                "control read {:#04x} {:#04x} value {:#06x} index {:#06x}",
+                request_type, request, value, index
+            ),
+            MockRequest::ControlWrite {
+                request_type,
+                request,
+                value,
+                index,
+            } => write!(
+                f,
+                "control write {:#04x} {:#04x} value {:#06x} index {:#06x}",
+                request_type, request, value, index
+            ),
+            MockRequest::BulkRead(endpoint) => write!(f, "bulk read {:#04x}", endpoint),
+            MockRequest::BulkWrite(endpoint) => write!(f, "bulk write {:#04x}", endpoint),
+            MockRequest::InterruptRead(endpoint) => write!(f, "interrupt read {:#04x}", endpoint),
+            MockRequest::InterruptWrite(endpoint) => {
+                write!(f, "interrupt write {:#04x}", endpoint)
+            }
+        }
+    }
+}
+/// Errors of the mock. The scripted ones (`Timeout`, `Stall`, ...) stand in for the backend
+/// errors, the rest report a transfer that doesn't match the script.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Error {
+    Timeout,
+    Stall,
+    NoDevice,
+    Overflow,
+    /// `got` doesn't match the next expectation (`None` when all of them are used up).
+    Unexpected {
+        expected: Option<MockRequest>,
+        got: MockRequest,
+    },
+    /// A write with different data than `MockExpectation::data`.
+    DataMismatch {
+        request: MockRequest,
+        expected: Vec<u8>,
+        got: Vec<u8>,
+    },
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => f.write_str("Operation timed out"),
+            Error::Stall => f.write_str("Endpoint stalled"),
+            Error::NoDevice => f.write_str("No such device"),
+            Error::Overflow => f.write_str("Overflow"),
+            Error::Unexpected {
+                expected: Some(expected),
+                got,
+            } => write!(f, "unexpected {}, expected {}", got, expected),
+            Error::Unexpected {
+                expected: None,
+                got,
+            } => write!(f, "unexpected {}, no more transfers expected", got),
+            Error::DataMismatch {
+                request,
+                expected,
+                got,
+            } => write!(
+                f,
+                "{} wrote {:02x?}, expected {:02x?}",
+                request, got, expected
+            ),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::Timeout => std::io::ErrorKind::TimedOut,
+            Error::NoDevice => std::io::ErrorKind::NotConnected,
+            Error::Stall | Error::Overflow => std::io::ErrorKind::Other,
+            Error::Unexpected { .. } | Error::DataMismatch { .. } => {
+                std::io::ErrorKind::InvalidInput
+            }
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+/// Lets libusb helpers that are generic over `AsyncUsbHandle` run against the mock.
+#[cfg(feature = "libusb")]
+impl From<Error> for crate::libusb::error::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Timeout => crate::libusb::error::Error::Timeout,
+            Error::Stall => crate::libusb::error::Error::Pipe,
+            Error::NoDevice => crate::libusb::error::Error::NoDevice,
+            Error::Overflow => crate::libusb::error::Error::Overflow,
+            Error::Unexpected { .. } | Error::DataMismatch { .. } => {
+                crate::libusb::error::Error::Other
+            }
+        }
+    }
+}
+
+/// Data written by an expected write. Filled in once the driver makes the transfer.
+#[derive(Clone, Debug, Default)]
+pub struct Captured(Arc<Mutex<Option<Vec<u8>>>>);
+impl Captured {
+    /// The written data or `None` if the write hasn't happened yet.
+    pub fn get(&self) -> Option<Vec<u8>> {
+        lock(&self.0).clone()
+    }
+}
+
+#[derive(Debug)]
+enum Response {
+    /// Copied into the read buffer.
+    Data(Vec<u8>),
+    /// Bytes a write reports as transferred. `None` is all of them.
+    Written(Option<usize>),
+    Fail(Error),
+}
+#[derive(Debug)]
+struct Expected {
+    request: MockRequest,
+    data: Option<Vec<u8>>,
+    capture: Option<Captured>,
+    response: Response,
+}
+#[derive(Debug, Default)]
+struct State {
+    expected: VecDeque<Expected>,
+    /// Transfers that didn't match the script, reported by `verify`.
+    failures: Vec<String>,
+    made: usize,
+}
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panicking test shouldn't hide the original panic behind a poisoned lock
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Expectation being built. Added to the script by `respond`, `respond_len`, `capture` or `fail`.
+#[must_use = "the expectation is only added by respond, respond_len, capture or fail"]
+pub struct MockExpectation<'a> {
+    mock: &'a MockAsyncDevice,
+    request: MockRequest,
+    data: Option<Vec<u8>>,
+}
+impl<'a> MockExpectation<'a> {
+    /// Only matches a write of exactly `data`.
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.data = Some(data.to_vec());
+        self
+    }
+    /// Reads return `data`, writes return `data.len()`.
+    pub fn respond(self, data: &[u8]) {
+        let response = if self.request.is_read() {
+            Response::Data(data.to_vec())
+        } else {
+            Response::Written(Some(data.len()))
+        };
+        self.push(response, None)
+    }
+    /// Writes report `len` bytes as written (a short write), reads return `len` zeros.
+    pub fn respond_len(self, len: usize) {
+        let response = if self.request.is_read() {
+            Response::Data(vec![0; len])
+        } else {
+            Response::Written(Some(len))
+        };
+        self.push(response, None)
+    }
+    /// Accepts the write and returns what was written once the driver makes it. Reads capture
+    /// nothing and return no data.
+    pub fn capture(self) -> Captured {
+        let captured = Captured::default();
+        let response = if self.request.is_read() {
+            Response::Data(Vec::new())
+        } else {
+            Response::Written(None)
+        };
+        self.push(response, Some(captured.clone()));
+        captured
+    }
+    /// The transfer fails with `error`.
+    pub fn fail(self, error: Error) {
+        self.push(Response::Fail(error), None)
+    }
+    fn push(self, response: Response, capture: Option<Captured>) {
+        lock(&self.mock.state).expected.push_back(Expected {
+            request: self.request,
+            data: self.data,
+            capture,
+            response,
+        })
+    }
+}
+
+/// `AsyncUsbHandle` that answers from a script of expected transfers instead of a device. The
+/// futures it returns are always ready.
+#[derive(Debug, Default)]
+pub struct MockAsyncDevice {
+    state: Mutex<State>,
+}
+impl MockAsyncDevice {
+    pub fn new() -> MockAsyncDevice {
+        MockAsyncDevice::default()
+    }
+    pub fn expect(&self, request: MockRequest) -> MockExpectation<'_> {
+        MockExpectation {
+            mock: self,
+            request,
+            data: None,
+        }
+    }
+    pub fn expect_control_read(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    ) -> MockExpectation<'_> {
+        self.expect(MockRequest::ControlRead {
+            request_type,
+            request,
+            value,
+            index,
+        })
+    }
+    pub fn expect_control_write(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    ) -> MockExpectation<'_> {
+        self.expect(MockRequest::ControlWrite {
+            request_type,
+            request,
+            value,
+            index,
+        })
+    }
+    pub fn expect_bulk_read(&self, endpoint: u8) -> MockExpectation<'_> {
+        self.expect(MockRequest::BulkRead(endpoint))
+    }
+    pub fn expect_bulk_write(&self, endpoint: u8) -> MockExpectation<'_> {
+        self.expect(MockRequest::BulkWrite(endpoint))
+    }
+    pub fn expect_interrupt_read(&self, endpoint: u8) -> MockExpectation<'_> {
+        self.expect(MockRequest::InterruptRead(endpoint))
+    }
+    pub fn expect_interrupt_write(&self, endpoint: u8) -> MockExpectation<'_> {
+        self.expect(MockRequest::InterruptWrite(endpoint))
+    }
+    /// Expectations the driver hasn't met yet, in order.
+    pub fn pending(&self) -> Vec<MockRequest> {
+        lock(&self.state)
+            .expected
+            .iter()
+            .map(|expected| expected.request)
+            .collect()
+    }
+    /// How many transfers the driver made (matching or not).
+    pub fn transfers(&self) -> usize {
+        lock(&self.state).made
+    }
+    /// Panics if a transfer didn't match the script or some expectations weren't met.
+    pub fn verify(&self) {
+        let state = lock(&self.state);
+        let mut problems: Vec<String> = state.failures.clone();
+        problems.extend(
+            state
+                .expected
+                .iter()
+                .map(|expected| format!("unmet expectation: {}", expected.request)),
+        );
+        if !problems.is_empty() {
+            panic!("mock USB device verify failed:\n{}", problems.join("\n"));
+        }
+    }
+    fn transfer(&self, request: MockRequest, write: Option<&[u8]>) -> Result<Response, Error> {
+        let mut state = lock(&self.state);
+        state.made += 1;
+        let result = match state.expected.front() {
+            Some(expected) if expected.request == request => {
+                let expected = state.expected.pop_front().expect("just matched");
+                match (&expected.data, write) {
+                    (Some(data), Some(got)) if data.as_slice() != got => Err(Error::DataMismatch {
+                        request,
+                        expected: data.clone(),
+                        got: got.to_vec(),
+                    }),
+                    _ => {
+                        if let (Some(captured), Some(got)) = (&expected.capture, write) {
+                            *lock(&captured.0) = Some(got.to_vec());
+                        }
+                        Ok(expected.response)
+                    }
+                }
+            }
+            expected => Err(Error::Unexpected {
+                expected: expected.map(|expected| expected.request),
+                got: request,
+            }),
+        };
+        if let Err(e) = &result {
+            state.failures.push(e.to_string());
+        }
+        result
+    }
+    fn read(&self, request: MockRequest, data: &mut [u8]) -> Result<usize, Error> {
+        match self.transfer(request, None)? {
+            Response::Data(response) => {
+                let buf = data.get_mut(..response.len()).ok_or(Error::Overflow)?;
+                buf.copy_from_slice(&response);
+                Ok(response.len())
+            }
+            Response::Written(_) => Ok(0),
+            Response::Fail(e) => Err(e),
+        }
+    }
+    fn write(&self, request: MockRequest, data: &[u8]) -> Result<usize, Error> {
+        match self.transfer(request, Some(data))? {
+            Response::Written(len) => Ok(len.unwrap_or(data.len())),
+            Response::Data(_) => Ok(data.len()),
+            Response::Fail(e) => Err(e),
+        }
+    }
+}
+impl AsyncUsbHandle for MockAsyncDevice {
+    type Error = Error;
+    fn control_read<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a mut [u8],
+        _timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let request = MockRequest::ControlRead {
+            request_type,
+            request,
+            value,
+            index,
+        };
+        Box::pin(core::future::ready(self.read(request, data)))
+    }
+    fn control_write<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a [u8],
+        _timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let request = MockRequest::ControlWrite {
+            request_type,
+            request,
+            value,
+            index,
+        };
+        Box::pin(core::future::ready(self.write(request, data)))
+    }
+    fn bulk_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        _timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        Box::pin(core::future::ready(
+            self.read(MockRequest::BulkRead(endpoint), data),
+        ))
+    }
+    fn bulk_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        _timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        Box::pin(core::future::ready(
+            self.write(MockRequest::BulkWrite(endpoint), data),
+        ))
+    }
+    fn interrupt_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        _timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        Box::pin(core::future::ready(
+            self.read(MockRequest::InterruptRead(endpoint), data),
+        ))
+    }
+    fn interrupt_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        _timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        Box::pin(core::future::ready(
+            self.write(MockRequest::InterruptWrite(endpoint), data),
+        ))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::mock::{Error, MockAsyncDevice, MockRequest};
+    use crate::traits::AsyncUsbHandle;
+    use core::time::Duration;
+    use futures_util::FutureExt;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Generic driver code under test.
+    async fn reset<H: AsyncUsbHandle>(handle: &H) -> Result<Vec<u8>, H::Error> {
+        handle
+            .control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x00], TIMEOUT)
+            .await?;
+        let mut event = [0_u8; 16];
+        let len = handle.interrupt_read(0x81, &mut event, TIMEOUT).await?;
+        Ok(event[..len].to_vec())
+    }
+    #[test]
+    pub fn test_scripted_transfers() {
+        let mock = MockAsyncDevice::new();
+        let command = mock.expect_control_write(0x20, 0, 0, 0).capture();
+        mock.expect_interrupt_read(0x81)
+            .respond(&[0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00]);
+        assert_eq!(mock.pending().len(), 2);
+        let event = reset(&mock).now_or_never().expect("ready");
+        assert_eq!(event, Ok(vec![0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00]));
+        assert_eq!(command.get(), Some(vec![0x03, 0x0C, 0x00]));
+        assert_eq!(mock.transfers(), 2);
+        mock.verify();
+    }
+    /// The mock doesn't depend on the executor driving it.
+    #[test]
+    pub fn test_runtimes() {
+        let script = |mock: &MockAsyncDevice| {
+            mock.expect_control_write(0x20, 0, 0, 0).respond_len(3);
+            mock.expect_interrupt_read(0x81).respond(&[0x0E]);
+        };
+        let mock = MockAsyncDevice::new();
+        script(&mock);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can't make async runtime");
+        assert_eq!(runtime.block_on(reset(&mock)), Ok(vec![0x0E]));
+        script(&mock);
+        assert_eq!(async_std::task::block_on(reset(&mock)), Ok(vec![0x0E]));
+        mock.verify();
+    }
+    #[test]
+    pub fn test_scripted_failures() {
+        let mock = MockAsyncDevice::new();
+        mock.expect_bulk_write(0x02).data(&[1, 2]).respond_len(1);
+        mock.expect_bulk_read(0x82).fail(Error::Stall);
+        mock.expect_bulk_read(0x82).respond(&[0; 8]);
+        let write = mock.bulk_write(0x02, &[1, 2], TIMEOUT).now_or_never();
+        assert_eq!(write, Some(Ok(1)));
+        let mut buf = [0_u8; 4];
+        let read = mock.bulk_read(0x82, &mut buf, TIMEOUT).now_or_never();
+        assert_eq!(read, Some(Err(Error::Stall)));
+        // Doesn't fit in `buf`
+        let read = mock.bulk_read(0x82, &mut buf, TIMEOUT).now_or_never();
+        assert_eq!(read, Some(Err(Error::Overflow)));
+        mock.verify();
+    }
+    #[test]
+    pub fn test_unexpected() {
+        let mock = MockAsyncDevice::new();
+        mock.expect_bulk_write(0x02).data(&[1]).respond(&[1]);
+        mock.expect_bulk_write(0x03).respond(&[1]);
+        let write = mock.bulk_write(0x02, &[2], TIMEOUT).now_or_never();
+        assert!(matches!(write, Some(Err(Error::DataMismatch { .. }))));
+        let write = mock.bulk_write(0x02, &[1], TIMEOUT).now_or_never();
+        assert_eq!(
+            write,
+            Some(Err(Error::Unexpected {
+                expected: Some(MockRequest::BulkWrite(0x03)),
+                got: MockRequest::BulkWrite(0x02),
+            }))
+        );
+        let verify = std::panic::catch_unwind(|| mock.verify());
+        assert!(verify.is_err());
+    }
+    #[test]
+    #[should_panic(expected = "unmet expectation: bulk read 0x81")]
+    pub fn test_unmet() {
+        let mock = MockAsyncDevice::new();
+        mock.expect_bulk_read(0x81).respond(&[]);
+        mock.verify();
+    }
+}
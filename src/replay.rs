@@ -0,0 +1,842 @@
+//! Recording the transfers a driver makes and replaying them without the device.
+//!
+//! `RecordingDevice` wraps any `AsyncUsbHandle` and logs every transfer to a `Session`, which is
+//! saved in a small binary format. `ReplayDevice` answers from a saved `Session` and fails with
+//! `Error::Mismatch` as soon as the driver makes a different request than the recorded one.
+//!
+//! # Format
+//! Little endian. An 8 byte magic (`USBWREC\0`) and a `u16` format version, then the records:
+//! `kind: u8, endpoint: u8, request_type: u8, request: u8, value: u16, index: u16,
+//! timeout_ms: u32, at_us: u64, duration_us: u64, length: u32, data_len: u32, data,
+//! result: u8` followed by `actual: u32` for `0` (Ok) or `len: u32` and a UTF-8 message for `1`.
+use crate::traits::{AsyncUsbHandle, UsbFuture};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use core::time::Duration;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// First bytes of a saved session.
+pub const MAGIC: [u8; 8] = *b"USBWREC\0";
+/// Format version written by `Session::write_to`. Older versions are still read.
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum TransferKind {
+    ControlRead = 0,
+    ControlWrite = 1,
+    BulkRead = 2,
+    BulkWrite = 3,
+    InterruptRead = 4,
+    InterruptWrite = 5,
+}
+impl TransferKind {
+    pub fn is_read(self) -> bool {
+        matches!(
+            self,
+            TransferKind::ControlRead | TransferKind::BulkRead | TransferKind::InterruptRead
+        )
+    }
+    pub fn is_control(self) -> bool {
+        matches!(self, TransferKind::ControlRead | TransferKind::ControlWrite)
+    }
+}
+impl TryFrom<u8> for TransferKind {
+    type Error = FormatError;
+    fn try_from(value: u8) -> Result<Self, FormatError> {
+        match value {
+            0 => Ok(TransferKind::ControlRead),
+            1 => Ok(TransferKind::ControlWrite),
+            2 => Ok(TransferKind::BulkRead),
+            3 => Ok(TransferKind::BulkWrite),
+            4 => Ok(TransferKind::InterruptRead),
+            5 => Ok(TransferKind::InterruptWrite),
+            _ => Err(FormatError::BadKind(value)),
+        }
+    }
+}
+/// What the driver asked for. `endpoint` is 0 for control transfers, the setup fields are 0 for
+/// bulk/interrupt transfers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct RecordedRequest {
+    pub kind: TransferKind,
+    pub endpoint: u8,
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+impl RecordedRequest {
+    pub fn control(
+        kind: TransferKind,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+    ) -> RecordedRequest {
+        RecordedRequest {
+            kind,
+            endpoint: 0,
+            request_type,
+            request,
+            value,
+            index,
+        }
+    }
+    pub fn endpoint(kind: TransferKind, endpoint: u8) -> RecordedRequest {
+        RecordedRequest {
+            kind,
+            endpoint,
+            request_type: 0,
+            request: 0,
+            value: 0,
+            index: 0,
+        }
+    }
+}
+impl fmt::Display for RecordedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.kind.is_control() {
+            write!(
+                f,
+                "{:?} {:#04x} {:#04x} value {:#06x} index {:#06x}",
+                self.kind, self.request_type, self.request, self.value, self.index
+            )
+        } else {
+            write!(f, "{:?} {:#04x}", self.kind, self.endpoint)
+        }
+    }
+}
+/// One transfer of a `Session`.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Record {
+    pub request: RecordedRequest,
+    pub timeout: Duration,
+    /// When the transfer started, from the start of the recording.
+    pub at: Duration,
+    /// How long the transfer took.
+    pub duration: Duration,
+    /// Size of the read buffer or of the written data.
+    pub length: usize,
+    /// The data written or the data the device returned.
+    pub data: Vec<u8>,
+    /// Bytes transferred or the error message of the backend.
+    pub result: Result<usize, String>,
+}
+/// The transfers of one recording, in order.
+#[derive(Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct Session {
+    pub records: Vec<Record>,
+}
+impl Session {
+    pub fn read_from(mut reader: impl Read) -> Result<Session, FormatError> {
+        let mut magic = [0_u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        let version = read_u16(&mut reader)?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let mut records = Vec::new();
+        let mut kind = [0_u8; 1];
+        // A clean end of file is only allowed between records
+        while reader.read(&mut kind)? == 1 {
+            records.push(read_record(&mut reader, TransferKind::try_from(kind[0])?)?);
+        }
+        Ok(Session { records })
+    }
+    pub fn write_to(&self, mut writer: impl Write) -> Result<(), FormatError> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        for record in &self.records {
+            write_record(&mut writer, record)?;
+        }
+        Ok(())
+    }
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Session, FormatError> {
+        Session::read_from(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), FormatError> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+fn read_u16(reader: &mut impl Read) -> Result<u16, FormatError> {
+    let mut bytes = [0_u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+fn read_u32(reader: &mut impl Read) -> Result<u32, FormatError> {
+    let mut bytes = [0_u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+fn read_u64(reader: &mut impl Read) -> Result<u64, FormatError> {
+    let mut bytes = [0_u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, FormatError> {
+    let len = read_u32(reader)? as u64;
+    let mut bytes = Vec::new();
+    // `take` so a corrupt length can't allocate gigabytes up front
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+fn read_record(reader: &mut impl Read, kind: TransferKind) -> Result<Record, FormatError> {
+    let mut header = [0_u8; 3];
+    reader.read_exact(&mut header)?;
+    let request = RecordedRequest {
+        kind,
+        endpoint: header[0],
+        request_type: header[1],
+        request: header[2],
+        value: read_u16(reader)?,
+        index: read_u16(reader)?,
+    };
+    let timeout = Duration::from_millis(read_u32(reader)?.into());
+    let at = Duration::from_micros(read_u64(reader)?);
+    let duration = Duration::from_micros(read_u64(reader)?);
+    let length = read_u32(reader)? as usize;
+    let data = read_bytes(reader)?;
+    let mut tag = [0_u8; 1];
+    reader.read_exact(&mut tag)?;
+    let result = match tag[0] {
+        0 => Ok(read_u32(reader)? as usize),
+        1 => Err(String::from_utf8(read_bytes(reader)?).map_err(|_| FormatError::BadMessage)?),
+        tag => return Err(FormatError::BadResult(tag)),
+    };
+    Ok(Record {
+        request,
+        timeout,
+        at,
+        duration,
+        length,
+        data,
+        result,
+    })
+}
+fn len_u32(len: usize) -> Result<u32, FormatError> {
+    u32::try_from(len).map_err(|_| FormatError::TooLong)
+}
+fn write_record(writer: &mut impl Write, record: &Record) -> Result<(), FormatError> {
+    let request = &record.request;
+    writer.write_all(&[
+        request.kind as u8,
+        request.endpoint,
+        request.request_type,
+        request.request,
+    ])?;
+    writer.write_all(&request.value.to_le_bytes())?;
+    writer.write_all(&request.index.to_le_bytes())?;
+    let timeout = u32::try_from(record.timeout.as_millis()).unwrap_or(u32::MAX);
+    writer.write_all(&timeout.to_le_bytes())?;
+    writer.write_all(&(record.at.as_micros() as u64).to_le_bytes())?;
+    writer.write_all(&(record.duration.as_micros() as u64).to_le_bytes())?;
+    writer.write_all(&len_u32(record.length)?.to_le_bytes())?;
+    writer.write_all(&len_u32(record.data.len())?.to_le_bytes())?;
+    writer.write_all(&record.data)?;
+    match &record.result {
+        Ok(actual) => {
+            writer.write_all(&[0])?;
+            writer.write_all(&len_u32(*actual)?.to_le_bytes())?;
+        }
+        Err(message) => {
+            writer.write_all(&[1])?;
+            writer.write_all(&len_u32(message.len())?.to_le_bytes())?;
+            writer.write_all(message.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Errors reading or writing a saved `Session`.
+#[derive(Debug)]
+pub enum FormatError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    BadKind(u8),
+    BadResult(u8),
+    /// An error message that isn't UTF-8.
+    BadMessage,
+    /// Data or a length that doesn't fit the format's `u32`.
+    TooLong,
+}
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "session IO: {}", e),
+            FormatError::BadMagic => f.write_str("not a usbw session"),
+            FormatError::UnsupportedVersion(version) => {
+                write!(f, "unsupported session format version {}", version)
+            }
+            FormatError::BadKind(kind) => write!(f, "unknown transfer kind {}", kind),
+            FormatError::BadResult(tag) => write!(f, "unknown result tag {}", tag),
+            FormatError::BadMessage => f.write_str("error message isn't UTF-8"),
+            FormatError::TooLong => f.write_str("transfer too long for the session format"),
+        }
+    }
+}
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<std::io::Error> for FormatError {
+    fn from(e: std::io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+/// Forwards to `inner` and records every transfer. Errors are recorded by their `Display`.
+pub struct RecordingDevice<H> {
+    inner: H,
+    start: Instant,
+    records: Mutex<Vec<Record>>,
+}
+impl<H: AsyncUsbHandle> RecordingDevice<H>
+where
+    H::Error: fmt::Display,
+{
+    pub fn new(inner: H) -> RecordingDevice<H> {
+        RecordingDevice {
+            inner,
+            start: Instant::now(),
+            records: Mutex::new(Vec::new()),
+        }
+    }
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+    /// The transfers recorded so far.
+    pub fn session(&self) -> Session {
+        Session {
+            records: self.lock().clone(),
+        }
+    }
+    pub fn into_parts(self) -> (H, Session) {
+        let records = self.records.into_inner().unwrap_or_else(|e| e.into_inner());
+        (self.inner, Session { records })
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Record>> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn record(
+        &self,
+        request: RecordedRequest,
+        timeout: Duration,
+        started: Instant,
+        length: usize,
+        data: &[u8],
+        result: &Result<usize, H::Error>,
+    ) {
+        // Reads only keep what the device returned
+        let data = match result {
+            Ok(actual) if request.kind.is_read() => &data[..(*actual).min(data.len())],
+            Err(_) if request.kind.is_read() => &[],
+            _ => data,
+        };
+        self.lock().push(Record {
+            request,
+            timeout,
+            at: started.duration_since(self.start),
+            duration: started.elapsed(),
+            length,
+            data: data.to_vec(),
+            result: result.as_ref().map(|len| *len).map_err(|e| e.to_string()),
+        })
+    }
+}
+impl<H: AsyncUsbHandle> AsyncUsbHandle for RecordingDevice<H>
+where
+    H::Error: fmt::Display,
+{
+    type Error = H::Error;
+    fn control_read<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, H::Error>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self
+                .inner
+                .control_read(request_type, request, value, index, data, timeout)
+                .await;
+            let recorded = RecordedRequest::control(
+                TransferKind::ControlRead,
+                request_type,
+                request,
+                value,
+                index,
+            );
+            self.record(recorded, timeout, started, data.len(), data, &result);
+            result
+        })
+    }
+    fn control_write<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, H::Error>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self
+                .inner
+                .control_write(request_type, request, value, index, data, timeout)
+                .await;
+            let recorded = RecordedRequest::control(
+                TransferKind::ControlWrite,
+                request_type,
+                request,
+                value,
+                index,
+            );
+            self.record(recorded, timeout, started, data.len(), data, &result);
+            result
+        })
+    }
+    fn bulk_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, H::Error>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.inner.bulk_read(endpoint, data, timeout).await;
+            let recorded = RecordedRequest::endpoint(TransferKind::BulkRead, endpoint);
+            self.record(recorded, timeout, started, data.len(), data, &result);
+            result
+        })
+    }
+    fn bulk_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, H::Error>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.inner.bulk_write(endpoint, data, timeout).await;
+            let recorded = RecordedRequest::endpoint(TransferKind::BulkWrite, endpoint);
+            self.record(recorded, timeout, started, data.len(), data, &result);
+            result
+        })
+    }
+    fn interrupt_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, H::Error>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.inner.interrupt_read(endpoint, data, timeout).await;
+            let recorded = RecordedRequest::endpoint(TransferKind::InterruptRead, endpoint);
+            self.record(recorded, timeout, started, data.len(), data, &result);
+            result
+        })
+    }
+    fn interrupt_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, H::Error>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.inner.interrupt_write(endpoint, data, timeout).await;
+            let recorded = RecordedRequest::endpoint(TransferKind::InterruptWrite, endpoint);
+            self.record(recorded, timeout, started, data.len(), data, &result);
+            result
+        })
+    }
+}
+
+/// Errors of a `ReplayDevice`.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Error {
+    /// The recorded transfer failed with this message.
+    Recorded(String),
+    /// Record `position` doesn't match what the driver did.
+    Mismatch {
+        position: usize,
+        expected: Option<RecordedRequest>,
+        got: RecordedRequest,
+        reason: &'static str,
+    },
+    /// The read buffer is smaller than the recorded data.
+    Overflow,
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Recorded(message) => f.write_str(message),
+            Error::Mismatch {
+                position,
+                expected: Some(expected),
+                got,
+                reason,
+            } => write!(
+                f,
+                "replay mismatch at transfer {}: {} (recorded {}, got {})",
+                position, reason, expected, got
+            ),
+            Error::Mismatch {
+                position,
+                expected: None,
+                got,
+                reason,
+            } => write!(
+                f,
+                "replay mismatch at transfer {}: {} (got {})",
+                position, reason, got
+            ),
+            Error::Overflow => f.write_str("Overflow"),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::Mismatch { .. } => std::io::ErrorKind::InvalidInput,
+            Error::Recorded(_) | Error::Overflow => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+/// Answers transfers from a recorded `Session`, in order. Timeouts have to match the recording
+/// exactly unless `set_timeout_tolerance` loosens it. The futures are always ready (recorded
+/// timing isn't reproduced).
+#[derive(Debug)]
+pub struct ReplayDevice {
+    records: Vec<Record>,
+    next: Mutex<usize>,
+    timeout_tolerance: Option<Duration>,
+}
+impl ReplayDevice {
+    pub fn new(session: Session) -> ReplayDevice {
+        ReplayDevice {
+            records: session.records,
+            next: Mutex::new(0),
+            timeout_tolerance: Some(Duration::from_secs(0)),
+        }
+    }
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<ReplayDevice, FormatError> {
+        Ok(ReplayDevice::new(Session::load(path)?))
+    }
+    /// How far a timeout may be from the recorded one. `None` ignores timeouts.
+    pub fn set_timeout_tolerance(&mut self, tolerance: Option<Duration>) {
+        self.timeout_tolerance = tolerance
+    }
+    /// Records not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.records.len() - *self.lock()
+    }
+    /// If the whole session was replayed.
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+        self.next.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn timeout_matches(&self, recorded: Duration, timeout: Duration) -> bool {
+        match self.timeout_tolerance {
+            None => true,
+            Some(tolerance) => recorded.abs_diff(timeout) <= tolerance,
+        }
+    }
+    /// Checks `got` against the next record and moves past it.
+    fn next_record(
+        &self,
+        got: RecordedRequest,
+        timeout: Duration,
+        length: usize,
+        written: Option<&[u8]>,
+    ) -> Result<&Record, Error> {
+        let mut next = self.lock();
+        let position = *next;
+        let mismatch = |expected: Option<&Record>, reason| Error::Mismatch {
+            position,
+            expected: expected.map(|record| record.request),
+            got,
+            reason,
+        };
+        let record = self
+            .records
+            .get(position)
+            .ok_or_else(|| mismatch(None, "past the end of the recording"))?;
+        if record.request != got {
+            return Err(mismatch(Some(record), "different request"));
+        }
+        if !self.timeout_matches(record.timeout, timeout) {
+            return Err(mismatch(Some(record), "different timeout"));
+        }
+        match written {
+            Some(data) if data != record.data.as_slice() => {
+                return Err(mismatch(Some(record), "different data written"))
+            }
+            None if length < record.data.len() => return Err(Error::Overflow),
+            _ => (),
+        }
+        *next += 1;
+        Ok(record)
+    }
+    fn read(
+        &self,
+        got: RecordedRequest,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let record = self.next_record(got, timeout, data.len(), None)?;
+        data[..record.data.len()].copy_from_slice(&record.data);
+        record.result.clone().map_err(Error::Recorded)
+    }
+    fn write(&self, got: RecordedRequest, data: &[u8], timeout: Duration) -> Result<usize, Error> {
+        let record = self.next_record(got, timeout, data.len(), Some(data))?;
+        record.result.clone().map_err(Error::Recorded)
+    }
+}
+impl AsyncUsbHandle for ReplayDevice {
+    type Error = Error;
+    fn control_read<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let got = RecordedRequest::control(
+            TransferKind::ControlRead,
+            request_type,
+            request,
+            value,
+            index,
+        );
+        Box::pin(core::future::ready(self.read(got, data, timeout)))
+    }
+    fn control_write<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let got = RecordedRequest::control(
+            TransferKind::ControlWrite,
+            request_type,
+            request,
+            value,
+            index,
+        );
+        Box::pin(core::future::ready(self.write(got, data, timeout)))
+    }
+    fn bulk_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let got = RecordedRequest::endpoint(TransferKind::BulkRead, endpoint);
+        Box::pin(core::future::ready(self.read(got, data, timeout)))
+    }
+    fn bulk_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let got = RecordedRequest::endpoint(TransferKind::BulkWrite, endpoint);
+        Box::pin(core::future::ready(self.write(got, data, timeout)))
+    }
+    fn interrupt_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let got = RecordedRequest::endpoint(TransferKind::InterruptRead, endpoint);
+        Box::pin(core::future::ready(self.read(got, data, timeout)))
+    }
+    fn interrupt_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Error>> {
+        let got = RecordedRequest::endpoint(TransferKind::InterruptWrite, endpoint);
+        Box::pin(core::future::ready(self.write(got, data, timeout)))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::replay::{
+        Error, FormatError, Record, RecordedRequest, RecordingDevice, ReplayDevice, Session,
+        TransferKind,
+    };
+    use crate::traits::AsyncUsbHandle;
+    use core::time::Duration;
+    use futures_util::FutureExt;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn session() -> Session {
+        let record = |request, length, data: &[u8], result| Record {
+            request,
+            timeout: TIMEOUT,
+            at: Duration::from_micros(1500),
+            duration: Duration::from_micros(250),
+            length,
+            data: data.to_vec(),
+            result,
+        };
+        Session {
+            records: vec![
+                record(
+                    RecordedRequest::control(TransferKind::ControlWrite, 0x20, 0, 0, 0),
+                    3,
+                    &[0x03, 0x0C, 0x00],
+                    Ok(3),
+                ),
+                record(
+                    RecordedRequest::endpoint(TransferKind::InterruptRead, 0x81),
+                    16,
+                    &[0x0E, 0x01],
+                    Ok(2),
+                ),
+                record(
+                    RecordedRequest::endpoint(TransferKind::BulkRead, 0x82),
+                    16,
+                    &[],
+                    Err("Operation timed out".to_string()),
+                ),
+            ],
+        }
+    }
+    /// Generic driver code under test.
+    async fn driver<H: AsyncUsbHandle>(handle: &H) -> Result<Vec<u8>, H::Error> {
+        handle
+            .control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x00], TIMEOUT)
+            .await?;
+        let mut event = [0_u8; 16];
+        let len = handle.interrupt_read(0x81, &mut event, TIMEOUT).await?;
+        handle.bulk_read(0x82, &mut [0_u8; 16], TIMEOUT).await?;
+        Ok(event[..len].to_vec())
+    }
+    #[test]
+    pub fn test_format_round_trip() {
+        let session = session();
+        let mut bytes = Vec::new();
+        session.write_to(&mut bytes).expect("in memory");
+        assert_eq!(&bytes[..10], b"USBWREC\0\x01\x00");
+        assert_eq!(
+            Session::read_from(bytes.as_slice()).expect("valid"),
+            session
+        );
+        // Truncated in the middle of a record
+        assert!(matches!(
+            Session::read_from(&bytes[..bytes.len() - 1]),
+            Err(FormatError::Io(_))
+        ));
+        bytes[8] = 2;
+        assert!(matches!(
+            Session::read_from(bytes.as_slice()),
+            Err(FormatError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Session::read_from(&b"RIFF\0\0\0\0\x01\x00"[..]),
+            Err(FormatError::BadMagic)
+        ));
+    }
+    #[test]
+    pub fn test_replay_and_record() {
+        // Recording a replay records the same session (timing aside)
+        let recorder = RecordingDevice::new(ReplayDevice::new(session()));
+        let result = driver(&recorder).now_or_never().expect("ready");
+        assert_eq!(result, Err(Error::Recorded("Operation timed out".into())));
+        let (replay, recorded) = recorder.into_parts();
+        assert!(replay.is_finished());
+        assert_eq!(recorded.records.len(), 3);
+        for (recorded, original) in recorded.records.iter().zip(session().records) {
+            assert_eq!(recorded.request, original.request);
+            assert_eq!(recorded.length, original.length);
+            assert_eq!(recorded.data, original.data);
+            assert_eq!(recorded.result, original.result);
+        }
+    }
+    #[test]
+    pub fn test_mismatch() {
+        let replay = ReplayDevice::new(session());
+        let write = replay
+            .control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x01], TIMEOUT)
+            .now_or_never();
+        assert!(matches!(
+            write,
+            Some(Err(Error::Mismatch {
+                position: 0,
+                reason: "different data written",
+                ..
+            }))
+        ));
+        // Nothing was consumed
+        assert_eq!(replay.remaining(), 3);
+        let write = replay
+            .control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x00], TIMEOUT * 2)
+            .now_or_never();
+        assert!(matches!(
+            write,
+            Some(Err(Error::Mismatch {
+                reason: "different timeout",
+                ..
+            }))
+        ));
+        let mut replay = replay;
+        replay.set_timeout_tolerance(Some(TIMEOUT));
+        let write = replay
+            .control_write(0x20, 0, 0, 0, &[0x03, 0x0C, 0x00], TIMEOUT * 2)
+            .now_or_never();
+        assert_eq!(write, Some(Ok(3)));
+        let err = replay
+            .bulk_read(0x82, &mut [0_u8; 16], TIMEOUT)
+            .now_or_never()
+            .expect("ready")
+            .expect_err("interrupt read is next");
+        assert_eq!(
+            err.to_string(),
+            "replay mismatch at transfer 1: different request (recorded InterruptRead 0x81, got BulkRead 0x82)"
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! (De)serializing types as strings through `Display` and `FromStr`.
+use core::fmt;
+use core::marker::PhantomData;
+use core::str::FromStr;
+use serde::de::{Deserializer, Error, Visitor};
+
+struct StrVisitor<T> {
+    expecting: &'static str,
+    _marker: PhantomData<T>,
+}
+impl<'de, T: FromStr> Visitor<'de> for StrVisitor<T>
+where
+    T::Err: fmt::Display,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+    fn visit_str<E: Error>(self, v: &str) -> Result<T, E> {
+        v.parse().map_err(E::custom)
+    }
+}
+/// Deserializes a string with `T::from_str`. `expecting` describes the format (`"a USB version
+/// like 2.1.0"`).
+pub(crate) fn deserialize<'de, D: Deserializer<'de>, T: FromStr>(
+    deserializer: D,
+    expecting: &'static str,
+) -> Result<T, D::Error>
+where
+    T::Err: fmt::Display,
+{
+    deserializer.deserialize_str(StrVisitor {
+        expecting,
+        _marker: PhantomData,
+    })
+}
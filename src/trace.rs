@@ -0,0 +1,24 @@
+//! Internal logging macros. With the `tracing` feature they forward to the `tracing` crate,
+//! without it they expand to nothing (the arguments aren't even evaluated).
+// Only the `libusb` module logs
+#![allow(unused_macros)]
+#[cfg(feature = "tracing")]
+macro_rules! usb_trace {
+    ($($arg:tt)*) => {
+        tracing::trace!($($arg)*)
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! usb_trace {
+    ($($arg:tt)*) => {};
+}
+#[cfg(feature = "tracing")]
+macro_rules! usb_debug {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! usb_debug {
+    ($($arg:tt)*) => {};
+}
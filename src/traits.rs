@@ -0,0 +1,406 @@
+//! Backend agnostic USB traits so code can be generic over libusb, WinUSB and rusb.
+use crate::device::Descriptor;
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::time::Duration;
+
+/// Boxed future returned by `AsyncUsbHandle` (traits can't have `async fn`s).
+pub type UsbFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// A device (opened or not) that can report its device descriptor.
+pub trait UsbDeviceInfo {
+    type Error;
+    fn descriptor(&self) -> Result<Descriptor, Self::Error>;
+}
+/// Blocking IO on an opened device. Same parameters as the libusb `DeviceHandle`. All return
+/// the amount of bytes transferred.
+pub trait UsbHandle {
+    type Error;
+    fn control_read(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Self::Error>;
+    fn control_write(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Self::Error>;
+    fn bulk_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Self::Error>;
+    fn bulk_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Self::Error>;
+    fn interrupt_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Self::Error>;
+    fn interrupt_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, Self::Error>;
+}
+/// Async IO on an opened device. Same parameters as `AsyncDevice`.
+pub trait AsyncUsbHandle {
+    type Error;
+    fn control_read<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Self::Error>>;
+    fn control_write<'a>(
+        &'a self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Self::Error>>;
+    fn bulk_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Self::Error>>;
+    fn bulk_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Self::Error>>;
+    fn interrupt_read<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a mut [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Self::Error>>;
+    fn interrupt_write<'a>(
+        &'a self,
+        endpoint: u8,
+        data: &'a [u8],
+        timeout: Duration,
+    ) -> UsbFuture<'a, Result<usize, Self::Error>>;
+}
+
+#[cfg(feature = "libusb")]
+mod libusb_impls {
+    use crate::device::Descriptor;
+    use crate::libusb::async_device::AsyncDevice;
+    use crate::libusb::device::Device;
+    use crate::libusb::device_handle::DeviceHandle;
+    use crate::libusb::error::Error;
+    use crate::traits::{AsyncUsbHandle, UsbDeviceInfo, UsbFuture, UsbHandle};
+    use alloc::boxed::Box;
+    use core::time::Duration;
+
+    impl UsbDeviceInfo for Device {
+        type Error = Error;
+        fn descriptor(&self) -> Result<Descriptor, Error> {
+            Ok(Descriptor::from(&self.device_descriptor()?))
+        }
+    }
+    impl UsbDeviceInfo for DeviceHandle {
+        type Error = Error;
+        fn descriptor(&self) -> Result<Descriptor, Error> {
+            self.device().descriptor()
+        }
+    }
+    impl UsbDeviceInfo for AsyncDevice {
+        type Error = Error;
+        fn descriptor(&self) -> Result<Descriptor, Error> {
+            self.device().descriptor()
+        }
+    }
+    impl UsbHandle for DeviceHandle {
+        type Error = Error;
+        fn control_read(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::control_read(self, request_type, request, value, index, data, timeout)
+        }
+        fn control_write(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &[u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::control_write(self, request_type, request, value, index, data, timeout)
+        }
+        fn bulk_read(
+            &self,
+            endpoint: u8,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::bulk_read(self, endpoint, data, timeout)
+        }
+        fn bulk_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize, Error> {
+            DeviceHandle::bulk_write(self, endpoint, data, timeout)
+        }
+        fn interrupt_read(
+            &self,
+            endpoint: u8,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::interrupt_read(self, endpoint, data, timeout)
+        }
+        fn interrupt_write(
+            &self,
+            endpoint: u8,
+            data: &[u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::interrupt_write(self, endpoint, data, timeout)
+        }
+    }
+    impl AsyncUsbHandle for AsyncDevice {
+        type Error = Error;
+        fn control_read<'a>(
+            &'a self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &'a mut [u8],
+            timeout: Duration,
+        ) -> UsbFuture<'a, Result<usize, Error>> {
+            Box::pin(AsyncDevice::control_read(
+                self,
+                request_type,
+                request,
+                value,
+                index,
+                data,
+                timeout,
+            ))
+        }
+        fn control_write<'a>(
+            &'a self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &'a [u8],
+            timeout: Duration,
+        ) -> UsbFuture<'a, Result<usize, Error>> {
+            Box::pin(AsyncDevice::control_write(
+                self,
+                request_type,
+                request,
+                value,
+                index,
+                data,
+                timeout,
+            ))
+        }
+        fn bulk_read<'a>(
+            &'a self,
+            endpoint: u8,
+            data: &'a mut [u8],
+            timeout: Duration,
+        ) -> UsbFuture<'a, Result<usize, Error>> {
+            Box::pin(AsyncDevice::bulk_read(self, endpoint, data, timeout))
+        }
+        fn bulk_write<'a>(
+            &'a self,
+            endpoint: u8,
+            data: &'a [u8],
+            timeout: Duration,
+        ) -> UsbFuture<'a, Result<usize, Error>> {
+            Box::pin(AsyncDevice::bulk_write(self, endpoint, data, timeout))
+        }
+        fn interrupt_read<'a>(
+            &'a self,
+            endpoint: u8,
+            data: &'a mut [u8],
+            timeout: Duration,
+        ) -> UsbFuture<'a, Result<usize, Error>> {
+            Box::pin(AsyncDevice::interrupt_read(self, endpoint, data, timeout))
+        }
+        fn interrupt_write<'a>(
+            &'a self,
+            endpoint: u8,
+            data: &'a [u8],
+            timeout: Duration,
+        ) -> UsbFuture<'a, Result<usize, Error>> {
+            Box::pin(AsyncDevice::interrupt_write(self, endpoint, data, timeout))
+        }
+    }
+}
+
+#[cfg(all(feature = "winusb", windows))]
+mod winusb_impls {
+    use crate::device::Descriptor;
+    use crate::traits::{UsbDeviceInfo, UsbHandle};
+    use crate::winusb::error::Error;
+    use crate::winusb::handle::DeviceHandle;
+    use core::time::Duration;
+
+    impl UsbDeviceInfo for DeviceHandle {
+        type Error = Error;
+        fn descriptor(&self) -> Result<Descriptor, Error> {
+            self.device_descriptor()
+        }
+    }
+    impl UsbHandle for DeviceHandle {
+        type Error = Error;
+        fn control_read(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::control_read(self, request_type, request, value, index, data, timeout)
+        }
+        fn control_write(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &[u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::control_write(self, request_type, request, value, index, data, timeout)
+        }
+        fn bulk_read(
+            &self,
+            endpoint: u8,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::bulk_read(self, endpoint, data, timeout)
+        }
+        fn bulk_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize, Error> {
+            DeviceHandle::bulk_write(self, endpoint, data, timeout)
+        }
+        fn interrupt_read(
+            &self,
+            endpoint: u8,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::interrupt_read(self, endpoint, data, timeout)
+        }
+        fn interrupt_write(
+            &self,
+            endpoint: u8,
+            data: &[u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::interrupt_write(self, endpoint, data, timeout)
+        }
+    }
+}
+
+#[cfg(feature = "rusb")]
+mod rusb_impls {
+    use crate::device::Descriptor;
+    use crate::error::Error;
+    use crate::manager::{Device, DeviceHandle};
+    use crate::traits::{UsbDeviceInfo, UsbHandle};
+    use core::time::Duration;
+
+    impl UsbDeviceInfo for Device {
+        type Error = Error;
+        fn descriptor(&self) -> Result<Descriptor, Error> {
+            Device::descriptor(self)
+        }
+    }
+    impl UsbDeviceInfo for DeviceHandle {
+        type Error = Error;
+        fn descriptor(&self) -> Result<Descriptor, Error> {
+            self.device().descriptor()
+        }
+    }
+    impl UsbHandle for DeviceHandle {
+        type Error = Error;
+        fn control_read(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::control_read(self, request_type, request, value, index, data, timeout)
+        }
+        fn control_write(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &[u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::control_write(self, request_type, request, value, index, data, timeout)
+        }
+        fn bulk_read(
+            &self,
+            endpoint: u8,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::bulk_read(self, endpoint, data, timeout)
+        }
+        fn bulk_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize, Error> {
+            DeviceHandle::bulk_write(self, endpoint, data, timeout)
+        }
+        fn interrupt_read(
+            &self,
+            endpoint: u8,
+            data: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::interrupt_read(self, endpoint, data, timeout)
+        }
+        fn interrupt_write(
+            &self,
+            endpoint: u8,
+            data: &[u8],
+            timeout: Duration,
+        ) -> Result<usize, Error> {
+            DeviceHandle::interrupt_write(self, endpoint, data, timeout)
+        }
+    }
+}
@@ -1,5 +1,11 @@
 //! USB Semvar versioning.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+use crate::error::ConversionError;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// USB BCD version (`bcdUSB`/`bcdDevice`). Comparing the raw BCD values orders versions
+/// correctly, so `Ord` is just the `u16` ordering. Serializes as the `Display` string.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub struct Version(pub u16);
 impl From<u16> for Version {
     fn from(i: u16) -> Self {
@@ -12,6 +18,13 @@ impl From<Version> for u16 {
     }
 }
 impl Version {
+    pub const USB1_1: Version = Version(0x0110);
+    pub const USB2_0: Version = Version(0x0200);
+    pub const USB3_0: Version = Version(0x0300);
+    pub const USB3_1: Version = Version(0x0310);
+    pub const fn from_bcd(bcd: u16) -> Version {
+        Version(bcd)
+    }
     /// Creates a new USB BCD (Binary Coded Decimal) Version in the format `A.B.C`, where `A` is
     /// the major, `B` is the minor, and `C` is the sub minor. `A` is 8 bits while `B` and `C` are
     /// only `4 bits`.
@@ -19,10 +32,10 @@ impl Version {
     /// Panics if `minor > 0x0F_u8 || sub_minor > 0x0F_u8`
     pub fn new(major: u8, minor: u8, sub_minor: u8) -> Version {
         assert!(
-            minor <= 0x0F_u8 && sub_minor < 0x0F_u8,
+            minor <= 0x0F_u8 && sub_minor <= 0x0F_u8,
             "minor or sub_minor greater than 0x0F"
         );
-        Version(u16::from(major) << 8 | u16::from(minor << 4) | u16::from(sub_minor))
+        Version((u16::from(major) << 8) | (u16::from(minor) << 4) | u16::from(sub_minor))
     }
     pub const fn major(self) -> u8 {
         ((self.0 & 0xFF00_u16) >> 8) as u8
@@ -34,3 +47,120 @@ impl Version {
         (self.0 & 0x000F_u16) as u8
     }
 }
+/// Displays as `major.minor.sub_minor` (`2.1.0` for `0x0210`).
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:x}.{:x}.{:x}",
+            self.major(),
+            self.minor(),
+            self.sub_minor()
+        )
+    }
+}
+/// Parses `A.B.C`, `A.B` or `A.BC` (how `bcdUSB` is usually written, `2.10` is `2.1.0`). `A` is
+/// one or two decimal digits, `B` and `C` are single decimal digits.
+impl core::str::FromStr for Version {
+    type Err = ConversionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digit = |s: &str| match s.as_bytes() {
+            [d @ b'0'..=b'9'] => Ok(d - b'0'),
+            _ => Err(ConversionError::new("USB version")),
+        };
+        let mut parts = s.split('.');
+        let major = parts.next().ok_or(ConversionError::new("USB version"))?;
+        let major = match major.len() {
+            1 => digit(major)?,
+            2 => (digit(&major[..1])? << 4) | digit(&major[1..])?,
+            _ => return Err(ConversionError::new("USB version")),
+        };
+        let (minor, sub_minor) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(minor), Some(sub_minor), None) => (digit(minor)?, digit(sub_minor)?),
+            (Some(minor), None, None) if minor.len() == 2 && minor.is_ascii() => {
+                (digit(&minor[..1])?, digit(&minor[1..])?)
+            }
+            (Some(minor), None, None) => (digit(minor)?, 0),
+            _ => return Err(ConversionError::new("USB version")),
+        };
+        Ok(Version::new(major, minor, sub_minor))
+    }
+}
+#[cfg(feature = "serde")]
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::serde_str::deserialize(deserializer, "a USB version like 2.1.0")
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::version::Version;
+
+    #[test]
+    pub fn test_new_boundaries() {
+        assert_eq!(Version::new(0, 0, 0), Version(0x0000));
+        assert_eq!(Version::new(2, 0x0F, 0), Version(0x02F0));
+        assert_eq!(Version::new(2, 0, 0x0F), Version(0x020F));
+        assert_eq!(Version::new(0xFF, 0x0F, 0x0F), Version(0xFFFF));
+        let v = Version::new(0x12, 0x0F, 0x0F);
+        assert_eq!((v.major(), v.minor(), v.sub_minor()), (0x12, 0x0F, 0x0F));
+    }
+    #[test]
+    #[should_panic]
+    pub fn test_new_minor_too_big() {
+        Version::new(2, 0x10, 0);
+    }
+    #[test]
+    #[should_panic]
+    pub fn test_new_sub_minor_too_big() {
+        Version::new(2, 0, 0x10);
+    }
+    #[test]
+    pub fn test_display() {
+        assert_eq!(Version::USB2_0.to_string(), "2.0.0");
+        assert_eq!(Version::USB1_1.to_string(), "1.1.0");
+        assert_eq!(Version::from_bcd(0x0210).to_string(), "2.1.0");
+        assert_eq!(Version::from_bcd(0x1099).to_string(), "10.9.9");
+    }
+    #[test]
+    pub fn test_from_str() {
+        assert_eq!("2.10".parse(), Ok(Version(0x0210)));
+        assert_eq!("2.1.0".parse(), Ok(Version(0x0210)));
+        assert_eq!("2.1".parse(), Ok(Version(0x0210)));
+        assert_eq!("3.0".parse(), Ok(Version::USB3_0));
+        assert_eq!("0.0.0".parse(), Ok(Version(0x0000)));
+        assert_eq!("99.9.9".parse(), Ok(Version(0x9999)));
+        assert_eq!("1.11".parse(), Ok(Version(0x0111)));
+        for bad in &[
+            "", "2", "2.", "2.a", "100.0", "2.1.10", "2.1.0.0", "2.100", "a.1",
+        ] {
+            assert!(bad.parse::<Version>().is_err(), "{} parsed", bad);
+        }
+    }
+    #[test]
+    pub fn test_ordering() {
+        assert!(Version::USB1_1 < Version::USB2_0);
+        assert!(Version::USB2_0 < Version::from_bcd(0x0201));
+        assert!(Version::from_bcd(0x0210) < Version::USB3_0);
+        assert!(Version::USB3_0 < Version::USB3_1);
+        assert!(Version::from_bcd(0x0999) < Version::from_bcd(0x1000));
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn test_serde() {
+        let json = serde_json::to_string(&Version::from_bcd(0x0210)).expect("serializes");
+        assert_eq!(json, "\"2.1.0\"");
+        assert_eq!(
+            serde_json::from_str::<Version>(&json).expect("deserializes"),
+            Version::from_bcd(0x0210)
+        );
+        assert!(serde_json::from_str::<Version>("\"2.a\"").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Device enumeration through SetupAPI.
+use crate::device::{DeviceIdentifier, ProductID, VendorID};
+use crate::winusb::error::Error;
+use crate::winusb::handle::DeviceHandle;
+use std::os::windows::ffi::OsStringExt;
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::shared::usbiodef::GUID_DEVINTERFACE_USB_DEVICE;
+use winapi::shared::winerror::ERROR_NO_MORE_ITEMS;
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::setupapi::{
+    SetupDiDestroyDeviceInfoList, SetupDiEnumDeviceInterfaces, SetupDiGetClassDevsW,
+    SetupDiGetDeviceInterfaceDetailW, DIGCF_DEVICEINTERFACE, DIGCF_PRESENT, HDEVINFO,
+    SP_DEVICE_INTERFACE_DATA, SP_DEVICE_INTERFACE_DETAIL_DATA_W,
+};
+
+/// Destroys the device information set on drop.
+struct InfoSet(HDEVINFO);
+impl Drop for InfoSet {
+    fn drop(&mut self) {
+        unsafe { SetupDiDestroyDeviceInfoList(self.0) };
+    }
+}
+impl InfoSet {
+    /// Returns the nul terminated device path of an interface.
+    fn interface_path(&self, interface: &mut SP_DEVICE_INTERFACE_DATA) -> Result<Vec<u16>, Error> {
+        let mut required: DWORD = 0;
+        // Only asking for the size, so this "fails" with `ERROR_INSUFFICIENT_BUFFER`
+        unsafe {
+            SetupDiGetDeviceInterfaceDetailW(
+                self.0,
+                interface,
+                core::ptr::null_mut(),
+                0,
+                &mut required,
+                core::ptr::null_mut(),
+            )
+        };
+        if required == 0 {
+            return Err(Error::last());
+        }
+        // `u32`s so the detail struct is aligned
+        let mut buf = vec![0_u32; (required as usize + 3) / 4];
+        let detail = buf.as_mut_ptr() as *mut SP_DEVICE_INTERFACE_DETAIL_DATA_W;
+        unsafe {
+            (*detail).cbSize = core::mem::size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_W>() as DWORD
+        };
+        let ok = unsafe {
+            SetupDiGetDeviceInterfaceDetailW(
+                self.0,
+                interface,
+                detail,
+                required,
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+            )
+        };
+        if ok == FALSE {
+            return Err(Error::last());
+        }
+        let path_ptr = unsafe { (*detail).DevicePath.as_ptr() };
+        let max_len = (required as usize - (path_ptr as usize - detail as usize)) / 2;
+        let path = unsafe { core::slice::from_raw_parts(path_ptr, max_len) };
+        let len = path.iter().position(|&c| c == 0).unwrap_or(max_len);
+        Ok(path[..len].iter().copied().chain(Some(0)).collect())
+    }
+}
+/// Parses the `vid_XXXX&pid_XXXX` out of a device path like
+/// `\\?\usb#vid_1234&pid_5678#serial#{guid}`.
+fn parse_identifier(path: &str) -> Option<DeviceIdentifier> {
+    let path = path.to_ascii_lowercase();
+    let hex_after = |prefix: &str| {
+        let start = path.find(prefix)? + prefix.len();
+        u16::from_str_radix(path.get(start..start + 4)?, 16).ok()
+    };
+    Some(DeviceIdentifier {
+        vendor_id: VendorID(hex_after("vid_")?),
+        product_id: ProductID(hex_after("pid_")?),
+    })
+}
+/// A present USB device. Only devices using the WinUSB driver can be opened.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// Nul terminated.
+    path: Vec<u16>,
+    device_identifier: Option<DeviceIdentifier>,
+}
+impl DeviceInfo {
+    pub fn path(&self) -> std::ffi::OsString {
+        std::ffi::OsString::from_wide(&self.path[..self.path.len() - 1])
+    }
+    /// The vendor and product id from the device path. `None` if the path doesn't have them.
+    pub fn device_identifier(&self) -> Option<DeviceIdentifier> {
+        self.device_identifier
+    }
+    pub fn open(&self) -> Result<DeviceHandle, Error> {
+        DeviceHandle::open_wide(&self.path)
+    }
+}
+/// The USB devices present when the list was made.
+#[derive(Clone, Debug)]
+pub struct DeviceList(Vec<DeviceInfo>);
+impl DeviceList {
+    pub fn new() -> Result<DeviceList, Error> {
+        let set = unsafe {
+            SetupDiGetClassDevsW(
+                &GUID_DEVINTERFACE_USB_DEVICE,
+                core::ptr::null(),
+                core::ptr::null_mut(),
+                DIGCF_PRESENT | DIGCF_DEVICEINTERFACE,
+            )
+        };
+        if set == INVALID_HANDLE_VALUE {
+            return Err(Error::last());
+        }
+        let set = InfoSet(set);
+        let mut devices = Vec::new();
+        for index in 0.. {
+            let mut interface: SP_DEVICE_INTERFACE_DATA = unsafe { core::mem::zeroed() };
+            interface.cbSize = core::mem::size_of::<SP_DEVICE_INTERFACE_DATA>() as DWORD;
+            let ok = unsafe {
+                SetupDiEnumDeviceInterfaces(
+                    set.0,
+                    core::ptr::null_mut(),
+                    &GUID_DEVINTERFACE_USB_DEVICE,
+                    index,
+                    &mut interface,
+                )
+            };
+            if ok == FALSE {
+                match Error::last() {
+                    Error(ERROR_NO_MORE_ITEMS) => break,
+                    e => return Err(e),
+                }
+            }
+            let path = set.interface_path(&mut interface)?;
+            let device_identifier = parse_identifier(&String::from_utf16_lossy(&path));
+            devices.push(DeviceInfo {
+                path,
+                device_identifier,
+            });
+        }
+        Ok(DeviceList(devices))
+    }
+    pub fn as_slice(&self) -> &[DeviceInfo] {
+        &self.0
+    }
+    pub fn iter(&self) -> core::slice::Iter<'_, DeviceInfo> {
+        self.0.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+impl<'a> IntoIterator for &'a DeviceList {
+    type Item = &'a DeviceInfo;
+    type IntoIter = core::slice::Iter<'a, DeviceInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
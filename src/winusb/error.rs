@@ -0,0 +1,28 @@
+use core::fmt;
+use winapi::shared::winerror::{ERROR_INVALID_PARAMETER, ERROR_SEM_TIMEOUT};
+
+/// Win32 error code (`GetLastError`).
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Error(pub u32);
+impl Error {
+    pub const INVALID_PARAM: Error = Error(ERROR_INVALID_PARAMETER);
+    /// What WinUSB fails with when a pipe's `PIPE_TRANSFER_TIMEOUT` elapses.
+    pub const TIMEOUT: Error = Error(ERROR_SEM_TIMEOUT);
+    pub fn last() -> Error {
+        Error(unsafe { winapi::um::errhandlingapi::GetLastError() })
+    }
+    pub fn is_timeout(self) -> bool {
+        self == Error::TIMEOUT
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&std::io::Error::from_raw_os_error(self.0 as i32), f)
+    }
+}
+impl std::error::Error for Error {}
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::from_raw_os_error(e.0 as i32)
+    }
+}
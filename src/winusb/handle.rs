@@ -0,0 +1,260 @@
+use crate::descriptors::{parse_device_descriptor, DEVICE_DESCRIPTOR_SIZE};
+use crate::device::Descriptor;
+use crate::winusb::error::Error;
+use core::convert::TryInto;
+use std::os::windows::ffi::OsStrExt;
+use winapi::shared::minwindef::{BOOL, FALSE, ULONG};
+use winapi::shared::usbspec::USB_DEVICE_DESCRIPTOR_TYPE;
+use winapi::shared::winusbio::PIPE_TRANSFER_TIMEOUT;
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::winbase::FILE_FLAG_OVERLAPPED;
+use winapi::um::winnt::{
+    FILE_ATTRIBUTE_NORMAL, FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE, HANDLE,
+};
+use winapi::um::winusb::{
+    WinUsb_ControlTransfer, WinUsb_Free, WinUsb_GetDescriptor, WinUsb_Initialize, WinUsb_ReadPipe,
+    WinUsb_SetPipePolicy, WinUsb_WritePipe, WINUSB_INTERFACE_HANDLE, WINUSB_SETUP_PACKET,
+};
+
+const ENDPOINT_DIR_MASK: u8 = 0x80;
+const ENDPOINT_IN: u8 = 0x80;
+
+fn check(ok: BOOL) -> Result<(), Error> {
+    if ok == FALSE {
+        Err(Error::last())
+    } else {
+        Ok(())
+    }
+}
+fn len_to_ulong(len: usize) -> Result<ULONG, Error> {
+    len.try_into().map_err(|_| Error::INVALID_PARAM)
+}
+fn setup_packet(
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    len: usize,
+) -> Result<WINUSB_SETUP_PACKET, Error> {
+    Ok(WINUSB_SETUP_PACKET {
+        RequestType: request_type,
+        Request: request,
+        Value: value,
+        Index: index,
+        Length: len.try_into().map_err(|_| Error::INVALID_PARAM)?,
+    })
+}
+
+/// Opened WinUSB device (the first interface). Same methods as the `libusb` `DeviceHandle` so
+/// code can be written against either.
+pub struct DeviceHandle {
+    file: HANDLE,
+    interface: WINUSB_INTERFACE_HANDLE,
+}
+unsafe impl Send for DeviceHandle {}
+unsafe impl Sync for DeviceHandle {}
+impl DeviceHandle {
+    /// Opens a device interface path (see `DeviceInfo::path`).
+    pub fn open(path: &std::ffi::OsStr) -> Result<DeviceHandle, Error> {
+        let path: Vec<u16> = path.encode_wide().chain(Some(0)).collect();
+        Self::open_wide(&path)
+    }
+    /// `path` must be nul terminated.
+    pub(crate) fn open_wide(path: &[u16]) -> Result<DeviceHandle, Error> {
+        debug_assert_eq!(path.last(), Some(&0), "path isn't nul terminated");
+        let file = unsafe {
+            CreateFileW(
+                path.as_ptr(),
+                GENERIC_READ | GENERIC_WRITE,
+                FILE_SHARE_READ | FILE_SHARE_WRITE,
+                core::ptr::null_mut(),
+                OPEN_EXISTING,
+                // WinUSB requires overlapped handles (even for blocking calls)
+                FILE_ATTRIBUTE_NORMAL | FILE_FLAG_OVERLAPPED,
+                core::ptr::null_mut(),
+            )
+        };
+        if file == INVALID_HANDLE_VALUE {
+            return Err(Error::last());
+        }
+        let mut interface = core::ptr::null_mut();
+        if let Err(e) = check(unsafe { WinUsb_Initialize(file, &mut interface) }) {
+            unsafe { CloseHandle(file) };
+            return Err(e);
+        }
+        Ok(DeviceHandle { file, interface })
+    }
+    pub fn interface_handle(&self) -> WINUSB_INTERFACE_HANDLE {
+        self.interface
+    }
+    fn set_timeout(&self, pipe: u8, timeout: core::time::Duration) -> Result<(), Error> {
+        // 0 is no timeout, same as libusb. Any other timeout is rounded up so it can't become 0.
+        let millis = timeout.as_millis() + u128::from(timeout.subsec_nanos() % 1_000_000 != 0);
+        let mut ms: ULONG = millis.try_into().unwrap_or(ULONG::MAX);
+        check(unsafe {
+            WinUsb_SetPipePolicy(
+                self.interface,
+                pipe,
+                PIPE_TRANSFER_TIMEOUT,
+                core::mem::size_of::<ULONG>() as ULONG,
+                &mut ms as *mut ULONG as *mut _,
+            )
+        })
+    }
+    fn control_transfer(
+        &self,
+        setup: WINUSB_SETUP_PACKET,
+        data: *mut u8,
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.set_timeout(0, timeout)?;
+        let mut transferred: ULONG = 0;
+        check(unsafe {
+            WinUsb_ControlTransfer(
+                self.interface,
+                setup,
+                data,
+                ULONG::from(setup.Length),
+                &mut transferred,
+                core::ptr::null_mut(),
+            )
+        })?;
+        Ok(transferred as usize)
+    }
+    pub fn control_read(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        if request_type & ENDPOINT_DIR_MASK != ENDPOINT_IN {
+            return Err(Error::INVALID_PARAM);
+        }
+        let setup = setup_packet(request_type, request, value, index, data.len())?;
+        self.control_transfer(setup, data.as_mut_ptr(), timeout)
+    }
+    pub fn control_write(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        if request_type & ENDPOINT_DIR_MASK == ENDPOINT_IN {
+            return Err(Error::INVALID_PARAM);
+        }
+        // WinUSB doesn't write to the buffer of an OUT transfer
+        let setup = setup_packet(request_type, request, value, index, data.len())?;
+        self.control_transfer(setup, data.as_ptr() as *mut u8, timeout)
+    }
+    fn read_pipe(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        if endpoint & ENDPOINT_DIR_MASK != ENDPOINT_IN {
+            return Err(Error::INVALID_PARAM);
+        }
+        self.set_timeout(endpoint, timeout)?;
+        let mut transferred: ULONG = 0;
+        check(unsafe {
+            WinUsb_ReadPipe(
+                self.interface,
+                endpoint,
+                data.as_mut_ptr(),
+                len_to_ulong(data.len())?,
+                &mut transferred,
+                core::ptr::null_mut(),
+            )
+        })?;
+        Ok(transferred as usize)
+    }
+    fn write_pipe(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        if endpoint & ENDPOINT_DIR_MASK == ENDPOINT_IN {
+            return Err(Error::INVALID_PARAM);
+        }
+        self.set_timeout(endpoint, timeout)?;
+        let mut transferred: ULONG = 0;
+        check(unsafe {
+            WinUsb_WritePipe(
+                self.interface,
+                endpoint,
+                data.as_ptr() as *mut u8,
+                len_to_ulong(data.len())?,
+                &mut transferred,
+                core::ptr::null_mut(),
+            )
+        })?;
+        Ok(transferred as usize)
+    }
+    pub fn bulk_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.write_pipe(endpoint, data, timeout)
+    }
+    pub fn bulk_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.read_pipe(endpoint, data, timeout)
+    }
+    /// WinUSB uses the same calls for bulk and interrupt pipes.
+    pub fn interrupt_write(
+        &self,
+        endpoint: u8,
+        data: &[u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.write_pipe(endpoint, data, timeout)
+    }
+    pub fn interrupt_read(
+        &self,
+        endpoint: u8,
+        data: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.read_pipe(endpoint, data, timeout)
+    }
+    /// Reads the device descriptor (cached by WinUSB, no bus traffic).
+    pub fn device_descriptor(&self) -> Result<Descriptor, Error> {
+        let mut buf = [0_u8; DEVICE_DESCRIPTOR_SIZE];
+        let mut transferred: ULONG = 0;
+        check(unsafe {
+            WinUsb_GetDescriptor(
+                self.interface,
+                USB_DEVICE_DESCRIPTOR_TYPE,
+                0,
+                0,
+                buf.as_mut_ptr(),
+                buf.len() as ULONG,
+                &mut transferred,
+            )
+        })?;
+        parse_device_descriptor(&buf[..transferred as usize]).map_err(|_| Error::INVALID_PARAM)
+    }
+}
+impl Drop for DeviceHandle {
+    fn drop(&mut self) {
+        unsafe {
+            WinUsb_Free(self.interface);
+            CloseHandle(self.file);
+        }
+    }
+}
@@ -1,2 +1,5 @@
-
+//! Native Windows backend using WinUSB (the device must have WinUSB as its driver). Mirrors the
+//! blocking parts of the `libusb` `DeviceHandle`.
+pub mod device_list;
+pub mod error;
 pub mod handle;